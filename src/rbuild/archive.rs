@@ -0,0 +1,534 @@
+//! Writes static libraries in the `ar` format directly, without a system
+//! archiver. Members are stored with zeroed timestamps, owners and modes, so
+//! archives of the same objects are always identical. The symbol index the
+//! linker needs is built from the ELF and Mach-O symbol tables of the
+//! members.
+
+use std::io::{File, IoResult};
+use std::io::fs;
+use std::str;
+
+/// The flavor of the archive.
+#[deriving(Clone, Eq, Show, Encodable, Decodable)]
+pub enum Format {
+    /// Used by GNU and LLVM tools, with a `/` symbol index and a `//` table
+    /// of long member names.
+    Gnu,
+    /// Used by macOS and the BSDs, with a `__.SYMDEF` symbol index and long
+    /// member names stored in front of their data.
+    Bsd,
+}
+
+impl Format {
+    /// The format the platform's linker expects.
+    #[cfg(target_os = "macos")]
+    pub fn native() -> Format {
+        Bsd
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    pub fn native() -> Format {
+        Gnu
+    }
+}
+
+static MAGIC: &'static str = "!<arch>\n";
+static HEADER_LEN: uint = 60;
+
+struct Member {
+    name: ~str,
+    data: Vec<u8>,
+    symbols: Vec<~str>,
+}
+
+/// Writes the archive `dst` of the files in `members`, in order. The
+/// archive is written next to `dst` and renamed into place.
+pub fn write(dst: &Path, members: &[Path], format: Format) -> IoResult<()> {
+    let mut loaded = Vec::new();
+
+    for path in members.iter() {
+        let data = try!(File::open(path).read_to_end());
+        let symbols = object_symbols(path, data.as_slice());
+
+        loaded.push(Member {
+            name: path.filename_str().unwrap().to_owned(),
+            data: data,
+            symbols: symbols,
+        });
+    }
+
+    let bytes = match format {
+        Gnu => gnu_archive(loaded.as_slice()),
+        Bsd => bsd_archive(loaded.as_slice()),
+    };
+
+    let tmp = dst.with_filename(format!("{}.tmp", dst.filename_str().unwrap()));
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(file.write(bytes.as_slice()));
+    }
+    fs::rename(&tmp, dst)
+}
+
+/// Pads `s` with spaces to `width`, as the fields of member headers are.
+fn field(s: &str, width: uint) -> ~str {
+    assert!(s.len() <= width, "`{}` doesn't fit in an archive header", s);
+
+    let mut field = StrBuf::from_str(s);
+    for _ in range(s.len(), width) {
+        field.push_char(' ');
+    }
+    field.into_owned()
+}
+
+fn push_header(out: &mut Vec<u8>, name: &str, size: uint) {
+    let header = format!("{}{}{}{}{}{}`\n",
+        field(name, 16),
+        field("0", 12),
+        field("0", 6),
+        field("0", 6),
+        field("644", 8),
+        field(size.to_str(), 10));
+
+    out.push_all(header.as_bytes());
+}
+
+/// Members start at even offsets.
+fn pad(out: &mut Vec<u8>) {
+    if out.len() % 2 == 1 {
+        out.push('\n' as u8);
+    }
+}
+
+fn padded(len: uint) -> uint {
+    len + len % 2
+}
+
+fn push_be_u32(out: &mut Vec<u8>, n: u32) {
+    out.push((n >> 24) as u8);
+    out.push((n >> 16) as u8);
+    out.push((n >> 8) as u8);
+    out.push(n as u8);
+}
+
+fn push_le_u32(out: &mut Vec<u8>, n: u32) {
+    out.push(n as u8);
+    out.push((n >> 8) as u8);
+    out.push((n >> 16) as u8);
+    out.push((n >> 24) as u8);
+}
+
+fn gnu_archive(members: &[Member]) -> Vec<u8> {
+    // Names that don't fit in the header go in the `//` member, and are
+    // referred to by their offset in it.
+    let mut long_names = Vec::new();
+    let mut names = Vec::new();
+
+    for member in members.iter() {
+        if member.name.len() < 16 {
+            names.push(format!("{}/", member.name));
+        } else {
+            names.push(format!("/{}", long_names.len()));
+            long_names.push_all(member.name.as_bytes());
+            long_names.push_all(bytes!("/\n"));
+        }
+    }
+
+    let symbol_count = members.iter().fold(0, |n, member| n + member.symbols.len());
+    let symbol_names_len = members.iter().fold(0, |n, member| {
+        member.symbols.iter().fold(n, |n, symbol| n + symbol.len() + 1)
+    });
+    let index_len = 4 + 4 * symbol_count + symbol_names_len;
+
+    let mut offset = MAGIC.len() + HEADER_LEN + padded(index_len);
+    if !long_names.is_empty() {
+        offset += HEADER_LEN + padded(long_names.len());
+    }
+
+    let mut offsets = Vec::new();
+    for member in members.iter() {
+        offsets.push(offset);
+        offset += HEADER_LEN + padded(member.data.len());
+    }
+
+    let mut out = Vec::new();
+    out.push_all(MAGIC.as_bytes());
+
+    push_header(&mut out, "/", index_len);
+    push_be_u32(&mut out, symbol_count as u32);
+    for (member, offset) in members.iter().zip(offsets.iter()) {
+        for _ in member.symbols.iter() {
+            push_be_u32(&mut out, *offset as u32);
+        }
+    }
+    for member in members.iter() {
+        for symbol in member.symbols.iter() {
+            out.push_all(symbol.as_bytes());
+            out.push(0);
+        }
+    }
+    pad(&mut out);
+
+    if !long_names.is_empty() {
+        push_header(&mut out, "//", long_names.len());
+        out.push_all(long_names.as_slice());
+        pad(&mut out);
+    }
+
+    for (member, name) in members.iter().zip(names.iter()) {
+        push_header(&mut out, name.as_slice(), member.data.len());
+        out.push_all(member.data.as_slice());
+        pad(&mut out);
+    }
+
+    out
+}
+
+/// Whether `name` has to be stored in front of the member's data.
+fn bsd_long_name(name: &str) -> bool {
+    name.len() > 16 || name.contains(" ")
+}
+
+fn bsd_archive(members: &[Member]) -> Vec<u8> {
+    let mut strings = Vec::new();
+    let mut string_offsets = Vec::new();
+
+    for member in members.iter() {
+        for symbol in member.symbols.iter() {
+            string_offsets.push(strings.len());
+            strings.push_all(symbol.as_bytes());
+            strings.push(0);
+        }
+    }
+
+    let index_len = 4 + 8 * string_offsets.len() + 4 + strings.len();
+
+    let mut offset = MAGIC.len() + HEADER_LEN + padded(index_len);
+    let mut offsets = Vec::new();
+    for member in members.iter() {
+        offsets.push(offset);
+
+        let mut len = member.data.len();
+        if bsd_long_name(member.name) {
+            len += member.name.len();
+        }
+        offset += HEADER_LEN + padded(len);
+    }
+
+    let mut out = Vec::new();
+    out.push_all(MAGIC.as_bytes());
+
+    push_header(&mut out, "__.SYMDEF", index_len);
+    push_le_u32(&mut out, (8 * string_offsets.len()) as u32);
+
+    let mut strx = string_offsets.iter();
+    for (member, offset) in members.iter().zip(offsets.iter()) {
+        for _ in member.symbols.iter() {
+            push_le_u32(&mut out, *strx.next().unwrap() as u32);
+            push_le_u32(&mut out, *offset as u32);
+        }
+    }
+
+    push_le_u32(&mut out, strings.len() as u32);
+    out.push_all(strings.as_slice());
+    pad(&mut out);
+
+    for member in members.iter() {
+        if bsd_long_name(member.name) {
+            let mut name = StrBuf::from_str("#1/");
+            name.push_str(member.name.len().to_str());
+            push_header(&mut out, name.as_slice(), member.name.len() + member.data.len());
+            out.push_all(member.name.as_bytes());
+        } else {
+            push_header(&mut out, member.name, member.data.len());
+        }
+
+        out.push_all(member.data.as_slice());
+        pad(&mut out);
+    }
+
+    out
+}
+
+/// Reads the integers of an object file, failing with the file's name if
+/// it's truncated.
+struct Object<'a> {
+    path: &'a Path,
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Object<'a> {
+    fn uint(&self, offset: uint, size: uint) -> uint {
+        if offset + size > self.data.len() {
+            fail!("{} is truncated", self.path.display());
+        }
+
+        let bytes = self.data.slice(offset, offset + size);
+        let mut n = 0;
+        for i in range(0, size) {
+            let byte = if self.big_endian { bytes[i] } else { bytes[size - 1 - i] };
+            n = (n << 8) | byte as uint;
+        }
+        n
+    }
+
+    fn u8(&self, offset: uint) -> uint { self.uint(offset, 1) }
+    fn u16(&self, offset: uint) -> uint { self.uint(offset, 2) }
+    fn u32(&self, offset: uint) -> uint { self.uint(offset, 4) }
+    fn u64(&self, offset: uint) -> uint { self.uint(offset, 8) }
+
+    /// The NUL-terminated string at `offset`.
+    fn cstr(&self, offset: uint) -> ~str {
+        let mut end = offset;
+        while end < self.data.len() && self.data[end] != 0 {
+            end += 1;
+        }
+
+        str::from_utf8_lossy(self.data.slice(offset, end)).into_owned()
+    }
+}
+
+/// The global symbols `data` defines, if it's an ELF or Mach-O object.
+/// Anything else, such as an LLVM bitcode file, contributes nothing to the
+/// index.
+fn object_symbols(path: &Path, data: &[u8]) -> Vec<~str> {
+    if data.starts_with(bytes!("\x7fELF")) && data.len() > 5 {
+        let object = Object { path: path, data: data, big_endian: data[5] == 2 };
+        elf_symbols(&object, data[4] == 2)
+    } else if data.starts_with([0xce, 0xfa, 0xed, 0xfe]) {
+        let object = Object { path: path, data: data, big_endian: false };
+        macho_symbols(&object, false)
+    } else if data.starts_with([0xcf, 0xfa, 0xed, 0xfe]) {
+        let object = Object { path: path, data: data, big_endian: false };
+        macho_symbols(&object, true)
+    } else {
+        Vec::new()
+    }
+}
+
+fn elf_symbols(object: &Object, is_64: bool) -> Vec<~str> {
+    static SHT_SYMTAB: uint = 2;
+    static STB_GLOBAL: uint = 1;
+    static STB_WEAK: uint = 2;
+    static SHN_UNDEF: uint = 0;
+
+    let (shoff, shentsize, shnum) = if is_64 {
+        (object.u64(0x28), object.u16(0x3a), object.u16(0x3c))
+    } else {
+        (object.u32(0x20), object.u16(0x2e), object.u16(0x30))
+    };
+
+    // The offset, size, link and entry size of a section.
+    let section = |index: uint| -> (uint, uint, uint, uint) {
+        let base = shoff + index * shentsize;
+        if is_64 {
+            (object.u64(base + 0x18), object.u64(base + 0x20),
+             object.u32(base + 0x28), object.u64(base + 0x38))
+        } else {
+            (object.u32(base + 0x10), object.u32(base + 0x14),
+             object.u32(base + 0x18), object.u32(base + 0x24))
+        }
+    };
+
+    let mut symbols = Vec::new();
+
+    for index in range(0, shnum) {
+        if object.u32(shoff + index * shentsize + 4) != SHT_SYMTAB {
+            continue;
+        }
+
+        let (offset, size, link, entsize) = section(index);
+        let (strtab, _, _, _) = section(link);
+
+        // The first symbol is always the null symbol.
+        for i in range(1, size / entsize) {
+            let sym = offset + i * entsize;
+            let (name, info, shndx) = if is_64 {
+                (object.u32(sym), object.u8(sym + 4), object.u16(sym + 6))
+            } else {
+                (object.u32(sym), object.u8(sym + 12), object.u16(sym + 14))
+            };
+
+            let bind = info >> 4;
+            if (bind == STB_GLOBAL || bind == STB_WEAK) && shndx != SHN_UNDEF {
+                symbols.push(object.cstr(strtab + name));
+            }
+        }
+    }
+
+    symbols
+}
+
+fn macho_symbols(object: &Object, is_64: bool) -> Vec<~str> {
+    static LC_SYMTAB: uint = 2;
+    static N_STAB: uint = 0xe0;
+    static N_TYPE: uint = 0x0e;
+    static N_EXT: uint = 0x01;
+    static N_UNDF: uint = 0x00;
+
+    let ncmds = object.u32(16);
+    let mut cmd = if is_64 { 32 } else { 28 };
+
+    let mut symbols = Vec::new();
+
+    for _ in range(0, ncmds) {
+        if object.u32(cmd) == LC_SYMTAB {
+            let symoff = object.u32(cmd + 8);
+            let nsyms = object.u32(cmd + 12);
+            let stroff = object.u32(cmd + 16);
+            let nlist_size = if is_64 { 16 } else { 12 };
+
+            for i in range(0, nsyms) {
+                let nlist = symoff + i * nlist_size;
+                let strx = object.u32(nlist);
+                let n_type = object.u8(nlist + 4);
+
+                // Common symbols are undefined with a size as their value.
+                let value = if is_64 { object.u64(nlist + 8) } else { object.u32(nlist + 8) };
+                let defined = n_type & N_TYPE != N_UNDF || value != 0;
+
+                if n_type & N_STAB == 0 && n_type & N_EXT != 0 && defined {
+                    symbols.push(object.cstr(stroff + strx));
+                }
+            }
+        }
+
+        cmd += object.u32(cmd + 4);
+    }
+
+    symbols
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{File, Process, TempDir};
+    use std::str;
+    use super::{Member, MAGIC, HEADER_LEN, gnu_archive, bsd_archive, object_symbols, write, Format};
+
+    fn member(name: &str, data: &str, symbols: &[&str]) -> Member {
+        Member {
+            name: name.to_owned(),
+            data: Vec::from_slice(data.as_bytes()),
+            symbols: symbols.iter().map(|symbol| symbol.to_owned()).collect(),
+        }
+    }
+
+    fn be_u32(bytes: &[u8], offset: uint) -> uint {
+        range(0u, 4).fold(0, |n, i| (n << 8) | bytes[offset + i] as uint)
+    }
+
+    fn le_u32(bytes: &[u8], offset: uint) -> uint {
+        range(0u, 4).fold(0, |n, i| (n << 8) | bytes[offset + 3 - i] as uint)
+    }
+
+    #[test]
+    fn test_gnu_index() {
+        let out = gnu_archive([
+            member("a.o", "aaaa", ["foo", "bar"]),
+            member("a_very_long_name.o", "bbb", ["baz"]),
+        ]);
+        let out = out.as_slice();
+
+        assert!(out.starts_with(MAGIC.as_bytes()));
+        assert_eq!(out.slice(8, 24), "/               ".as_bytes());
+
+        let index = MAGIC.len() + HEADER_LEN;
+        assert_eq!(be_u32(out, index), 3);
+        assert_eq!(out.slice(index + 16, index + 28), "foo\0bar\0baz\0".as_bytes());
+
+        // Every symbol points at the header of the member defining it.
+        let (foo, bar, baz) = (be_u32(out, index + 4), be_u32(out, index + 8), be_u32(out, index + 12));
+        assert_eq!(foo, bar);
+        assert_eq!(out.slice(foo, foo + 16), "a.o/            ".as_bytes());
+        assert_eq!(out.slice(foo + HEADER_LEN, foo + HEADER_LEN + 4), "aaaa".as_bytes());
+
+        // The long name is at offset 0 of the `//` member.
+        assert_eq!(out.slice(baz, baz + 16), "/0              ".as_bytes());
+        assert!(out.len() % 2 == 0);
+    }
+
+    #[test]
+    fn test_bsd_index() {
+        let out = bsd_archive([
+            member("a.o", "aaaa", ["_foo"]),
+            member("a_very_long_name.o", "bbb", ["_bar"]),
+        ]);
+        let out = out.as_slice();
+
+        assert_eq!(out.slice(8, 24), "__.SYMDEF       ".as_bytes());
+
+        let index = MAGIC.len() + HEADER_LEN;
+        assert_eq!(le_u32(out, index), 16);
+        assert_eq!(le_u32(out, index + 20), 10);
+        assert_eq!(out.slice(index + 24, index + 34), "_foo\0_bar\0".as_bytes());
+
+        let (foo, bar) = (le_u32(out, index + 8), le_u32(out, index + 16));
+        assert_eq!(le_u32(out, index + 4), 0);
+        assert_eq!(le_u32(out, index + 12), 5);
+        assert_eq!(out.slice(foo, foo + 16), "a.o             ".as_bytes());
+
+        // The long name is stored in front of the data.
+        assert_eq!(out.slice(bar, bar + 16), "#1/18           ".as_bytes());
+        assert_eq!(out.slice(bar + HEADER_LEN, bar + HEADER_LEN + 21), "a_very_long_name.obbb".as_bytes());
+    }
+
+    /// Runs a tool, returning its stdout, or `None` if it isn't installed.
+    fn run(prog: &str, args: &[~str]) -> Option<~str> {
+        match Process::output(prog, args) {
+            Ok(output) => {
+                assert!(output.status.success(), "{} failed: {}", prog,
+                        str::from_utf8_lossy(output.error.as_slice()));
+                Some(str::from_utf8(output.output.as_slice()).unwrap().to_owned())
+            }
+            Err(_) => None,
+        }
+    }
+
+    // The members and symbol index of a real object are read back the same
+    // way by the system's `ar` and `nm`.
+    #[test]
+    fn test_against_ar() {
+        let dir = TempDir::new("rbuild-archive").unwrap();
+        let src = dir.path().join("foo.c");
+        let obj = dir.path().join("foo.o");
+        let lib = dir.path().join("libfoo.a");
+
+        File::create(&src).write_str("int foo(void) { return 1; }\n\
+                                      int bar = 2;\n\
+                                      static int baz(void) { return 3; }\n\
+                                      extern int qux(void);\n").unwrap();
+
+        let args = [~"-c", src.as_str().unwrap().to_owned(), ~"-o", obj.as_str().unwrap().to_owned()];
+        if run("cc", args).is_none() {
+            return;
+        }
+
+        let data = File::open(&obj).read_to_end().unwrap();
+        let mut symbols = object_symbols(&obj, data.as_slice());
+        symbols.sort();
+        let prefix = if symbols.iter().any(|symbol| symbol.starts_with("_")) { "_" } else { "" };
+        assert_eq!(symbols, vec!(prefix + "bar", prefix + "foo"));
+
+        write(&lib, [obj.clone()], Format::native()).unwrap();
+
+        match run("ar", [~"t", lib.as_str().unwrap().to_owned()]) {
+            Some(members) => { assert_eq!(members, ~"foo.o\n"); }
+            None => { return; }
+        }
+
+        match run("nm", [~"-s", lib.as_str().unwrap().to_owned()]) {
+            Some(output) => {
+                let mut index: Vec<~str> = output.lines()
+                    .skip_while(|line| !line.starts_with("Archive index:"))
+                    .skip(1)
+                    .take_while(|line| !line.is_empty())
+                    .map(|line| line.to_owned())
+                    .collect();
+                index.sort();
+                assert_eq!(index, vec!(format!("{}bar in foo.o", prefix), format!("{}foo in foo.o", prefix)));
+            }
+            None => { }
+        }
+    }
+}
@@ -2,128 +2,218 @@ use std::io;
 use std::io::fs;
 use sync::Future;
 
+use archive;
+use builders::tool::{Tool, ToolBase};
 use context::{Context, Call};
 use into_path::IntoPath;
 use into_future::IntoFuture;
 use path_util;
 
+/// The command line conventions of the archiver.
+#[deriving(Clone, Eq)]
+pub enum ArchiverKind {
+    /// `ar -rc dst srcs...`
+    SystemAr,
+    /// `libtool -static -o dst srcs...`, which always writes a symbol table
+    /// the macOS linker accepts.
+    Libtool,
+    /// rbuild writes the archive itself. See `archive`.
+    Builtin(archive::Format),
+}
+
+impl ArchiverKind {
+    /// Guesses the kind of the archiver `exe` from its name.
+    pub fn detect(exe: &Path) -> ArchiverKind {
+        match exe.filename_str() {
+            Some(name) if name.contains("libtool") => Libtool,
+            _ => SystemAr,
+        }
+    }
+}
+
 #[deriving(Clone)]
 pub struct Ar {
-    ctx: Context,
-    exe: Path,
-    dst_prefix: Option<&'static str>,
-    dst_suffix: Option<&'static str>,
-    dst: Option<Path>,
-    srcs: Vec<Path>,
-    flags: Vec<~str>,
+    base: ToolBase,
+    kind: ArchiverKind,
+    /// Run on the archive once it's written, to build its symbol table.
+    ranlib: Option<Path>,
 }
 
+#[cfg(not(target_os = "macos"))]
 static EXES: &'static [&'static str] = &["ar"];
 
+/// Archives written by `ar` on macOS can have a stale or missing symbol
+/// table, so `libtool` is preferred.
+#[cfg(target_os = "macos")]
+static EXES: &'static [&'static str] = &["libtool", "ar"];
+
+static RANLIB_EXES: &'static [&'static str] = &["ranlib"];
+
 impl Ar {
+    /// Use the system archiver, or the builtin one if there isn't any.
     pub fn new(ctx: Context) -> Ar {
-        let exe = path_util::find_program(ctx.clone(), EXES);
-        Ar::new_with(ctx, exe)
+        let exe = match path_util::try_find_program(ctx.clone(), EXES) {
+            Ok(exe) => exe,
+            Err(_) => { return Ar::new_builtin(ctx, archive::Format::native()); }
+        };
+        let ar = Ar::new_with(ctx.clone(), exe);
+
+        // When macOS has to fall back to `ar`, the archive is indexed
+        // afterwards.
+        if cfg!(target_os = "macos") && ar.kind == SystemAr {
+            let ranlib = path_util::find_program(ctx, RANLIB_EXES);
+            ar.set_ranlib(ranlib)
+        } else {
+            ar
+        }
     }
 
+    /// Use the archiver `exe`, whose kind is detected from its name.
     pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Ar {
-        let mut flags = Vec::new();
-        flags.push(~"-rc");
+        let exe = exe.into_future().unwrap();
+        let kind = ArchiverKind::detect(&exe);
+        let mut base = ToolBase::new(ctx, exe);
+
+        match kind {
+            SystemAr => {
+                // `D` leaves timestamps, owners and modes out of the archive.
+                if base.ctx.deterministic() {
+                    base.flags.push(~"-rcD");
+                } else {
+                    base.flags.push(~"-rc");
+                }
+            }
+            Libtool => {
+                base.flags.push(~"-static");
+                if base.ctx.deterministic() {
+                    base.flags.push(~"-D");
+                }
+            }
+            Builtin(_) => { }
+        }
+
         Ar {
-            ctx: ctx,
-            exe: exe.into_future().unwrap(),
-            dst_prefix: None,
-            dst_suffix: None,
-            dst: None,
-            srcs: Vec::new(),
-            flags: flags,
+            base: base,
+            kind: kind,
+            ranlib: None,
         }
     }
 
-    pub fn set_dst_prefix(mut self, dst_prefix: &'static str) -> Ar {
-        self.dst_prefix = Some(dst_prefix);
-        self
+    /// Write archives in `format` without running any program. They're
+    /// always deterministic, and flags are ignored.
+    pub fn new_builtin(ctx: Context, format: archive::Format) -> Ar {
+        Ar {
+            base: ToolBase::new(ctx, Path::new("ar")),
+            kind: Builtin(format),
+            ranlib: None,
+        }
     }
 
-    pub fn set_dst_suffix(mut self, dst_suffix: &'static str) -> Ar {
-        self.dst_suffix = Some(dst_suffix);
+    /// Run `ranlib` on the archive after it's written, for archivers that
+    /// don't index archives themselves.
+    pub fn set_ranlib<T: IntoFuture<Path>>(mut self, ranlib: T) -> Ar {
+        self.ranlib = Some(ranlib.into_future().unwrap());
         self
     }
+}
 
-    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Ar {
-        let mut dst = dst.into_path();
+tool_methods!(Ar)
 
-        // Make sure we write the output in the build/ directory.
-        if !dst.is_ancestor_of(&self.ctx.root) {
-            dst = self.ctx.root.join(dst);
+impl Ar {
+    fn write_builtin(self, format: archive::Format) -> Future<Path> {
+        let dst = self.base.dst();
+        let srcs = self.base.all_srcs();
+
+        let mut prep = self.base.ctx.prep("Archive");
+        prep.declare_input("value", "format", &format);
+        prep.declare_input("value", "dst", &dst);
+        // The order of the members matters to the linker.
+        prep.declare_input("value", "srcs", &srcs);
+        for src in srcs.iter() {
+            prep.declare_input_path(src.clone()).unwrap();
         }
 
-        self.dst = Some(dst);
-        self
-    }
+        prep.exec(proc(exec) {
+            // Make sure the parent directories exist.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
 
-    pub fn add_src<T: IntoFuture<Path>>(mut self, src: T) -> Ar {
-        self.srcs.push(src.into_future().unwrap());
-        self
-    }
+            match archive::write(&dst, srcs.as_slice(), format) {
+                Ok(()) => { }
+                Err(err) => fail!("failed to write {}: {}", dst.display(), err),
+            }
+            exec.discover_output_path("output", &dst);
 
-    pub fn add_flag<T: Str>(mut self, flag: T) -> Ar {
-        self.flags.push(flag.into_owned());
-        self
+            dst
+        })
     }
+}
 
-    pub fn run(self) -> Path {
-        self.into_future().unwrap()
+impl Tool for Ar {
+    /// Fails for the builtin archiver, which doesn't run a command.
+    fn to_call(&self) -> Call {
+        match self.kind {
+            Builtin(_) => { fail!("the builtin archiver doesn't have a command line"); }
+            _ => { }
+        }
+
+        let mut call = self.base.call();
+        self.base.push_flags(&mut call);
+
+        match self.kind {
+            Libtool => { call.push_str(~"-o"); }
+            _ => { }
+        }
+
+        call.push_output_path(self.base.dst());
+        self.base.push_srcs(&mut call);
+        call
     }
 }
 
 impl IntoFuture<Path> for Ar {
     fn into_future(self) -> Future<Path> {
-        let Ar {
-            ctx,
-            exe,
-            dst_prefix,
-            dst_suffix,
-            dst,
-            srcs,
-            flags
-        } = self;
-
-        assert!(dst.is_some());
-        let mut dst = dst.unwrap();
-        dst = path_util::add_prefix_suffix(dst, dst_prefix, dst_suffix);
-
-        let mut call = Call::new(exe.clone()).unwrap();
-
-        for flag in flags.move_iter() {
-            call.push_str(flag);
+        match self.kind {
+            Builtin(format) => { return self.write_builtin(format); }
+            _ => { }
         }
 
-        call.push_output_path(dst.clone());
+        let call = self.to_call();
+        let Ar { base, kind: _, ranlib } = self;
 
-        for src in srcs.iter() {
-            call.push_input_path(src.clone()).unwrap();
-        }
+        let exe = base.exe.clone();
+        let dst = base.dst();
+        let srcs = base.all_srcs();
 
-        let mut prep = ctx.prep("Call");
+        let mut prep = base.ctx.prep("Call");
         prep.declare_call(&call);
 
+        match ranlib {
+            Some(ref ranlib) => { prep.declare_input_path(ranlib.clone()).unwrap(); }
+            None => { }
+        }
+
         prep.exec(proc(exec) {
-            let (prog, args) = call.cmd();
+            let (prog, args) = call.tmp_cmd().unwrap();
 
             // Make sure the parent directories exist.
             fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
 
-            let status = exec.process_builder(prog, args.as_slice())
+            exec.process_builder(prog, args.as_slice())
                 .description(exe.filename_display())
                 .msg(dst.display())
                 .msg("<-")
                 .msgs(srcs.iter().map(|src| src.display()))
-                .run()
-                .unwrap();
-
-            if !status.success() {
-                fail!("command failed");
+                .run_or_fail();
+            call.rename_outputs().unwrap();
+
+            match ranlib {
+                Some(ranlib) => {
+                    exec.process_builder(ranlib.as_str().unwrap(), [dst.as_str().unwrap().to_owned()])
+                        .description(ranlib.filename_display())
+                        .msg(dst.display())
+                        .run_or_fail();
+                }
+                None => { }
             }
 
             dst
@@ -0,0 +1,168 @@
+use std::io;
+use std::io::fs;
+use sync::Future;
+
+use builders::c::gcc;
+use builders::tool::{Tool, ToolBase};
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static EXES: &'static [&'static str] = &'static ["as"];
+
+/// Assembles `.s` sources with the assembler, and `.S` sources, which need
+/// the C preprocessor, with the C compiler driver.
+#[deriving(Clone)]
+pub struct Assembler {
+    /// The assembler is the base's program.
+    base: ToolBase,
+    /// The C compiler driver, for `.S` sources. It's looked up when it's
+    /// first needed, unless it was given to `new_with`.
+    cc_exe: Option<Path>,
+    includes: Vec<Path>,
+    macros: Vec<~str>,
+}
+
+impl Assembler {
+    pub fn new(ctx: Context) -> Assembler {
+        let exe = path_util::find_program(ctx.clone(), EXES);
+
+        Assembler {
+            base: ToolBase::new(ctx, exe),
+            cc_exe: None,
+            includes: Vec::new(),
+            macros: Vec::new(),
+        }
+    }
+
+    pub fn new_with<T: IntoFuture<Path>, U: IntoFuture<Path>>(
+        ctx: Context,
+        as_exe: T,
+        cc_exe: U
+    ) -> Assembler {
+        Assembler {
+            base: ToolBase::new(ctx, as_exe),
+            cc_exe: Some(cc_exe.into_future().unwrap()),
+            includes: Vec::new(),
+            macros: Vec::new(),
+        }
+    }
+
+    /// Assemble `src` into an object file next to it in the build directory.
+    pub fn compile<T: IntoFuture<Path>>(&self, src: T) -> Assembler {
+        let src = src.into_future().unwrap();
+        let dst = src.with_extension("o");
+
+        self.clone()
+            .set_dst(dst)
+            .set_src(src)
+    }
+
+    /// Assemble `src`, the only source an assembly takes.
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Assembler {
+        self.base.srcs = vec!(src.into_future().unwrap());
+        self
+    }
+
+    pub fn add_include<T: IntoFuture<Path>>(mut self, include: T) -> Assembler {
+        self.includes.push(include.into_future().unwrap());
+        self
+    }
+
+    /// Define a macro, as `NAME` or `NAME=VALUE`. Plain `.s` files get it
+    /// as an assembler symbol via `--defsym`.
+    pub fn add_macro<T: Str>(mut self, macro: T) -> Assembler {
+        self.macros.push(macro.into_owned());
+        self
+    }
+
+    /// The source, which only `.S` files run through the preprocessor.
+    fn src(&self) -> (Path, bool) {
+        let srcs = self.base.all_srcs();
+        assert!(srcs.len() == 1, "an assembly takes exactly one source");
+
+        let src = srcs.get(0).clone();
+        let preprocess = src.extension_str() == Some("S");
+        (src, preprocess)
+    }
+}
+
+tool_methods!(Assembler)
+
+impl Tool for Assembler {
+    fn to_call(&self) -> Call {
+        let (src, preprocess) = self.src();
+
+        let mut call = if preprocess {
+            let cc_exe = match self.cc_exe {
+                Some(ref exe) => exe.clone(),
+                None => path_util::find_program(self.base.ctx.clone(), gcc::EXES).unwrap(),
+            };
+            let mut call = Call::new(cc_exe).unwrap();
+            call.push_str(~"-c");
+            call.push_str(~"-x");
+            call.push_str(~"assembler-with-cpp");
+            call
+        } else {
+            self.base.call()
+        };
+
+        call.push_str(~"-o");
+        call.push_output_path(self.base.dst());
+
+        for include in self.includes.iter() {
+            call.push_str(~"-I");
+            call.push_input_path(include.clone()).unwrap();
+        }
+
+        for macro in self.macros.iter() {
+            if preprocess {
+                call.push_str(~"-D");
+                call.push_str(macro.clone());
+            } else {
+                call.push_str(~"--defsym");
+                if macro.contains_char('=') {
+                    call.push_str(macro.clone());
+                } else {
+                    call.push_str(format!("{}=1", macro));
+                }
+            }
+        }
+
+        self.base.push_flags(&mut call);
+        call.push_input_path(src).ok().expect("src");
+        call
+    }
+}
+
+impl IntoFuture<Path> for Assembler {
+    fn into_future(self) -> Future<Path> {
+        let call = self.to_call();
+        let (src, _) = self.src();
+
+        let (exe, _) = call.cmd();
+        let exe = Path::new(exe);
+        let dst = self.base.dst();
+
+        let mut prep = self.base.ctx.prep("Call");
+        prep.declare_call(&call);
+
+        prep.exec(proc(exec) {
+            let (prog, args) = call.tmp_cmd().unwrap();
+
+            // Make sure the parent directories exist.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            exec.process_builder(prog, args.as_slice())
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msg(src.display())
+                .run_or_fail();
+            call.rename_outputs().unwrap();
+
+            dst
+        })
+    }
+}
@@ -0,0 +1,265 @@
+//! Lays out macOS application bundles:
+//!
+//! ```text
+//! Foo.app/Contents/Info.plist
+//! Foo.app/Contents/PkgInfo
+//! Foo.app/Contents/MacOS/foo
+//! Foo.app/Contents/Resources/...
+//! ```
+
+use std::io;
+use std::io::{fs, File};
+use sync::Future;
+
+use builders::sign::Codesign;
+use context::Context;
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+/// The keys of a bundle's `Info.plist`.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct InfoPlist {
+    /// `CFBundleIdentifier`, e.g. "com.example.foo".
+    pub identifier: ~str,
+    /// `CFBundleShortVersionString`, the version shown to users.
+    pub version: ~str,
+    /// `CFBundleVersion`, the build number. Defaults to the version.
+    pub build_version: Option<~str>,
+    /// `CFBundleIconFile`, the name of an icon in the resources.
+    pub icon: Option<~str>,
+    /// `LSMinimumSystemVersion`, e.g. "10.7".
+    pub minimum_system_version: Option<~str>,
+    /// Any other string keys.
+    pub extra: Vec<(~str, ~str)>,
+}
+
+impl InfoPlist {
+    pub fn new<T: Str, U: Str>(identifier: T, version: U) -> InfoPlist {
+        InfoPlist {
+            identifier: identifier.into_owned(),
+            version: version.into_owned(),
+            build_version: None,
+            icon: None,
+            minimum_system_version: None,
+            extra: Vec::new(),
+        }
+    }
+
+    pub fn set_build_version<T: Str>(mut self, build_version: T) -> InfoPlist {
+        self.build_version = Some(build_version.into_owned());
+        self
+    }
+
+    pub fn set_icon<T: Str>(mut self, icon: T) -> InfoPlist {
+        self.icon = Some(icon.into_owned());
+        self
+    }
+
+    pub fn set_minimum_system_version<T: Str>(mut self, version: T) -> InfoPlist {
+        self.minimum_system_version = Some(version.into_owned());
+        self
+    }
+
+    pub fn add_key<T: Str, U: Str>(mut self, key: T, value: U) -> InfoPlist {
+        self.extra.push((key.into_owned(), value.into_owned()));
+        self
+    }
+
+    /// Renders the plist for the bundle `name` whose executable is
+    /// `executable`.
+    pub fn render(&self, name: &str, executable: &str) -> ~str {
+        let mut keys = vec!(
+            (~"CFBundleDevelopmentRegion", ~"English"),
+            (~"CFBundleExecutable", executable.to_owned()),
+            (~"CFBundleIdentifier", self.identifier.clone()),
+            (~"CFBundleInfoDictionaryVersion", ~"6.0"),
+            (~"CFBundleName", name.to_owned()),
+            (~"CFBundlePackageType", ~"APPL"),
+            (~"CFBundleShortVersionString", self.version.clone()),
+            (~"CFBundleVersion", self.build_version.clone().unwrap_or(self.version.clone())));
+
+        match self.icon {
+            Some(ref icon) => { keys.push((~"CFBundleIconFile", icon.clone())); }
+            None => { }
+        }
+
+        match self.minimum_system_version {
+            Some(ref version) => { keys.push((~"LSMinimumSystemVersion", version.clone())); }
+            None => { }
+        }
+
+        keys.push_all(self.extra.as_slice());
+
+        let mut plist = StrBuf::new();
+        plist.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        plist.push_str("<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+                        \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n");
+        plist.push_str("<plist version=\"1.0\">\n<dict>\n");
+        for &(ref key, ref value) in keys.iter() {
+            plist.push_str(format!("    <key>{}</key>\n", escape_xml(key.as_slice())));
+            plist.push_str(format!("    <string>{}</string>\n", escape_xml(value.as_slice())));
+        }
+        plist.push_str("</dict>\n</plist>\n");
+
+        plist.into_owned()
+    }
+}
+
+fn escape_xml(s: &str) -> ~str {
+    let mut escaped = StrBuf::new();
+
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push_char(c),
+        }
+    }
+
+    escaped.into_owned()
+}
+
+#[deriving(Clone)]
+pub struct Bundle {
+    ctx: Context,
+    name: ~str,
+    dst: Option<Path>,
+    plist: InfoPlist,
+    executable: Option<Path>,
+    binaries: Vec<Path>,
+    resources: Vec<Path>,
+    resource_dirs: Vec<Path>,
+    codesign: Option<Codesign>,
+}
+
+impl Bundle {
+    /// A bundle called `name`, which becomes `name.app`.
+    pub fn new<T: Str>(ctx: Context, name: T, plist: InfoPlist) -> Bundle {
+        Bundle {
+            ctx: ctx,
+            name: name.into_owned(),
+            dst: None,
+            plist: plist,
+            executable: None,
+            binaries: Vec::new(),
+            resources: Vec::new(),
+            resource_dirs: Vec::new(),
+            codesign: None,
+        }
+    }
+
+    /// Set where the bundle goes. Defaults to `name.app` in the build root.
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Bundle {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    /// Set the program the bundle launches.
+    pub fn set_executable<T: IntoFuture<Path>>(mut self, executable: T) -> Bundle {
+        self.executable = Some(executable.into_future().unwrap());
+        self
+    }
+
+    /// Add another program to `Contents/MacOS`.
+    pub fn add_binary<T: IntoFuture<Path>>(mut self, binary: T) -> Bundle {
+        self.binaries.push(binary.into_future().unwrap());
+        self
+    }
+
+    /// Add a file to `Contents/Resources`.
+    pub fn add_resource<T: IntoFuture<Path>>(mut self, resource: T) -> Bundle {
+        self.resources.push(resource.into_future().unwrap());
+        self
+    }
+
+    /// Add a directory, such as `en.lproj`, to `Contents/Resources`.
+    pub fn add_resource_dir<T: IntoPath>(mut self, dir: T) -> Bundle {
+        self.resource_dirs.push(dir.into_path());
+        self
+    }
+
+    /// Sign the finished bundle with `codesign`, whose source is set to the
+    /// bundle. The signed copy is what `run` returns.
+    pub fn set_codesign(mut self, codesign: Codesign) -> Bundle {
+        self.codesign = Some(codesign);
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+
+    fn layout(self) -> Future<Path> {
+        let Bundle { ctx, name, dst, plist, executable, binaries, resources, resource_dirs, .. } = self;
+
+        assert!(executable.is_some());
+        let executable = executable.unwrap();
+        let dst = dst.unwrap_or_else(|| ctx.root.join(format!("{}.app", name)));
+
+        let mut prep = ctx.prep("Bundle");
+        prep.declare_input("value", "name", &name);
+        prep.declare_input("value", "dst", &dst);
+        prep.declare_input("value", "plist", &plist);
+        prep.declare_input("value", "executable", &executable);
+        prep.declare_input("value", "binaries", &binaries);
+        prep.declare_input("value", "resources", &resources);
+        prep.declare_input("value", "resource_dirs", &resource_dirs);
+
+        prep.declare_input_path(executable.clone()).unwrap();
+        for path in binaries.iter().chain(resources.iter()) {
+            prep.declare_input_path(path.clone()).unwrap();
+        }
+        for dir in resource_dirs.iter() {
+            prep.declare_input_dir(dir.clone()).unwrap();
+        }
+
+        prep.exec(proc(exec) {
+            // Start over, so nothing that was dropped from the bundle lingers.
+            if dst.is_dir() {
+                fs::rmdir_recursive(&dst).unwrap();
+            }
+
+            let contents = dst.join("Contents");
+            let macos = contents.join("MacOS");
+            let res = contents.join("Resources");
+            fs::mkdir_recursive(&macos, io::UserDir).unwrap();
+            fs::mkdir_recursive(&res, io::UserDir).unwrap();
+
+            for path in Some(&executable).move_iter().chain(binaries.iter()) {
+                fs::copy(path, &macos.join(path.filename().unwrap())).unwrap();
+            }
+
+            for path in resources.iter() {
+                fs::copy(path, &res.join(path.filename().unwrap())).unwrap();
+            }
+
+            for dir in resource_dirs.iter() {
+                path_util::copy_dir(dir, &res.join(dir.filename().unwrap())).unwrap();
+            }
+
+            let executable_name = executable.filename_str().unwrap();
+            let info = plist.render(name.as_slice(), executable_name);
+            File::create(&contents.join("Info.plist")).write_str(info).unwrap();
+            File::create(&contents.join("PkgInfo")).write_str("APPL????").unwrap();
+
+            exec.discover_output_path("output", &dst);
+
+            dst
+        })
+    }
+}
+
+impl IntoFuture<Path> for Bundle {
+    fn into_future(self) -> Future<Path> {
+        let codesign = self.codesign.clone();
+        let bundle = self.layout();
+
+        match codesign {
+            Some(codesign) => codesign.set_src(bundle).into_future(),
+            None => bundle,
+        }
+    }
+}
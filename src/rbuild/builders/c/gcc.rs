@@ -1,41 +1,340 @@
 use std::io;
+use std::io::File;
 use std::io::fs;
+use std::os;
+use serialize::{json, Encodable, Decodable};
 use sync::Future;
 
+use builders::c::library::IntoLibrary;
+use builders::c::search_dirs::SearchDirs;
+use builders::pgo;
+use builders::tool::{Tool, ToolBase};
 use context::{Context, Call};
 use into_path::IntoPath;
 use into_future::IntoFuture;
 use path_util;
+use shared_future::SharedFuture;
 
 pub static EXES: &'static [&'static str] = &'static ["gcc", "cc"];
 
+/// The default memory weight of a link, which usually needs several times
+/// the memory of a compile.
+pub static LINK_MEMORY_WEIGHT: uint = 4;
+
+/// How many warnings the compiler should report.
+#[deriving(Clone, Eq)]
+pub enum WarningLevel {
+    /// Disable all warnings (`-w`).
+    NoWarnings,
+    /// Whatever the compiler reports by default.
+    DefaultWarnings,
+    AllWarnings,
+    ExtraWarnings,
+    /// Every warning the compiler knows about. Only clang has a flag for
+    /// this, so gcc gets a broad selection instead.
+    EverythingWarnings,
+}
+
+impl WarningLevel {
+    fn flags(&self, clang: bool) -> Vec<~str> {
+        match *self {
+            NoWarnings => vec!(~"-w"),
+            DefaultWarnings => vec!(),
+            AllWarnings => vec!(~"-Wall"),
+            ExtraWarnings => vec!(~"-Wall", ~"-Wextra"),
+            EverythingWarnings if clang => vec!(~"-Weverything"),
+            EverythingWarnings => vec!(
+                ~"-Wall",
+                ~"-Wextra",
+                ~"-pedantic",
+                ~"-Wshadow",
+                ~"-Wconversion"),
+        }
+    }
+}
+
+/// A runtime sanitizer to instrument the build with.
+#[deriving(Clone, Eq)]
+pub enum Sanitizer {
+    AddressSanitizer,
+    UndefinedSanitizer,
+    ThreadSanitizer,
+    /// Only supported by clang.
+    MemorySanitizer,
+}
+
+impl Sanitizer {
+    /// The name of the build variant, which is also the directory that
+    /// instrumented outputs are written to.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AddressSanitizer => "asan",
+            UndefinedSanitizer => "ubsan",
+            ThreadSanitizer => "tsan",
+            MemorySanitizer => "msan",
+        }
+    }
+
+    fn flags(&self) -> Vec<~str> {
+        match *self {
+            AddressSanitizer => vec!(~"-fsanitize=address", ~"-fno-omit-frame-pointer"),
+            UndefinedSanitizer => vec!(~"-fsanitize=undefined"),
+            ThreadSanitizer => vec!(~"-fsanitize=thread"),
+            MemorySanitizer => vec!(~"-fsanitize=memory", ~"-fno-omit-frame-pointer"),
+        }
+    }
+}
+
+/// A phase of a profile-guided optimization build, with the directory the
+/// profiles are kept in. See `builders::pgo`.
+#[deriving(Clone, Eq)]
+pub enum Pgo {
+    /// Instrument the build to record profiles when it runs.
+    PgoInstrument(Path),
+    /// Optimize with the profiles recorded by the instrumented build.
+    PgoUse(Path),
+}
+
+impl Pgo {
+    /// The name of the build variant, which is also the directory that
+    /// its outputs are written to.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PgoInstrument(_) => "pgo_instrument",
+            PgoUse(_) => "pgo_use",
+        }
+    }
+
+    fn flags(&self, clang: bool) -> Vec<~str> {
+        match *self {
+            PgoInstrument(ref dir) => {
+                vec!(format!("-fprofile-generate={}", pgo::raw_dir(dir).display()))
+            }
+            PgoUse(ref dir) => {
+                // Clang reads the merged profile, gcc a directory of them.
+                let data = pgo::data_dir(dir);
+                let data = if clang { data.join(pgo::PROFDATA) } else { data };
+                vec!(format!("-fprofile-use={}", data.display()), ~"-fprofile-correction")
+            }
+        }
+    }
+}
+
+/// Flags for compiling hardened code.
+static HARDENING_COMPILE_FLAGS: &'static [&'static str] = &[
+    "-fstack-protector-strong",
+    "-fPIE",
+];
+
+/// Checks buffer sizes in the C library. It needs the optimizer, and glibc
+/// warns about it otherwise.
+static HARDENING_OPTIMIZED_FLAGS: &'static [&'static str] = &[
+    "-D_FORTIFY_SOURCE=2",
+];
+
+/// Flags for linking hardened executables.
+#[cfg(target_os = "linux")]
+static HARDENING_LINK_FLAGS: &'static [&'static str] = &[
+    "-pie",
+    "-Wl,-z,relro",
+    "-Wl,-z,now",
+];
+
+/// Executables are always position independent on macOS, and its linker has
+/// no notion of relro.
+#[cfg(target_os = "macos")]
+static HARDENING_LINK_FLAGS: &'static [&'static str] = &[
+    "-Wl,-pie",
+];
+
+/// MinGW marks executables as relocatable and non-executable-data instead.
+#[cfg(windows)]
+static HARDENING_LINK_FLAGS: &'static [&'static str] = &[
+    "-Wl,--dynamicbase",
+    "-Wl,--nxcompat",
+];
+
+/// Links every member of the archives in `libs`, instead of only those
+/// that resolve undefined symbols.
+#[cfg(target_os = "linux")]
+#[cfg(windows)]
+fn push_whole_archives(call: &mut Call, libs: Vec<Path>) {
+    if libs.is_empty() { return; }
+
+    call.push_str(~"-Wl,--whole-archive");
+    for lib in libs.move_iter() {
+        call.push_input_path(lib).unwrap();
+    }
+    call.push_str(~"-Wl,--no-whole-archive");
+}
+
+#[cfg(target_os = "macos")]
+fn push_whole_archives(call: &mut Call, libs: Vec<Path>) {
+    for lib in libs.move_iter() {
+        call.push_str(~"-Wl,-force_load");
+        call.push_input_path(lib).unwrap();
+    }
+}
+
+/// Renders the list of symbols a shared library exports as a version
+/// script, hiding everything else.
+#[cfg(target_os = "linux")]
+fn render_exports(symbols: &[~str]) -> ~str {
+    let mut s = StrBuf::from_str("{\n  global:\n");
+    for symbol in symbols.iter() {
+        s.push_str(format!("    {};\n", symbol));
+    }
+    s.push_str("  local: *;\n};\n");
+    s.into_owned()
+}
+
+#[cfg(target_os = "macos")]
+fn render_exports(symbols: &[~str]) -> ~str {
+    let mut s = StrBuf::new();
+    for symbol in symbols.iter() {
+        s.push_str(format!("_{}\n", symbol));
+    }
+    s.into_owned()
+}
+
+/// Renders the exports of a DLL as a module-definition (`.def`) file.
+#[cfg(windows)]
+fn render_exports(symbols: &[~str]) -> ~str {
+    let mut s = StrBuf::from_str("EXPORTS\n");
+    for symbol in symbols.iter() {
+        s.push_str(format!("    {}\n", symbol));
+    }
+    s.into_owned()
+}
+
+#[cfg(target_os = "linux")]
+fn exports_flag(path: &Path) -> ~str {
+    format!("-Wl,--version-script={}", path.as_str().unwrap())
+}
+
+#[cfg(target_os = "macos")]
+fn exports_flag(path: &Path) -> ~str {
+    format!("-Wl,-exported_symbols_list,{}", path.as_str().unwrap())
+}
+
+/// The MinGW linker picks up `.def` files passed like any other input.
+#[cfg(windows)]
+fn exports_flag(path: &Path) -> ~str {
+    path.as_str().unwrap().to_owned()
+}
+
+/// The extension of the list of exports, which the linker looks at on
+/// Windows.
+#[cfg(not(windows))]
+static EXPORTS_EXTENSION: &'static str = "exports";
+
+#[cfg(windows)]
+static EXPORTS_EXTENSION: &'static str = "def";
+
+/// Everything a compile or link step writes.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct GccOutputs {
+    pub dst: Path,
+    /// The linker map, if one was requested with `set_map_file`.
+    pub map_file: Option<Path>,
+    /// The links to `dst` added with `add_symlink`.
+    pub symlinks: Vec<Path>,
+}
+
+/// What a project needs to use a library built by another rbuild project:
+/// the include directories, macros and everything to link. It's written
+/// next to the library with `write_metadata` and read back with
+/// `add_dependency_metadata`.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct LibraryMetadata {
+    pub lib: Path,
+    pub includes: Vec<Path>,
+    pub libpaths: Vec<Path>,
+    /// The library itself comes first, followed by everything it links
+    /// against, so that static libraries are linked in the right order.
+    pub external_libs: Vec<~str>,
+    pub macros: Vec<~str>,
+}
+
+impl LibraryMetadata {
+    pub fn read(path: &Path) -> Result<LibraryMetadata, ~str> {
+        let mut stream = match File::open(path) {
+            Ok(stream) => stream,
+            Err(err) => { return Err(err.to_str()); }
+        };
+
+        let json = match json::from_reader(&mut stream) {
+            Ok(json) => json,
+            Err(err) => { return Err(err.to_str()); }
+        };
+
+        let mut decoder = json::Decoder::new(json);
+        match Decodable::decode(&mut decoder) {
+            Ok(metadata) => Ok(metadata),
+            Err(err) => Err(err.to_str()),
+        }
+    }
+}
+
+/// Returns the name to pass to `-l` to link `lib`.
+fn link_name(lib: &Path) -> ~str {
+    let name = lib.filestem_str().unwrap();
+    if name.starts_with("lib") {
+        name.slice_from(3).to_owned()
+    } else {
+        name.to_owned()
+    }
+}
+
 #[deriving(Clone)]
 pub struct Gcc {
-    ctx: Context,
-    exe: Path,
-    dst_prefix: Option<&'static str>,
-    dst_suffix: Option<&'static str>,
-    dst: Option<Path>,
-    srcs: Vec<Path>,
+    base: ToolBase,
+    /// Steps that have to finish first, such as generated headers.
+    ordered_deps: Vec<SharedFuture<Path>>,
     includes: Vec<Path>,
+    system_includes: Vec<Path>,
+    input_dirs: Vec<Path>,
     lib_prefix: &'static str,
     lib_suffix: &'static str,
-    libs: Vec<Path>,
+    /// Only waited on by links, so compiles don't wait for libraries.
+    libs: Vec<SharedFuture<Path>>,
+    whole_archives: Vec<Path>,
+    /// Libraries imported with `add_dependency_metadata`, which are already
+    /// in `external_libs`, but are still inputs of links.
+    dependency_libs: Vec<Path>,
     external_libs: Vec<~str>,
     libpaths: Vec<Path>,
+    frameworks: Vec<~str>,
+    framework_paths: Vec<Path>,
     macros: Vec<~str>,
     warnings: Vec<~str>,
+    warning_level: WarningLevel,
+    warnings_as_errors: bool,
     debug: bool,
+    debug_prefix_maps: Vec<(Path, Path)>,
     profile: bool,
     optimize: bool,
-    flags: Vec<~str>,
+    sanitizer: Option<Sanitizer>,
+    pgo: Option<Pgo>,
+    coverage: bool,
+    hardening: bool,
+    deterministic: bool,
+    linker_script: Option<Path>,
+    version_script: Option<Path>,
+    exported_symbols: Vec<~str>,
+    exported_symbols_file: Option<Path>,
+    map_file: Option<Path>,
+    symlinks: Vec<Path>,
+    memory_weight: Option<uint>,
+    launcher: Option<Path>,
+    target_name: Option<~str>,
 }
 
 impl Gcc {
     pub fn new(ctx: Context, lib_prefix: &'static str, lib_suffix: &'static str) -> Gcc {
         let exe = path_util::find_program(ctx.clone(), EXES);
 
-        Gcc::new_with(ctx, exe, lib_prefix, lib_suffix)
+        Gcc::new_with(ctx, exe, lib_prefix, lib_suffix).record_search_dirs()
     }
 
     pub fn new_with<T: IntoFuture<Path>>(
@@ -44,62 +343,134 @@ impl Gcc {
         lib_prefix: &'static str,
         lib_suffix: &'static str
     ) -> Gcc {
+        let launcher = ctx.compiler_launcher();
+        let hardening = ctx.hardening();
+        let deterministic = ctx.deterministic();
+
         Gcc {
-            ctx: ctx,
-            exe: exe.into_future().unwrap(),
-            dst_prefix: None,
-            dst_suffix: None,
-            dst: None,
-            srcs: Vec::new(),
+            base: ToolBase::new(ctx, exe),
+            ordered_deps: Vec::new(),
             includes: Vec::new(),
+            system_includes: Vec::new(),
+            input_dirs: Vec::new(),
             lib_prefix: lib_prefix,
             lib_suffix: lib_suffix,
             libs: Vec::new(),
+            whole_archives: Vec::new(),
+            dependency_libs: Vec::new(),
             external_libs: Vec::new(),
             libpaths: Vec::new(),
+            frameworks: Vec::new(),
+            framework_paths: Vec::new(),
             macros: Vec::new(),
             warnings: Vec::new(),
+            warning_level: DefaultWarnings,
+            warnings_as_errors: false,
             debug: false,
+            debug_prefix_maps: Vec::new(),
             profile: false,
             optimize: false,
-            flags: Vec::new(),
+            sanitizer: None,
+            pgo: None,
+            coverage: false,
+            hardening: hardening,
+            deterministic: deterministic,
+            linker_script: None,
+            version_script: None,
+            exported_symbols: Vec::new(),
+            exported_symbols_file: None,
+            map_file: None,
+            symlinks: Vec::new(),
+            memory_weight: None,
+            launcher: launcher,
+            target_name: None,
         }
     }
 
-    pub fn set_dst_prefix(mut self, dst_prefix: &'static str) -> Gcc {
-        self.dst_prefix = Some(dst_prefix);
-        self
+    pub fn ctx(&self) -> Context {
+        self.base.ctx.clone()
     }
 
-    pub fn set_dst_suffix(mut self, dst_suffix: &'static str) -> Gcc {
-        self.dst_suffix = Some(dst_suffix);
-        self
+    /// The directories the compiler searches by default.
+    pub fn search_dirs(&self) -> SearchDirs {
+        SearchDirs::query(self.base.ctx.clone(), &self.base.exe).unwrap()
     }
 
-    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Gcc {
-        let mut dst = dst.into_path();
+    /// Record the compiler's default search directories as system
+    /// directories in the context. See `Context::is_system_path`.
+    pub fn record_search_dirs(self) -> Gcc {
+        // A compiler that wasn't found is reported by `Context::validate`.
+        if !self.base.exe.exists() {
+            return self;
+        }
+
+        let SearchDirs { includes, libraries } = self.search_dirs();
 
-        // Make sure we write the output in the build/ directory.
-        if !dst.is_ancestor_of(&self.ctx.root) {
-            dst = self.ctx.root.join(dst);
+        for dir in includes.move_iter().chain(libraries.move_iter()) {
+            self.base.ctx.add_system_dir(dir);
         }
 
-        self.dst = Some(dst);
         self
     }
 
-    pub fn add_src<T: IntoFuture<Path>>(mut self, src: T) -> Gcc {
-        self.srcs.push(src.into_future().unwrap());
+    /// Wait for `dep` before running, e.g. a step generating headers that
+    /// are included by the sources. Changes to `dep` don't cause a rebuild
+    /// by themselves, only the headers that are actually included do.
+    pub fn add_ordered_dep<T: IntoFuture<Path>>(mut self, dep: T) -> Gcc {
+        self.ordered_deps.push(SharedFuture::new(dep.into_future()));
         self
     }
 
+    /// Add an include directory. Directories that were already added are
+    /// ignored, since only the first occurrence affects the search order.
     pub fn add_include<T: IntoFuture<Path>>(mut self, include: T) -> Gcc {
-        self.includes.push(include.into_future().unwrap());
+        let include = include.into_future().unwrap();
+        if !self.includes.contains(&include) {
+            self.includes.push(include);
+        }
+        self
+    }
+
+    /// Add a system include directory with `-isystem`, which suppresses
+    /// warnings from the headers in it. Useful for third-party headers.
+    pub fn add_system_include<T: IntoFuture<Path>>(mut self, include: T) -> Gcc {
+        let include = include.into_future().unwrap();
+        if !self.system_includes.contains(&include) {
+            self.system_includes.push(include);
+        }
+        self
+    }
+
+    /// Treat every file under `dir` as an input, e.g. a directory of
+    /// headers that no step produces.
+    pub fn add_input_dir<T: IntoPath>(mut self, dir: T) -> Gcc {
+        let dir = dir.into_path();
+        if !self.input_dirs.contains(&dir) {
+            self.input_dirs.push(dir);
+        }
         self
     }
 
-    pub fn add_lib<T: IntoFuture<Path>>(mut self, lib: T) -> Gcc {
-        self.libs.push(lib.into_future().unwrap());
+    /// Link against `lib`. If it's a `Library`, its include directories
+    /// and macros are added as well, so on a builder they reach every
+    /// compile, while only links get the library and its link
+    /// requirements.
+    pub fn add_lib<T: IntoLibrary>(self, lib: T) -> Gcc {
+        lib.into_library().apply(self)
+    }
+
+    /// Link every object in the static library `lib`, even ones nothing
+    /// refers to, e.g. plugins that register themselves from static
+    /// constructors.
+    pub fn add_whole_archive<T: IntoFuture<Path>>(mut self, lib: T) -> Gcc {
+        self.whole_archives.push(lib.into_future().unwrap());
+        self
+    }
+
+    /// Link against the library file `lib`, without any of the
+    /// requirements a `Library` carries. Compiles ignore it.
+    pub fn add_lib_file<T: IntoFuture<Path>>(mut self, lib: T) -> Gcc {
+        self.libs.push(SharedFuture::new(lib.into_future()));
         self
     }
 
@@ -113,6 +484,110 @@ impl Gcc {
         self
     }
 
+    /// Use the library described by the metadata file at `path`, as
+    /// written by `write_metadata` in another rbuild project. Its include
+    /// directories and macros are used for compiles, and it's linked along
+    /// with everything it links against.
+    pub fn add_dependency_metadata<T: IntoFuture<Path>>(mut self, path: T) -> Gcc {
+        let path = path.into_future().unwrap();
+        let metadata = match LibraryMetadata::read(&path) {
+            Ok(metadata) => metadata,
+            Err(err) => fail!("failed to read library metadata {}: {}", path.display(), err),
+        };
+
+        let LibraryMetadata { lib, includes, libpaths, external_libs, macros } = metadata;
+
+        for include in includes.move_iter() {
+            self = self.add_include(include);
+        }
+
+        for libpath in libpaths.move_iter() {
+            if !self.libpaths.contains(&libpath) {
+                self.libpaths.push(libpath);
+            }
+        }
+
+        self.external_libs.push_all_move(external_libs);
+        self.macros.push_all_move(macros);
+        self.dependency_libs.push(lib);
+        self
+    }
+
+    /// Returns the metadata other projects need to use `lib`, when it's
+    /// built with this configuration.
+    pub fn metadata(&self, lib: &Path) -> LibraryMetadata {
+        let lib = os::make_absolute(lib);
+
+        let mut libpaths = vec!(lib.dir_path());
+        let mut external_libs = vec!(link_name(&lib));
+
+        let libs: Vec<Path> = self.libs.iter().map(|lib| lib.get()).collect();
+
+        for dep in libs.iter().chain(self.dependency_libs.iter()) {
+            let libpath = os::make_absolute(&dep.dir_path());
+            if !libpaths.contains(&libpath) {
+                libpaths.push(libpath);
+            }
+        }
+
+        for libpath in self.libpaths.iter() {
+            let libpath = os::make_absolute(libpath);
+            if !libpaths.contains(&libpath) {
+                libpaths.push(libpath);
+            }
+        }
+
+        for dep in libs.iter() {
+            external_libs.push(link_name(dep));
+        }
+        external_libs.push_all(self.external_libs.as_slice());
+
+        LibraryMetadata {
+            lib: lib,
+            includes: self.includes.iter().map(|include| os::make_absolute(include)).collect(),
+            libpaths: libpaths,
+            external_libs: external_libs,
+            macros: self.macros.clone(),
+        }
+    }
+
+    /// Write the metadata for `lib` to `dst` as JSON, so that other rbuild
+    /// projects can use it with `add_dependency_metadata`.
+    pub fn write_metadata<T: IntoFuture<Path>, U: IntoPath>(&self, lib: T, dst: U) -> Future<Path> {
+        let lib = lib.into_future().unwrap();
+        let dst = self.base.ctx.output_path(dst.into_path());
+        let metadata = self.metadata(&lib);
+
+        let mut prep = self.base.ctx.prep("LibraryMetadata");
+        prep.declare_input("value", "metadata", &metadata);
+        prep.declare_input("value", "dst", &dst);
+
+        prep.exec(proc(exec) {
+            // Make sure the parent directories exist.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            {
+                let mut file = File::create(&dst).unwrap();
+                let mut encoder = json::Encoder::new(&mut file);
+                metadata.encode(&mut encoder).unwrap();
+            }
+            exec.discover_output_path("output", &dst);
+
+            dst
+        })
+    }
+
+    /// Link against a macOS framework, e.g. "Foundation".
+    pub fn add_framework<T: Str>(mut self, framework: T) -> Gcc {
+        self.frameworks.push(framework.into_owned());
+        self
+    }
+
+    pub fn add_framework_path<T: IntoPath>(mut self, framework_path: T) -> Gcc {
+        self.framework_paths.push(framework_path.into_path());
+        self
+    }
+
     pub fn add_macro<T: Str>(mut self, macro: T) -> Gcc {
         self.macros.push(macro.into_owned());
         self
@@ -123,11 +598,30 @@ impl Gcc {
         self
     }
 
+    pub fn set_warning_level(mut self, warning_level: WarningLevel) -> Gcc {
+        self.warning_level = warning_level;
+        self
+    }
+
+    /// Turn warnings into errors with `-Werror`.
+    pub fn set_warnings_as_errors(mut self, warnings_as_errors: bool) -> Gcc {
+        self.warnings_as_errors = warnings_as_errors;
+        self
+    }
+
     pub fn set_debug(mut self, debug: bool) -> Gcc {
         self.debug = debug;
         self
     }
 
+    /// Replace the `from` prefix of paths in debug info with `to`. The
+    /// build root is always mapped to ".", so that debug info doesn't record
+    /// where the developer happened to build.
+    pub fn set_debug_prefix_map<T: IntoPath, U: IntoPath>(mut self, from: T, to: U) -> Gcc {
+        self.debug_prefix_maps.push((from.into_path(), to.into_path()));
+        self
+    }
+
     pub fn set_optimize(mut self, optimize: bool) -> Gcc {
         self.optimize = optimize;
         self
@@ -138,126 +632,604 @@ impl Gcc {
         self
     }
 
-    pub fn add_flag<S: Str>(mut self, flag: S) -> Gcc {
-        self.flags.push(flag.into_owned());
+    /// Instrument with `sanitizer`. The flags are passed to both compiles
+    /// and links, and the outputs are written under a directory named after
+    /// the sanitizer, so instrumented and plain objects never mix.
+    pub fn set_sanitizer(mut self, sanitizer: Sanitizer) -> Gcc {
+        self.sanitizer = Some(sanitizer);
         self
     }
 
-    pub fn run(self) -> Path {
-        self.into_future().unwrap()
+    /// Build a phase of a profile-guided optimization. Like a sanitizer,
+    /// each phase writes its outputs under a directory of its own. The
+    /// profiles of `PgoUse` are an input of the compiles, so it has to be
+    /// given the directory once the training has run.
+    pub fn set_pgo(mut self, pgo: Pgo) -> Gcc {
+        // The profiles are written and read by processes that may run
+        // elsewhere.
+        self.pgo = Some(match pgo {
+            PgoInstrument(dir) => PgoInstrument(os::make_absolute(&dir)),
+            PgoUse(dir) => PgoUse(os::make_absolute(&dir)),
+        });
+        self
     }
-}
 
-impl IntoFuture<Path> for Gcc {
-    fn into_future(self) -> Future<Path> {
-        let Gcc {
-            ctx,
-            exe,
-            dst,
-            dst_prefix,
-            dst_suffix,
-            lib_prefix,
-            lib_suffix,
-            srcs,
-            includes,
-            libs,
-            mut external_libs,
-            mut libpaths,
-            macros,
-            warnings,
-            debug,
-            profile,
-            optimize,
-            flags
-        } = self;
+    /// Instrument for coverage with `--coverage`. Run the tests and then
+    /// `builders::coverage::Lcov` to get a report.
+    pub fn set_coverage(mut self, coverage: bool) -> Gcc {
+        self.coverage = coverage;
+        self
+    }
 
-        assert!(!srcs.is_empty());
+    /// Build with the hardening flags distributions expect: a strong stack
+    /// protector, `_FORTIFY_SOURCE` and position independent executables
+    /// with read-only relocations. Defaults to the context's setting.
+    pub fn set_hardening(mut self, hardening: bool) -> Gcc {
+        self.hardening = hardening;
+        self
+    }
 
-        let mut prep = ctx.prep("Call");
-        let mut call = Call::new(exe.clone()).unwrap();
+    /// Link with the linker script `script` (`-T`). Edits to the script
+    /// cause a relink.
+    pub fn set_linker_script<T: IntoFuture<Path>>(mut self, script: T) -> Gcc {
+        self.linker_script = Some(script.into_future().unwrap());
+        self
+    }
 
-        let dst = match dst {
-            Some(mut dst) => {
-                dst = path_util::add_prefix_suffix(dst, dst_prefix, dst_suffix);
+    /// Control the exported symbols of a shared library with a version
+    /// script. Edits to the script cause a relink.
+    pub fn set_version_script<T: IntoFuture<Path>>(mut self, script: T) -> Gcc {
+        self.version_script = Some(script.into_future().unwrap());
+        self
+    }
 
-                call.push_str(~"-o");
-                call.push_output_path(dst.clone());
-                dst
-            }
-            None => { Path::new("") }
+    /// Only export `symbol` and the other exported symbols from a shared
+    /// library, hiding everything else. Can't be combined with
+    /// `set_version_script`.
+    pub fn add_exported_symbol<T: Str>(mut self, symbol: T) -> Gcc {
+        self.exported_symbols.push(symbol.into_owned());
+        self
+    }
+
+    /// Like `add_exported_symbol`, for every symbol listed in `file`, one
+    /// per line.
+    pub fn set_exported_symbols_file<T: IntoFuture<Path>>(mut self, file: T) -> Gcc {
+        self.exported_symbols_file = Some(file.into_future().unwrap());
+        self
+    }
+
+    /// Have the linker write a map file to `map_file`, for size analysis.
+    /// It can be waited on with `outputs`.
+    pub fn set_map_file<T: IntoPath>(mut self, map_file: T) -> Gcc {
+        self.map_file = Some(self.base.ctx.output_path(map_file.into_path()));
+        self
+    }
+
+    /// Create a symlink `link` to the output once it's built, such as the
+    /// soname of a versioned shared library. It's an output of the step.
+    pub fn add_symlink<T: IntoPath>(mut self, link: T) -> Gcc {
+        self.symlinks.push(self.base.ctx.output_path(link.into_path()));
+        self
+    }
+
+    /// How much memory this step needs relative to an ordinary compile, e.g.
+    /// for a template-heavy source. Links default to `LINK_MEMORY_WEIGHT`.
+    pub fn set_memory_weight(mut self, memory_weight: uint) -> Gcc {
+        self.memory_weight = Some(memory_weight);
+        self
+    }
+
+    /// The directory, relative to the build root, that outputs of this
+    /// configuration are written to.
+    pub fn variant_dir(&self) -> Path {
+        let mut dir = Path::new(".");
+
+        match self.sanitizer {
+            Some(sanitizer) => { dir.push(sanitizer.name()); }
+            None => { }
+        }
+
+        match self.pgo {
+            Some(ref pgo) => { dir.push(pgo.name()); }
+            None => { }
+        }
+
+        dir
+    }
+
+    /// Prefix compile commands with a launcher such as `ccache`. Link
+    /// steps always run the compiler directly.
+    pub fn set_compiler_launcher<T: IntoPath>(mut self, launcher: T) -> Gcc {
+        self.launcher = Some(launcher.into_path());
+        self
+    }
+
+    /// Name the target being built, so that its objects are kept in their
+    /// own `obj/<name>/` directory. Targets compiling the same source with
+    /// different flags then don't overwrite each other's objects.
+    pub fn set_target_name<S: Str>(mut self, name: S) -> Gcc {
+        self.target_name = Some(name.into_owned());
+        self
+    }
+
+    /// Returns where the object for `src` goes: the source's path under
+    /// `obj/<target name>/`, so that `a/util.c` and `b/util.c` don't collide.
+    pub fn object_path(&self, src: &Path) -> Path {
+        let mut dst = Path::new("obj");
+
+        match self.target_name {
+            Some(ref name) => { dst.push(name.as_slice()); }
+            None => { }
+        }
+
+        // Generated sources already live in the build directory.
+        let rel = if self.base.ctx.root.is_ancestor_of(src) {
+            src.path_relative_from(&self.base.ctx.root).unwrap()
+        } else if src.is_absolute() {
+            src.path_relative_from(&os::getcwd())
+                .or_else(|| src.path_relative_from(&Path::new("/")))
+                .unwrap()
+        } else {
+            src.clone()
         };
 
-        for include in includes.move_iter() {
-            call.push_str(~"-I");
-            call.push_input_path(include).unwrap();
+        // Don't let `..` climb out of the object directory.
+        for component in rel.components() {
+            if component != bytes!("..") {
+                dst.push(component);
+            }
         }
 
+        dst
+    }
+
+    /// Adds the `-L` and `-l` flags of the libraries to link against.
+    /// Libraries we build come first, since they may depend on the
+    /// external ones but not the other way around.
+    fn push_libs(&self, call: &mut Call) {
+        let mut libpaths = self.libpaths.clone();
+        let mut lib_names = Vec::new();
+
         // We need to extract the relative lib info from a lib path
-        for lib in libs.move_iter() {
-            prep.declare_input_path(lib.clone()).unwrap();
+        for lib in self.libs.iter() {
+            let lib = lib.get();
 
-            libpaths.push(lib.dir_path());
+            // A system directory is already searched, and naming it could
+            // change which copy of a library wins.
+            if !self.base.ctx.is_system_path(&lib) {
+                libpaths.push(lib.dir_path());
+            }
 
             let name = lib.filename_str().unwrap();
 
-            assert!(name.starts_with(lib_prefix) && name.ends_with(lib_suffix));
+            // Versioned shared libraries such as `libfoo.so.1.2.3` are linked
+            // through their unversioned symlink.
+            let suffix = format!(".{}", self.lib_suffix);
+            assert!(name.starts_with(self.lib_prefix) && name.contains(suffix));
 
-            external_libs.push(name.slice(lib_prefix.len(), name.len() - (lib_suffix.len() + 1)).to_owned());
+            let name = name.slice_from(self.lib_prefix.len());
+            let end = if name.ends_with(suffix) {
+                name.len() - suffix.len()
+            } else {
+                name.find_str(suffix).unwrap()
+            };
+            lib_names.push(name.slice_to(end).to_owned());
         }
 
-        for libpath in libpaths.move_iter() {
+        for libpath in libpaths.iter() {
             call.push_str(~"-L");
             call.push_str(libpath.as_str().unwrap().to_owned());
         }
 
-        for lib in external_libs.move_iter() {
+        for lib in lib_names.move_iter().chain(self.external_libs.iter().map(|lib| lib.clone())) {
             call.push_str(~"-l");
             call.push_str(lib);
         }
+    }
 
-        if debug { call.push_str(~"-g"); }
-        if optimize { call.push_str(~"-O2"); }
-        if profile { call.push_str(~"-pg"); }
+    fn compile_only(&self) -> bool {
+        self.base.flags.iter().any(|flag| flag.as_slice() == "-c")
+    }
 
-        for macro in macros.move_iter() {
-            call.push_str(~"-D");
-            call.push_str(macro);
+    /// Where the output is written: the destination with its prefix and
+    /// suffix, under the variant's directory if there is one.
+    fn output_dst(&self) -> Option<Path> {
+        self.base.dst_path().map(|dst| self.variant_output(dst))
+    }
+
+    /// Moves `path` under the variant's directory, if there is one. A path
+    /// outside the build root keeps only its file name there, so that it
+    /// can't overwrite the output of the plain build.
+    fn variant_output(&self, path: Path) -> Path {
+        let variant = self.variant_dir();
+        if variant == Path::new(".") {
+            return path;
         }
 
-        for warning in warnings.move_iter() {
-            call.push_str(~"-W");
-            call.push_str(warning);
+        let root = &self.base.ctx.root;
+        let rel = if root.is_ancestor_of(&path) {
+            path.path_relative_from(root).unwrap()
+        } else {
+            Path::new(path.filename().unwrap())
+        };
+        root.join(variant).join(rel)
+    }
+
+    /// The list of exports is generated next to the output of links that
+    /// export symbols, in the form the linker expects.
+    fn exports_path(&self, dst: &Path) -> Option<Path> {
+        if self.compile_only() ||
+                (self.exported_symbols.is_empty() && self.exported_symbols_file.is_none()) {
+            None
+        } else {
+            Some(dst.with_extension(EXPORTS_EXTENSION))
         }
+    }
 
-        for flag in flags.move_iter() {
-            call.push_str(flag);
+    /// Like `into_future`, but also exposes the secondary outputs of the
+    /// step such as the map file.
+    pub fn outputs(self) -> SharedFuture<GccOutputs> {
+        let mut prep = self.base.ctx.prep("Call");
+
+        for dep in self.ordered_deps.iter() {
+            prep.declare_order_only(dep.clone());
         }
 
-        for src in srcs.iter() {
-            call.push_input_path(src.clone()).ok().expect("src");
+        let call = self.to_call();
+        let compile_only = self.compile_only();
+        let dst = self.output_dst().unwrap_or(Path::new(""));
+        let exports = self.exports_path(&dst);
+        let symlinks: Vec<Path> = self.symlinks.iter().map(|link| {
+            self.variant_output(link.clone())
+        }).collect();
+
+        let Gcc {
+            base,
+            input_dirs,
+            libs,
+            dependency_libs,
+            pgo,
+            coverage,
+            version_script,
+            exported_symbols,
+            exported_symbols_file,
+            map_file,
+            launcher,
+            ..
+        } = self;
+
+        let exe = base.exe.clone();
+        let srcs = base.all_srcs();
+
+        assert!(!srcs.is_empty());
+
+        for dir in input_dirs.move_iter() {
+            prep.declare_input_dir(dir).unwrap();
         }
 
+        if !compile_only {
+            for lib in libs.move_iter() {
+                prep.declare_input_path(lib.get()).unwrap();
+            }
+
+            for lib in dependency_libs.move_iter() {
+                prep.declare_input_path(lib).unwrap();
+            }
+
+            assert!(version_script.is_none() ||
+                    (exported_symbols.is_empty() && exported_symbols_file.is_none()),
+                    "a version script can't be combined with exported symbols");
+
+            match version_script {
+                Some(script) => { prep.declare_input_path(script).unwrap(); }
+                None => { }
+            }
+        }
+
+        let map_file = if compile_only { None } else { map_file };
+
+        let exports = match exports {
+            Some(path) => {
+                prep.declare_input("value", "exported_symbols", &exported_symbols);
+                match exported_symbols_file {
+                    Some(ref file) => { prep.declare_input_path(file.clone()).unwrap(); }
+                    None => { }
+                }
+
+                Some((path, exported_symbols, exported_symbols_file))
+            }
+            None => None,
+        };
+
+        // The profiles are an input of every compile of the optimized
+        // build.
+        match pgo {
+            Some(PgoUse(ref dir)) => { prep.declare_input_dir(pgo::data_dir(dir)).unwrap(); }
+            _ => { }
+        }
+
+        // Only compiles go through the launcher.
+        let launcher = if compile_only { launcher } else { None };
+
         prep.declare_call(&call);
 
-        prep.exec(proc(exec) {
-            let (prog, args) = call.cmd();
+        // Coverage notes and profiles are named after the object gcc writes,
+        // so those compiles have to write it in place.
+        let atomic = !coverage && pgo.is_none();
+
+        // An exported build doesn't scan for includes, so its compiles write
+        // depfiles for it to find the headers in instead.
+        let (exported, depfile) = if compile_only {
+            let depfile = dst.with_extension("d");
+            let mut exported = call.clone();
+            for arg in ["-MD", "-MT", dst.as_str().unwrap(), "-MF", depfile.as_str().unwrap()].iter() {
+                exported.push_str(arg.to_owned());
+            }
+            (exported, Some(depfile))
+        } else {
+            (call.clone(), None)
+        };
+        prep.export_command(exported.shell_cmd(launcher.as_ref(), atomic), depfile);
+
+        prep.exec_shared(proc(exec) {
+            let (prog, args) = if atomic {
+                call.tmp_cmd().unwrap()
+            } else {
+                call.cmd()
+            };
+
+            // The launcher is left out of the call so that the cache is keyed
+            // on the real compiler.
+            let (prog, args) = match launcher {
+                Some(launcher) => {
+                    let mut launched = Vec::new();
+                    launched.push(prog);
+                    launched.push_all_move(args);
+                    (launcher.as_str().unwrap().to_owned(), launched)
+                }
+                None => (prog, args),
+            };
 
             // Make sure the parent directories exist.
             fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
 
-            let status = exec.process_builder(prog, args.as_slice())
+            match exports {
+                Some((path, mut symbols, file)) => {
+                    match file {
+                        Some(file) => {
+                            let contents = File::open(&file).read_to_str().unwrap();
+                            for line in contents.lines() {
+                                let line = line.trim();
+                                if !line.is_empty() {
+                                    symbols.push(line.to_owned());
+                                }
+                            }
+                        }
+                        None => { }
+                    }
+
+                    File::create(&path).write_str(render_exports(symbols.as_slice())).unwrap();
+                }
+                None => { }
+            }
+
+            exec.process_builder(prog, args.as_slice())
+                .memory_weight(call.memory_weight())
                 .description(exe.filename_display())
                 .msg(dst.display())
                 .msg("<-")
                 .msgs(srcs.iter().map(|src| src.display()))
-                .run()
-                .unwrap();
+                .run_or_fail();
 
-            if !status.success() {
-                fail!("command failed");
+            if atomic {
+                call.rename_outputs().unwrap();
             }
 
-            dst
+            match map_file {
+                Some(ref map_file) => { exec.discover_output_path("map_file", map_file); }
+                None => { }
+            }
+
+            // The links are relative, so the build directory can be moved.
+            let target = Path::new(dst.filename_str().unwrap());
+            for link in symlinks.iter() {
+                if fs::lstat(link).is_ok() {
+                    fs::unlink(link).unwrap();
+                }
+                fs::symlink(&target, link).unwrap();
+                exec.discover_output_path(link.as_str().unwrap(), link);
+            }
+
+            GccOutputs {
+                dst: dst,
+                map_file: map_file,
+                symlinks: symlinks,
+            }
         })
     }
 }
+
+tool_methods!(Gcc)
+
+impl Tool for Gcc {
+    fn to_call(&self) -> Call {
+        let compile_only = self.compile_only();
+        let mut call = self.base.call();
+
+        let dst = match self.output_dst() {
+            Some(dst) => {
+                call.push_str(~"-o");
+                call.push_output_path(dst.clone());
+                dst
+            }
+            None => { Path::new("") }
+        };
+
+        for include in self.includes.iter() {
+            call.push_str(~"-I");
+            call.push_input_path(include.clone()).unwrap();
+        }
+
+        for include in self.system_includes.iter() {
+            call.push_str(~"-isystem");
+            call.push_input_path(include.clone()).unwrap();
+        }
+
+        if !compile_only {
+            self.push_libs(&mut call);
+        }
+
+        for framework_path in self.framework_paths.iter() {
+            call.push_str(~"-F");
+            call.push_str(framework_path.as_str().unwrap().to_owned());
+        }
+
+        if !compile_only {
+            for framework in self.frameworks.iter() {
+                call.push_str(~"-framework");
+                call.push_str(framework.clone());
+            }
+        }
+
+        if self.debug {
+            call.push_str(~"-g");
+
+            // Later maps take precedence, so the user's come last.
+            call.push_prefix_map("-fdebug-prefix-map", self.base.ctx.root.clone(), ~".");
+            for &(ref from, ref to) in self.debug_prefix_maps.iter() {
+                call.push_str(format!("-fdebug-prefix-map={}={}", from.display(), to.display()));
+            }
+        }
+        if self.optimize { call.push_str(~"-O2"); }
+        if self.profile { call.push_str(~"-pg"); }
+        if self.coverage { call.push_str(~"--coverage"); }
+
+        if self.hardening {
+            // Shared libraries are already position independent, and must
+            // not be linked with -pie.
+            let shared = self.base.flags.iter().any(|flag| {
+                flag.as_slice() == "-shared" || flag.as_slice() == "-dynamiclib"
+            });
+
+            for flag in HARDENING_COMPILE_FLAGS.iter() {
+                call.push_str(flag.to_owned());
+            }
+
+            if self.optimize {
+                for flag in HARDENING_OPTIMIZED_FLAGS.iter() {
+                    call.push_str(flag.to_owned());
+                }
+            }
+
+            if !compile_only && !shared {
+                for flag in HARDENING_LINK_FLAGS.iter() {
+                    call.push_str(flag.to_owned());
+                }
+            }
+        }
+
+        match self.sanitizer {
+            Some(sanitizer) => {
+                for flag in sanitizer.flags().move_iter() {
+                    call.push_str(flag);
+                }
+            }
+            None => { }
+        }
+
+        match self.pgo {
+            Some(ref pgo) => {
+                let clang = self.base.exe.filename_str().map_or(false, |name| name.contains("clang"));
+                for flag in pgo.flags(clang).move_iter() {
+                    call.push_str(flag);
+                }
+            }
+            None => { }
+        }
+
+        for macro in self.macros.iter() {
+            call.push_str(~"-D");
+            call.push_str(macro.clone());
+        }
+
+        let clang = self.base.exe.filename_str().map_or(false, |name| name.contains("clang"));
+        for flag in self.warning_level.flags(clang).move_iter() {
+            call.push_str(flag);
+        }
+
+        for warning in self.warnings.iter() {
+            call.push_str(~"-W");
+            call.push_str(warning.clone());
+        }
+
+        if self.warnings_as_errors { call.push_str(~"-Werror"); }
+
+        if self.deterministic {
+            // Strip the working directory from `__FILE__` and debug info.
+            call.push_prefix_map("-ffile-prefix-map", Path::new("."), ~".");
+
+            // GCC seeds some symbol names randomly, unless told otherwise.
+            if compile_only {
+                call.push_str(format!("-frandom-seed={}", dst.display()));
+            }
+        }
+
+        if !compile_only {
+            match self.linker_script {
+                Some(ref script) => {
+                    call.push_str(~"-T");
+                    call.push_input_path(script.clone()).unwrap();
+                }
+                None => { }
+            }
+
+            match self.version_script {
+                Some(ref script) => {
+                    call.push_str(format!("-Wl,--version-script={}", script.as_str().unwrap()));
+                }
+                None => { }
+            }
+
+            match self.map_file {
+                Some(ref map_file) => {
+                    call.push_str(format!("-Wl,-Map,{}", map_file.as_str().unwrap()));
+                }
+                None => { }
+            }
+        }
+
+        match self.exports_path(&dst) {
+            Some(path) => { call.push_str(exports_flag(&path)); }
+            None => { }
+        }
+
+        self.base.push_flags(&mut call);
+        self.base.push_srcs(&mut call);
+
+        if !compile_only {
+            push_whole_archives(&mut call, self.whole_archives.clone());
+        }
+
+        call.set_memory_weight(match self.memory_weight {
+            Some(memory_weight) => memory_weight,
+            None if compile_only => 1,
+            None => LINK_MEMORY_WEIGHT,
+        });
+
+        call
+    }
+
+    fn output_paths(&self) -> Vec<Path> {
+        let mut paths = self.to_call().output_paths();
+        for link in self.symlinks.iter() {
+            paths.push(self.variant_output(link.clone()));
+        }
+        paths
+    }
+}
+
+impl IntoFuture<Path> for Gcc {
+    fn into_future(self) -> Future<Path> {
+        self.outputs().map(proc(outputs) outputs.dst)
+    }
+}
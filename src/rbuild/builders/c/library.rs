@@ -0,0 +1,332 @@
+use sync::Future;
+
+use builders::ar::Ar;
+use builders::c::gcc::Gcc;
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use shared_future::SharedFuture;
+
+/// A library along with what its users need: the include directories and
+/// macros its headers expect, and what it has to be linked with. Adding it
+/// to a builder with `add_lib` applies all of these, so its users don't
+/// have to repeat them. Compiles get the includes and macros, and only links
+/// get the library and what it links against:
+///
+/// ```ignore
+/// let bar = Library::new(c_static.link_lib("bar").add_src(obj))
+///     .add_public_include("bar/include")
+///     .add_external_lib("m");
+///
+/// let c_static = c_static.add_lib(bar);
+/// let foo = c_static.compile("foo.c");
+/// c_static.link_exe("foo").add_src(foo).run();
+/// ```
+#[deriving(Clone)]
+pub struct Library {
+    /// Interface libraries have nothing to link.
+    lib: Option<SharedFuture<Path>>,
+    includes: Vec<Path>,
+    /// Directories whose contents are inputs of every user, for headers
+    /// that aren't built by any step.
+    header_dirs: Vec<Path>,
+    macros: Vec<~str>,
+    libs: Vec<Library>,
+    external_libs: Vec<~str>,
+    libpaths: Vec<Path>,
+}
+
+impl Library {
+    pub fn new<T: IntoFuture<Path>>(lib: T) -> Library {
+        Library {
+            lib: Some(SharedFuture::new(lib.into_future())),
+            includes: Vec::new(),
+            header_dirs: Vec::new(),
+            macros: Vec::new(),
+            libs: Vec::new(),
+            external_libs: Vec::new(),
+            libpaths: Vec::new(),
+        }
+    }
+
+    /// A library with nothing to link, only requirements for its users. Its
+    /// include directories aren't inputs of their compiles, which suits
+    /// system libraries; see `InterfaceLibrary` for headers in the project.
+    pub fn interface() -> Library {
+        Library {
+            lib: None,
+            includes: Vec::new(),
+            header_dirs: Vec::new(),
+            macros: Vec::new(),
+            libs: Vec::new(),
+            external_libs: Vec::new(),
+            libpaths: Vec::new(),
+        }
+    }
+
+    /// Add an include directory that users of the library compile with.
+    pub fn add_public_include<T: IntoFuture<Path>>(mut self, include: T) -> Library {
+        self.includes.push(include.into_future().unwrap());
+        self
+    }
+
+    /// Add a macro that users of the library compile with.
+    pub fn add_public_macro<T: Str>(mut self, macro: T) -> Library {
+        self.macros.push(macro.into_owned());
+        self
+    }
+
+    /// Add a library this one links against. Its requirements are passed
+    /// on to the users of this library too.
+    pub fn add_lib<T: IntoLibrary>(mut self, lib: T) -> Library {
+        self.libs.push(lib.into_library());
+        self
+    }
+
+    pub fn add_external_lib<T: Str>(mut self, lib: T) -> Library {
+        self.external_libs.push(lib.into_owned());
+        self
+    }
+
+    pub fn add_libpath<T: IntoPath>(mut self, libpath: T) -> Library {
+        self.libpaths.push(libpath.into_path());
+        self
+    }
+
+    /// Applies the requirements of this library and everything it links
+    /// against to `gcc`. Libraries come before the libraries they depend
+    /// on, as static linking requires.
+    pub fn apply(self, gcc: Gcc) -> Gcc {
+        let Library { lib, includes, header_dirs, macros, libs, external_libs, libpaths } = self;
+
+        let mut gcc = match lib {
+            Some(lib) => gcc.add_lib_file(lib),
+            None => gcc,
+        };
+
+        for include in includes.move_iter() {
+            gcc = gcc.add_include(include);
+        }
+
+        for dir in header_dirs.move_iter() {
+            gcc = gcc.add_input_dir(dir);
+        }
+
+        for macro in macros.move_iter() {
+            gcc = gcc.add_macro(macro);
+        }
+
+        for lib in libs.move_iter() {
+            gcc = lib.apply(gcc);
+        }
+
+        for lib in external_libs.move_iter() {
+            gcc = gcc.add_external_lib(lib);
+        }
+
+        for libpath in libpaths.move_iter() {
+            gcc = gcc.add_libpath(libpath);
+        }
+
+        gcc
+    }
+
+    /// Everything users of the library need, including what it links
+    /// against, flattened in link order. Waits for the library files.
+    pub fn requirements(&self) -> Requirements {
+        let mut reqs = Requirements {
+            libs: Vec::new(),
+            includes: Vec::new(),
+            macros: Vec::new(),
+            external_libs: Vec::new(),
+            libpaths: Vec::new(),
+        };
+        self.collect_requirements(&mut reqs);
+        reqs
+    }
+
+    fn collect_requirements(&self, reqs: &mut Requirements) {
+        fn push_new<T: Eq + Clone>(dst: &mut Vec<T>, src: &[T]) {
+            for item in src.iter() {
+                if !dst.contains(item) {
+                    dst.push(item.clone());
+                }
+            }
+        }
+
+        match self.lib {
+            Some(ref lib) => { push_new(&mut reqs.libs, [lib.get()]); }
+            None => { }
+        }
+
+        push_new(&mut reqs.includes, self.includes.as_slice());
+        push_new(&mut reqs.macros, self.macros.as_slice());
+
+        for lib in self.libs.iter() {
+            lib.collect_requirements(reqs);
+        }
+
+        push_new(&mut reqs.external_libs, self.external_libs.as_slice());
+        push_new(&mut reqs.libpaths, self.libpaths.as_slice());
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+/// The flattened requirements of a `Library`.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct Requirements {
+    pub libs: Vec<Path>,
+    pub includes: Vec<Path>,
+    pub macros: Vec<~str>,
+    pub external_libs: Vec<~str>,
+    pub libpaths: Vec<Path>,
+}
+
+impl IntoFuture<Path> for Library {
+    fn into_future(self) -> Future<Path> {
+        match self.lib {
+            Some(lib) => lib.into_future(),
+            None => fail!("an interface library has no file to link"),
+        }
+    }
+}
+
+/// A library without anything to compile or link, such as a header-only
+/// library or a wrapper around system libraries. Users that `add_lib` it
+/// get its include directories, macros and external libraries, and are
+/// rebuilt whenever one of its headers changes.
+#[deriving(Clone)]
+pub struct InterfaceLibrary {
+    library: Library,
+}
+
+impl InterfaceLibrary {
+    pub fn new() -> InterfaceLibrary {
+        InterfaceLibrary {
+            library: Library::interface(),
+        }
+    }
+
+    /// Add an include directory for users. Every file under it is an
+    /// input of their compiles.
+    pub fn add_public_include<T: IntoFuture<Path>>(self, include: T) -> InterfaceLibrary {
+        let include = include.into_future().unwrap();
+        let InterfaceLibrary { mut library } = self;
+        library.header_dirs.push(include.clone());
+        InterfaceLibrary { library: library.add_public_include(include) }
+    }
+
+    pub fn add_public_macro<T: Str>(self, macro: T) -> InterfaceLibrary {
+        let InterfaceLibrary { library } = self;
+        InterfaceLibrary { library: library.add_public_macro(macro) }
+    }
+
+    pub fn add_lib<T: IntoLibrary>(self, lib: T) -> InterfaceLibrary {
+        let InterfaceLibrary { library } = self;
+        InterfaceLibrary { library: library.add_lib(lib) }
+    }
+
+    pub fn add_external_lib<T: Str>(self, lib: T) -> InterfaceLibrary {
+        let InterfaceLibrary { library } = self;
+        InterfaceLibrary { library: library.add_external_lib(lib) }
+    }
+
+    pub fn add_libpath<T: IntoPath>(self, libpath: T) -> InterfaceLibrary {
+        let InterfaceLibrary { library } = self;
+        InterfaceLibrary { library: library.add_libpath(libpath) }
+    }
+}
+
+/// A set of objects that are compiled once and linked into several
+/// executables or libraries, without being archived. Pass it to `add_srcs`
+/// of each link:
+///
+/// ```ignore
+/// let common = c_static.object_library(vec!("util.c", "log.c"));
+/// c_static.link_exe("foo").add_src(foo).add_srcs(common.clone()).run();
+/// c_static.link_exe("bar").add_src(bar).add_srcs(common).run();
+/// ```
+#[deriving(Clone)]
+pub struct ObjectLibrary {
+    objects: SharedFuture<Vec<Path>>,
+}
+
+impl ObjectLibrary {
+    pub fn new<T: IntoFuture<Vec<Path>>>(objects: T) -> ObjectLibrary {
+        ObjectLibrary {
+            objects: SharedFuture::new(objects.into_future()),
+        }
+    }
+
+    pub fn objects(&self) -> Future<Vec<Path>> {
+        self.objects.clone().into_future()
+    }
+}
+
+impl IntoFuture<Vec<Path>> for ObjectLibrary {
+    fn into_future(self) -> Future<Vec<Path>> {
+        self.objects.into_future()
+    }
+}
+
+/// Anything that can be passed to `add_lib`: a `Library`, or the path of a
+/// library without any requirements of its own.
+pub trait IntoLibrary {
+    fn into_library(self) -> Library;
+}
+
+impl IntoLibrary for Library {
+    fn into_library(self) -> Library {
+        self
+    }
+}
+
+impl IntoLibrary for InterfaceLibrary {
+    fn into_library(self) -> Library {
+        self.library
+    }
+}
+
+impl<'a> IntoLibrary for &'a str {
+    fn into_library(self) -> Library {
+        Library::new(self)
+    }
+}
+
+impl IntoLibrary for ~str {
+    fn into_library(self) -> Library {
+        Library::new(self)
+    }
+}
+
+impl IntoLibrary for Path {
+    fn into_library(self) -> Library {
+        Library::new(self)
+    }
+}
+
+impl IntoLibrary for Future<Path> {
+    fn into_library(self) -> Library {
+        Library::new(self)
+    }
+}
+
+impl IntoLibrary for SharedFuture<Path> {
+    fn into_library(self) -> Library {
+        Library::new(self)
+    }
+}
+
+impl IntoLibrary for Ar {
+    fn into_library(self) -> Library {
+        Library::new(self)
+    }
+}
+
+impl IntoLibrary for Gcc {
+    fn into_library(self) -> Library {
+        Library::new(self)
+    }
+}
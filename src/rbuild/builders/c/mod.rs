@@ -1,11 +1,163 @@
+use sync::Future;
+
 use builders::ar::Ar;
 use context::Context;
 use into_path::IntoPath;
 use into_future::IntoFuture;
+use path_util;
 
-use self::gcc::Gcc;
+use self::gcc::{Gcc, Pgo, Sanitizer, WarningLevel};
+use self::library::{IntoLibrary, ObjectLibrary};
 
 pub mod gcc;
+pub mod library;
+pub mod search_dirs;
+
+/// Implements setters on a builder that forward to its `gcc` field, which
+/// is destructured along with the builder's other fields.
+macro_rules! gcc_setters(
+    ($builder:ident { $($field:ident),* }) => (
+        impl $builder {
+            pub fn add_include<T: IntoFuture<Path>>(self, include: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_include(include) $(, $field: $field)* }
+            }
+
+            pub fn add_ordered_dep<T: IntoFuture<Path>>(self, dep: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_ordered_dep(dep) $(, $field: $field)* }
+            }
+
+            pub fn add_system_include<T: IntoFuture<Path>>(self, include: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_system_include(include) $(, $field: $field)* }
+            }
+
+            pub fn add_input_dir<T: IntoPath>(self, dir: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_input_dir(dir) $(, $field: $field)* }
+            }
+
+            /// Use `lib` in every step of the builder. A `Library`'s
+            /// include directories and macros apply to compiles, while the
+            /// library itself and its link requirements only apply to links.
+            pub fn add_lib<T: IntoLibrary>(self, lib: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_lib(lib) $(, $field: $field)* }
+            }
+
+            pub fn add_whole_archive<T: IntoFuture<Path>>(self, lib: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_whole_archive(lib) $(, $field: $field)* }
+            }
+
+            pub fn add_external_lib<T: Str>(self, lib: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_external_lib(lib) $(, $field: $field)* }
+            }
+
+            pub fn add_libpath<T: IntoPath>(self, libpath: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_libpath(libpath) $(, $field: $field)* }
+            }
+
+            pub fn add_dependency_metadata<T: IntoFuture<Path>>(self, path: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_dependency_metadata(path) $(, $field: $field)* }
+            }
+
+            pub fn add_framework<T: Str>(self, framework: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_framework(framework) $(, $field: $field)* }
+            }
+
+            pub fn add_framework_path<T: IntoPath>(self, framework_path: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_framework_path(framework_path) $(, $field: $field)* }
+            }
+
+            pub fn add_macro<T: Str>(self, macro: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_macro(macro) $(, $field: $field)* }
+            }
+
+            pub fn add_warning<T: Str>(self, warning: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_warning(warning) $(, $field: $field)* }
+            }
+
+            pub fn set_warning_level(self, warning_level: WarningLevel) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_warning_level(warning_level) $(, $field: $field)* }
+            }
+
+            pub fn set_warnings_as_errors(self, warnings_as_errors: bool) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_warnings_as_errors(warnings_as_errors) $(, $field: $field)* }
+            }
+
+            pub fn set_debug(self, debug: bool) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_debug(debug) $(, $field: $field)* }
+            }
+
+            pub fn set_debug_prefix_map<T: IntoPath, U: IntoPath>(self, from: T, to: U) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_debug_prefix_map(from, to) $(, $field: $field)* }
+            }
+
+            pub fn set_optimize(self, optimize: bool) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_optimize(optimize) $(, $field: $field)* }
+            }
+
+            pub fn set_profile(self, profile: bool) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_profile(profile) $(, $field: $field)* }
+            }
+
+            pub fn set_sanitizer(self, sanitizer: Sanitizer) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_sanitizer(sanitizer) $(, $field: $field)* }
+            }
+
+            pub fn set_pgo(self, pgo: Pgo) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_pgo(pgo) $(, $field: $field)* }
+            }
+
+            pub fn set_coverage(self, coverage: bool) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_coverage(coverage) $(, $field: $field)* }
+            }
+
+            pub fn set_hardening(self, hardening: bool) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_hardening(hardening) $(, $field: $field)* }
+            }
+
+            pub fn set_memory_weight(self, memory_weight: uint) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_memory_weight(memory_weight) $(, $field: $field)* }
+            }
+
+            pub fn add_flag<S: Str>(self, flag: S) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.add_flag(flag) $(, $field: $field)* }
+            }
+
+            pub fn set_compiler_launcher<T: IntoPath>(self, launcher: T) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_compiler_launcher(launcher) $(, $field: $field)* }
+            }
+
+            pub fn set_target_name<S: Str>(self, name: S) -> $builder {
+                let $builder { gcc $(, $field)* } = self;
+                $builder { gcc: gcc.set_target_name(name) $(, $field: $field)* }
+            }
+        }
+    )
+)
 
 #[deriving(Clone)]
 pub struct StaticBuilder {
@@ -25,6 +177,74 @@ pub static SHARED_LIB_SUFFIX: &'static str = "so";
 #[cfg(target_os = "macos")]
 pub static SHARED_LIB_SUFFIX: &'static str = "dylib";
 
+#[cfg(windows)]
+pub static SHARED_LIB_SUFFIX: &'static str = "dll";
+
+/// The file names of version `major.minor.patch` of the shared library
+/// `name`: the library itself, its soname and the link used when linking.
+#[cfg(not(target_os = "macos"))]
+fn versioned_names(name: &str, (major, minor, patch): (uint, uint, uint)) -> (~str, ~str, ~str) {
+    let link = format!("{}{}.{}", LIB_PREFIX, name, SHARED_LIB_SUFFIX);
+    (format!("{}.{}.{}.{}", link, major, minor, patch), format!("{}.{}", link, major), link)
+}
+
+#[cfg(target_os = "macos")]
+fn versioned_names(name: &str, (major, minor, patch): (uint, uint, uint)) -> (~str, ~str, ~str) {
+    let lib = format!("{}{}", LIB_PREFIX, name);
+    (format!("{}.{}.{}.{}.{}", lib, major, minor, patch, SHARED_LIB_SUFFIX),
+     format!("{}.{}.{}", lib, major, SHARED_LIB_SUFFIX),
+     format!("{}.{}", lib, SHARED_LIB_SUFFIX))
+}
+
+/// Records the soname in the library, so programs load the library of the
+/// major version they were linked against.
+#[cfg(not(target_os = "macos"))]
+fn version_flags(soname: &str, _version: (uint, uint, uint)) -> Vec<~str> {
+    vec!(format!("-Wl,-soname,{}", soname))
+}
+
+#[cfg(target_os = "macos")]
+fn version_flags(soname: &str, (major, minor, patch): (uint, uint, uint)) -> Vec<~str> {
+    vec!(format!("-Wl,-install_name,@rpath/{}", soname),
+         format!("-Wl,-compatibility_version,{}.0.0", major),
+         format!("-Wl,-current_version,{}.{}.{}", major, minor, patch))
+}
+
+/// The definition of the macro `name` that marks the public API of a shared
+/// library while it's being built, e.g. `FOO_API` in
+/// `FOO_API int foo(void);`.
+#[cfg(windows)]
+pub fn export_macro(name: &str) -> ~str {
+    format!("{}=__declspec(dllexport)", name)
+}
+
+#[cfg(not(windows))]
+pub fn export_macro(name: &str) -> ~str {
+    format!("{}=__attribute__((visibility(\"default\")))", name)
+}
+
+/// The definition of the export macro `name` for the users of the library,
+/// e.g. `Library::new(lib).add_public_macro(import_macro("FOO_API"))`.
+#[cfg(windows)]
+pub fn import_macro(name: &str) -> ~str {
+    format!("{}=__declspec(dllimport)", name)
+}
+
+#[cfg(not(windows))]
+pub fn import_macro(name: &str) -> ~str {
+    format!("{}=", name)
+}
+
+/// Returns the `-x` language for sources whose language has to be spelled
+/// out, such as Objective-C (`.m`) and Objective-C++ (`.mm`).
+pub fn source_language(src: &Path) -> Option<&'static str> {
+    match src.extension_str() {
+        Some("m") => Some("objective-c"),
+        Some("mm") => Some("objective-c++"),
+        _ => None,
+    }
+}
+
 impl StaticBuilder {
     pub fn new(ctx: Context) -> StaticBuilder {
         StaticBuilder::new_with(
@@ -40,19 +260,63 @@ impl StaticBuilder {
     }
 
     pub fn compile<T: IntoFuture<Path>>(&self, src: T) -> Gcc {
+        self.compile_with(src, |gcc| gcc)
+    }
+
+    /// Compile every file under `dir` with one of `extensions`, returning
+    /// the objects.
+    pub fn compile_dir<T: IntoPath>(&self, dir: T, extensions: &[&str]) -> Vec<Future<Path>> {
+        self.compile_dir_with(dir, extensions, true, [])
+    }
+
+    /// Like `compile_dir`, optionally without descending into
+    /// subdirectories, and skipping files matching any of the `excludes`
+    /// patterns. See `path_util::find_files_with`.
+    pub fn compile_dir_with<T: IntoPath>(
+        &self,
+        dir: T,
+        extensions: &[&str],
+        recursive: bool,
+        excludes: &[&str]
+    ) -> Vec<Future<Path>> {
+        let srcs = path_util::find_files_with(&dir.into_path(), extensions, recursive, excludes).unwrap();
+
+        srcs.move_iter().map(|src| self.compile(src).into_future()).collect()
+    }
+
+    /// Compile `src` with extra settings applied on top of the builder's,
+    /// e.g. `compile_with(src, |gcc| gcc.add_flag("-O0"))`, to special-case
+    /// a single translation unit.
+    pub fn compile_with<T: IntoFuture<Path>>(&self, src: T, f: |Gcc| -> Gcc) -> Gcc {
         let src = src.into_future().unwrap();
-        let dst = src.with_extension(COMPILE_SUFFIX);
+        let dst = self.gcc.object_path(&src).with_extension(COMPILE_SUFFIX);
 
-        self.gcc.clone()
-            .set_dst(dst)
+        let gcc = match source_language(&src) {
+            Some(language) => self.gcc.clone().add_flag(~"-x").add_flag(language),
+            None => self.gcc.clone(),
+        };
+        let gcc = f(gcc);
+
+        gcc.set_dst(dst)
             .set_dst_suffix(COMPILE_SUFFIX)
             .add_src(src)
             .add_flag(~"-c")
     }
 
+    /// Compile `srcs` into an `ObjectLibrary`, whose objects can be linked
+    /// into several targets.
+    pub fn object_library<T: IntoFuture<Path>>(&self, srcs: Vec<T>) -> ObjectLibrary {
+        let objects: Vec<Future<Path>> = srcs.move_iter().map(|src| {
+            self.compile(src).into_future()
+        }).collect();
+
+        ObjectLibrary::new(objects)
+    }
+
     pub fn link_lib<T: IntoPath>(&self, dst: T) -> Ar {
+        // Archives of instrumented objects go with the rest of the variant.
         self.ar.clone()
-            .set_dst(dst)
+            .set_dst(self.gcc.variant_dir().join(dst.into_path()))
             .set_dst_prefix(LIB_PREFIX)
             .set_dst_suffix(STATIC_LIB_SUFFIX)
     }
@@ -62,60 +326,19 @@ impl StaticBuilder {
             .set_dst(dst)
     }
 
-    pub fn add_include<T: IntoFuture<Path>>(self, include: T) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.add_include(include), ar: ar }
-    }
-
-    pub fn add_lib<T: IntoFuture<Path>>(self, lib: T) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.add_lib(lib), ar: ar }
-    }
-
-    pub fn add_external_lib<T: Str>(self, lib: T) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.add_external_lib(lib), ar: ar }
-    }
-
-    pub fn add_libpath<T: IntoPath>(self, libpath: T) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.add_libpath(libpath), ar: ar }
-    }
-
-    pub fn add_macro<T: Str>(self, macro: T) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.add_macro(macro), ar: ar }
-    }
-
-    pub fn add_warning<T: Str>(self, warning: T) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.add_warning(warning), ar: ar }
-    }
-
-    pub fn set_debug(self, debug: bool) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.set_debug(debug), ar: ar }
-    }
-
-    pub fn set_optimize(self, optimize: bool) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.set_optimize(optimize), ar: ar }
-    }
-
-    pub fn set_profile(self, profile: bool) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.set_profile(profile), ar: ar }
-    }
-
-    pub fn add_flag<S: Str>(self, flag: S) -> StaticBuilder {
-        let StaticBuilder { gcc, ar } = self;
-        StaticBuilder { gcc: gcc.add_flag(flag), ar: ar }
+    /// Write the metadata other rbuild projects need to use `lib`, built
+    /// by `link_lib`, to `dst`.
+    pub fn write_metadata<T: IntoFuture<Path>, U: IntoPath>(&self, lib: T, dst: U) -> Future<Path> {
+        self.gcc.write_metadata(lib, dst)
     }
 }
 
+gcc_setters!(StaticBuilder { ar })
+
 #[deriving(Clone)]
 pub struct SharedBuilder {
     gcc: Gcc,
+    version: Option<(uint, uint, uint)>,
 }
 
 impl SharedBuilder {
@@ -126,82 +349,143 @@ impl SharedBuilder {
     pub fn new_with(gcc: Gcc) -> SharedBuilder {
         SharedBuilder {
             gcc: gcc,
+            version: None,
         }
     }
 
     pub fn compile<T: IntoFuture<Path>>(&self, src: T) -> Gcc {
+        self.compile_with(src, |gcc| gcc)
+    }
+
+    /// Compile every file under `dir` with one of `extensions`, returning
+    /// the objects.
+    pub fn compile_dir<T: IntoPath>(&self, dir: T, extensions: &[&str]) -> Vec<Future<Path>> {
+        self.compile_dir_with(dir, extensions, true, [])
+    }
+
+    /// Like `compile_dir`, optionally without descending into
+    /// subdirectories, and skipping files matching any of the `excludes`
+    /// patterns. See `path_util::find_files_with`.
+    pub fn compile_dir_with<T: IntoPath>(
+        &self,
+        dir: T,
+        extensions: &[&str],
+        recursive: bool,
+        excludes: &[&str]
+    ) -> Vec<Future<Path>> {
+        let srcs = path_util::find_files_with(&dir.into_path(), extensions, recursive, excludes).unwrap();
+
+        srcs.move_iter().map(|src| self.compile(src).into_future()).collect()
+    }
+
+    /// Compile `src` with extra settings applied on top of the builder's,
+    /// e.g. `compile_with(src, |gcc| gcc.add_flag("-O0"))`, to special-case
+    /// a single translation unit.
+    pub fn compile_with<T: IntoFuture<Path>>(&self, src: T, f: |Gcc| -> Gcc) -> Gcc {
         let src = src.into_future().unwrap();
-        let dst = src.with_extension(COMPILE_SUFFIX);
+        let dst = self.gcc.object_path(&src).with_extension(COMPILE_SUFFIX);
 
-        self.gcc.clone()
-            .set_dst(dst)
+        let gcc = match source_language(&src) {
+            Some(language) => self.gcc.clone().add_flag(~"-x").add_flag(language),
+            None => self.gcc.clone(),
+        };
+        let gcc = f(gcc);
+
+        gcc.set_dst(dst)
             .set_dst_suffix(COMPILE_SUFFIX)
             .add_src(src)
             .add_flag(~"-c")
             .add_flag(~"-fPIC")
     }
 
-    pub fn link_lib<T: IntoPath>(&self, dst: T) -> Gcc {
-        self.gcc.clone()
-            .set_dst(dst)
-            .set_dst_prefix(LIB_PREFIX)
-            .set_dst_suffix(SHARED_LIB_SUFFIX)
-            .add_flag(~"-fPIC")
-            .add_flag(~"-dynamiclib")
-    }
+    /// Compile `srcs` into an `ObjectLibrary`, whose objects can be linked
+    /// into several targets.
+    pub fn object_library<T: IntoFuture<Path>>(&self, srcs: Vec<T>) -> ObjectLibrary {
+        let objects: Vec<Future<Path>> = srcs.move_iter().map(|src| {
+            self.compile(src).into_future()
+        }).collect();
 
-    pub fn link_exe<T: IntoPath>(&self, dst: T) -> Gcc {
-        self.gcc.clone()
-            .set_dst(dst)
+        ObjectLibrary::new(objects)
     }
 
-    pub fn add_include<T: IntoFuture<Path>>(self, include: T) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.add_include(include) }
-    }
-
-    pub fn add_lib<T: IntoFuture<Path>>(self, lib: T) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.add_lib(lib) }
-    }
-
-    pub fn add_external_lib<T: Str>(self, lib: T) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.add_external_lib(lib) }
-    }
+    pub fn link_lib<T: IntoPath>(&self, dst: T) -> Gcc {
+        let version = match self.version {
+            Some(version) => version,
+            None => {
+                return self.gcc.clone()
+                    .set_dst(dst)
+                    .set_dst_prefix(LIB_PREFIX)
+                    .set_dst_suffix(SHARED_LIB_SUFFIX)
+                    .add_flag(~"-fPIC")
+                    .add_flag(~"-dynamiclib");
+            }
+        };
+
+        let dst = dst.into_path();
+        let (lib, soname, link) = versioned_names(dst.filename_str().unwrap(), version);
+
+        let mut gcc = self.gcc.clone()
+            .set_dst(dst.with_filename(lib))
+            .add_symlink(dst.with_filename(soname.as_slice()))
+            .add_symlink(dst.with_filename(link))
+            .add_flag(~"-fPIC")
+            .add_flag(~"-dynamiclib");
 
-    pub fn add_libpath<T: IntoPath>(self, libpath: T) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.add_libpath(libpath) }
-    }
+        for flag in version_flags(soname, version).move_iter() {
+            gcc = gcc.add_flag(flag);
+        }
 
-    pub fn add_macro<T: Str>(self, macro: T) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.add_macro(macro) }
+        gcc
     }
 
-    pub fn add_warning<T: Str>(self, warning: T) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.add_warning(warning) }
+    /// Version the libraries built by `link_lib`, e.g. `set_version(1, 2, 3)`
+    /// builds `libfoo.so.1.2.3` with the soname `libfoo.so.1`, along with
+    /// the `libfoo.so.1` and `libfoo.so` symlinks.
+    pub fn set_version(mut self, major: uint, minor: uint, patch: uint) -> SharedBuilder {
+        self.version = Some((major, minor, patch));
+        self
     }
 
-    pub fn set_debug(self, debug: bool) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.set_debug(debug) }
+    pub fn link_exe<T: IntoPath>(&self, dst: T) -> Gcc {
+        self.gcc.clone()
+            .set_dst(dst)
     }
 
-    pub fn set_optimize(self, optimize: bool) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.set_optimize(optimize) }
+    /// Write the metadata other rbuild projects need to use `lib`, built
+    /// by `link_lib`, to `dst`.
+    pub fn write_metadata<T: IntoFuture<Path>, U: IntoPath>(&self, lib: T, dst: U) -> Future<Path> {
+        self.gcc.write_metadata(lib, dst)
     }
 
-    pub fn set_profile(self, profile: bool) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.set_profile(profile) }
+    /// Define the export macro `name` for the library's own compiles. See
+    /// `export_macro`. Symbols can also be exported by name with
+    /// `add_exported_symbol` on the link, which writes a `.def` file on
+    /// Windows.
+    pub fn add_export_macro(self, name: &str) -> SharedBuilder {
+        self.add_macro(export_macro(name))
     }
 
-    pub fn add_flag<S: Str>(self, flag: S) -> SharedBuilder {
-        let SharedBuilder { gcc } = self;
-        SharedBuilder { gcc: gcc.add_flag(flag) }
+    /// Build a plugin from every subdirectory of `dir` whose name matches
+    /// `pattern`, e.g. `build_plugins("plugins", "*", ..)`. `recipe` is
+    /// given the builder and a plugin's directory, and returns its link,
+    /// such as:
+    ///
+    /// ```ignore
+    /// |c, dir| c.link_lib(dir.join(dir.filename_str().unwrap()))
+    ///     .add_srcs(c.compile_dir(dir.clone(), ["c"]))
+    /// ```
+    ///
+    /// New plugin directories are picked up on the next run.
+    pub fn build_plugins<T: IntoPath>(
+        &self,
+        dir: T,
+        pattern: &str,
+        recipe: |&SharedBuilder, &Path| -> Gcc
+    ) -> Vec<Future<Path>> {
+        let dirs = path_util::find_dirs(self.gcc.ctx(), &dir.into_path(), pattern).unwrap();
+
+        dirs.iter().map(|dir| recipe(self, dir).into_future()).collect()
     }
 }
+
+gcc_setters!(SharedBuilder { version })
@@ -0,0 +1,111 @@
+use std::str;
+use sync::Future;
+
+use context::Context;
+
+/// The directories a compiler searches by default, which hold the system's
+/// headers and libraries rather than the project's.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct SearchDirs {
+    pub includes: Vec<Path>,
+    pub libraries: Vec<Path>,
+}
+
+impl SearchDirs {
+    /// Asks the compiler `exe` for its search directories. The answer is
+    /// cached until the compiler changes.
+    pub fn query(ctx: Context, exe: &Path) -> Future<SearchDirs> {
+        let exe = exe.clone();
+
+        let mut prep = ctx.prep("SearchDirs");
+        prep.declare_input_path(exe.clone()).unwrap();
+
+        prep.exec(proc(exec) {
+            let prog = exe.as_str().unwrap();
+
+            // The include directories are only listed by a verbose
+            // preprocessor run.
+            let args = [~"-E", ~"-v", ~"-x", ~"c", ~"/dev/null"];
+            let output = exec.process_builder(prog, args)
+                .description("search dirs")
+                .msg(exe.display())
+                .run_or_fail();
+            let includes = parse_includes(str::from_utf8_lossy(output.error.as_slice()).as_slice());
+
+            let args = [~"-print-search-dirs"];
+            let output = exec.process_builder(prog, args)
+                .description("search dirs")
+                .msg(exe.display())
+                .run_or_fail();
+            let libraries = parse_libraries(str::from_utf8_lossy(output.output.as_slice()).as_slice());
+
+            SearchDirs {
+                includes: includes,
+                libraries: libraries,
+            }
+        })
+    }
+
+    /// Whether `path` is in one of the directories.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.includes.iter().chain(self.libraries.iter()).any(|dir| dir.is_ancestor_of(path))
+    }
+}
+
+/// Parses the include directories out of the stderr of `cc -E -v`, which
+/// lists them between "search starts here:" and "End of search list.".
+pub fn parse_includes(output: &str) -> Vec<Path> {
+    let mut dirs = Vec::new();
+    let mut in_list = false;
+
+    for line in output.lines() {
+        if line.starts_with("#include ") && line.ends_with("search starts here:") {
+            in_list = true;
+        } else if line.starts_with("End of search list.") {
+            in_list = false;
+        } else if in_list {
+            // clang marks the directories of macOS frameworks.
+            let dir = line.trim();
+            let dir = if dir.ends_with(" (framework directory)") {
+                dir.slice_to(dir.len() - " (framework directory)".len())
+            } else {
+                dir
+            };
+
+            let dir = Path::new(dir);
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+
+    dirs
+}
+
+/// Parses the library directories out of the output of
+/// `cc -print-search-dirs`, from its "libraries: =a:b" line.
+pub fn parse_libraries(output: &str) -> Vec<Path> {
+    let mut dirs = Vec::new();
+
+    for line in output.lines() {
+        if !line.starts_with("libraries:") {
+            continue;
+        }
+
+        let list = line.slice_from("libraries:".len()).trim().trim_left_chars('=');
+        for dir in list.split(':') {
+            if dir.is_empty() {
+                continue;
+            }
+
+            // gcc doesn't normalize the directories, so the same one may
+            // appear with several spellings.
+            let dir = Path::new(dir);
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+
+    dirs
+}
@@ -0,0 +1,212 @@
+use std::io;
+use std::io::fs;
+use sync::Future;
+
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static SWIG_EXES: &'static [&'static str] = &'static ["swig"];
+
+/// Generates language bindings from a SWIG `.i` interface file. The future
+/// resolves to the generated C/C++ wrapper source, so it can be handed
+/// straight to a C builder's `compile`. The language side files (e.g. the
+/// Python module) are written to the output directory and registered as
+/// outputs too.
+#[deriving(Clone)]
+pub struct Swig {
+    ctx: Context,
+    exe: Path,
+    lang: ~str,
+    cplusplus: bool,
+    src: Option<Path>,
+    dst: Option<Path>,
+    outdir: Option<Path>,
+    header: Option<Path>,
+    includes: Vec<Path>,
+    macros: Vec<~str>,
+    flags: Vec<~str>,
+}
+
+impl Swig {
+    pub fn new<T: Str>(ctx: Context, lang: T) -> Swig {
+        let exe = path_util::find_program(ctx.clone(), SWIG_EXES);
+
+        Swig::new_with(ctx, exe, lang)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>, U: Str>(ctx: Context, exe: T, lang: U) -> Swig {
+        Swig {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            lang: lang.into_owned(),
+            cplusplus: false,
+            src: None,
+            dst: None,
+            outdir: None,
+            header: None,
+            includes: Vec::new(),
+            macros: Vec::new(),
+            flags: Vec::new(),
+        }
+    }
+
+    /// Wrap interface file `src`, writing the wrapper next to it in the build
+    /// directory.
+    pub fn wrap<T: IntoFuture<Path>>(&self, src: T) -> Swig {
+        let src = src.into_future().unwrap();
+        let ext = if self.cplusplus { "cxx" } else { "c" };
+        let name = format!("{}_wrap.{}", src.filestem_str().unwrap(), ext);
+        let dst = src.with_filename(name);
+
+        self.clone()
+            .set_src(src)
+            .set_dst(dst)
+    }
+
+    pub fn set_cplusplus(mut self, cplusplus: bool) -> Swig {
+        self.cplusplus = cplusplus;
+        self
+    }
+
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Swig {
+        self.src = Some(src.into_future().unwrap());
+        self
+    }
+
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Swig {
+        self.dst = Some(self.build_path(dst.into_path()));
+        self
+    }
+
+    /// Where to write the language side files. Everything in it is taken to
+    /// be generated by this step, so it can't be shared with another `Swig`.
+    /// Defaults to a directory named after the wrapper and the target
+    /// language next to the wrapper, e.g. `foo_wrap.python`.
+    pub fn set_outdir<T: IntoPath>(mut self, outdir: T) -> Swig {
+        self.outdir = Some(self.build_path(outdir.into_path()));
+        self
+    }
+
+    /// Also write a header for the generated code (`-oh`), which C++
+    /// directors need.
+    pub fn set_header<T: IntoPath>(mut self, header: T) -> Swig {
+        self.header = Some(self.build_path(header.into_path()));
+        self
+    }
+
+    pub fn add_include<T: IntoFuture<Path>>(mut self, include: T) -> Swig {
+        self.includes.push(include.into_future().unwrap());
+        self
+    }
+
+    pub fn add_macro<T: Str>(mut self, macro: T) -> Swig {
+        self.macros.push(macro.into_owned());
+        self
+    }
+
+    pub fn add_flag<S: Str>(mut self, flag: S) -> Swig {
+        self.flags.push(flag.into_owned());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+
+    fn build_path(&self, path: Path) -> Path {
+        self.ctx.output_path(path)
+    }
+}
+
+impl IntoFuture<Path> for Swig {
+    fn into_future(self) -> Future<Path> {
+        let Swig {
+            ctx,
+            exe,
+            lang,
+            cplusplus,
+            src,
+            dst,
+            outdir,
+            header,
+            includes,
+            macros,
+            flags
+        } = self;
+
+        assert!(src.is_some());
+        assert!(dst.is_some());
+        let src = src.unwrap();
+        let dst = dst.unwrap();
+
+        let outdir = match outdir {
+            Some(outdir) => outdir,
+            None => dst.dir_path().join(format!("{}.{}", dst.filestem_str().unwrap(), lang)),
+        };
+
+        let mut prep = ctx.prep("Call");
+        let mut call = Call::new(exe.clone()).unwrap();
+
+        call.push_str("-" + lang);
+
+        if cplusplus { call.push_str(~"-c++"); }
+
+        for include in includes.move_iter() {
+            call.push_str(~"-I");
+            call.push_input_path(include).unwrap();
+        }
+
+        for macro in macros.move_iter() {
+            call.push_str(~"-D");
+            call.push_str(macro);
+        }
+
+        for flag in flags.move_iter() {
+            call.push_str(flag);
+        }
+
+        call.push_str(~"-outdir");
+        call.push_str(outdir.as_str().unwrap().to_owned());
+
+        call.push_str(~"-o");
+        call.push_output_path(dst.clone());
+
+        match header {
+            Some(ref header) => {
+                call.push_str(~"-oh");
+                call.push_output_path(header.clone());
+            }
+            None => { }
+        }
+
+        call.push_input_path(src.clone()).ok().expect("src");
+
+        prep.declare_call(&call);
+        prep.export_command(call.shell_cmd(None, false), None);
+
+        prep.exec(proc(exec) {
+            let (prog, args) = call.cmd();
+
+            // Make sure the output directories exist.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+            fs::mkdir_recursive(&outdir, io::UserDir).unwrap();
+
+            exec.process_builder(prog, args.as_slice())
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msg(src.display())
+                .run_or_fail();
+
+            // The output directory belongs to this step, so everything in it
+            // was generated by swig.
+            for path in fs::readdir(&outdir).unwrap().iter() {
+                exec.discover_output_path(path.as_str().unwrap(), path);
+            }
+
+            dst
+        })
+    }
+}
@@ -0,0 +1,260 @@
+//! Runs the compiles listed in a `compile_commands.json` written by another
+//! build system, so a project can be built and cached by rbuild before its
+//! build is rewritten:
+//!
+//! ```ignore
+//! let objects = CompileCommands::new(ctx.clone(), "old-build/compile_commands.json").run();
+//! c_static.link_exe("foo").add_srcs(objects).run();
+//! ```
+//!
+//! The objects are written to the build root instead of where the original
+//! build put them, and the compiler's depfile records the headers each
+//! source includes.
+
+use std::io;
+use std::io::{fs, File, IoResult};
+use std::os;
+use serialize::json;
+use sync::Future;
+
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+use sha256;
+use shell;
+
+/// One compile from the database.
+#[deriving(Clone)]
+pub struct Entry {
+    /// The directory the compile runs in, which relative paths are
+    /// relative to.
+    pub directory: Path,
+    pub file: Path,
+    pub args: Vec<~str>,
+}
+
+/// Reads the entries of the database at `path`.
+pub fn read(path: &Path) -> IoResult<Vec<Entry>> {
+    let contents = try!(File::open(path).read_to_str());
+
+    let invalid = |detail: ~str| io::IoError {
+        kind: io::InvalidInput,
+        desc: "invalid compile_commands.json",
+        detail: Some(detail),
+    };
+
+    let json = match json::from_str(contents) {
+        Ok(json) => json,
+        Err(err) => { return Err(invalid(err.to_str())); }
+    };
+
+    let list = match json.as_list() {
+        Some(list) => list,
+        None => { return Err(invalid(~"expected a list of entries")); }
+    };
+
+    let mut entries = Vec::new();
+
+    for item in list.iter() {
+        let string = |key: &str| item.find(&key.to_owned()).and_then(|value| value.as_string());
+
+        let directory = match string("directory") {
+            Some(directory) => Path::new(directory),
+            None => { return Err(invalid(~"entry without a directory")); }
+        };
+
+        let file = match string("file") {
+            Some(file) => directory.join(file),
+            None => { return Err(invalid(~"entry without a file")); }
+        };
+
+        // Entries have either a list of arguments or a command line.
+        let args = match item.find(&~"arguments").and_then(|value| value.as_list()) {
+            Some(args) => args.iter().filter_map(|arg| arg.as_string()).map(|arg| arg.to_owned()).collect(),
+            None => match string("command") {
+                Some(command) => shell::split(command),
+                None => { return Err(invalid(format!("no command for {}", file.display()))); }
+            },
+        };
+
+        if args.is_empty() {
+            return Err(invalid(format!("empty command for {}", file.display())));
+        }
+
+        entries.push(Entry {
+            directory: directory,
+            file: file,
+            args: args,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Finds `program` the way a shell would from `directory`. Bare names are
+/// looked up like any other program, so the lookup is redone when `PATH` or
+/// an override like `CC` changes.
+fn find_program(ctx: &Context, program: &str, directory: &Path) -> IoResult<Path> {
+    let path = Path::new(program);
+    if path.is_absolute() || path.dirname() != bytes!(".") {
+        return Ok(directory.join(path));
+    }
+
+    path_util::try_find_program_named(ctx.clone(), program)
+}
+
+/// Flags whose argument is a path, which is made absolute so that the call
+/// is keyed by the files it reads rather than by the entry's directory.
+static PATH_FLAGS: &'static [&'static str] = &[
+    "-I", "-isystem", "-iquote", "-idirafter", "-include", "-imacros", "-L",
+];
+
+/// Turns an entry into a call of the same compile, writing the object to
+/// `dst`. Dependency flags are dropped, since the call adds its own.
+fn to_call(ctx: &Context, entry: &Entry, dst: &Path) -> IoResult<Call> {
+    let dir = &entry.directory;
+    let prog = try!(find_program(ctx, entry.args.get(0).as_slice(), dir));
+
+    let mut call = try!(Call::new(prog));
+    let mut args = entry.args.slice_from(1).iter();
+
+    loop {
+        let arg = match args.next() {
+            Some(arg) => arg.as_slice(),
+            None => { break; }
+        };
+
+        match arg {
+            "-o" => { args.next(); }
+            "-MF" | "-MT" | "-MQ" => { args.next(); }
+            "-M" | "-MM" | "-MD" | "-MMD" | "-MP" => { }
+            _ if PATH_FLAGS.contains(&arg) => {
+                call.push_str(arg.to_owned());
+                match args.next() {
+                    Some(path) => { call.push_str(dir.join(path.as_slice()).as_str().unwrap().to_owned()); }
+                    None => { }
+                }
+            }
+            _ => {
+                // A path can also be joined to its flag, as in "-Iinclude".
+                let joined = PATH_FLAGS.iter().find(|flag| {
+                    arg.starts_with(**flag) && arg.len() > flag.len()
+                });
+
+                match joined {
+                    Some(flag) => {
+                        let path = dir.join(arg.slice_from(flag.len()));
+                        call.push_str(format!("{}{}", *flag, path.display()));
+                    }
+                    None if dir.join(arg) == entry.file => {
+                        try!(call.push_input_path(entry.file.clone()));
+                    }
+                    None => { call.push_str(arg.to_owned()); }
+                }
+            }
+        }
+    }
+
+    call.push_str(~"-o");
+    call.push_output_path(dst.clone());
+
+    Ok(call)
+}
+
+#[deriving(Clone)]
+pub struct CompileCommands {
+    ctx: Context,
+    path: Path,
+}
+
+impl CompileCommands {
+    pub fn new<T: IntoPath>(ctx: Context, path: T) -> CompileCommands {
+        CompileCommands {
+            ctx: ctx,
+            path: path.into_path(),
+        }
+    }
+
+    /// Starts every compile, returning the objects.
+    pub fn compile(&self) -> Vec<Future<Path>> {
+        let entries = match read(&self.path) {
+            Ok(entries) => entries,
+            Err(err) => fail!("failed to read {}: {}", self.path.display(), err),
+        };
+
+        entries.iter().map(|entry| self.compile_entry(entry)).collect()
+    }
+
+    pub fn run(self) -> Vec<Path> {
+        self.into_future().unwrap()
+    }
+
+    fn compile_entry(&self, entry: &Entry) -> Future<Path> {
+        // Objects keep the source's place in the tree, and sources outside
+        // it their full path, so two sources with the same name don't
+        // collide. A source can be compiled more than once with different
+        // flags, so the object is also named after the command.
+        let file = os::make_absolute(&entry.file);
+        let rel = match file.path_relative_from(&os::getcwd()) {
+            Some(rel) if !rel.as_vec().starts_with(bytes!("..")) => rel,
+            _ => Path::new("external").join(file.path_relative_from(&Path::new("/")).unwrap()),
+        };
+        let command = format!("{}\0{}", entry.directory.display(), entry.args.connect("\0"));
+        let hash = sha256::hex_digest(command.as_bytes()).slice_to(8).to_owned();
+        let name = format!("{}-{}.o", rel.filename_str().unwrap(), hash);
+        let dst = os::make_absolute(&self.ctx.output_path(rel.with_filename(name)));
+        let depfile = dst.with_extension("d");
+
+        let call = match to_call(&self.ctx, entry, &dst) {
+            Ok(call) => call,
+            Err(err) => fail!("can't import the compile of {}: {}", entry.file.display(), err),
+        };
+
+        let mut prep = self.ctx.prep("Call");
+        prep.declare_call(&call);
+
+        let mut exported = call.clone();
+        for arg in ["-MD", "-MT", dst.as_str().unwrap(), "-MF", depfile.as_str().unwrap()].iter() {
+            exported.push_str(arg.to_owned());
+        }
+        prep.export_command(format!("cd {} && {}",
+                                    shell::quote(entry.directory.as_str().unwrap()),
+                                    exported.shell_cmd(None, true)),
+                            Some(depfile.clone()));
+
+        let directory = entry.directory.clone();
+
+        prep.exec(proc(exec) {
+            let (prog, mut args) = call.tmp_cmd().unwrap();
+            args.push(~"-MD");
+            args.push(~"-MF");
+            args.push(depfile.as_str().unwrap().to_owned());
+
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            // Arguments that aren't known to be paths, like `@file`, may
+            // be relative to the entry's directory, so the compile runs
+            // there.
+            exec.process_builder(prog, args.as_slice())
+                .cwd(&directory)
+                .depfile(&depfile)
+                .description("compile")
+                .msg(file.display())
+                .run_or_fail();
+            call.rename_outputs().unwrap();
+
+            dst
+        })
+    }
+}
+
+impl IntoFuture<Vec<Path>> for CompileCommands {
+    fn into_future(self) -> Future<Vec<Path>> {
+        let objects = self.compile();
+
+        Future::from_fn(proc() {
+            objects.move_iter().map(|object| object.unwrap()).collect()
+        })
+    }
+}
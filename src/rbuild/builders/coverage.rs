@@ -0,0 +1,144 @@
+use std::io;
+use std::io::fs;
+use sync::Future;
+
+use context::Context;
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static LCOV_EXES: &'static [&'static str] = &'static ["lcov"];
+pub static GENHTML_EXES: &'static [&'static str] = &'static ["genhtml"];
+
+/// The files gcov reads: the notes written at compile time and the counts
+/// written when the instrumented program runs.
+static COVERAGE_EXTS: &'static [&'static str] = &["gcno", "gcda"];
+
+/// Collects the coverage counts of a build compiled with `set_coverage(true)`
+/// and renders them as an HTML report with `lcov` and `genhtml`.
+///
+/// The counts only exist once the tests have run, so pass whatever the test
+/// run produces to `add_dep` to make sure the report is generated after it.
+#[deriving(Clone)]
+pub struct Lcov {
+    ctx: Context,
+    lcov: Path,
+    genhtml: Path,
+    gcov_tool: Option<Path>,
+    dir: Option<Path>,
+    dst: Option<Path>,
+    deps: Vec<Path>,
+}
+
+impl Lcov {
+    pub fn new(ctx: Context) -> Lcov {
+        let lcov = path_util::find_program(ctx.clone(), LCOV_EXES);
+        let genhtml = path_util::find_program(ctx.clone(), GENHTML_EXES);
+
+        Lcov::new_with(ctx, lcov, genhtml)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>, U: IntoFuture<Path>>(ctx: Context, lcov: T, genhtml: U) -> Lcov {
+        Lcov {
+            ctx: ctx,
+            lcov: lcov.into_future().unwrap(),
+            genhtml: genhtml.into_future().unwrap(),
+            gcov_tool: None,
+            dir: None,
+            dst: None,
+            deps: Vec::new(),
+        }
+    }
+
+    /// Use a specific `gcov`, e.g. one matching the compiler version.
+    pub fn set_gcov_tool<T: IntoPath>(mut self, gcov_tool: T) -> Lcov {
+        self.gcov_tool = Some(gcov_tool.into_path());
+        self
+    }
+
+    /// The directory the instrumented objects were written to. Defaults to
+    /// the build root.
+    pub fn set_dir<T: IntoPath>(mut self, dir: T) -> Lcov {
+        self.dir = Some(dir.into_path());
+        self
+    }
+
+    /// Set the directory the HTML report is written to.
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Lcov {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    pub fn add_dep<T: IntoFuture<Path>>(mut self, dep: T) -> Lcov {
+        self.deps.push(dep.into_future().unwrap());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Lcov {
+    fn into_future(self) -> Future<Path> {
+        let Lcov { ctx, lcov, genhtml, gcov_tool, dir, dst, deps } = self;
+
+        assert!(dst.is_some());
+        let dst = dst.unwrap();
+        let dir = dir.unwrap_or(ctx.root.clone());
+
+        let mut prep = ctx.prep("Lcov");
+        prep.declare_input_path(lcov.clone()).unwrap();
+        prep.declare_input_path(genhtml.clone()).unwrap();
+        prep.declare_input("value", "gcov_tool", &gcov_tool);
+        prep.declare_input("value", "dst", &dst);
+
+        for dep in deps.move_iter() {
+            prep.declare_input_path(dep).unwrap();
+        }
+
+        for file in path_util::find_files(&dir, COVERAGE_EXTS).unwrap().move_iter() {
+            prep.declare_input_path(file).unwrap();
+        }
+
+        prep.exec(proc(exec) {
+            fs::mkdir_recursive(&dst, io::UserDir).unwrap();
+
+            let info = dst.join("coverage.info");
+
+            let mut args = vec!(
+                ~"--capture",
+                ~"--directory", dir.as_str().unwrap().to_owned(),
+                ~"--output-file", info.as_str().unwrap().to_owned());
+            match gcov_tool {
+                Some(ref gcov_tool) => {
+                    args.push(~"--gcov-tool");
+                    args.push(gcov_tool.as_str().unwrap().to_owned());
+                }
+                None => { }
+            }
+
+            exec.process_builder(lcov.as_str().unwrap(), args.as_slice())
+                .description(lcov.filename_display())
+                .msg(info.display())
+                .msg("<-")
+                .msg(dir.display())
+                .run_or_fail();
+
+            let args = vec!(
+                info.as_str().unwrap().to_owned(),
+                ~"--output-directory", dst.as_str().unwrap().to_owned());
+
+            exec.process_builder(genhtml.as_str().unwrap(), args.as_slice())
+                .description(genhtml.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msg(info.display())
+                .run_or_fail();
+
+            exec.discover_output_path("report", &dst);
+
+            dst
+        })
+    }
+}
@@ -0,0 +1,249 @@
+use std::io;
+use std::io::fs;
+use sync::Future;
+
+use builders::tool::{Tool, ToolBase};
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static EXES: &'static [&'static str] = &'static ["nvcc"];
+
+/// How to link the CUDA runtime library.
+#[deriving(Clone, Eq)]
+pub enum Cudart {
+    StaticCudart,
+    SharedCudart,
+    NoCudart,
+}
+
+#[deriving(Clone)]
+pub struct Nvcc {
+    base: ToolBase,
+    includes: Vec<Path>,
+    libs: Vec<Path>,
+    external_libs: Vec<~str>,
+    libpaths: Vec<Path>,
+    macros: Vec<~str>,
+    gencodes: Vec<(~str, ~str)>,
+    host_flags: Vec<~str>,
+    device_flags: Vec<~str>,
+    cudart: Cudart,
+    debug: bool,
+    device_debug: bool,
+    optimize: bool,
+}
+
+impl Nvcc {
+    pub fn new(ctx: Context) -> Nvcc {
+        let exe = path_util::find_program(ctx.clone(), EXES);
+
+        Nvcc::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Nvcc {
+        Nvcc {
+            base: ToolBase::new(ctx, exe),
+            includes: Vec::new(),
+            libs: Vec::new(),
+            external_libs: Vec::new(),
+            libpaths: Vec::new(),
+            macros: Vec::new(),
+            gencodes: Vec::new(),
+            host_flags: Vec::new(),
+            device_flags: Vec::new(),
+            cudart: StaticCudart,
+            debug: false,
+            device_debug: false,
+            optimize: false,
+        }
+    }
+
+    /// Compile a `.cu` source into an object file.
+    pub fn compile<T: IntoFuture<Path>>(&self, src: T) -> Nvcc {
+        let src = src.into_future().unwrap();
+        let dst = src.with_extension("o");
+
+        self.clone()
+            .set_dst(dst)
+            .add_src(src)
+            .add_flag(~"-c")
+    }
+
+    /// Link objects into an executable against the CUDA runtime.
+    pub fn link_exe<T: IntoPath>(&self, dst: T) -> Nvcc {
+        self.clone()
+            .set_dst(dst)
+    }
+
+    pub fn add_include<T: IntoFuture<Path>>(mut self, include: T) -> Nvcc {
+        self.includes.push(include.into_future().unwrap());
+        self
+    }
+
+    pub fn add_lib<T: IntoFuture<Path>>(mut self, lib: T) -> Nvcc {
+        self.libs.push(lib.into_future().unwrap());
+        self
+    }
+
+    pub fn add_external_lib<T: Str>(mut self, lib: T) -> Nvcc {
+        self.external_libs.push(lib.into_owned());
+        self
+    }
+
+    pub fn add_libpath<T: IntoPath>(mut self, libpath: T) -> Nvcc {
+        self.libpaths.push(libpath.into_path());
+        self
+    }
+
+    pub fn add_macro<T: Str>(mut self, macro: T) -> Nvcc {
+        self.macros.push(macro.into_owned());
+        self
+    }
+
+    /// Generate code for a GPU architecture, e.g. `add_gencode("compute_70",
+    /// "sm_70")`.
+    pub fn add_gencode<T: Str, U: Str>(mut self, arch: T, code: U) -> Nvcc {
+        self.gencodes.push((arch.into_owned(), code.into_owned()));
+        self
+    }
+
+    /// Pass a flag through to the host compiler (`-Xcompiler`).
+    pub fn add_host_flag<T: Str>(mut self, flag: T) -> Nvcc {
+        self.host_flags.push(flag.into_owned());
+        self
+    }
+
+    /// Pass a flag that only affects device code generation, e.g.
+    /// `--use_fast_math`.
+    pub fn add_device_flag<T: Str>(mut self, flag: T) -> Nvcc {
+        self.device_flags.push(flag.into_owned());
+        self
+    }
+
+    pub fn set_cudart(mut self, cudart: Cudart) -> Nvcc {
+        self.cudart = cudart;
+        self
+    }
+
+    pub fn set_debug(mut self, debug: bool) -> Nvcc {
+        self.debug = debug;
+        self
+    }
+
+    /// Generate debug info for device code (`-G`). This disables most
+    /// device optimizations.
+    pub fn set_device_debug(mut self, device_debug: bool) -> Nvcc {
+        self.device_debug = device_debug;
+        self
+    }
+
+    pub fn set_optimize(mut self, optimize: bool) -> Nvcc {
+        self.optimize = optimize;
+        self
+    }
+
+    fn compile_only(&self) -> bool {
+        self.base.flags.iter().any(|flag| flag.as_slice() == "-c")
+    }
+}
+
+tool_methods!(Nvcc)
+
+impl Tool for Nvcc {
+    fn to_call(&self) -> Call {
+        let mut call = self.base.call();
+
+        call.push_str(~"-o");
+        call.push_output_path(self.base.dst());
+
+        for include in self.includes.iter() {
+            call.push_str(~"-I");
+            call.push_input_path(include.clone()).unwrap();
+        }
+
+        for macro in self.macros.iter() {
+            call.push_str(~"-D");
+            call.push_str(macro.clone());
+        }
+
+        for &(ref arch, ref code) in self.gencodes.iter() {
+            call.push_str(~"-gencode");
+            call.push_str(format!("arch={},code={}", arch, code));
+        }
+
+        for flag in self.host_flags.iter() {
+            call.push_str(~"-Xcompiler");
+            call.push_str(flag.clone());
+        }
+
+        for flag in self.device_flags.iter() {
+            call.push_str(flag.clone());
+        }
+
+        if self.debug { call.push_str(~"-g"); }
+        if self.device_debug { call.push_str(~"-G"); }
+        if self.optimize { call.push_str(~"-O2"); }
+
+        if !self.compile_only() {
+            for libpath in self.libpaths.iter() {
+                call.push_str(~"-L");
+                call.push_str(libpath.as_str().unwrap().to_owned());
+            }
+
+            for lib in self.libs.iter() {
+                call.push_input_path(lib.clone()).unwrap();
+            }
+
+            for lib in self.external_libs.iter() {
+                call.push_str(~"-l");
+                call.push_str(lib.clone());
+            }
+
+            call.push_str(~"--cudart");
+            call.push_str(match self.cudart {
+                StaticCudart => ~"static",
+                SharedCudart => ~"shared",
+                NoCudart => ~"none",
+            });
+        }
+
+        self.base.push_flags(&mut call);
+        self.base.push_srcs(&mut call);
+        call
+    }
+}
+
+impl IntoFuture<Path> for Nvcc {
+    fn into_future(self) -> Future<Path> {
+        let call = self.to_call();
+        let Nvcc { base, .. } = self;
+
+        let exe = base.exe.clone();
+        let dst = base.dst();
+        let srcs = base.all_srcs();
+
+        assert!(!srcs.is_empty());
+
+        let mut prep = base.ctx.prep("Call");
+        prep.declare_call(&call);
+
+        prep.exec(proc(exec) {
+            let (prog, args) = call.tmp_cmd().unwrap();
+
+            // Make sure the parent directories exist.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            exec.process_builder(prog, args.as_slice())
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msgs(srcs.iter().map(|src| src.display()))
+                .run_or_fail();
+            call.rename_outputs().unwrap();
+
+            dst
+        })
+    }
+}
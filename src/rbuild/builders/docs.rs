@@ -0,0 +1,355 @@
+use std::io;
+use std::io::File;
+use std::io::fs;
+use sync::Future;
+
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static DOXYGEN_EXES: &'static [&'static str] = &'static ["doxygen"];
+pub static SPHINX_EXES: &'static [&'static str] = &'static ["sphinx-build"];
+pub static PANDOC_EXES: &'static [&'static str] = &'static ["pandoc"];
+
+/// Extensions of the files Doxygen reads by default.
+pub static SOURCE_EXTS: &'static [&'static str] = &[
+    "h", "hh", "hpp", "hxx", "c", "cc", "cpp", "cxx", "dox", "md",
+];
+
+/// Extensions of the files a Sphinx project is made of.
+static SPHINX_EXTS: &'static [&'static str] = &[
+    "rst", "md", "txt", "py", "css", "html", "png", "svg",
+];
+
+fn build_path(ctx: &Context, path: Path) -> Path {
+    ctx.output_path(path)
+}
+
+/// Runs Doxygen over a set of sources. The Doxyfile and every source are
+/// inputs, and the generated `html` directory is the output, so the docs are
+/// only regenerated when something they document changes.
+#[deriving(Clone)]
+pub struct Doxygen {
+    ctx: Context,
+    exe: Path,
+    doxyfile: Option<Path>,
+    dst: Option<Path>,
+    srcs: Vec<Path>,
+}
+
+impl Doxygen {
+    pub fn new(ctx: Context) -> Doxygen {
+        let exe = path_util::find_program(ctx.clone(), DOXYGEN_EXES);
+
+        Doxygen::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Doxygen {
+        Doxygen {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            doxyfile: None,
+            dst: None,
+            srcs: Vec::new(),
+        }
+    }
+
+    pub fn set_doxyfile<T: IntoPath>(mut self, doxyfile: T) -> Doxygen {
+        self.doxyfile = Some(doxyfile.into_path());
+        self
+    }
+
+    /// Set the output directory. The HTML ends up in its `html` subdirectory.
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Doxygen {
+        self.dst = Some(build_path(&self.ctx, dst.into_path()));
+        self
+    }
+
+    pub fn add_src<T: IntoFuture<Path>>(mut self, src: T) -> Doxygen {
+        self.srcs.push(src.into_future().unwrap());
+        self
+    }
+
+    /// Add every source and header under `dir`.
+    pub fn add_src_dir<T: IntoPath>(mut self, dir: T) -> Doxygen {
+        let srcs = path_util::find_files(&dir.into_path(), SOURCE_EXTS).unwrap();
+        self.srcs.push_all_move(srcs);
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Doxygen {
+    fn into_future(self) -> Future<Path> {
+        let Doxygen { ctx, exe, doxyfile, dst, srcs } = self;
+
+        assert!(dst.is_some());
+        assert!(!srcs.is_empty());
+        let dst = dst.unwrap();
+
+        let mut prep = ctx.prep("Doxygen");
+        prep.declare_input_path(exe.clone()).unwrap();
+        prep.declare_input("value", "dst", &dst);
+
+        match doxyfile {
+            Some(ref doxyfile) => { prep.declare_named_input_path("doxyfile", doxyfile.clone()).unwrap(); }
+            None => { }
+        }
+
+        for src in srcs.iter() {
+            prep.declare_named_input_path(src.as_str().unwrap(), src.clone()).unwrap();
+        }
+
+        prep.exec(proc(exec) {
+            fs::mkdir_recursive(&dst, io::UserDir).unwrap();
+
+            // Point doxygen at our sources and output directory on top of
+            // the user's own configuration.
+            let config = dst.join("Doxyfile");
+            {
+                let mut file = File::create(&config);
+                match doxyfile {
+                    Some(ref doxyfile) => {
+                        (write!(&mut file, "@INCLUDE = \"{}\"\n", doxyfile.display())).unwrap();
+                    }
+                    None => { }
+                }
+                (write!(&mut file, "OUTPUT_DIRECTORY = \"{}\"\n", dst.display())).unwrap();
+                (write!(&mut file, "GENERATE_HTML = YES\n")).unwrap();
+                (write!(&mut file, "INPUT =")).unwrap();
+                for src in srcs.iter() {
+                    (write!(&mut file, " \\\n    \"{}\"", src.display())).unwrap();
+                }
+                (write!(&mut file, "\n")).unwrap();
+            }
+
+            let prog = exe.as_str().unwrap();
+            let args = [config.as_str().unwrap().to_owned()];
+
+            exec.process_builder(prog, args)
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .run_or_fail();
+
+            let html = dst.join("html");
+            exec.discover_output_path("html", &html);
+
+            html
+        })
+    }
+}
+
+/// Runs `sphinx-build` over a Sphinx project directory.
+#[deriving(Clone)]
+pub struct Sphinx {
+    ctx: Context,
+    exe: Path,
+    src_dir: Option<Path>,
+    dst: Option<Path>,
+    builder: ~str,
+    flags: Vec<~str>,
+}
+
+impl Sphinx {
+    pub fn new(ctx: Context) -> Sphinx {
+        let exe = path_util::find_program(ctx.clone(), SPHINX_EXES);
+
+        Sphinx::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Sphinx {
+        Sphinx {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            src_dir: None,
+            dst: None,
+            builder: ~"html",
+            flags: Vec::new(),
+        }
+    }
+
+    pub fn set_src_dir<T: IntoPath>(mut self, src_dir: T) -> Sphinx {
+        self.src_dir = Some(src_dir.into_path());
+        self
+    }
+
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Sphinx {
+        self.dst = Some(build_path(&self.ctx, dst.into_path()));
+        self
+    }
+
+    /// Select the Sphinx builder, e.g. "html" (the default) or "man".
+    pub fn set_builder<T: Str>(mut self, builder: T) -> Sphinx {
+        self.builder = builder.into_owned();
+        self
+    }
+
+    pub fn add_flag<S: Str>(mut self, flag: S) -> Sphinx {
+        self.flags.push(flag.into_owned());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Sphinx {
+    fn into_future(self) -> Future<Path> {
+        let Sphinx { ctx, exe, src_dir, dst, builder, flags } = self;
+
+        assert!(src_dir.is_some());
+        assert!(dst.is_some());
+        let src_dir = src_dir.unwrap();
+        let dst = dst.unwrap();
+
+        let mut prep = ctx.prep("Call");
+        let mut call = Call::new(exe.clone()).unwrap();
+
+        for src in path_util::find_files(&src_dir, SPHINX_EXTS).unwrap().move_iter() {
+            prep.declare_named_input_path(src.as_str().unwrap(), src.clone()).unwrap();
+        }
+
+        call.push_str(~"-q");
+        call.push_str(~"-b");
+        call.push_str(builder);
+
+        for flag in flags.move_iter() {
+            call.push_str(flag);
+        }
+
+        call.push_str(src_dir.as_str().unwrap().to_owned());
+        call.push_output_path(dst.clone());
+
+        prep.declare_call(&call);
+        prep.export_command(call.shell_cmd(None, false), None);
+
+        prep.exec(proc(exec) {
+            let (prog, args) = call.cmd();
+
+            fs::mkdir_recursive(&dst, io::UserDir).unwrap();
+
+            exec.process_builder(prog, args.as_slice())
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msg(src_dir.display())
+                .run_or_fail();
+
+            dst
+        })
+    }
+}
+
+/// Converts documents with pandoc, e.g. markdown into HTML or man pages.
+#[deriving(Clone)]
+pub struct Pandoc {
+    ctx: Context,
+    exe: Path,
+    dst: Option<Path>,
+    srcs: Vec<Path>,
+    format: Option<~str>,
+    flags: Vec<~str>,
+}
+
+impl Pandoc {
+    pub fn new(ctx: Context) -> Pandoc {
+        let exe = path_util::find_program(ctx.clone(), PANDOC_EXES);
+
+        Pandoc::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Pandoc {
+        Pandoc {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            dst: None,
+            srcs: Vec::new(),
+            format: None,
+            flags: Vec::new(),
+        }
+    }
+
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Pandoc {
+        self.dst = Some(build_path(&self.ctx, dst.into_path()));
+        self
+    }
+
+    pub fn add_src<T: IntoFuture<Path>>(mut self, src: T) -> Pandoc {
+        self.srcs.push(src.into_future().unwrap());
+        self
+    }
+
+    /// Set the output format, e.g. "man". By default pandoc guesses it from
+    /// the output's extension.
+    pub fn set_format<T: Str>(mut self, format: T) -> Pandoc {
+        self.format = Some(format.into_owned());
+        self
+    }
+
+    pub fn add_flag<S: Str>(mut self, flag: S) -> Pandoc {
+        self.flags.push(flag.into_owned());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Pandoc {
+    fn into_future(self) -> Future<Path> {
+        let Pandoc { ctx, exe, dst, srcs, format, flags } = self;
+
+        assert!(dst.is_some());
+        assert!(!srcs.is_empty());
+        let dst = dst.unwrap();
+
+        let mut prep = ctx.prep("Call");
+        let mut call = Call::new(exe.clone()).unwrap();
+
+        call.push_str(~"-s");
+
+        match format {
+            Some(format) => {
+                call.push_str(~"-t");
+                call.push_str(format);
+            }
+            None => { }
+        }
+
+        for flag in flags.move_iter() {
+            call.push_str(flag);
+        }
+
+        call.push_str(~"-o");
+        call.push_output_path(dst.clone());
+
+        for src in srcs.iter() {
+            call.push_input_path(src.clone()).ok().expect("src");
+        }
+
+        prep.declare_call(&call);
+
+        prep.exec(proc(exec) {
+            let (prog, args) = call.tmp_cmd().unwrap();
+
+            // Make sure the parent directories exist.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            exec.process_builder(prog, args.as_slice())
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msgs(srcs.iter().map(|src| src.display()))
+                .run_or_fail();
+            call.rename_outputs().unwrap();
+
+            dst
+        })
+    }
+}
@@ -0,0 +1,179 @@
+use std::io;
+use std::io::fs;
+use std::io::timer;
+use std::os;
+use sync::Future;
+
+use context::Context;
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+use sha256;
+
+pub static CURL_EXES: &'static [&'static str] = &'static ["curl"];
+
+/// The proxy for `url` from the usual environment variables, which curl
+/// only partly honors on its own.
+fn proxy_from_env(url: &str) -> Option<~str> {
+    let vars = if url.starts_with("https:") {
+        ["https_proxy", "HTTPS_PROXY", "all_proxy", "ALL_PROXY"]
+    } else {
+        ["http_proxy", "HTTP_PROXY", "all_proxy", "ALL_PROXY"]
+    };
+
+    vars.iter().filter_map(|var| os::getenv(*var)).find(|proxy| !proxy.is_empty())
+}
+
+/// Downloads a file with `curl`. The download is only repeated when the URL
+/// changes or the file is modified, so it's usually paired with `Verify`.
+#[deriving(Clone)]
+pub struct Download {
+    ctx: Context,
+    exe: Path,
+    url: ~str,
+    dst: Option<Path>,
+    proxy: Option<~str>,
+    retries: uint,
+    backoff: u64,
+}
+
+impl Download {
+    pub fn new<T: Str>(ctx: Context, url: T) -> Download {
+        let exe = path_util::find_program(ctx.clone(), CURL_EXES);
+
+        Download::new_with(ctx, exe, url)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>, U: Str>(ctx: Context, exe: T, url: U) -> Download {
+        Download {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            url: url.into_owned(),
+            dst: None,
+            proxy: None,
+            retries: 3,
+            backoff: 1000,
+        }
+    }
+
+    /// Set where the file goes. Defaults to the last component of the URL
+    /// in a `downloads` directory in the build root.
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Download {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    /// Use `proxy` rather than the one from `https_proxy`, `http_proxy` or
+    /// `all_proxy`.
+    pub fn set_proxy<T: Str>(mut self, proxy: T) -> Download {
+        self.proxy = Some(proxy.into_owned());
+        self
+    }
+
+    /// How many times to retry a failed download. Defaults to 3.
+    pub fn set_retries(mut self, retries: uint) -> Download {
+        self.retries = retries;
+        self
+    }
+
+    /// How many milliseconds to wait before the first retry. The wait
+    /// doubles with every retry. Defaults to a second.
+    pub fn set_backoff(mut self, backoff: u64) -> Download {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Download {
+    fn into_future(self) -> Future<Path> {
+        let Download { ctx, exe, url, dst, proxy, retries, backoff } = self;
+
+        let dst = dst.unwrap_or_else(|| {
+            let name = url.split('/').last().unwrap_or("download");
+            let name = name.split('?').next().unwrap();
+            ctx.root.join("downloads").join(name)
+        });
+        let offline = ctx.offline();
+
+        let mut prep = ctx.prep("Download");
+        prep.declare_input("value", "url", &url);
+        prep.declare_input("value", "dst", &dst);
+
+        prep.exec(proc(exec) {
+            if offline {
+                fail!("{} has to be downloaded from {}, but the build is offline",
+                      dst.display(), url);
+            }
+
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            // An interrupted download is resumed, as long as it's of the
+            // same URL.
+            let partial = dst.with_filename(format!("{}.{}.part",
+                dst.filename_str().unwrap(),
+                sha256::hex_digest(url.as_bytes()).slice_to(16)));
+
+            let mut args = vec!(
+                ~"--fail", ~"--location", ~"--silent", ~"--show-error",
+                ~"--continue-at", ~"-",
+                ~"--output", partial.as_str().unwrap().to_owned());
+
+            match proxy.or_else(|| proxy_from_env(url.as_slice())) {
+                Some(proxy) => {
+                    args.push(~"--proxy");
+                    args.push(proxy);
+                }
+                None => { }
+            }
+
+            match os::getenv("no_proxy").or_else(|| os::getenv("NO_PROXY")) {
+                Some(no_proxy) => {
+                    args.push(~"--noproxy");
+                    args.push(no_proxy);
+                }
+                None => { }
+            }
+
+            args.push(url.clone());
+
+            let mut attempt = 0;
+            loop {
+                let status = exec.process_builder(exe.as_str().unwrap(), args.as_slice())
+                    .description("download")
+                    .msg(dst.display())
+                    .msg("<-")
+                    .msg(url.as_slice())
+                    .run();
+
+                match status {
+                    Ok(ref status) if status.success() => { break; }
+                    _ if attempt < retries => {
+                        timer::sleep(backoff << attempt);
+                        attempt += 1;
+                    }
+                    // Whatever was downloaded isn't resumed by the next
+                    // build, in case it's what curl keeps failing on.
+                    Ok(status) => {
+                        let _ = fs::unlink(&partial);
+                        fail!("failed to download {} after {} attempts: curl {}",
+                              url, attempt + 1, status);
+                    }
+                    Err(err) => {
+                        let _ = fs::unlink(&partial);
+                        fail!("failed to download {} after {} attempts: {}",
+                              url, attempt + 1, err);
+                    }
+                }
+            }
+
+            fs::rename(&partial, &dst).unwrap();
+            exec.discover_output_path("output", &dst);
+
+            dst
+        })
+    }
+}
@@ -0,0 +1,308 @@
+use std::io;
+use std::io::File;
+use std::io::fs;
+use sync::Future;
+
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static OBJCOPY_EXES: &'static [&'static str] = &'static ["arm-none-eabi-objcopy", "objcopy"];
+
+/// The format to convert an ELF file to.
+#[deriving(Clone, Eq, Encodable, Decodable)]
+pub enum ImageFormat {
+    IntelHex,
+    RawBinary,
+}
+
+impl ImageFormat {
+    fn bfd_name(&self) -> &'static str {
+        match *self {
+            IntelHex => "ihex",
+            RawBinary => "binary",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match *self {
+            IntelHex => "hex",
+            RawBinary => "bin",
+        }
+    }
+}
+
+/// Converts a linked ELF file into an image that can be flashed, with
+/// `objcopy`.
+#[deriving(Clone)]
+pub struct Objcopy {
+    ctx: Context,
+    exe: Path,
+    src: Option<Path>,
+    dst: Option<Path>,
+    format: ImageFormat,
+    gap_fill: Option<u8>,
+    sections: Vec<~str>,
+}
+
+impl Objcopy {
+    pub fn new(ctx: Context) -> Objcopy {
+        let exe = path_util::find_program(ctx.clone(), OBJCOPY_EXES);
+
+        Objcopy::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Objcopy {
+        Objcopy {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            src: None,
+            dst: None,
+            format: RawBinary,
+            gap_fill: None,
+            sections: Vec::new(),
+        }
+    }
+
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Objcopy {
+        self.src = Some(src.into_future().unwrap());
+        self
+    }
+
+    /// Set the output path. Defaults to the source with a `.hex` or `.bin`
+    /// extension.
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Objcopy {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    pub fn set_format(mut self, format: ImageFormat) -> Objcopy {
+        self.format = format;
+        self
+    }
+
+    /// Fill the gaps between sections with `fill` instead of zeros, e.g.
+    /// 0xff to match erased flash.
+    pub fn set_gap_fill(mut self, fill: u8) -> Objcopy {
+        self.gap_fill = Some(fill);
+        self
+    }
+
+    /// Only copy the named section. May be given several times.
+    pub fn add_section<T: Str>(mut self, section: T) -> Objcopy {
+        self.sections.push(section.into_owned());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Objcopy {
+    fn into_future(self) -> Future<Path> {
+        let Objcopy { ctx, exe, src, dst, format, gap_fill, sections } = self;
+
+        assert!(src.is_some());
+        let src = src.unwrap();
+        let dst = match dst {
+            Some(dst) => dst,
+            None => ctx.root.join(src.with_extension(format.extension()).filename().unwrap()),
+        };
+
+        let mut call = Call::new(exe.clone()).unwrap();
+        call.push_str(~"-O");
+        call.push_str(format.bfd_name().to_owned());
+
+        match gap_fill {
+            Some(fill) => {
+                call.push_str(~"--gap-fill");
+                call.push_str(format!("0x{:02x}", fill));
+            }
+            None => { }
+        }
+
+        for section in sections.move_iter() {
+            call.push_str(~"-j");
+            call.push_str(section);
+        }
+
+        call.push_input_path(src.clone()).unwrap();
+        call.push_output_path(dst.clone());
+
+        let mut prep = ctx.prep("Call");
+        prep.declare_call(&call);
+
+        prep.exec(proc(exec) {
+            let (prog, args) = call.tmp_cmd().unwrap();
+
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            exec.process_builder(prog, args.as_slice())
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msg(src.display())
+                .run_or_fail();
+            call.rename_outputs().unwrap();
+
+            dst
+        })
+    }
+}
+
+/// Combines raw binary images into a single flash image, placing each at its
+/// offset and filling the gaps. Optionally pads the image to a fixed size and
+/// appends a little-endian CRC-32 of the contents, for bootloaders that
+/// verify the application before jumping to it.
+#[deriving(Clone)]
+pub struct FlashImage {
+    ctx: Context,
+    dst: Option<Path>,
+    images: Vec<(u64, Path)>,
+    size: Option<u64>,
+    fill: u8,
+    checksum: bool,
+}
+
+impl FlashImage {
+    pub fn new(ctx: Context) -> FlashImage {
+        FlashImage {
+            ctx: ctx,
+            dst: None,
+            images: Vec::new(),
+            size: None,
+            fill: 0xff,
+            checksum: false,
+        }
+    }
+
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> FlashImage {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    /// Place the raw binary `image` at `offset` bytes into the flash image.
+    pub fn add_image<T: IntoFuture<Path>>(mut self, offset: u64, image: T) -> FlashImage {
+        self.images.push((offset, image.into_future().unwrap()));
+        self
+    }
+
+    /// Pad the image to `size` bytes, not counting the checksum.
+    pub fn set_size(mut self, size: u64) -> FlashImage {
+        self.size = Some(size);
+        self
+    }
+
+    /// The byte used for padding. Defaults to 0xff, the value of erased
+    /// flash.
+    pub fn set_fill(mut self, fill: u8) -> FlashImage {
+        self.fill = fill;
+        self
+    }
+
+    pub fn set_checksum(mut self, checksum: bool) -> FlashImage {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for FlashImage {
+    fn into_future(self) -> Future<Path> {
+        let FlashImage { ctx, dst, mut images, size, fill, checksum } = self;
+
+        assert!(dst.is_some());
+        assert!(!images.is_empty());
+        let dst = dst.unwrap();
+
+        images.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+
+        let mut prep = ctx.prep("FlashImage");
+        for &(_, ref image) in images.iter() {
+            prep.declare_input_path(image.clone()).unwrap();
+        }
+        let offsets: Vec<u64> = images.iter().map(|&(offset, _)| offset).collect();
+        prep.declare_input("value", "offsets", &offsets);
+        prep.declare_input("value", "size", &size);
+        prep.declare_input("value", "fill", &fill);
+        prep.declare_input("value", "checksum", &checksum);
+
+        prep.exec(proc(exec) {
+            let mut contents: Vec<u8> = Vec::new();
+
+            for &(offset, ref image) in images.iter() {
+                let offset = offset as uint;
+                if contents.len() > offset {
+                    fail!("{} overlaps the previous image in {}", image.display(), dst.display());
+                }
+
+                let padding = offset - contents.len();
+                contents.grow(padding, &fill);
+                contents.push_all_move(File::open(image).read_to_end().unwrap());
+            }
+
+            match size {
+                Some(size) => {
+                    let size = size as uint;
+                    if contents.len() > size {
+                        fail!("{} is {} bytes, more than the {} available",
+                              dst.display(), contents.len(), size);
+                    }
+                    let padding = size - contents.len();
+                    contents.grow(padding, &fill);
+                }
+                None => { }
+            }
+
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            {
+                let mut file = File::create(&dst);
+                file.write(contents.as_slice()).unwrap();
+                if checksum {
+                    file.write_le_u32(crc32(contents.as_slice())).unwrap();
+                }
+            }
+
+            exec.discover_output_path("image", &dst);
+
+            dst
+        })
+    }
+}
+
+/// The CRC-32 used by zlib and most bootloaders (reflected, polynomial
+/// 0xedb88320).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+
+    for &byte in data.iter() {
+        crc ^= byte as u32;
+        for _ in range(0, 8) {
+            let mask = if crc & 1 == 1 { 0xedb88320u32 } else { 0 };
+            crc = (crc >> 1) ^ mask;
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::crc32;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32([]), 0);
+        assert_eq!(crc32("a".as_bytes()), 0xe8b7be43);
+        // The check value of the CRC-32 catalogue.
+        assert_eq!(crc32("123456789".as_bytes()), 0xcbf43926);
+        assert_eq!(crc32("The quick brown fox jumps over the lazy dog".as_bytes()), 0x414fa339);
+        assert_eq!(crc32([0u8, ..32]), 0x190a55ad);
+    }
+}
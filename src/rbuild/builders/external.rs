@@ -0,0 +1,356 @@
+use std::io;
+use std::io::fs;
+use std::os;
+use sync::Future;
+
+use builders::c::{LIB_PREFIX, STATIC_LIB_SUFFIX, SHARED_LIB_SUFFIX};
+use context::{Context, Exec};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static CMAKE_EXES: &'static [&'static str] = &'static ["cmake"];
+pub static MAKE_EXES: &'static [&'static str] = &'static ["gmake", "make"];
+
+/// The install tree of a third-party project built inside the build
+/// directory.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct Installed {
+    pub prefix: Path,
+}
+
+impl Installed {
+    pub fn include_dir(&self) -> Path {
+        self.prefix.join("include")
+    }
+
+    pub fn lib_dir(&self) -> Path {
+        self.prefix.join("lib")
+    }
+
+    /// The path of installed static library `name`, for `add_lib`.
+    pub fn static_lib(&self, name: &str) -> Path {
+        self.lib_dir().join(format!("{}{}.{}", LIB_PREFIX, name, STATIC_LIB_SUFFIX))
+    }
+
+    /// The path of installed shared library `name`, for `add_lib`.
+    pub fn shared_lib(&self, name: &str) -> Path {
+        self.lib_dir().join(format!("{}{}.{}", LIB_PREFIX, name, SHARED_LIB_SUFFIX))
+    }
+}
+
+/// Where an external project called `name` is built and installed.
+fn project_dir(ctx: &Context, name: &str) -> Path {
+    os::make_absolute(&ctx.root.join("external").join(name))
+}
+
+fn run_step(exec: &mut Exec, name: &str, exe: &Path, args: &[~str], cwd: &Path) {
+    let step = args.iter().next().map_or("", |arg| arg.as_slice());
+
+    exec.process_builder(exe.as_str().unwrap(), args)
+        .cwd(cwd)
+        .description(exe.filename_display())
+        .msg(name)
+        .msg(step)
+        .run_or_fail();
+}
+
+fn jobs_args(jobs: Option<uint>) -> Vec<~str> {
+    match jobs {
+        Some(jobs) => vec!(format!("-j{}", jobs)),
+        None => Vec::new(),
+    }
+}
+
+/// Configures, builds and installs a CMake project.
+#[deriving(Clone)]
+pub struct CMake {
+    ctx: Context,
+    exe: Path,
+    name: ~str,
+    src_dir: Option<Path>,
+    build_type: ~str,
+    defines: Vec<~str>,
+    jobs: Option<uint>,
+}
+
+impl CMake {
+    pub fn new<T: Str>(ctx: Context, name: T) -> CMake {
+        let exe = path_util::find_program(ctx.clone(), CMAKE_EXES);
+
+        CMake::new_with(ctx, exe, name)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>, U: Str>(ctx: Context, exe: T, name: U) -> CMake {
+        CMake {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            name: name.into_owned(),
+            src_dir: None,
+            build_type: ~"Release",
+            defines: Vec::new(),
+            jobs: None,
+        }
+    }
+
+    pub fn set_src_dir<T: IntoPath>(mut self, src_dir: T) -> CMake {
+        self.src_dir = Some(src_dir.into_path());
+        self
+    }
+
+    pub fn set_build_type<T: Str>(mut self, build_type: T) -> CMake {
+        self.build_type = build_type.into_owned();
+        self
+    }
+
+    /// Pass a cache entry to the configure step, e.g. "BUILD_SHARED_LIBS=OFF".
+    pub fn add_define<T: Str>(mut self, define: T) -> CMake {
+        self.defines.push(define.into_owned());
+        self
+    }
+
+    pub fn set_jobs(mut self, jobs: uint) -> CMake {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    pub fn run(self) -> Installed {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Installed> for CMake {
+    fn into_future(self) -> Future<Installed> {
+        let CMake { ctx, exe, name, src_dir, build_type, defines, jobs } = self;
+
+        assert!(src_dir.is_some());
+        let src_dir = os::make_absolute(&src_dir.unwrap());
+        let dir = project_dir(&ctx, name.as_slice());
+
+        let mut prep = ctx.prep("CMake");
+        prep.declare_input_path(exe.clone()).unwrap();
+        prep.declare_input_dir(src_dir.clone()).unwrap();
+        prep.declare_input("value", "name", &name);
+        prep.declare_input("value", "build_type", &build_type);
+        prep.declare_input("value", "defines", &defines);
+
+        prep.exec(proc(exec) {
+            let build_dir = dir.join("build");
+            let prefix = dir.join("install");
+
+            fs::mkdir_recursive(&build_dir, io::UserDir).unwrap();
+
+            let mut configure = vec!(
+                src_dir.as_str().unwrap().to_owned(),
+                format!("-DCMAKE_INSTALL_PREFIX={}", prefix.as_str().unwrap()),
+                format!("-DCMAKE_BUILD_TYPE={}", build_type));
+            for define in defines.iter() {
+                configure.push("-D" + *define);
+            }
+            run_step(exec, name.as_slice(), &exe, configure.as_slice(), &build_dir);
+
+            let mut build = vec!(~"--build", ~".");
+            if jobs.is_some() {
+                build.push(~"--");
+                build.push_all_move(jobs_args(jobs));
+            }
+            run_step(exec, name.as_slice(), &exe, build.as_slice(), &build_dir);
+
+            let install = vec!(~"--build", ~".", ~"--target", ~"install");
+            run_step(exec, name.as_slice(), &exe, install.as_slice(), &build_dir);
+
+            exec.discover_output_path("install", &prefix);
+
+            Installed { prefix: prefix }
+        })
+    }
+}
+
+/// Runs `configure && make && make install` for an autotools project,
+/// building out of tree.
+#[deriving(Clone)]
+pub struct Autotools {
+    ctx: Context,
+    make: Path,
+    name: ~str,
+    src_dir: Option<Path>,
+    configure_args: Vec<~str>,
+    jobs: Option<uint>,
+}
+
+impl Autotools {
+    pub fn new<T: Str>(ctx: Context, name: T) -> Autotools {
+        let make = path_util::find_program(ctx.clone(), MAKE_EXES);
+
+        Autotools::new_with(ctx, make, name)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>, U: Str>(ctx: Context, make: T, name: U) -> Autotools {
+        Autotools {
+            ctx: ctx,
+            make: make.into_future().unwrap(),
+            name: name.into_owned(),
+            src_dir: None,
+            configure_args: Vec::new(),
+            jobs: None,
+        }
+    }
+
+    pub fn set_src_dir<T: IntoPath>(mut self, src_dir: T) -> Autotools {
+        self.src_dir = Some(src_dir.into_path());
+        self
+    }
+
+    /// Pass an extra argument to `configure`, e.g. "--disable-shared".
+    pub fn add_configure_arg<T: Str>(mut self, arg: T) -> Autotools {
+        self.configure_args.push(arg.into_owned());
+        self
+    }
+
+    pub fn set_jobs(mut self, jobs: uint) -> Autotools {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    pub fn run(self) -> Installed {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Installed> for Autotools {
+    fn into_future(self) -> Future<Installed> {
+        let Autotools { ctx, make, name, src_dir, configure_args, jobs } = self;
+
+        assert!(src_dir.is_some());
+        let src_dir = os::make_absolute(&src_dir.unwrap());
+        let dir = project_dir(&ctx, name.as_slice());
+
+        let mut prep = ctx.prep("Autotools");
+        prep.declare_input_path(make.clone()).unwrap();
+        prep.declare_input_dir(src_dir.clone()).unwrap();
+        prep.declare_input("value", "name", &name);
+        prep.declare_input("value", "configure_args", &configure_args);
+
+        prep.exec(proc(exec) {
+            let build_dir = dir.join("build");
+            let prefix = dir.join("install");
+
+            fs::mkdir_recursive(&build_dir, io::UserDir).unwrap();
+
+            let configure = src_dir.join("configure");
+            let mut args = vec!(format!("--prefix={}", prefix.as_str().unwrap()));
+            args.push_all(configure_args.as_slice());
+            run_step(exec, name.as_slice(), &configure, args.as_slice(), &build_dir);
+
+            let build = jobs_args(jobs);
+            run_step(exec, name.as_slice(), &make, build.as_slice(), &build_dir);
+
+            let install = vec!(~"install");
+            run_step(exec, name.as_slice(), &make, install.as_slice(), &build_dir);
+
+            exec.discover_output_path("install", &prefix);
+
+            Installed { prefix: prefix }
+        })
+    }
+}
+
+/// Builds a plain Makefile project. Such projects usually build in their
+/// source tree, so the sources are copied into the build directory first.
+#[deriving(Clone)]
+pub struct Make {
+    ctx: Context,
+    make: Path,
+    name: ~str,
+    src_dir: Option<Path>,
+    prefix_var: ~str,
+    vars: Vec<~str>,
+    jobs: Option<uint>,
+}
+
+impl Make {
+    pub fn new<T: Str>(ctx: Context, name: T) -> Make {
+        let make = path_util::find_program(ctx.clone(), MAKE_EXES);
+
+        Make::new_with(ctx, make, name)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>, U: Str>(ctx: Context, make: T, name: U) -> Make {
+        Make {
+            ctx: ctx,
+            make: make.into_future().unwrap(),
+            name: name.into_owned(),
+            src_dir: None,
+            prefix_var: ~"PREFIX",
+            vars: Vec::new(),
+            jobs: None,
+        }
+    }
+
+    pub fn set_src_dir<T: IntoPath>(mut self, src_dir: T) -> Make {
+        self.src_dir = Some(src_dir.into_path());
+        self
+    }
+
+    /// The make variable the project reads its install prefix from.
+    /// Defaults to "PREFIX".
+    pub fn set_prefix_var<T: Str>(mut self, prefix_var: T) -> Make {
+        self.prefix_var = prefix_var.into_owned();
+        self
+    }
+
+    /// Pass a variable to make, e.g. "CFLAGS=-O2".
+    pub fn add_var<T: Str>(mut self, var: T) -> Make {
+        self.vars.push(var.into_owned());
+        self
+    }
+
+    pub fn set_jobs(mut self, jobs: uint) -> Make {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    pub fn run(self) -> Installed {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Installed> for Make {
+    fn into_future(self) -> Future<Installed> {
+        let Make { ctx, make, name, src_dir, prefix_var, vars, jobs } = self;
+
+        assert!(src_dir.is_some());
+        let src_dir = os::make_absolute(&src_dir.unwrap());
+        let dir = project_dir(&ctx, name.as_slice());
+
+        let mut prep = ctx.prep("Make");
+        prep.declare_input_path(make.clone()).unwrap();
+        prep.declare_input_dir(src_dir.clone()).unwrap();
+        prep.declare_input("value", "name", &name);
+        prep.declare_input("value", "prefix_var", &prefix_var);
+        prep.declare_input("value", "vars", &vars);
+
+        prep.exec(proc(exec) {
+            let build_dir = dir.join("src");
+            let prefix = dir.join("install");
+
+            // Start from a clean copy so stale objects can't leak in.
+            if build_dir.exists() {
+                fs::rmdir_recursive(&build_dir).unwrap();
+            }
+            path_util::copy_dir(&src_dir, &build_dir).unwrap();
+
+            let mut build = jobs_args(jobs);
+            build.push_all(vars.as_slice());
+            run_step(exec, name.as_slice(), &make, build.as_slice(), &build_dir);
+
+            let mut install = vec!(~"install", format!("{}={}", prefix_var, prefix.as_str().unwrap()));
+            install.push_all(vars.as_slice());
+            run_step(exec, name.as_slice(), &make, install.as_slice(), &build_dir);
+
+            exec.discover_output_path("install", &prefix);
+
+            Installed { prefix: prefix }
+        })
+    }
+}
@@ -0,0 +1,222 @@
+use std::ascii::StrAsciiExt;
+use std::io;
+use std::io::{File, IoResult};
+use std::io::fs;
+use sync::Future;
+
+use builders::tool::{Tool, ToolBase};
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static EXES: &'static [&'static str] = &'static ["gfortran"];
+
+#[deriving(Clone)]
+pub struct Gfortran {
+    base: ToolBase,
+    includes: Vec<Path>,
+    module_dir: Option<Path>,
+    module_deps: Vec<Path>,
+    debug: bool,
+    optimize: bool,
+}
+
+impl Gfortran {
+    pub fn new(ctx: Context) -> Gfortran {
+        let exe = path_util::find_program(ctx.clone(), EXES);
+
+        Gfortran::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Gfortran {
+        Gfortran {
+            base: ToolBase::new(ctx, exe),
+            includes: Vec::new(),
+            module_dir: None,
+            module_deps: Vec::new(),
+            debug: false,
+            optimize: false,
+        }
+    }
+
+    /// Compile `src` to an object file.
+    pub fn compile<T: IntoFuture<Path>>(&self, src: T) -> Gfortran {
+        let src = src.into_future().unwrap();
+        let dst = src.with_extension("o");
+
+        self.clone()
+            .set_dst(dst)
+            .add_src(src)
+            .add_flag(~"-c")
+    }
+
+    pub fn add_include<T: IntoFuture<Path>>(mut self, include: T) -> Gfortran {
+        self.includes.push(include.into_future().unwrap());
+        self
+    }
+
+    /// Write `.mod` files to, and look them up in, `module_dir` (`-J`).
+    pub fn set_module_dir<T: IntoPath>(mut self, module_dir: T) -> Gfortran {
+        self.module_dir = Some(self.base.ctx.output_path(module_dir.into_path()));
+        self
+    }
+
+    /// Wait for the compile of a file defining modules this one uses, so its
+    /// `.mod` files exist before this compile starts.
+    pub fn add_module_dep<T: IntoFuture<Path>>(mut self, dep: T) -> Gfortran {
+        self.module_deps.push(dep.into_future().unwrap());
+        self
+    }
+
+    pub fn set_debug(mut self, debug: bool) -> Gfortran {
+        self.debug = debug;
+        self
+    }
+
+    pub fn set_optimize(mut self, optimize: bool) -> Gfortran {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Modules land next to the output unless told otherwise.
+    fn module_dir(&self) -> Path {
+        match self.module_dir {
+            Some(ref module_dir) => module_dir.clone(),
+            None => self.base.dst().dir_path(),
+        }
+    }
+}
+
+tool_methods!(Gfortran)
+
+/// Returns the modules a Fortran source defines and the modules it uses.
+/// Module names are case insensitive, so they are returned lowercased.
+pub fn scan_modules(src: &Path) -> IoResult<(Vec<~str>, Vec<~str>)> {
+    let contents = try!(File::open(src).read_to_str());
+
+    let mut defines = Vec::new();
+    let mut uses = Vec::new();
+
+    for line in contents.lines() {
+        // Strip comments.
+        let line = match line.find('!') {
+            Some(idx) => line.slice_to(idx),
+            None => line,
+        };
+        let line = line.trim().to_ascii_lower();
+        let mut words = line.as_slice().split(|c: char| c.is_whitespace() || c == ',');
+
+        match words.next() {
+            Some("module") => {
+                match words.next() {
+                    Some("procedure") | None => { }
+                    Some(name) => { defines.push(name.to_owned()); }
+                }
+            }
+            Some("use") => {
+                match words.find(|word| !word.is_empty()) {
+                    // `use, intrinsic :: iso_c_binding` refers to a compiler
+                    // module we don't build.
+                    Some("intrinsic") | None => { }
+                    Some(name) => {
+                        let name = name.trim_left_chars(':');
+                        if !name.is_empty() && !uses.contains(&name.to_owned()) {
+                            uses.push(name.to_owned());
+                        }
+                    }
+                }
+            }
+            _ => { }
+        }
+    }
+
+    Ok((defines, uses))
+}
+
+impl Tool for Gfortran {
+    fn to_call(&self) -> Call {
+        let module_dir = self.module_dir();
+        let mut call = self.base.call();
+
+        call.push_str(~"-o");
+        call.push_output_path(self.base.dst());
+
+        call.push_str(~"-J");
+        call.push_str(module_dir.as_str().unwrap().to_owned());
+        call.push_str(~"-I");
+        call.push_str(module_dir.as_str().unwrap().to_owned());
+
+        for include in self.includes.iter() {
+            call.push_str(~"-I");
+            call.push_input_path(include.clone()).unwrap();
+        }
+
+        if self.debug { call.push_str(~"-g"); }
+        if self.optimize { call.push_str(~"-O2"); }
+
+        self.base.push_flags(&mut call);
+        self.base.push_srcs(&mut call);
+        call
+    }
+}
+
+impl IntoFuture<Path> for Gfortran {
+    fn into_future(self) -> Future<Path> {
+        let call = self.to_call();
+        let module_dir = self.module_dir();
+        let Gfortran { base, module_deps, .. } = self;
+
+        let exe = base.exe.clone();
+        let dst = base.dst();
+        let srcs = base.all_srcs();
+
+        assert!(!srcs.is_empty());
+
+        let mut prep = base.ctx.prep("Call");
+
+        for dep in module_deps.move_iter() {
+            prep.declare_input_path(dep).unwrap();
+        }
+
+        prep.declare_call(&call);
+
+        prep.exec(proc(exec) {
+            let (prog, args) = call.tmp_cmd().unwrap();
+
+            // Make sure the output directories exist.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+            fs::mkdir_recursive(&module_dir, io::UserDir).unwrap();
+
+            exec.process_builder(prog, args.as_slice())
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msgs(srcs.iter().map(|src| src.display()))
+                .run_or_fail();
+            call.rename_outputs().unwrap();
+
+            // Record the module interfaces this compile produced and the ones
+            // it read, so a changed interface recompiles its users.
+            for src in srcs.iter() {
+                let (defines, uses) = scan_modules(src).unwrap();
+
+                for name in defines.iter() {
+                    let path = module_dir.join(format!("{}.mod", name));
+                    exec.discover_output_path(name.as_slice(), &path);
+                }
+
+                for name in uses.iter() {
+                    if defines.contains(name) { continue; }
+
+                    let path = module_dir.join(format!("{}.mod", name));
+                    if path.exists() {
+                        exec.discover_input_path(name.as_slice(), &path).unwrap();
+                    }
+                }
+            }
+
+            dst
+        })
+    }
+}
@@ -0,0 +1,266 @@
+//! Message catalogs with GNU gettext. `Xgettext` extracts the translatable
+//! strings from sources into a `.pot` template, and `Msgfmt` compiles each
+//! language's `.po` catalog into the `.mo` file loaded at runtime:
+//!
+//! ```ignore
+//! let msgfmt = Msgfmt::new(ctx.clone()).set_domain("foo");
+//! let catalogs = msgfmt.compile_dir("po");
+//! ```
+
+use std::io;
+use std::io::fs;
+use sync::Future;
+
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static XGETTEXT_EXES: &'static [&'static str] = &'static ["xgettext"];
+pub static MSGFMT_EXES: &'static [&'static str] = &'static ["msgfmt"];
+
+/// Runs `call`, which writes `dst`, atomically.
+fn run_call(ctx: Context, exe: Path, call: Call, dst: Path) -> Future<Path> {
+    let mut prep = ctx.prep("Call");
+    prep.declare_call(&call);
+
+    prep.exec(proc(exec) {
+        let (prog, args) = call.tmp_cmd().unwrap();
+
+        fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+        exec.process_builder(prog, args.as_slice())
+            .description(exe.filename_display())
+            .msg(dst.display())
+            .run_or_fail();
+        call.rename_outputs().unwrap();
+
+        dst
+    })
+}
+
+/// Extracts translatable strings into a `.pot` template.
+#[deriving(Clone)]
+pub struct Xgettext {
+    ctx: Context,
+    exe: Path,
+    srcs: Vec<Path>,
+    dst: Option<Path>,
+    language: Option<~str>,
+    keywords: Vec<~str>,
+    package_name: Option<~str>,
+    flags: Vec<~str>,
+}
+
+impl Xgettext {
+    pub fn new(ctx: Context) -> Xgettext {
+        let exe = path_util::find_program(ctx.clone(), XGETTEXT_EXES);
+
+        Xgettext::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Xgettext {
+        Xgettext {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            srcs: Vec::new(),
+            dst: None,
+            language: None,
+            keywords: Vec::new(),
+            package_name: None,
+            flags: Vec::new(),
+        }
+    }
+
+    pub fn add_src<T: IntoFuture<Path>>(mut self, src: T) -> Xgettext {
+        self.srcs.push(src.into_future().unwrap());
+        self
+    }
+
+    /// Set where the template goes. Defaults to `po/messages.pot` in the
+    /// build root.
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Xgettext {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    /// Set the language of the sources, e.g. "C++", rather than guessing
+    /// it from their extensions.
+    pub fn set_language<T: Str>(mut self, language: T) -> Xgettext {
+        self.language = Some(language.into_owned());
+        self
+    }
+
+    /// Also extract the arguments of calls to `keyword`, e.g. "_" or
+    /// "N_".
+    pub fn add_keyword<T: Str>(mut self, keyword: T) -> Xgettext {
+        self.keywords.push(keyword.into_owned());
+        self
+    }
+
+    pub fn set_package_name<T: Str>(mut self, package_name: T) -> Xgettext {
+        self.package_name = Some(package_name.into_owned());
+        self
+    }
+
+    pub fn add_flag<S: Str>(mut self, flag: S) -> Xgettext {
+        self.flags.push(flag.into_owned());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Xgettext {
+    fn into_future(self) -> Future<Path> {
+        let Xgettext { ctx, exe, srcs, dst, language, keywords, package_name, flags } = self;
+
+        let dst = dst.unwrap_or_else(|| ctx.root.join("po").join("messages.pot"));
+
+        let mut call = Call::new(exe.clone()).unwrap();
+        call.push_str(~"--from-code=UTF-8");
+
+        match language {
+            Some(language) => { call.push_str(format!("--language={}", language)); }
+            None => { }
+        }
+
+        for keyword in keywords.move_iter() {
+            call.push_str(format!("--keyword={}", keyword));
+        }
+
+        match package_name {
+            Some(package_name) => { call.push_str(format!("--package-name={}", package_name)); }
+            None => { }
+        }
+
+        for flag in flags.move_iter() {
+            call.push_str(flag);
+        }
+
+        call.push_str(~"-o");
+        call.push_output_path(dst.clone());
+
+        for src in srcs.move_iter() {
+            call.push_input_path(src).unwrap();
+        }
+
+        run_call(ctx, exe, call, dst)
+    }
+}
+
+/// Compiles a `.po` catalog into a `.mo` file.
+#[deriving(Clone)]
+pub struct Msgfmt {
+    ctx: Context,
+    exe: Path,
+    domain: ~str,
+    locale_dir: Option<Path>,
+    src: Option<Path>,
+    dst: Option<Path>,
+    check: bool,
+}
+
+impl Msgfmt {
+    pub fn new(ctx: Context) -> Msgfmt {
+        let exe = path_util::find_program(ctx.clone(), MSGFMT_EXES);
+
+        Msgfmt::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Msgfmt {
+        Msgfmt {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            domain: ~"messages",
+            locale_dir: None,
+            src: None,
+            dst: None,
+            check: true,
+        }
+    }
+
+    /// Compile the catalog `src`, which is named after its language, e.g.
+    /// `po/de.po`, into `<locale dir>/de/LC_MESSAGES/<domain>.mo`.
+    pub fn compile<T: IntoFuture<Path>>(&self, src: T) -> Msgfmt {
+        let src = src.into_future().unwrap();
+        let dst = self.locale_dir()
+            .join(src.filestem_str().unwrap())
+            .join("LC_MESSAGES")
+            .join(format!("{}.mo", self.domain));
+
+        self.clone()
+            .set_src(src)
+            .set_dst(dst)
+    }
+
+    /// Compile every `.po` catalog under `dir`, returning the `.mo` files.
+    pub fn compile_dir<T: IntoPath>(&self, dir: T) -> Vec<Future<Path>> {
+        let srcs = path_util::find_files(&dir.into_path(), ["po"]).unwrap();
+
+        srcs.move_iter().map(|src| self.compile(src).into_future()).collect()
+    }
+
+    /// Set the text domain, which names the `.mo` files. Defaults to
+    /// "messages".
+    pub fn set_domain<T: Str>(mut self, domain: T) -> Msgfmt {
+        self.domain = domain.into_owned();
+        self
+    }
+
+    /// Set the directory `compile` lays the catalogs out in. Defaults to
+    /// `locale` in the build root.
+    pub fn set_locale_dir<T: IntoPath>(mut self, locale_dir: T) -> Msgfmt {
+        self.locale_dir = Some(self.ctx.output_path(locale_dir.into_path()));
+        self
+    }
+
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Msgfmt {
+        self.src = Some(src.into_future().unwrap());
+        self
+    }
+
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Msgfmt {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    /// Check the format strings and header of the catalog, which is on by
+    /// default.
+    pub fn set_check(mut self, check: bool) -> Msgfmt {
+        self.check = check;
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+
+    fn locale_dir(&self) -> Path {
+        match self.locale_dir {
+            Some(ref locale_dir) => locale_dir.clone(),
+            None => self.ctx.root.join("locale"),
+        }
+    }
+}
+
+impl IntoFuture<Path> for Msgfmt {
+    fn into_future(self) -> Future<Path> {
+        let Msgfmt { ctx, exe, src, dst, check, .. } = self;
+
+        assert!(src.is_some());
+        let src = src.unwrap();
+        let dst = dst.unwrap_or_else(|| ctx.root.join(src.with_extension("mo").filename().unwrap()));
+
+        let mut call = Call::new(exe.clone()).unwrap();
+        if check { call.push_str(~"--check"); }
+
+        call.push_str(~"-o");
+        call.push_output_path(dst.clone());
+        call.push_input_path(src).unwrap();
+
+        run_call(ctx, exe, call, dst)
+    }
+}
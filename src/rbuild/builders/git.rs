@@ -0,0 +1,76 @@
+use std::io;
+use std::io::File;
+use std::io::fs;
+use sync::Future;
+
+use context::Context;
+use into_future::IntoFuture;
+use into_path::IntoPath;
+
+/// Generates a `version.h` or `version.rs` file holding the current git
+/// revision. The file is only rewritten when the revision changes.
+#[deriving(Clone)]
+pub struct GitInfo {
+    ctx: Context,
+    repo: Path,
+    dst: Option<Path>,
+}
+
+impl GitInfo {
+    pub fn new(ctx: Context) -> GitInfo {
+        GitInfo {
+            ctx: ctx,
+            repo: Path::new("."),
+            dst: None,
+        }
+    }
+
+    pub fn set_repo<T: IntoPath>(mut self, repo: T) -> GitInfo {
+        self.repo = repo.into_path();
+        self
+    }
+
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> GitInfo {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+fn render(dst: &Path, rev: &str) -> ~str {
+    match dst.extension_str() {
+        Some("rs") => format!("pub static GIT_REV: &'static str = \"{}\";\n", rev),
+        _ => format!("#define GIT_REV \"{}\"\n", rev),
+    }
+}
+
+impl IntoFuture<Path> for GitInfo {
+    fn into_future(self) -> Future<Path> {
+        let GitInfo { ctx, repo, dst } = self;
+
+        assert!(dst.is_some());
+        let dst = dst.unwrap();
+
+        let mut prep = ctx.prep("GitInfo");
+        let rev = match prep.declare_input_git_rev(&repo) {
+            Ok(rev) => rev,
+            Err(err) => fail!("couldn't read the git revision of {}: {}", repo.display(), err),
+        };
+        prep.declare_input("value", "dst", &dst);
+
+        prep.exec(proc(exec) {
+            // Make sure the parent directories exist.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            File::create(&dst).write_str(render(&dst, rev.as_slice())).unwrap();
+            exec.discover_output_path("output", &dst);
+
+            ctx.logger().verbose(format!(" * {:10}: {} ({})", "git", dst.display(), rev));
+
+            dst
+        })
+    }
+}
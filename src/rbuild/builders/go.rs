@@ -0,0 +1,140 @@
+use std::io;
+use std::io::fs;
+use sync::Future;
+
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static EXES: &'static [&'static str] = &'static ["go"];
+
+/// Environment variables that change what `go build` produces.
+static ENV_VARS: &'static [&'static str] = &[
+    "GOOS", "GOARCH", "GOPATH", "GOFLAGS", "CGO_ENABLED",
+];
+
+/// Builds a Go package directory into a binary with `go build`. The Go
+/// toolchain does its own dependency resolution, so every `.go` file in the
+/// package is tracked as an input and the whole package is rebuilt when any
+/// of them change.
+#[deriving(Clone)]
+pub struct GoBuild {
+    ctx: Context,
+    exe: Path,
+    pkg: Option<Path>,
+    dst: Option<Path>,
+    tags: Vec<~str>,
+    flags: Vec<~str>,
+}
+
+impl GoBuild {
+    pub fn new(ctx: Context) -> GoBuild {
+        let exe = path_util::find_program(ctx.clone(), EXES);
+
+        GoBuild::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> GoBuild {
+        GoBuild {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            pkg: None,
+            dst: None,
+            tags: Vec::new(),
+            flags: Vec::new(),
+        }
+    }
+
+    pub fn set_pkg<T: IntoPath>(mut self, pkg: T) -> GoBuild {
+        self.pkg = Some(pkg.into_path());
+        self
+    }
+
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> GoBuild {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    pub fn add_tag<T: Str>(mut self, tag: T) -> GoBuild {
+        self.tags.push(tag.into_owned());
+        self
+    }
+
+    pub fn add_flag<S: Str>(mut self, flag: S) -> GoBuild {
+        self.flags.push(flag.into_owned());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for GoBuild {
+    fn into_future(self) -> Future<Path> {
+        let GoBuild { ctx, exe, pkg, dst, tags, flags } = self;
+
+        assert!(pkg.is_some());
+        assert!(dst.is_some());
+        let pkg = pkg.unwrap();
+        let dst = dst.unwrap();
+
+        let mut prep = ctx.prep("Call");
+        let mut call = Call::new(exe.clone()).unwrap();
+
+        for var in ENV_VARS.iter() {
+            prep.declare_input_env(*var);
+        }
+
+        // The file listing is part of the key so that adding a file to the
+        // package triggers a rebuild.
+        let srcs = path_util::find_files(&pkg, &["go", "mod", "sum"]).unwrap();
+        let names: Vec<~str> = srcs.iter().map(|src| src.as_str().unwrap().to_owned()).collect();
+        prep.declare_input("value", "srcs", &names);
+
+        call.push_str(~"build");
+        call.push_str(~"-o");
+        call.push_output_path(dst.clone());
+
+        if !tags.is_empty() {
+            call.push_str(~"-tags");
+            call.push_str(tags.connect(","));
+        }
+
+        for flag in flags.move_iter() {
+            call.push_str(flag);
+        }
+
+        // Go treats arguments without a leading "./" as import paths.
+        let pkg_arg = if pkg.is_absolute() {
+            pkg.as_str().unwrap().to_owned()
+        } else {
+            format!("./{}", pkg.as_str().unwrap())
+        };
+        call.push_str(pkg_arg);
+
+        prep.declare_call(&call);
+
+        prep.exec(proc(exec) {
+            let (prog, args) = call.tmp_cmd().unwrap();
+
+            // Make sure the parent directories exist.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            exec.process_builder(prog, args.as_slice())
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msg(pkg.display())
+                .run_or_fail();
+            call.rename_outputs().unwrap();
+
+            for src in srcs.iter() {
+                exec.discover_input_path(src.as_str().unwrap(), src).unwrap();
+            }
+
+            dst
+        })
+    }
+}
@@ -1,2 +1,31 @@
+// Declared first, so that its macros are available to the other builders.
+#[macro_escape]
+pub mod tool;
+
 pub mod ar;
+pub mod asm;
+pub mod bundle;
 pub mod c;
+pub mod codegen;
+pub mod compile_commands;
+pub mod coverage;
+pub mod cuda;
+pub mod docs;
+pub mod download;
+pub mod embedded;
+pub mod external;
+pub mod fortran;
+pub mod gettext;
+pub mod git;
+pub mod go;
+pub mod package;
+pub mod package_config;
+pub mod pgo;
+pub mod python;
+pub mod resolver;
+pub mod resource;
+pub mod sign;
+pub mod size;
+pub mod third_party;
+pub mod transform;
+pub mod verify;
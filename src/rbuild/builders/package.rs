@@ -0,0 +1,441 @@
+//! Builds release packages out of a staged install tree. The tree is put
+//! together from files and directories, such as the prefix of an
+//! `external::Installed`, laid out the way they get installed:
+//!
+//! ```ignore
+//! let info = PackageInfo::new("foo", "1.2.0")
+//!     .set_maintainer("Foo Maintainers <foo@example.com>")
+//!     .set_summary("Does foo");
+//!
+//! let deb = Deb::new(ctx.clone(), info.clone())
+//!     .add_file(foo, "usr/bin/foo")
+//!     .add_dir(docs, "usr/share/doc/foo")
+//!     .run();
+//! ```
+
+use std::io;
+use std::io::{fs, File};
+use std::os;
+use sync::Future;
+
+use context::{Context, Exec, Prep};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static DPKG_DEB_EXES: &'static [&'static str] = &'static ["dpkg-deb"];
+pub static RPMBUILD_EXES: &'static [&'static str] = &'static ["rpmbuild"];
+
+/// The metadata shared by every package format.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct PackageInfo {
+    pub name: ~str,
+    pub version: ~str,
+    /// The revision of the packaging of this version.
+    pub release: uint,
+    /// The architecture in the format's own spelling, e.g. "amd64" for a
+    /// `.deb` and "x86_64" for an `.rpm`. Defaults to the host's.
+    pub arch: Option<~str>,
+    pub maintainer: ~str,
+    pub summary: ~str,
+    pub description: ~str,
+    pub license: ~str,
+    pub homepage: Option<~str>,
+    /// Packages this one depends on, in the format's syntax, e.g.
+    /// "libc6 (>= 2.15)" or "glibc >= 2.15".
+    pub depends: Vec<~str>,
+}
+
+impl PackageInfo {
+    pub fn new<T: Str, U: Str>(name: T, version: U) -> PackageInfo {
+        PackageInfo {
+            name: name.into_owned(),
+            version: version.into_owned(),
+            release: 1,
+            arch: None,
+            maintainer: ~"",
+            summary: ~"",
+            description: ~"",
+            license: ~"",
+            homepage: None,
+            depends: Vec::new(),
+        }
+    }
+
+    pub fn set_release(mut self, release: uint) -> PackageInfo {
+        self.release = release;
+        self
+    }
+
+    pub fn set_arch<T: Str>(mut self, arch: T) -> PackageInfo {
+        self.arch = Some(arch.into_owned());
+        self
+    }
+
+    pub fn set_maintainer<T: Str>(mut self, maintainer: T) -> PackageInfo {
+        self.maintainer = maintainer.into_owned();
+        self
+    }
+
+    pub fn set_summary<T: Str>(mut self, summary: T) -> PackageInfo {
+        self.summary = summary.into_owned();
+        self
+    }
+
+    pub fn set_description<T: Str>(mut self, description: T) -> PackageInfo {
+        self.description = description.into_owned();
+        self
+    }
+
+    pub fn set_license<T: Str>(mut self, license: T) -> PackageInfo {
+        self.license = license.into_owned();
+        self
+    }
+
+    pub fn set_homepage<T: Str>(mut self, homepage: T) -> PackageInfo {
+        self.homepage = Some(homepage.into_owned());
+        self
+    }
+
+    pub fn add_depend<T: Str>(mut self, depend: T) -> PackageInfo {
+        self.depends.push(depend.into_owned());
+        self
+    }
+}
+
+/// The files that go into a package, keyed on where they get installed,
+/// relative to the root of the target system.
+#[deriving(Clone)]
+struct Stage {
+    files: Vec<(Path, Path)>,
+    dirs: Vec<(Path, Path)>,
+}
+
+impl Stage {
+    fn new() -> Stage {
+        Stage {
+            files: Vec::new(),
+            dirs: Vec::new(),
+        }
+    }
+
+    fn prep(&self, ctx: &Context, fn_name: &'static str) -> Prep {
+        let mut prep = ctx.prep(fn_name);
+
+        prep.declare_input("value", "files", &self.files);
+        prep.declare_input("value", "dirs", &self.dirs);
+
+        for &(ref src, _) in self.files.iter() {
+            prep.declare_input_path(src.clone()).unwrap();
+        }
+
+        for &(ref src, _) in self.dirs.iter() {
+            prep.declare_input_dir(src.clone()).unwrap();
+        }
+
+        prep
+    }
+
+    /// Copies everything into the fresh directory `root`.
+    fn copy_to(&self, root: &Path) {
+        if root.is_dir() {
+            fs::rmdir_recursive(root).unwrap();
+        }
+        fs::mkdir_recursive(root, io::UserDir).unwrap();
+
+        for &(ref src, ref dst) in self.dirs.iter() {
+            path_util::copy_dir(src, &root.join(dst)).unwrap();
+        }
+
+        for &(ref src, ref dst) in self.files.iter() {
+            let dst = root.join(dst);
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+            fs::copy(src, &dst).unwrap();
+        }
+    }
+}
+
+/// Where the packaging of `name` in `format` happens.
+fn package_dir(ctx: &Context, name: &str, format: &str) -> Path {
+    os::make_absolute(&ctx.root.join("package").join(format!("{}.{}", name, format)))
+}
+
+fn write_file(path: &Path, contents: &str) {
+    match File::create(path).write_str(contents) {
+        Ok(()) => { }
+        Err(err) => fail!("failed to write {}: {}", path.display(), err),
+    }
+}
+
+fn run_packager(exec: &mut Exec, exe: &Path, args: &[~str], dst: &Path) {
+    exec.process_builder(exe.as_str().unwrap(), args)
+        .description(exe.filename_display())
+        .msg(dst.display())
+        .run_or_fail();
+}
+
+#[cfg(target_arch = "x86_64")]
+fn deb_arch() -> ~str { ~"amd64" }
+
+#[cfg(target_arch = "x86")]
+fn deb_arch() -> ~str { ~"i386" }
+
+#[cfg(target_arch = "arm")]
+fn deb_arch() -> ~str { ~"armhf" }
+
+#[cfg(target_arch = "x86_64")]
+fn rpm_arch() -> ~str { ~"x86_64" }
+
+#[cfg(target_arch = "x86")]
+fn rpm_arch() -> ~str { ~"i686" }
+
+#[cfg(target_arch = "arm")]
+fn rpm_arch() -> ~str { ~"armv7hl" }
+
+macro_rules! stage_methods(
+    ($package:ident) => (
+        impl $package {
+            /// Set where the package goes. Defaults to the format's usual
+            /// file name in the build root.
+            pub fn set_dst<T: IntoPath>(mut self, dst: T) -> $package {
+                self.dst = Some(self.ctx.output_path(dst.into_path()));
+                self
+            }
+
+            /// Install `src` as `dst`, e.g. "usr/bin/foo".
+            pub fn add_file<T: IntoFuture<Path>, U: IntoPath>(mut self, src: T, dst: U) -> $package {
+                self.stage.files.push((src.into_future().unwrap(), dst.into_path()));
+                self
+            }
+
+            /// Install the contents of the directory `src` into `dst`,
+            /// e.g. "usr".
+            pub fn add_dir<T: IntoPath, U: IntoPath>(mut self, src: T, dst: U) -> $package {
+                self.stage.dirs.push((src.into_path(), dst.into_path()));
+                self
+            }
+
+            pub fn run(self) -> Path {
+                self.into_future().unwrap()
+            }
+        }
+    )
+)
+
+/// Builds a Debian package with `dpkg-deb`.
+#[deriving(Clone)]
+pub struct Deb {
+    ctx: Context,
+    exe: Path,
+    info: PackageInfo,
+    dst: Option<Path>,
+    stage: Stage,
+}
+
+impl Deb {
+    pub fn new(ctx: Context, info: PackageInfo) -> Deb {
+        let exe = path_util::find_program(ctx.clone(), DPKG_DEB_EXES);
+
+        Deb::new_with(ctx, exe, info)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T, info: PackageInfo) -> Deb {
+        Deb {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            info: info,
+            dst: None,
+            stage: Stage::new(),
+        }
+    }
+}
+
+stage_methods!(Deb)
+
+/// The `DEBIAN/control` file describing the package.
+fn deb_control(info: &PackageInfo, arch: &str) -> ~str {
+    let mut control = StrBuf::new();
+
+    control.push_str(format!("Package: {}\n", info.name));
+    control.push_str(format!("Version: {}-{}\n", info.version, info.release));
+    control.push_str(format!("Architecture: {}\n", arch));
+    control.push_str(format!("Maintainer: {}\n", info.maintainer));
+
+    if !info.depends.is_empty() {
+        control.push_str(format!("Depends: {}\n", info.depends.as_slice().connect(", ")));
+    }
+
+    match info.homepage {
+        Some(ref homepage) => { control.push_str(format!("Homepage: {}\n", *homepage)); }
+        None => { }
+    }
+
+    // The extended description is indented, with "." standing in for
+    // blank lines.
+    control.push_str(format!("Description: {}\n", info.summary));
+    for line in info.description.lines() {
+        if line.trim().is_empty() {
+            control.push_str(" .\n");
+        } else {
+            control.push_str(format!(" {}\n", line));
+        }
+    }
+
+    control.into_owned()
+}
+
+impl IntoFuture<Path> for Deb {
+    fn into_future(self) -> Future<Path> {
+        let Deb { ctx, exe, info, dst, stage } = self;
+
+        let arch = info.arch.clone().unwrap_or_else(|| deb_arch());
+        let dst = dst.unwrap_or_else(|| {
+            ctx.root.join(format!("{}_{}-{}_{}.deb", info.name, info.version, info.release, arch))
+        });
+        let dir = package_dir(&ctx, info.name.as_slice(), "deb");
+
+        let mut prep = stage.prep(&ctx, "Deb");
+        prep.declare_input_path(exe.clone()).unwrap();
+        prep.declare_input("value", "info", &info);
+        prep.declare_input("value", "dst", &dst);
+
+        prep.exec(proc(exec) {
+            let root = dir.join("root");
+            stage.copy_to(&root);
+
+            let control = root.join("DEBIAN").join("control");
+            fs::mkdir_recursive(&control.dir_path(), io::UserDir).unwrap();
+            write_file(&control, deb_control(&info, arch.as_slice()));
+
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            // The files are owned by whoever runs the build, which
+            // shouldn't leak into the package.
+            let args = vec!(
+                ~"--root-owner-group",
+                ~"--build",
+                root.as_str().unwrap().to_owned(),
+                dst.as_str().unwrap().to_owned());
+            run_packager(exec, &exe, args.as_slice(), &dst);
+
+            exec.discover_output_path("output", &dst);
+
+            dst
+        })
+    }
+}
+
+/// Builds an RPM package with `rpmbuild`.
+#[deriving(Clone)]
+pub struct Rpm {
+    ctx: Context,
+    exe: Path,
+    info: PackageInfo,
+    dst: Option<Path>,
+    stage: Stage,
+}
+
+impl Rpm {
+    pub fn new(ctx: Context, info: PackageInfo) -> Rpm {
+        let exe = path_util::find_program(ctx.clone(), RPMBUILD_EXES);
+
+        Rpm::new_with(ctx, exe, info)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T, info: PackageInfo) -> Rpm {
+        Rpm {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            info: info,
+            dst: None,
+            stage: Stage::new(),
+        }
+    }
+}
+
+stage_methods!(Rpm)
+
+/// A spec file that installs the staged tree at `root` as is.
+fn rpm_spec(info: &PackageInfo, root: &Path) -> ~str {
+    let mut spec = StrBuf::new();
+
+    // The files were already built, so rpmbuild shouldn't strip them or
+    // split out debug info.
+    spec.push_str("%global __os_install_post %{nil}\n");
+    spec.push_str("%global debug_package %{nil}\n\n");
+
+    spec.push_str(format!("Name: {}\n", info.name));
+    spec.push_str(format!("Version: {}\n", info.version));
+    spec.push_str(format!("Release: {}\n", info.release));
+    spec.push_str(format!("Summary: {}\n", info.summary));
+    spec.push_str(format!("License: {}\n", info.license));
+    spec.push_str(format!("Packager: {}\n", info.maintainer));
+
+    match info.homepage {
+        Some(ref homepage) => { spec.push_str(format!("URL: {}\n", *homepage)); }
+        None => { }
+    }
+
+    for depend in info.depends.iter() {
+        spec.push_str(format!("Requires: {}\n", *depend));
+    }
+
+    spec.push_str(format!("\n%description\n{}\n", info.description));
+
+    spec.push_str("\n%install\n");
+    spec.push_str(format!("cp -a '{}/.' \"$RPM_BUILD_ROOT\"/\n", root.display()));
+
+    spec.push_str("\n%files\n");
+    for path in fs::walk_dir(root).unwrap() {
+        if !path.is_dir() {
+            spec.push_str(format!("\"/{}\"\n", path.path_relative_from(root).unwrap().display()));
+        }
+    }
+
+    spec.into_owned()
+}
+
+impl IntoFuture<Path> for Rpm {
+    fn into_future(self) -> Future<Path> {
+        let Rpm { ctx, exe, info, dst, stage } = self;
+
+        let arch = info.arch.clone().unwrap_or_else(|| rpm_arch());
+        let name = format!("{}-{}-{}.{}.rpm", info.name, info.version, info.release, arch);
+        let dst = dst.unwrap_or_else(|| ctx.root.join(name.as_slice()));
+        let dir = package_dir(&ctx, info.name.as_slice(), "rpm");
+
+        let mut prep = stage.prep(&ctx, "Rpm");
+        prep.declare_input_path(exe.clone()).unwrap();
+        prep.declare_input("value", "info", &info);
+        prep.declare_input("value", "dst", &dst);
+
+        prep.exec(proc(exec) {
+            let root = dir.join("root");
+            stage.copy_to(&root);
+
+            let top = dir.join("rpmbuild");
+            if top.is_dir() {
+                fs::rmdir_recursive(&top).unwrap();
+            }
+            fs::mkdir_recursive(&top, io::UserDir).unwrap();
+
+            let spec = dir.join(format!("{}.spec", info.name));
+            write_file(&spec, rpm_spec(&info, &root));
+
+            let args = vec!(
+                ~"-bb",
+                ~"--target", arch.clone(),
+                ~"--define", format!("_topdir {}", top.display()),
+                spec.as_str().unwrap().to_owned());
+            run_packager(exec, &exe, args.as_slice(), &dst);
+
+            // rpmbuild always names the package itself.
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+            fs::rename(&top.join("RPMS").join(arch.as_slice()).join(name.as_slice()), &dst).unwrap();
+
+            exec.discover_output_path("output", &dst);
+
+            dst
+        })
+    }
+}
@@ -0,0 +1,300 @@
+//! Describes installed libraries to builds that don't use rbuild, with a
+//! pkg-config `.pc` file or a CMake package config. Both are derived from a
+//! `Library`'s requirements and written into an install tree laid out as
+//! `include/`, `lib/`, `lib/pkgconfig/` and `lib/cmake/<Name>/`:
+//!
+//! ```ignore
+//! PcFile::new(ctx.clone(), "foo", foo.clone()).set_version("1.2.0").run();
+//! CMakeConfig::new(ctx.clone(), "Foo", foo).set_version("1.2.0").run();
+//! ```
+
+use std::ascii::StrAsciiExt;
+use std::io;
+use std::io::{fs, File};
+use sync::Future;
+
+use builders::c::{LIB_PREFIX, STATIC_LIB_SUFFIX, SHARED_LIB_SUFFIX};
+use builders::c::library::{Library, Requirements};
+use context::Context;
+use into_path::IntoPath;
+use into_future::IntoFuture;
+
+/// The name to link the library file `lib` with, e.g. "foo" for
+/// `libfoo.a`.
+fn link_name(lib: &Path) -> ~str {
+    let stem = lib.filename_str().unwrap();
+    let stem = match stem.find('.') {
+        Some(i) => stem.slice_to(i),
+        None => stem,
+    };
+
+    if stem.starts_with(LIB_PREFIX) {
+        stem.slice_from(LIB_PREFIX.len()).to_owned()
+    } else {
+        stem.to_owned()
+    }
+}
+
+/// Writes `contents` to `dst` as a step, so it's only rewritten when the
+/// contents change.
+fn write_generated(ctx: &Context, fn_name: &'static str, dst: Path, contents: ~str) -> Future<Path> {
+    let mut prep = ctx.prep(fn_name);
+    prep.declare_input("value", "dst", &dst);
+    prep.declare_input("value", "contents", &contents);
+
+    prep.exec(proc(exec) {
+        fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+        match File::create(&dst).write_str(contents) {
+            Ok(()) => { }
+            Err(err) => fail!("failed to write {}: {}", dst.display(), err),
+        }
+        exec.discover_output_path("output", &dst);
+
+        dst
+    })
+}
+
+/// Writes a pkg-config `.pc` file.
+#[deriving(Clone)]
+pub struct PcFile {
+    ctx: Context,
+    name: ~str,
+    library: Library,
+    install_dir: Option<Path>,
+    prefix: ~str,
+    version: ~str,
+    description: ~str,
+    requires: Vec<~str>,
+}
+
+impl PcFile {
+    pub fn new<T: Str>(ctx: Context, name: T, library: Library) -> PcFile {
+        PcFile {
+            ctx: ctx,
+            name: name.into_owned(),
+            library: library,
+            install_dir: None,
+            prefix: ~"/usr/local",
+            version: ~"0.0.0",
+            description: ~"",
+            requires: Vec::new(),
+        }
+    }
+
+    /// Set the install tree the file is written to. Defaults to `install`
+    /// in the build root.
+    pub fn set_install_dir<T: IntoPath>(mut self, install_dir: T) -> PcFile {
+        self.install_dir = Some(self.ctx.output_path(install_dir.into_path()));
+        self
+    }
+
+    /// Set the prefix the tree gets installed to. Defaults to
+    /// "/usr/local".
+    pub fn set_prefix<T: Str>(mut self, prefix: T) -> PcFile {
+        self.prefix = prefix.into_owned();
+        self
+    }
+
+    pub fn set_version<T: Str>(mut self, version: T) -> PcFile {
+        self.version = version.into_owned();
+        self
+    }
+
+    pub fn set_description<T: Str>(mut self, description: T) -> PcFile {
+        self.description = description.into_owned();
+        self
+    }
+
+    /// Add another pkg-config package that users need too.
+    pub fn add_requires<T: Str>(mut self, requires: T) -> PcFile {
+        self.requires.push(requires.into_owned());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+
+    fn render(&self, reqs: &Requirements) -> ~str {
+        let mut pc = StrBuf::new();
+
+        pc.push_str(format!("prefix={}\n", self.prefix));
+        pc.push_str("exec_prefix=${prefix}\n");
+        pc.push_str("libdir=${exec_prefix}/lib\n");
+        pc.push_str("includedir=${prefix}/include\n\n");
+
+        pc.push_str(format!("Name: {}\n", self.name));
+        pc.push_str(format!("Description: {}\n", self.description));
+        pc.push_str(format!("Version: {}\n", self.version));
+
+        if !self.requires.is_empty() {
+            pc.push_str(format!("Requires: {}\n", self.requires.as_slice().connect(", ")));
+        }
+
+        pc.push_str("Cflags: -I${includedir}");
+        for macro in reqs.macros.iter() {
+            pc.push_str(format!(" -D{}", *macro));
+        }
+        pc.push_str("\n");
+
+        pc.push_str("Libs: -L${libdir}");
+        for lib in reqs.libs.iter() {
+            pc.push_str(format!(" -l{}", link_name(lib)));
+        }
+        pc.push_str("\n");
+
+        // The system libraries are only needed when linking statically.
+        if !reqs.external_libs.is_empty() {
+            pc.push_str("Libs.private:");
+            for lib in reqs.external_libs.iter() {
+                pc.push_str(format!(" -l{}", *lib));
+            }
+            pc.push_str("\n");
+        }
+
+        pc.into_owned()
+    }
+}
+
+impl IntoFuture<Path> for PcFile {
+    fn into_future(self) -> Future<Path> {
+        let contents = self.render(&self.library.requirements());
+        let install_dir = self.install_dir.clone().unwrap_or_else(|| self.ctx.root.join("install"));
+        let dst = install_dir.join("lib").join("pkgconfig").join(format!("{}.pc", self.name));
+
+        write_generated(&self.ctx, "PcFile", dst, contents)
+    }
+}
+
+/// Writes a CMake package config, `<Name>Config.cmake`, defining the
+/// imported target `<Name>::<name>`, along with `<Name>ConfigVersion.cmake`.
+/// The config finds the install tree relative to itself, so the tree can be
+/// installed anywhere.
+#[deriving(Clone)]
+pub struct CMakeConfig {
+    ctx: Context,
+    name: ~str,
+    library: Library,
+    install_dir: Option<Path>,
+    version: ~str,
+}
+
+impl CMakeConfig {
+    pub fn new<T: Str>(ctx: Context, name: T, library: Library) -> CMakeConfig {
+        CMakeConfig {
+            ctx: ctx,
+            name: name.into_owned(),
+            library: library,
+            install_dir: None,
+            version: ~"0.0.0",
+        }
+    }
+
+    /// Set the install tree the config is written to. Defaults to
+    /// `install` in the build root.
+    pub fn set_install_dir<T: IntoPath>(mut self, install_dir: T) -> CMakeConfig {
+        self.install_dir = Some(self.ctx.output_path(install_dir.into_path()));
+        self
+    }
+
+    pub fn set_version<T: Str>(mut self, version: T) -> CMakeConfig {
+        self.version = version.into_owned();
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+
+    fn render_config(&self, reqs: &Requirements) -> ~str {
+        let mut cmake = StrBuf::new();
+        let prefix = format!("_{}_PREFIX", self.name.to_ascii_upper());
+
+        // The config is installed in lib/cmake/<Name>.
+        cmake.push_str(format!("get_filename_component({} \"${}/../../..\" ABSOLUTE)\n\n",
+                               prefix, brace("CMAKE_CURRENT_LIST_DIR")));
+
+        for lib in reqs.libs.iter() {
+            let lib_name = link_name(lib);
+            let target = format!("{}::{}", self.name, lib_name);
+            let kind = match lib.extension_str() {
+                Some(ext) if ext == STATIC_LIB_SUFFIX => "STATIC",
+                Some(ext) if ext == SHARED_LIB_SUFFIX => "SHARED",
+                _ => "UNKNOWN",
+            };
+
+            cmake.push_str(format!("if(NOT TARGET {})\n", target));
+            cmake.push_str(format!("  add_library({} {} IMPORTED)\n", target, kind));
+            cmake.push_str(format!("  set_target_properties({} PROPERTIES\n", target));
+            cmake.push_str(format!("    IMPORTED_LOCATION \"${}/lib/{}\"\n",
+                                   brace(prefix.as_slice()), lib.filename_str().unwrap()));
+            cmake.push_str(format!("    INTERFACE_INCLUDE_DIRECTORIES \"${}/include\"\n",
+                                   brace(prefix.as_slice())));
+
+            if !reqs.macros.is_empty() {
+                cmake.push_str(format!("    INTERFACE_COMPILE_DEFINITIONS \"{}\"\n",
+                                       reqs.macros.as_slice().connect(";")));
+            }
+
+            if !reqs.external_libs.is_empty() {
+                cmake.push_str(format!("    INTERFACE_LINK_LIBRARIES \"{}\"\n",
+                                       reqs.external_libs.as_slice().connect(";")));
+            }
+
+            cmake.push_str("  )\nendif()\n\n");
+        }
+
+        cmake.push_str(format!("unset({})\n", prefix));
+
+        cmake.into_owned()
+    }
+
+    /// Accepts requests for the same major version, no newer than ours.
+    fn render_version(&self) -> ~str {
+        let major = self.version.split('.').next().unwrap();
+
+        let mut cmake = StrBuf::new();
+        cmake.push_str(format!("set(PACKAGE_VERSION \"{}\")\n\n", self.version));
+        cmake.push_str("if(PACKAGE_VERSION VERSION_LESS PACKAGE_FIND_VERSION)\n");
+        cmake.push_str("  set(PACKAGE_VERSION_COMPATIBLE FALSE)\n");
+        cmake.push_str(format!("elseif(NOT PACKAGE_FIND_VERSION_MAJOR STREQUAL \"{}\")\n", major));
+        cmake.push_str("  set(PACKAGE_VERSION_COMPATIBLE FALSE)\n");
+        cmake.push_str("else()\n");
+        cmake.push_str("  set(PACKAGE_VERSION_COMPATIBLE TRUE)\n");
+        cmake.push_str("  if(PACKAGE_FIND_VERSION STREQUAL PACKAGE_VERSION)\n");
+        cmake.push_str("    set(PACKAGE_VERSION_EXACT TRUE)\n");
+        cmake.push_str("  endif()\n");
+        cmake.push_str("endif()\n");
+
+        cmake.into_owned()
+    }
+}
+
+/// `name` wrapped in braces, for CMake variable references.
+fn brace(name: &str) -> ~str {
+    let mut s = StrBuf::new();
+    s.push_char('{');
+    s.push_str(name);
+    s.push_char('}');
+    s.into_owned()
+}
+
+impl IntoFuture<Path> for CMakeConfig {
+    fn into_future(self) -> Future<Path> {
+        let config = self.render_config(&self.library.requirements());
+        let version = self.render_version();
+
+        let install_dir = self.install_dir.clone().unwrap_or_else(|| self.ctx.root.join("install"));
+        let dir = install_dir.join("lib").join("cmake").join(self.name.as_slice());
+
+        let version_dst = dir.join(format!("{}ConfigVersion.cmake", self.name));
+        let version_file = write_generated(&self.ctx, "CMakeConfig", version_dst, version);
+        let config_dst = dir.join(format!("{}Config.cmake", self.name));
+        let config_file = write_generated(&self.ctx, "CMakeConfig", config_dst, config);
+
+        // The version file is only useful next to the config.
+        version_file.unwrap();
+        config_file
+    }
+}
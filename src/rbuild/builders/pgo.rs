@@ -0,0 +1,157 @@
+//! Profile-guided optimization in two phases: an instrumented build, a
+//! training run that records how it behaves, and an optimized build that
+//! reads the profiles:
+//!
+//! ```ignore
+//! fn train(exec: &mut Exec, exe: &Path) {
+//!     let args = [~"--benchmark"];
+//!     exec.process_builder(exe.as_str().unwrap(), args).run_or_fail();
+//! }
+//!
+//! let profiles = ctx.root.join("pgo");
+//!
+//! let instrumented = c_static.clone()
+//!     .set_pgo(PgoInstrument(profiles.clone()))
+//!     .link_exe("foo").add_srcs(srcs.clone());
+//! let profiles = Train::new(ctx.clone(), instrumented, train).set_profile_dir(profiles).run();
+//!
+//! c_static.set_pgo(PgoUse(profiles)).link_exe("foo").add_srcs(srcs).run();
+//! ```
+//!
+//! The training is rerun when the instrumented program or its inputs change,
+//! and the optimized build is recompiled when the profiles do.
+
+use std::io;
+use std::io::fs;
+use std::os;
+use sync::Future;
+
+use context::{Context, Exec};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+pub static LLVM_PROFDATA_EXES: &'static [&'static str] = &'static ["llvm-profdata"];
+
+/// The merged profile clang reads, in the data directory.
+pub static PROFDATA: &'static str = "default.profdata";
+
+/// Runs the instrumented program `exe` on a representative workload.
+pub type Workload = fn(exec: &mut Exec, exe: &Path);
+
+/// Where the instrumented build writes its profiles.
+pub fn raw_dir(profile_dir: &Path) -> Path {
+    profile_dir.join("raw")
+}
+
+/// Where the optimized build reads the profiles from.
+pub fn data_dir(profile_dir: &Path) -> Path {
+    profile_dir.join("data")
+}
+
+/// Runs the training workload, turning the profiles it writes into the
+/// ones the optimized build reads. Resolves to the profile directory.
+#[deriving(Clone)]
+pub struct Train {
+    ctx: Context,
+    exe: Path,
+    workload: Workload,
+    profile_dir: Option<Path>,
+    inputs: Vec<Path>,
+}
+
+impl Train {
+    pub fn new<T: IntoFuture<Path>>(ctx: Context, exe: T, workload: Workload) -> Train {
+        Train {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            workload: workload,
+            profile_dir: None,
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Set the directory the profiles are kept in, which has to be the one
+    /// the instrumented build was given. Defaults to `pgo` in the build
+    /// root.
+    pub fn set_profile_dir<T: IntoPath>(mut self, profile_dir: T) -> Train {
+        self.profile_dir = Some(profile_dir.into_path());
+        self
+    }
+
+    /// Rerun the training when `input` changes, e.g. the data the workload
+    /// runs on.
+    pub fn add_input<T: IntoFuture<Path>>(mut self, input: T) -> Train {
+        self.inputs.push(input.into_future().unwrap());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Train {
+    fn into_future(self) -> Future<Path> {
+        let Train { ctx, exe, workload, profile_dir, inputs } = self;
+
+        let profile_dir = os::make_absolute(&profile_dir.unwrap_or_else(|| ctx.root.join("pgo")));
+        let profdata = path_util::try_find_program(ctx.clone(), LLVM_PROFDATA_EXES).ok();
+
+        let mut prep = ctx.prep("PgoTrain");
+        prep.declare_input_path(exe.clone()).unwrap();
+        for input in inputs.move_iter() {
+            prep.declare_input_path(input).unwrap();
+        }
+        prep.declare_input("value", "profile_dir", &profile_dir);
+
+        prep.exec(proc(exec) {
+            let raw = raw_dir(&profile_dir);
+            let data = data_dir(&profile_dir);
+
+            // Start over, so the profiles of an older build aren't merged in.
+            for dir in [raw.clone(), data.clone()].iter() {
+                if dir.is_dir() {
+                    fs::rmdir_recursive(dir).unwrap();
+                }
+                fs::mkdir_recursive(dir, io::UserDir).unwrap();
+            }
+
+            workload(exec, &exe);
+
+            let profraws = path_util::find_files(&raw, &["profraw"]).unwrap();
+            let gcdas = path_util::find_files(&raw, &["gcda"]).unwrap();
+
+            if !profraws.is_empty() {
+                // Clang writes a profile per process, which are merged into
+                // one.
+                let profdata = match profdata {
+                    Some(profdata) => profdata,
+                    None => fail!("llvm-profdata is needed to merge the profiles in {}", raw.display()),
+                };
+
+                let mut args = vec!(~"merge", format!("-output={}", data.join(PROFDATA).display()));
+                args.push_all_move(profraws.iter().map(|path| path.as_str().unwrap().to_owned()).collect());
+
+                exec.process_builder(profdata.as_str().unwrap(), args.as_slice())
+                    .description("profdata")
+                    .msg(data.join(PROFDATA).display())
+                    .run_or_fail();
+            } else if !gcdas.is_empty() {
+                // Gcc names each profile after the absolute path of its
+                // object, which is under the instrumented variant's
+                // directory rather than the optimized one's.
+                for gcda in gcdas.iter() {
+                    let name = gcda.filename_str().unwrap().replace("#pgo_instrument#", "#pgo_use#");
+                    fs::copy(gcda, &data.join(name)).unwrap();
+                }
+            } else {
+                fail!("the training workload didn't write any profiles to {}", raw.display());
+            }
+
+            exec.discover_output_path("data", &data);
+
+            profile_dir
+        })
+    }
+}
@@ -0,0 +1,158 @@
+use std::str;
+use sync::Future;
+
+use builders::c::SharedBuilder;
+use context::Context;
+use into_future::IntoFuture;
+use path_util;
+
+pub static EXES: &'static [&'static str] = &'static ["python3-config", "python-config"];
+
+/// What `python-config` reports about the interpreter we're building for.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct PythonConfig {
+    pub includes: Vec<Path>,
+    pub ldflags: Vec<~str>,
+    /// The extension module filename suffix, e.g.
+    /// `.cpython-311-x86_64-linux-gnu.so`.
+    pub suffix: ~str,
+}
+
+/// Query `python-config` for how to build extension modules. The result is
+/// cached until `python-config` itself changes.
+pub fn python_config(ctx: Context, exe: Path) -> Future<PythonConfig> {
+    let mut prep = ctx.prep("python_config");
+    prep.declare_input_path(exe.clone()).unwrap();
+
+    prep.exec(proc(exec) {
+        let prog = exe.as_str().unwrap();
+
+        let query = |arg: &str| -> ~str {
+            let args = [arg.to_owned()];
+            let output = exec.process_builder(prog, args).run_or_fail();
+            str::from_utf8_lossy(output.output.as_slice()).as_slice().trim().to_owned()
+        };
+
+        let includes = query("--includes");
+        let ldflags = query("--ldflags");
+        let suffix = query("--extension-suffix");
+
+        let mut seen = Vec::new();
+        let includes = includes.words()
+            .filter(|flag| flag.starts_with("-I"))
+            .map(|flag| flag.slice_from(2))
+            .filter(|include| {
+                // python-config lists the same directory twice when the
+                // platform include dir is shared.
+                let new = !seen.contains(include);
+                seen.push(*include);
+                new
+            })
+            .map(|include| Path::new(include))
+            .collect();
+
+        PythonConfig {
+            includes: includes,
+            ldflags: ldflags.words().map(|flag| flag.to_owned()).collect(),
+            suffix: suffix,
+        }
+    })
+}
+
+#[cfg(target_os = "macos")]
+static LINK_FLAGS: &'static [&'static str] = &["-bundle", "-undefined", "dynamic_lookup"];
+
+#[cfg(not(target_os = "macos"))]
+static LINK_FLAGS: &'static [&'static str] = &["-shared"];
+
+/// Builds a CPython extension module named `name` from C/C++ sources,
+/// producing e.g. `name.cpython-311-x86_64-linux-gnu.so`.
+#[deriving(Clone)]
+pub struct Extension {
+    builder: SharedBuilder,
+    config: PythonConfig,
+    name: ~str,
+    srcs: Vec<Path>,
+}
+
+impl Extension {
+    pub fn new<T: Str>(ctx: Context, name: T) -> Extension {
+        let exe = path_util::find_program(ctx.clone(), EXES);
+
+        Extension::new_with(ctx, exe, name)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>, U: Str>(ctx: Context, python_config_exe: T, name: U) -> Extension {
+        let config = python_config(ctx.clone(), python_config_exe.into_future().unwrap()).unwrap();
+
+        let mut builder = SharedBuilder::new(ctx);
+        for include in config.includes.iter() {
+            builder = builder.add_include(include.clone());
+        }
+
+        Extension {
+            builder: builder,
+            config: config,
+            name: name.into_owned(),
+            srcs: Vec::new(),
+        }
+    }
+
+    pub fn add_src<T: IntoFuture<Path>>(mut self, src: T) -> Extension {
+        self.srcs.push(src.into_future().unwrap());
+        self
+    }
+
+    pub fn add_include<T: IntoFuture<Path>>(mut self, include: T) -> Extension {
+        self.builder = self.builder.add_include(include);
+        self
+    }
+
+    pub fn add_macro<T: Str>(mut self, macro: T) -> Extension {
+        self.builder = self.builder.add_macro(macro);
+        self
+    }
+
+    pub fn set_debug(mut self, debug: bool) -> Extension {
+        self.builder = self.builder.set_debug(debug);
+        self
+    }
+
+    pub fn set_optimize(mut self, optimize: bool) -> Extension {
+        self.builder = self.builder.set_optimize(optimize);
+        self
+    }
+
+    pub fn add_flag<S: Str>(mut self, flag: S) -> Extension {
+        self.builder = self.builder.add_flag(flag);
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Extension {
+    fn into_future(self) -> Future<Path> {
+        let Extension { builder, config, name, srcs } = self;
+
+        assert!(!srcs.is_empty());
+
+        let mut link = builder.link_exe(name + config.suffix);
+
+        for flag in LINK_FLAGS.iter() {
+            link = link.add_flag(*flag);
+        }
+
+        for flag in config.ldflags.move_iter() {
+            link = link.add_flag(flag);
+        }
+
+        for src in srcs.move_iter() {
+            link = link.add_src(builder.compile(src));
+        }
+
+        link.into_future()
+    }
+}
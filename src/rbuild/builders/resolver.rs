@@ -0,0 +1,251 @@
+//! Picks between a dependency installed on the system and a vendored copy:
+//!
+//! ```ignore
+//! let zlib = Dependency::new(ctx.clone(), "zlib")
+//!     .set_min_version("1.2.8")
+//!     .set_fallback(ThirdParty::new(ctx.clone(), "zlib", ZLIB_URL, ZLIB_SHA256, build_zlib))
+//!     .resolve();
+//! ```
+//!
+//! The choice is recorded in the database, so later builds keep using the
+//! same copy until the dependency's settings or `PKG_CONFIG_PATH` change,
+//! even if another copy shows up in the meantime.
+
+use std::str;
+use sync::Future;
+
+use builders::c::library::{Library, IntoLibrary};
+use builders::third_party::ThirdParty;
+use context::{Context, Exec};
+use path_util;
+
+pub static PKG_CONFIG_EXES: &'static [&'static str] = &'static ["pkg-config", "pkgconf"];
+
+/// Looks for a dependency on the system some other way than pkg-config,
+/// e.g. by checking for a header. Returns `None` if it isn't installed.
+pub type Probe = fn(ctx: Context) -> Option<Library>;
+
+/// What pkg-config says a package's users need.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct PkgConfigFlags {
+    pub version: ~str,
+    pub includes: Vec<Path>,
+    pub macros: Vec<~str>,
+    pub libpaths: Vec<Path>,
+    pub libs: Vec<~str>,
+}
+
+impl PkgConfigFlags {
+    /// The flags as a library. The include directories belong to the
+    /// system, so they aren't inputs of every compile of its users.
+    pub fn to_library(&self) -> Library {
+        let mut lib = Library::interface();
+
+        for include in self.includes.iter() {
+            lib = lib.add_public_include(include.clone());
+        }
+
+        for macro in self.macros.iter() {
+            lib = lib.add_public_macro(macro.clone());
+        }
+
+        for libpath in self.libpaths.iter() {
+            lib = lib.add_libpath(libpath.clone());
+        }
+
+        for name in self.libs.iter() {
+            lib = lib.add_external_lib(name.clone());
+        }
+
+        lib
+    }
+}
+
+/// Where a dependency was found.
+#[deriving(Clone, Encodable, Decodable)]
+pub enum Resolution {
+    PkgConfig(PkgConfigFlags),
+    Probed,
+    Vendored,
+}
+
+#[deriving(Clone)]
+pub struct Dependency {
+    ctx: Context,
+    name: ~str,
+    pkg_config_name: Option<~str>,
+    min_version: Option<~str>,
+    probe: Option<Probe>,
+    fallback: Option<ThirdParty>,
+    prefer_system: bool,
+}
+
+impl Dependency {
+    pub fn new<T: Str>(ctx: Context, name: T) -> Dependency {
+        Dependency {
+            ctx: ctx,
+            name: name.into_owned(),
+            pkg_config_name: None,
+            min_version: None,
+            probe: None,
+            fallback: None,
+            prefer_system: true,
+        }
+    }
+
+    /// Set the name of the pkg-config package, if it isn't the name of the
+    /// dependency.
+    pub fn set_pkg_config<T: Str>(mut self, name: T) -> Dependency {
+        self.pkg_config_name = Some(name.into_owned());
+        self
+    }
+
+    /// Only accept a system copy of at least `version`.
+    pub fn set_min_version<T: Str>(mut self, version: T) -> Dependency {
+        self.min_version = Some(version.into_owned());
+        self
+    }
+
+    /// Look for the dependency with `probe` when pkg-config doesn't know
+    /// it.
+    pub fn set_probe(mut self, probe: Probe) -> Dependency {
+        self.probe = Some(probe);
+        self
+    }
+
+    /// Build `fallback` when the dependency isn't installed.
+    pub fn set_fallback(mut self, fallback: ThirdParty) -> Dependency {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Whether to look for a system copy before using the fallback.
+    /// Defaults to true; turning it off always builds the vendored copy.
+    pub fn prefer_system(mut self, prefer_system: bool) -> Dependency {
+        self.prefer_system = prefer_system;
+        self
+    }
+
+    /// Decides where the dependency comes from. The decision is cached.
+    pub fn resolution(&self) -> Future<Resolution> {
+        let ctx = self.ctx.clone();
+        let name = self.name.clone();
+        let package = self.pkg_config_name.clone().unwrap_or(self.name.clone());
+        let min_version = self.min_version.clone();
+        let probe = self.probe;
+        let has_fallback = self.fallback.is_some();
+        let prefer_system = self.prefer_system;
+        let pkg_config = path_util::try_find_program(ctx.clone(), PKG_CONFIG_EXES).ok();
+
+        let mut prep = ctx.prep("Resolve");
+        prep.declare_input("value", "name", &name);
+        prep.declare_input("value", "package", &package);
+        prep.declare_input("value", "min_version", &min_version);
+        prep.declare_input("value", "probe", &probe.is_some());
+        prep.declare_input("value", "fallback", &has_fallback);
+        prep.declare_input("value", "prefer_system", &prefer_system);
+        prep.declare_input_env("PKG_CONFIG_PATH");
+
+        prep.exec(proc(exec) {
+            if prefer_system {
+                let flags = pkg_config.and_then(|exe| {
+                    query_pkg_config(exec, &exe, package.as_slice(), min_version.as_ref())
+                });
+
+                match flags {
+                    Some(flags) => { return PkgConfig(flags); }
+                    None => { }
+                }
+
+                match probe {
+                    Some(probe) if probe(ctx.clone()).is_some() => { return Probed; }
+                    _ => { }
+                }
+            }
+
+            if !has_fallback {
+                fail!("{} isn't installed, and there is no vendored copy to build", name);
+            }
+
+            Vendored
+        })
+    }
+
+    pub fn resolve(self) -> Library {
+        match self.resolution().unwrap() {
+            PkgConfig(flags) => flags.to_library(),
+            Probed => {
+                match (self.probe.unwrap())(self.ctx.clone()) {
+                    Some(lib) => lib,
+                    None => fail!("{} was found before, but not anymore", self.name),
+                }
+            }
+            Vendored => self.fallback.unwrap().run(),
+        }
+    }
+}
+
+impl IntoLibrary for Dependency {
+    fn into_library(self) -> Library {
+        self.resolve()
+    }
+}
+
+/// Asks pkg-config for `package`, returning `None` if it isn't installed
+/// or is too old.
+fn query_pkg_config(
+    exec: &mut Exec,
+    exe: &Path,
+    package: &str,
+    min_version: Option<&~str>
+) -> Option<PkgConfigFlags> {
+    let spec = match min_version {
+        Some(version) => format!("{} >= {}", package, *version),
+        None => package.to_owned(),
+    };
+
+    let run = |exec: &mut Exec, flag: &str| -> Option<~str> {
+        let args = [flag.to_owned(), spec.clone()];
+        let output = exec.process_builder(exe.as_str().unwrap(), args)
+            .description("pkg-config")
+            .msg(spec.as_slice())
+            .run_with_output();
+
+        match output {
+            Ok(ref output) if output.status.success() => {
+                Some(str::from_utf8_lossy(output.output.as_slice()).into_owned())
+            }
+            _ => None,
+        }
+    };
+
+    let version = match run(exec, "--modversion") {
+        Some(version) => version.trim().to_owned(),
+        None => { return None; }
+    };
+
+    let cflags = run(exec, "--cflags").unwrap_or(~"");
+    let libs = run(exec, "--libs").unwrap_or(~"");
+
+    let mut flags = PkgConfigFlags {
+        version: version,
+        includes: Vec::new(),
+        macros: Vec::new(),
+        libpaths: Vec::new(),
+        libs: Vec::new(),
+    };
+
+    for flag in cflags.words().chain(libs.words()) {
+        if flag.starts_with("-I") {
+            flags.includes.push(Path::new(flag.slice_from(2)));
+        } else if flag.starts_with("-D") {
+            flags.macros.push(flag.slice_from(2).to_owned());
+        } else if flag.starts_with("-L") {
+            flags.libpaths.push(Path::new(flag.slice_from(2)));
+        } else if flag.starts_with("-l") {
+            flags.libs.push(flag.slice_from(2).to_owned());
+        }
+    }
+
+    Some(flags)
+}
@@ -0,0 +1,213 @@
+//! Bakes data files into executables. Each file becomes an array `sym`,
+//! with its length in `sym_size`, declared in a generated header:
+//!
+//! ```ignore
+//! let logo = Resource::new(ctx.clone())
+//!     .set_src("assets/logo.png")
+//!     .run();
+//!
+//! let c = StaticBuilder::new(ctx.clone())
+//!     .add_include(logo.header.dir_path());
+//!
+//! let exe = c.link_exe("viewer")
+//!     .add_src(c.compile("viewer.c"))
+//!     .add_src(logo.object)
+//!     .run();
+//! ```
+
+use std::ascii::StrAsciiExt;
+use std::io;
+use std::io::{fs, File};
+use std::os;
+use sync::Future;
+
+use context::Context;
+use into_path::IntoPath;
+use into_future::IntoFuture;
+
+pub static LD_EXES: &'static [&'static str] = &'static ["ld"];
+
+/// The generated header, and either a C source or an object holding the
+/// data. Both kinds can be handed to a C builder's `add_src`.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct Embedded {
+    pub header: Path,
+    pub object: Path,
+}
+
+#[deriving(Clone)]
+pub struct Resource {
+    ctx: Context,
+    src: Option<Path>,
+    symbol: Option<~str>,
+    dst_dir: Option<Path>,
+    ld: Option<Path>,
+}
+
+/// Turns `name` into a C identifier the way `ld -b binary` does, by
+/// replacing everything but letters and digits with underscores.
+fn mangle(name: &str) -> ~str {
+    name.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect()
+}
+
+impl Resource {
+    pub fn new(ctx: Context) -> Resource {
+        Resource {
+            ctx: ctx,
+            src: None,
+            symbol: None,
+            dst_dir: None,
+            ld: None,
+        }
+    }
+
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Resource {
+        self.src = Some(src.into_future().unwrap());
+        self
+    }
+
+    /// Set the name of the array. Defaults to the file name, with anything
+    /// that can't be in an identifier replaced by underscores.
+    pub fn set_symbol<T: Str>(mut self, symbol: T) -> Resource {
+        self.symbol = Some(symbol.into_owned());
+        self
+    }
+
+    /// Set the directory the header and object go in. Defaults to
+    /// `resources` in the build root.
+    pub fn set_dst_dir<T: IntoPath>(mut self, dst_dir: T) -> Resource {
+        self.dst_dir = Some(self.ctx.output_path(dst_dir.into_path()));
+        self
+    }
+
+    /// Have the linker `ld` turn the file into an object with `-b binary`,
+    /// instead of generating a C source. This is much faster for large
+    /// files, but only GNU ld supports it.
+    pub fn set_ld<T: IntoFuture<Path>>(mut self, ld: T) -> Resource {
+        self.ld = Some(ld.into_future().unwrap());
+        self
+    }
+
+    pub fn run(self) -> Embedded {
+        self.into_future().unwrap()
+    }
+}
+
+/// Writes `sym` as a C array definition.
+fn render_source(symbol: &str, data: &[u8]) -> ~str {
+    let mut source = StrBuf::new();
+    source.push_str("#include <stddef.h>\n\n");
+    source.push_str(format!("const unsigned char {}[] = ", symbol));
+    source.push_str("{\n");
+
+    for line in data.chunks(12) {
+        source.push_str("   ");
+        for byte in line.iter() {
+            source.push_str(format!(" 0x{:02x},", *byte));
+        }
+        source.push_str("\n");
+    }
+
+    source.push_str("};\n\n");
+    source.push_str(format!("const size_t {}_size = {};\n", symbol, data.len()));
+
+    source.into_owned()
+}
+
+/// Declares the array `symbol`. The linker's symbols are given the same
+/// names as the generated source's with macros, so the two are
+/// interchangeable.
+fn render_header(symbol: &str, linker_name: Option<&str>) -> ~str {
+    let guard = format!("{}_H", symbol.to_ascii_upper());
+
+    let mut header = StrBuf::new();
+    header.push_str(format!("#ifndef {}\n#define {}\n\n#include <stddef.h>\n\n", guard, guard));
+
+    match linker_name {
+        Some(name) => {
+            header.push_str(format!("extern const unsigned char _binary_{}_start[];\n", name));
+            header.push_str(format!("extern const unsigned char _binary_{}_end[];\n\n", name));
+            header.push_str(format!("#define {} _binary_{}_start\n", symbol, name));
+            header.push_str(format!("#define {}_size ((size_t)(_binary_{}_end - _binary_{}_start))\n",
+                                    symbol, name, name));
+        }
+        None => {
+            header.push_str(format!("extern const unsigned char {}[];\n", symbol));
+            header.push_str(format!("extern const size_t {}_size;\n", symbol));
+        }
+    }
+
+    header.push_str(format!("\n#endif /* {} */\n", guard));
+
+    header.into_owned()
+}
+
+impl IntoFuture<Embedded> for Resource {
+    fn into_future(self) -> Future<Embedded> {
+        let Resource { ctx, src, symbol, dst_dir, ld } = self;
+
+        assert!(src.is_some());
+        let src = src.unwrap();
+        let filename = src.filename_str().unwrap().to_owned();
+        let symbol = symbol.unwrap_or_else(|| mangle(filename.as_slice()));
+        let dst_dir = dst_dir.unwrap_or_else(|| ctx.root.join("resources"));
+
+        let header = dst_dir.join(format!("{}.h", symbol));
+        let object = match ld {
+            Some(_) => dst_dir.join(format!("{}.o", symbol)),
+            None => dst_dir.join(format!("{}.c", symbol)),
+        };
+
+        let mut prep = ctx.prep("Resource");
+        prep.declare_input_path(src.clone()).unwrap();
+        prep.declare_input("value", "symbol", &symbol);
+        prep.declare_input("value", "header", &header);
+        prep.declare_input("value", "object", &object);
+        match ld {
+            Some(ref ld) => { prep.declare_input_path(ld.clone()).unwrap(); }
+            None => { }
+        }
+
+        prep.exec(proc(exec) {
+            fs::mkdir_recursive(&dst_dir, io::UserDir).unwrap();
+
+            let linker_name = match ld {
+                Some(ld) => {
+                    // ld names the symbols after the path it's given, so
+                    // it's run next to the file.
+                    let args = vec!(
+                        ~"-r", ~"-b", ~"binary",
+                        ~"-o", os::make_absolute(&object).as_str().unwrap().to_owned(),
+                        filename.clone());
+
+                    exec.process_builder(ld.as_str().unwrap(), args.as_slice())
+                        .cwd(&src.dir_path())
+                        .description(ld.filename_display())
+                        .msg(object.display())
+                        .msg("<-")
+                        .msg(src.display())
+                        .run_or_fail();
+
+                    Some(mangle(filename.as_slice()))
+                }
+                None => {
+                    let data = File::open(&src).read_to_end().unwrap();
+                    File::create(&object).write_str(render_source(symbol.as_slice(),
+                                                                  data.as_slice())).unwrap();
+                    None
+                }
+            };
+
+            let contents = render_header(symbol.as_slice(), linker_name.as_ref().map(|name| name.as_slice()));
+            File::create(&header).write_str(contents).unwrap();
+
+            exec.discover_output_path("header", &header);
+            exec.discover_output_path("object", &object);
+
+            Embedded {
+                header: header,
+                object: object,
+            }
+        })
+    }
+}
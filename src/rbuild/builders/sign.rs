@@ -0,0 +1,392 @@
+//! Signs release artifacts. Since signing tools change the artifact in
+//! place, `Codesign` and `Signtool` sign a copy of it, so the step that
+//! built the artifact stays up to date. Every signature is keyed on the
+//! digest of the artifact, so it's only redone when the artifact changes.
+
+use std::io;
+use std::io::fs;
+use sync::Future;
+
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+use shell;
+
+pub static CODESIGN_EXES: &'static [&'static str] = &'static ["codesign"];
+pub static SIGNTOOL_EXES: &'static [&'static str] = &'static ["signtool"];
+pub static GPG_EXES: &'static [&'static str] = &'static ["gpg2", "gpg"];
+
+/// Where a signed copy of `src` goes by default: a `signed` directory in
+/// the build root.
+fn signed_path(ctx: &Context, src: &Path) -> Path {
+    ctx.root.join("signed").join(src.filename().unwrap())
+}
+
+/// Copies `src`, a file or a directory such as an app bundle, to `dst` and
+/// runs `call` on the copy.
+fn sign_copy(ctx: Context, exe: Path, call: Call, src: Path, dst: Path) -> Future<Path> {
+    let mut prep = ctx.prep("Call");
+    if src.is_dir() {
+        prep.declare_input_dir(src.clone()).unwrap();
+    } else {
+        prep.declare_input_path(src.clone()).unwrap();
+    }
+    prep.declare_call(&call);
+
+    let (src_arg, dst_arg) = (shell::quote(src.as_str().unwrap()), shell::quote(dst.as_str().unwrap()));
+    prep.export_command(format!("rm -rf {1} && cp -R {0} {1} && {2}", src_arg, dst_arg, call.shell_cmd(None, false)), None);
+
+    prep.exec(proc(exec) {
+        // The tool signs the copy in place, so it can't be written to a
+        // temporary path first.
+        let (prog, args) = call.cmd();
+
+        if dst.is_dir() {
+            fs::rmdir_recursive(&dst).unwrap();
+        }
+        fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+        if src.is_dir() {
+            path_util::copy_dir(&src, &dst).unwrap();
+        } else {
+            fs::copy(&src, &dst).unwrap();
+        }
+
+        exec.process_builder(prog, args.as_slice())
+            .description(exe.filename_display())
+            .msg(dst.display())
+            .msg("<-")
+            .msg(src.display())
+            .run_or_fail();
+
+        dst
+    })
+}
+
+/// Signs a macOS binary or bundle with `codesign`.
+#[deriving(Clone)]
+pub struct Codesign {
+    ctx: Context,
+    exe: Path,
+    src: Option<Path>,
+    dst: Option<Path>,
+    identity: ~str,
+    entitlements: Option<Path>,
+    hardened_runtime: bool,
+    timestamp: bool,
+}
+
+impl Codesign {
+    /// Sign with `identity`, e.g. "Developer ID Application: Foo", or "-"
+    /// for an ad-hoc signature.
+    pub fn new<T: Str>(ctx: Context, identity: T) -> Codesign {
+        let exe = path_util::find_program(ctx.clone(), CODESIGN_EXES);
+
+        Codesign::new_with(ctx, exe, identity)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>, U: Str>(ctx: Context, exe: T, identity: U) -> Codesign {
+        Codesign {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            src: None,
+            dst: None,
+            identity: identity.into_owned(),
+            entitlements: None,
+            hardened_runtime: false,
+            timestamp: false,
+        }
+    }
+
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Codesign {
+        self.src = Some(src.into_future().unwrap());
+        self
+    }
+
+    /// Set where the signed copy goes. Defaults to `signed/<name>` in the
+    /// build root.
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Codesign {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    pub fn set_entitlements<T: IntoFuture<Path>>(mut self, entitlements: T) -> Codesign {
+        self.entitlements = Some(entitlements.into_future().unwrap());
+        self
+    }
+
+    /// Enable the hardened runtime, which notarization requires.
+    pub fn set_hardened_runtime(mut self, hardened_runtime: bool) -> Codesign {
+        self.hardened_runtime = hardened_runtime;
+        self
+    }
+
+    /// Have Apple's server timestamp the signature.
+    pub fn set_timestamp(mut self, timestamp: bool) -> Codesign {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Codesign {
+    fn into_future(self) -> Future<Path> {
+        let Codesign { ctx, exe, src, dst, identity, entitlements, hardened_runtime, timestamp } = self;
+
+        assert!(src.is_some());
+        let src = src.unwrap();
+        let dst = dst.unwrap_or_else(|| signed_path(&ctx, &src));
+
+        let mut call = Call::new(exe.clone()).unwrap();
+        call.push_str(~"--force");
+        call.push_str(~"--sign");
+        call.push_str(identity);
+
+        match entitlements {
+            Some(entitlements) => {
+                call.push_str(~"--entitlements");
+                call.push_input_path(entitlements).unwrap();
+            }
+            None => { }
+        }
+
+        if hardened_runtime {
+            call.push_str(~"--options");
+            call.push_str(~"runtime");
+        }
+
+        if timestamp { call.push_str(~"--timestamp"); }
+
+        call.push_output_path(dst.clone());
+
+        sign_copy(ctx, exe, call, src, dst)
+    }
+}
+
+/// Signs a Windows executable or DLL with `signtool`.
+#[deriving(Clone)]
+pub struct Signtool {
+    ctx: Context,
+    exe: Path,
+    src: Option<Path>,
+    dst: Option<Path>,
+    certificate: Option<Path>,
+    subject: Option<~str>,
+    timestamp_url: Option<~str>,
+}
+
+impl Signtool {
+    pub fn new(ctx: Context) -> Signtool {
+        let exe = path_util::find_program(ctx.clone(), SIGNTOOL_EXES);
+
+        Signtool::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Signtool {
+        Signtool {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            src: None,
+            dst: None,
+            certificate: None,
+            subject: None,
+            timestamp_url: None,
+        }
+    }
+
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Signtool {
+        self.src = Some(src.into_future().unwrap());
+        self
+    }
+
+    /// Set where the signed copy goes. Defaults to `signed/<name>` in the
+    /// build root.
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Signtool {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    /// Sign with the certificate in the `.pfx` file `certificate`.
+    pub fn set_certificate<T: IntoFuture<Path>>(mut self, certificate: T) -> Signtool {
+        self.certificate = Some(certificate.into_future().unwrap());
+        self
+    }
+
+    /// Sign with the certificate in the store whose subject is `subject`.
+    pub fn set_subject<T: Str>(mut self, subject: T) -> Signtool {
+        self.subject = Some(subject.into_owned());
+        self
+    }
+
+    /// Timestamp the signature with the RFC 3161 server at `url`.
+    pub fn set_timestamp_url<T: Str>(mut self, url: T) -> Signtool {
+        self.timestamp_url = Some(url.into_owned());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Signtool {
+    fn into_future(self) -> Future<Path> {
+        let Signtool { ctx, exe, src, dst, certificate, subject, timestamp_url } = self;
+
+        assert!(src.is_some());
+        let src = src.unwrap();
+        let dst = dst.unwrap_or_else(|| signed_path(&ctx, &src));
+
+        let mut call = Call::new(exe.clone()).unwrap();
+        call.push_str(~"sign");
+        call.push_str(~"/fd");
+        call.push_str(~"SHA256");
+
+        match certificate {
+            Some(certificate) => {
+                call.push_str(~"/f");
+                call.push_input_path(certificate).unwrap();
+            }
+            None => { }
+        }
+
+        match subject {
+            Some(subject) => {
+                call.push_str(~"/n");
+                call.push_str(subject);
+            }
+            None => { }
+        }
+
+        match timestamp_url {
+            Some(url) => {
+                call.push_str(~"/tr");
+                call.push_str(url);
+                call.push_str(~"/td");
+                call.push_str(~"SHA256");
+            }
+            None => { }
+        }
+
+        call.push_output_path(dst.clone());
+
+        sign_copy(ctx, exe, call, src, dst)
+    }
+}
+
+/// Writes a detached GPG signature of any artifact.
+#[deriving(Clone)]
+pub struct Gpg {
+    ctx: Context,
+    exe: Path,
+    src: Option<Path>,
+    dst: Option<Path>,
+    key: Option<~str>,
+    armor: bool,
+}
+
+impl Gpg {
+    pub fn new(ctx: Context) -> Gpg {
+        let exe = path_util::find_program(ctx.clone(), GPG_EXES);
+
+        Gpg::new_with(ctx, exe)
+    }
+
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Gpg {
+        Gpg {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            src: None,
+            dst: None,
+            key: None,
+            armor: false,
+        }
+    }
+
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Gpg {
+        self.src = Some(src.into_future().unwrap());
+        self
+    }
+
+    /// Set the signature's path. Defaults to the artifact's with `.sig`
+    /// appended, or `.asc` when armored.
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Gpg {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    /// Sign with `key` rather than the default key.
+    pub fn set_key<T: Str>(mut self, key: T) -> Gpg {
+        self.key = Some(key.into_owned());
+        self
+    }
+
+    /// Write an ASCII armored signature.
+    pub fn set_armor(mut self, armor: bool) -> Gpg {
+        self.armor = armor;
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Gpg {
+    fn into_future(self) -> Future<Path> {
+        let Gpg { ctx, exe, src, dst, key, armor } = self;
+
+        assert!(src.is_some());
+        let src = src.unwrap();
+        let dst = match dst {
+            Some(dst) => dst,
+            None => {
+                let extension = if armor { "asc" } else { "sig" };
+                let name = format!("{}.{}", src.filename_str().unwrap(), extension);
+                ctx.output_path(src.with_filename(name))
+            }
+        };
+
+        let mut call = Call::new(exe.clone()).unwrap();
+        call.push_str(~"--batch");
+        call.push_str(~"--yes");
+        call.push_str(~"--detach-sign");
+
+        if armor { call.push_str(~"--armor"); }
+
+        match key {
+            Some(key) => {
+                call.push_str(~"--local-user");
+                call.push_str(key);
+            }
+            None => { }
+        }
+
+        call.push_str(~"--output");
+        call.push_output_path(dst.clone());
+        call.push_input_path(src.clone()).unwrap();
+
+        let mut prep = ctx.prep("Call");
+        prep.declare_call(&call);
+
+        prep.exec(proc(exec) {
+            let (prog, args) = call.tmp_cmd().unwrap();
+
+            fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+            exec.process_builder(prog, args.as_slice())
+                .description(exe.filename_display())
+                .msg(dst.display())
+                .msg("<-")
+                .msg(src.display())
+                .run_or_fail();
+            call.rename_outputs().unwrap();
+
+            dst
+        })
+    }
+}
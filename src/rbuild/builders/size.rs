@@ -0,0 +1,121 @@
+use std::str;
+use collections::TreeMap;
+use sync::Future;
+
+use context::Context;
+use into_future::IntoFuture;
+use path_util;
+use workcache::Logger;
+
+pub static EXES: &'static [&'static str] = &'static ["size"];
+
+/// Measures the section sizes of a linked artifact with `size -A`, records
+/// them in the database and prints how each section changed since the last
+/// time the artifact was measured. Handy for keeping an eye on flash usage.
+#[deriving(Clone)]
+pub struct SizeReport {
+    ctx: Context,
+    exe: Path,
+    artifact: Option<Path>,
+}
+
+impl SizeReport {
+    pub fn new(ctx: Context) -> SizeReport {
+        let exe = path_util::find_program(ctx.clone(), EXES);
+
+        SizeReport::new_with(ctx, exe)
+    }
+
+    /// Use a specific `size`, e.g. `arm-none-eabi-size`.
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> SizeReport {
+        SizeReport {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            artifact: None,
+        }
+    }
+
+    pub fn set_artifact<T: IntoFuture<Path>>(mut self, artifact: T) -> SizeReport {
+        self.artifact = Some(artifact.into_future().unwrap());
+        self
+    }
+
+    pub fn run(self) -> TreeMap<~str, u64> {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<TreeMap<~str, u64>> for SizeReport {
+    fn into_future(self) -> Future<TreeMap<~str, u64>> {
+        let SizeReport { ctx, exe, artifact } = self;
+
+        assert!(artifact.is_some());
+        let artifact = artifact.unwrap();
+
+        let mut prep = ctx.prep("SizeReport");
+        prep.declare_input_path(exe.clone()).unwrap();
+        prep.declare_input_path(artifact.clone()).unwrap();
+
+        let measured = {
+            let artifact = artifact.clone();
+            prep.exec(proc(exec) {
+                let args = [~"-A", artifact.as_str().unwrap().to_owned()];
+
+                let output = exec.process_builder(exe.as_str().unwrap(), args)
+                    .description(exe.filename_display())
+                    .msg(artifact.display())
+                    .run_or_fail();
+
+                parse_sysv(str::from_utf8(output.output.as_slice()).unwrap())
+            })
+        };
+
+        // The sizes are recorded and compared outside of the exec, so that
+        // a cache hit is measured against the last run too.
+        Future::from_fn(proc() {
+            let sizes = measured.unwrap();
+
+            match ctx.record_sizes(&artifact, sizes.clone()) {
+                Some(old) => print_deltas(ctx.logger(), &artifact, &old, &sizes),
+                None => { }
+            }
+
+            sizes
+        })
+    }
+}
+
+/// Parses the output of `size -A`, which lists one section per line after a
+/// header: `.text  1234  0`.
+pub fn parse_sysv(output: &str) -> TreeMap<~str, u64> {
+    let mut sizes = TreeMap::new();
+
+    for line in output.lines() {
+        let fields: Vec<&str> = line.words().collect();
+        if fields.len() != 3 {
+            continue;
+        }
+
+        match from_str::<u64>(*fields.get(1)) {
+            Some(size) => { sizes.insert(fields.get(0).to_owned(), size); }
+            None => { }
+        }
+    }
+
+    sizes
+}
+
+fn print_deltas(logger: &Logger, artifact: &Path, old: &TreeMap<~str, u64>, new: &TreeMap<~str, u64>) {
+    for (section, &size) in new.iter() {
+        let before = old.find(section).map_or(0, |size| *size);
+        if before == size {
+            continue;
+        }
+
+        let delta = size as i64 - before as i64;
+        let sign = if delta > 0 { "+" } else { "" };
+
+        logger.info(format!("{}: {} {} -> {} bytes ({}{})",
+                            artifact.display(), section, before, size, sign, delta));
+    }
+}
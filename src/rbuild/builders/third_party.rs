@@ -0,0 +1,133 @@
+//! Vendored dependencies. A dependency is described by where to get it, its
+//! digest, and a recipe that builds it, and each stage is cached on its own:
+//!
+//! ```ignore
+//! fn build_zlib(ctx: Context, src_dir: Path) -> Library {
+//!     let zlib = CMake::new(ctx, "zlib").set_src_dir(src_dir).run();
+//!
+//!     Library::new(zlib.static_lib("z"))
+//!         .add_public_include(zlib.include_dir())
+//! }
+//!
+//! let zlib = ThirdParty::new(ctx.clone(), "zlib", ZLIB_URL, ZLIB_SHA256, build_zlib).run();
+//!
+//! c_static.link_exe("foo").add_src(foo).add_lib(zlib).run();
+//! ```
+
+use std::io;
+use std::io::fs;
+use std::os;
+use sync::Future;
+
+use builders::c::library::Library;
+use builders::download::Download;
+use builders::verify::Verify;
+use context::Context;
+use into_future::IntoFuture;
+use path_util;
+
+pub static TAR_EXES: &'static [&'static str] = &'static ["bsdtar", "gtar", "tar"];
+
+/// Builds a dependency from its extracted sources, returning the library
+/// along with what its users need. The context writes under the
+/// dependency's own directory.
+pub type Recipe = fn(ctx: Context, src_dir: Path) -> Library;
+
+#[deriving(Clone)]
+pub struct ThirdParty {
+    ctx: Context,
+    name: ~str,
+    url: ~str,
+    sha256: ~str,
+    recipe: Recipe,
+    strip_components: uint,
+}
+
+impl ThirdParty {
+    pub fn new<T: Str, U: Str, V: Str>(
+        ctx: Context,
+        name: T,
+        url: U,
+        sha256: V,
+        recipe: Recipe
+    ) -> ThirdParty {
+        ThirdParty {
+            ctx: ctx,
+            name: name.into_owned(),
+            url: url.into_owned(),
+            sha256: sha256.into_owned(),
+            recipe: recipe,
+            strip_components: 1,
+        }
+    }
+
+    /// How many leading directories to strip from the archive's paths.
+    /// Defaults to 1, for the usual `name-version/` directory.
+    pub fn set_strip_components(mut self, strip_components: uint) -> ThirdParty {
+        self.strip_components = strip_components;
+        self
+    }
+
+    /// Downloads and verifies the archive.
+    pub fn fetch(&self) -> Future<Path> {
+        let archive = Download::new(self.ctx.clone(), self.url.as_slice())
+            .set_dst(self.dir().join("archive").join(self.archive_name()));
+
+        Verify::sha256(self.ctx.clone(), self.sha256.as_slice())
+            .set_src(archive)
+            .into_future()
+    }
+
+    /// Extracts the verified archive, resolving to the source directory.
+    pub fn extract(&self) -> Future<Path> {
+        let exe = path_util::find_program(self.ctx.clone(), TAR_EXES).unwrap();
+        let archive = self.fetch().unwrap();
+        let src_dir = self.dir().join("src");
+        let strip_components = self.strip_components;
+
+        let mut prep = self.ctx.prep("Extract");
+        prep.declare_input_path(archive.clone()).unwrap();
+        prep.declare_input("value", "src_dir", &src_dir);
+        prep.declare_input("value", "strip_components", &strip_components);
+
+        prep.exec(proc(exec) {
+            // Start over, so files dropped from a new version don't linger.
+            if src_dir.is_dir() {
+                fs::rmdir_recursive(&src_dir).unwrap();
+            }
+            fs::mkdir_recursive(&src_dir, io::UserDir).unwrap();
+
+            let args = vec!(
+                ~"-xf", archive.as_str().unwrap().to_owned(),
+                ~"-C", src_dir.as_str().unwrap().to_owned(),
+                format!("--strip-components={}", strip_components));
+
+            exec.process_builder(exe.as_str().unwrap(), args.as_slice())
+                .description("extract")
+                .msg(src_dir.display())
+                .msg("<-")
+                .msg(archive.display())
+                .run_or_fail();
+
+            exec.discover_output_path("src_dir", &src_dir);
+
+            src_dir
+        })
+    }
+
+    pub fn run(self) -> Library {
+        let src_dir = self.extract().unwrap();
+        let ctx = self.ctx.with_root(self.dir().join("build"));
+
+        (self.recipe)(ctx, src_dir)
+    }
+
+    fn dir(&self) -> Path {
+        os::make_absolute(&self.ctx.root.join("third_party").join(self.name.as_slice()))
+    }
+
+    fn archive_name(&self) -> ~str {
+        let name = self.url.split('/').last().unwrap_or(self.name.as_slice());
+        name.split('?').next().unwrap().to_owned()
+    }
+}
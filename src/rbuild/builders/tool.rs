@@ -0,0 +1,153 @@
+//! What every command-line builder has in common. A builder keeps its
+//! program, destination, sources and flags in a `ToolBase`, gets the usual
+//! setters from `tool_methods!`, and implements `Tool` by saying how its
+//! settings turn into a `Call`:
+//!
+//! ```ignore
+//! #[deriving(Clone)]
+//! pub struct Strip {
+//!     base: ToolBase,
+//! }
+//!
+//! tool_methods!(Strip)
+//!
+//! impl Tool for Strip {
+//!     fn to_call(&self) -> Call {
+//!         let mut call = self.base.call();
+//!         call.push_str(~"-o");
+//!         call.push_output_path(self.base.dst());
+//!         self.base.push_srcs(&mut call);
+//!         call
+//!     }
+//! }
+//! ```
+
+use context::{Context, Call};
+use into_future::IntoFuture;
+use path_util;
+use shared_future::SharedFuture;
+
+#[deriving(Clone)]
+pub struct ToolBase {
+    pub ctx: Context,
+    pub exe: Path,
+    pub dst_prefix: Option<&'static str>,
+    pub dst_suffix: Option<&'static str>,
+    pub dst: Option<Path>,
+    pub srcs: Vec<Path>,
+    /// Sources that aren't known until another step has run.
+    pub pending_srcs: Vec<SharedFuture<Vec<Path>>>,
+    pub flags: Vec<~str>,
+}
+
+impl ToolBase {
+    pub fn new<T: IntoFuture<Path>>(ctx: Context, exe: T) -> ToolBase {
+        ToolBase {
+            ctx: ctx,
+            exe: exe.into_future().unwrap(),
+            dst_prefix: None,
+            dst_suffix: None,
+            dst: None,
+            srcs: Vec::new(),
+            pending_srcs: Vec::new(),
+            flags: Vec::new(),
+        }
+    }
+
+    /// The destination with its prefix and suffix, if one was set.
+    pub fn dst_path(&self) -> Option<Path> {
+        self.dst.as_ref().map(|dst| {
+            path_util::add_prefix_suffix(dst.clone(), self.dst_prefix, self.dst_suffix)
+        })
+    }
+
+    /// Like `dst_path`, for tools that can't run without a destination.
+    pub fn dst(&self) -> Path {
+        match self.dst_path() {
+            Some(dst) => dst,
+            None => fail!("{} has no destination", self.exe.display()),
+        }
+    }
+
+    /// Every source, waiting for the pending ones.
+    pub fn all_srcs(&self) -> Vec<Path> {
+        let mut srcs = self.srcs.clone();
+
+        for pending in self.pending_srcs.iter() {
+            srcs.push_all_move(pending.get());
+        }
+
+        srcs
+    }
+
+    /// A call of the program, without any arguments yet.
+    pub fn call(&self) -> Call {
+        Call::new(self.exe.clone()).unwrap()
+    }
+
+    pub fn push_flags(&self, call: &mut Call) {
+        for flag in self.flags.iter() {
+            call.push_str(flag.clone());
+        }
+    }
+
+    pub fn push_srcs(&self, call: &mut Call) {
+        for src in self.all_srcs().move_iter() {
+            call.push_input_path(src).unwrap();
+        }
+    }
+}
+
+/// A builder that runs a single command.
+pub trait Tool: IntoFuture<Path> {
+    /// The command this step runs, which is also its cache key.
+    fn to_call(&self) -> Call;
+}
+
+/// Implements the setters shared by every builder with a `base: ToolBase`
+/// field, along with `run`. `IntoPath` and `IntoFuture` have to be in scope
+/// where it's used.
+macro_rules! tool_methods(
+    ($tool:ident) => (
+        impl $tool {
+            pub fn set_dst_prefix(mut self, dst_prefix: &'static str) -> $tool {
+                self.base.dst_prefix = Some(dst_prefix);
+                self
+            }
+
+            pub fn set_dst_suffix(mut self, dst_suffix: &'static str) -> $tool {
+                self.base.dst_suffix = Some(dst_suffix);
+                self
+            }
+
+            pub fn set_dst<T: ::into_path::IntoPath>(mut self, dst: T) -> $tool {
+                self.base.dst = Some(self.base.ctx.output_path(dst.into_path()));
+                self
+            }
+
+            pub fn add_src<T: ::into_future::IntoFuture<Path>>(mut self, src: T) -> $tool {
+                self.base.srcs.push(src.into_future().unwrap());
+                self
+            }
+
+            /// Add a set of sources that is only known once another step
+            /// has run, such as the output of a code generator. The set can be
+            /// passed on before it's known, but `into_future` and `run` block
+            /// until it is, since the sources are part of the call.
+            pub fn add_srcs<T: ::into_future::IntoFuture<Vec<Path>>>(mut self, srcs: T) -> $tool {
+                self.base.pending_srcs.push(
+                    ::shared_future::SharedFuture::new(srcs.into_future()));
+                self
+            }
+
+            pub fn add_flag<S: Str>(mut self, flag: S) -> $tool {
+                self.base.flags.push(flag.into_owned());
+                self
+            }
+
+            pub fn run(self) -> Path {
+                self.into_future().unwrap()
+            }
+        }
+    )
+)
@@ -0,0 +1,215 @@
+//! Maps input files one by one to outputs, either through a command or a
+//! function, for asset pipelines that don't warrant a builder of their own.
+//! Each file is its own step, so only the files that changed are redone:
+//!
+//! ```ignore
+//! let spirv = Transform::new_with(ctx.clone(), glslang)
+//!     .add_arg("-V").add_arg("$in").add_arg("-o").add_arg("$out")
+//!     .set_extension("spv")
+//!     .transform_glob("shaders", "*.glsl");
+//! ```
+
+use std::io;
+use std::io::{fs, IoResult};
+use sync::Future;
+
+use context::{Context, Call};
+use into_path::IntoPath;
+use into_future::IntoFuture;
+use path_util;
+
+/// Writes the output of a function transform, given the input and where the
+/// output goes.
+pub type TransformFn = fn(src: &Path, dst: &Path) -> IoResult<()>;
+
+#[deriving(Clone)]
+pub struct Transform {
+    ctx: Context,
+    exe: Option<Path>,
+    args: Vec<~str>,
+    function: Option<(~str, TransformFn)>,
+    extension: Option<~str>,
+    dst_dir: Option<Path>,
+    src: Option<Path>,
+    dst: Option<Path>,
+}
+
+impl Transform {
+    /// Transform files by running `exe` with the arguments given by
+    /// `add_arg`.
+    pub fn new_with<T: IntoFuture<Path>>(ctx: Context, exe: T) -> Transform {
+        Transform {
+            ctx: ctx,
+            exe: Some(exe.into_future().unwrap()),
+            args: Vec::new(),
+            function: None,
+            extension: None,
+            dst_dir: None,
+            src: None,
+            dst: None,
+        }
+    }
+
+    /// Transform files by calling `function`. Since a function can't be
+    /// compared between runs, `name` stands for it in the cache key, and has
+    /// to change whenever the function does.
+    pub fn new_fn<T: Str>(ctx: Context, name: T, function: TransformFn) -> Transform {
+        Transform {
+            ctx: ctx,
+            exe: None,
+            args: Vec::new(),
+            function: Some((name.into_owned(), function)),
+            extension: None,
+            dst_dir: None,
+            src: None,
+            dst: None,
+        }
+    }
+
+    /// Add an argument to the command, where "$in" stands for the input and
+    /// "$out" for the output.
+    pub fn add_arg<S: Str>(mut self, arg: S) -> Transform {
+        self.args.push(arg.into_owned());
+        self
+    }
+
+    /// Name the outputs after the inputs with their extension replaced by
+    /// `extension`.
+    pub fn set_extension<S: Str>(mut self, extension: S) -> Transform {
+        self.extension = Some(extension.into_owned());
+        self
+    }
+
+    /// Set the directory the outputs go in. Defaults to the build root.
+    pub fn set_dst_dir<T: IntoPath>(mut self, dst_dir: T) -> Transform {
+        self.dst_dir = Some(self.ctx.output_path(dst_dir.into_path()));
+        self
+    }
+
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Transform {
+        self.src = Some(src.into_future().unwrap());
+        self
+    }
+
+    pub fn set_dst<T: IntoPath>(mut self, dst: T) -> Transform {
+        self.dst = Some(self.ctx.output_path(dst.into_path()));
+        self
+    }
+
+    /// Transform `src` into the output directory.
+    pub fn transform<T: IntoFuture<Path>>(&self, src: T) -> Transform {
+        let src = src.into_future().unwrap();
+        let dst = self.dst_path(&Path::new(src.filename().unwrap()));
+
+        self.clone()
+            .set_src(src)
+            .set_dst(dst)
+    }
+
+    /// Transform every file under `dir` whose path relative to `dir`
+    /// matches the glob `pattern`, e.g. "*.glsl". The outputs keep their
+    /// place in the tree.
+    pub fn transform_glob<T: IntoPath>(&self, dir: T, pattern: &str) -> Vec<Future<Path>> {
+        let dir = dir.into_path();
+
+        let mut srcs: Vec<Path> = fs::walk_dir(&dir).unwrap().filter(|path| {
+            path.is_file() && match path.path_relative_from(&dir) {
+                Some(rel) => path_util::glob_matches(pattern, rel.as_str().unwrap()),
+                None => false,
+            }
+        }).collect();
+        srcs.sort_by(|a, b| a.as_vec().cmp(&b.as_vec()));
+
+        srcs.move_iter().map(|src| {
+            let dst = self.dst_path(&src.path_relative_from(&dir).unwrap());
+
+            self.clone()
+                .set_src(src)
+                .set_dst(dst)
+                .into_future()
+        }).collect()
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+
+    /// Where the input at `rel` in its tree goes.
+    fn dst_path(&self, rel: &Path) -> Path {
+        let dst = match self.dst_dir {
+            Some(ref dst_dir) => dst_dir.join(rel),
+            None => self.ctx.root.join(rel),
+        };
+
+        match self.extension {
+            Some(ref extension) => dst.with_extension(extension.as_slice()),
+            None => dst,
+        }
+    }
+}
+
+impl IntoFuture<Path> for Transform {
+    fn into_future(self) -> Future<Path> {
+        let Transform { ctx, exe, args, function, src, dst, .. } = self;
+
+        assert!(src.is_some());
+        assert!(dst.is_some());
+        let src = src.unwrap();
+        let dst = dst.unwrap();
+
+        match (exe, function) {
+            (Some(exe), _) => {
+                let mut call = Call::new(exe.clone()).unwrap();
+                for arg in args.move_iter() {
+                    match arg.as_slice() {
+                        "$in" => { call.push_input_path(src.clone()).unwrap(); }
+                        "$out" => { call.push_output_path(dst.clone()); }
+                        _ => { call.push_str(arg); }
+                    }
+                }
+
+                let mut prep = ctx.prep("Call");
+                prep.declare_call(&call);
+
+                prep.exec(proc(exec) {
+                    let (prog, args) = call.tmp_cmd().unwrap();
+
+                    fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+                    exec.process_builder(prog, args.as_slice())
+                        .description(exe.filename_display())
+                        .msg(dst.display())
+                        .msg("<-")
+                        .msg(src.display())
+                        .run_or_fail();
+                    call.rename_outputs().unwrap();
+
+                    dst
+                })
+            }
+            (None, Some((name, function))) => {
+                let mut prep = ctx.prep("Transform");
+                prep.declare_input("value", "function", &name);
+                prep.declare_input("value", "dst", &dst);
+                prep.declare_input_path(src.clone()).unwrap();
+
+                prep.exec(proc(exec) {
+                    fs::mkdir_recursive(&dst.dir_path(), io::UserDir).unwrap();
+
+                    // Write to the side, so a failure doesn't leave a
+                    // partial output behind.
+                    let tmp = dst.with_filename(format!("{}.tmp", dst.filename_str().unwrap()));
+                    match function(&src, &tmp).and_then(|()| fs::rename(&tmp, &dst)) {
+                        Ok(()) => { }
+                        Err(err) => fail!("{} failed on {}: {}", name, src.display(), err),
+                    }
+
+                    exec.discover_output_path("output", &dst);
+
+                    dst
+                })
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::ascii::StrAsciiExt;
+use std::io::{fs, File};
+use sync::Future;
+
+use context::Context;
+use into_future::IntoFuture;
+use sha256;
+use sha512;
+
+#[deriving(Clone, Eq, Encodable, Decodable)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn name(&self) -> &'static str {
+        match *self {
+            Sha256 => "sha256",
+            Sha512 => "sha512",
+        }
+    }
+
+    fn hex_digest(&self, bytes: &[u8]) -> ~str {
+        match *self {
+            Sha256 => sha256::hex_digest(bytes),
+            Sha512 => sha512::hex_digest(bytes),
+        }
+    }
+}
+
+/// Checks a file, such as a vendored download, against the digest it's
+/// published with, and fails the build if they differ. The future resolves
+/// to the file once it has been checked, so it can stand in for the file in
+/// later steps. The check is only redone when the file changes. A file in
+/// the build root that doesn't match, such as a corrupt download, is
+/// removed, so the step that wrote it runs again next time.
+#[deriving(Clone)]
+pub struct Verify {
+    ctx: Context,
+    src: Option<Path>,
+    algorithm: Algorithm,
+    expected: ~str,
+}
+
+impl Verify {
+    pub fn new<T: Str>(ctx: Context, algorithm: Algorithm, expected: T) -> Verify {
+        Verify {
+            ctx: ctx,
+            src: None,
+            algorithm: algorithm,
+            // Digests are published in either case.
+            expected: expected.as_slice().trim().to_ascii_lower(),
+        }
+    }
+
+    pub fn sha256<T: Str>(ctx: Context, expected: T) -> Verify {
+        Verify::new(ctx, Sha256, expected)
+    }
+
+    pub fn sha512<T: Str>(ctx: Context, expected: T) -> Verify {
+        Verify::new(ctx, Sha512, expected)
+    }
+
+    pub fn set_src<T: IntoFuture<Path>>(mut self, src: T) -> Verify {
+        self.src = Some(src.into_future().unwrap());
+        self
+    }
+
+    pub fn run(self) -> Path {
+        self.into_future().unwrap()
+    }
+}
+
+impl IntoFuture<Path> for Verify {
+    fn into_future(self) -> Future<Path> {
+        let Verify { ctx, src, algorithm, expected } = self;
+
+        assert!(src.is_some());
+        let src = src.unwrap();
+
+        let generated = ctx.root.is_ancestor_of(&src);
+
+        let mut prep = ctx.prep("Verify");
+        prep.declare_input_path(src.clone()).unwrap();
+        prep.declare_input("value", "algorithm", &algorithm);
+        prep.declare_input("value", "expected", &expected);
+
+        prep.exec(proc(_exec) {
+            let bytes = match File::open(&src).read_to_end() {
+                Ok(bytes) => bytes,
+                Err(err) => fail!("failed to read {}: {}", src.display(), err),
+            };
+            let actual = algorithm.hex_digest(bytes.as_slice());
+
+            if actual != expected {
+                if generated {
+                    let _ = fs::unlink(&src);
+                }
+                fail!("{} checksum mismatch for {}:\n- expected {}\n+ actual   {}",
+                      algorithm.name(),
+                      src.display(),
+                      expected,
+                      actual);
+            }
+
+            src
+        })
+    }
+}
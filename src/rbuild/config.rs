@@ -0,0 +1,67 @@
+//! Typed settings shared by a build script, its builders and their execs,
+//! e.g. `ctx.cfg.set("verbose", &true)` before building and
+//! `ctx.cfg.get::<bool>("verbose")` while building. Steps whose output
+//! depends on a setting declare it with `Prep::declare_config`, so they
+//! rerun when it changes.
+
+use std::io::{IoError, MemWriter};
+use std::str;
+use collections::TreeMap;
+use serialize::json;
+use serialize::{Encodable, Decodable};
+use sync::{Arc, RWLock};
+
+#[deriving(Clone)]
+pub struct Config {
+    /// The values of the settings, as JSON.
+    values: Arc<RWLock<TreeMap<~str, ~str>>>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            values: Arc::new(RWLock::new(TreeMap::new())),
+        }
+    }
+
+    /// Set `name` to `value`, replacing any previous value. Every clone of
+    /// the context sees the change.
+    pub fn set<'a, T: Encodable<json::Encoder<'a>, IoError>>(&self, name: &str, value: &T) {
+        let mut writer = MemWriter::new();
+        {
+            let mut encoder = json::Encoder::new(&mut writer);
+            value.encode(&mut encoder).unwrap();
+        }
+        let value = str::from_utf8(writer.unwrap().as_slice()).unwrap().to_owned();
+
+        self.values.write().insert(name.to_owned(), value);
+    }
+
+    pub fn unset(&self, name: &str) {
+        self.values.write().remove(&name.to_owned());
+    }
+
+    /// Returns the value of `name`, or `None` if it isn't set. Fails if it
+    /// was set to a value of another type.
+    pub fn get<T: Decodable<json::Decoder, json::Error>>(&self, name: &str) -> Option<T> {
+        let value = match self.json(name) {
+            Some(value) => value,
+            None => { return None; }
+        };
+
+        let mut decoder = json::Decoder::new(json::from_str(value).unwrap());
+        match Decodable::decode(&mut decoder) {
+            Ok(value) => Some(value),
+            Err(err) => fail!("config setting `{}` has the wrong type: {}", name, err),
+        }
+    }
+
+    pub fn get_or<T: Decodable<json::Decoder, json::Error>>(&self, name: &str, default: T) -> T {
+        self.get(name).unwrap_or(default)
+    }
+
+    /// The value of `name` as JSON, which is how it's recorded in the cache.
+    pub fn json(&self, name: &str) -> Option<~str> {
+        self.values.read().find(&name.to_owned()).map(|value| value.clone())
+    }
+}
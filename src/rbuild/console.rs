@@ -0,0 +1,46 @@
+//! What the console rbuild prints to can do. When stdout isn't a terminal,
+//! as in a CI log, escape codes and lines that redraw themselves with a
+//! carriage return only make the output harder to read.
+
+use std::os;
+use libc;
+use term::color::Color;
+
+/// Whether to color the output.
+#[deriving(Clone, Eq, Show)]
+pub enum ColorChoice {
+    Always,
+    /// Color only when stdout is a terminal that supports it. This is the
+    /// default.
+    Auto,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn enabled(&self) -> bool {
+        match *self {
+            Always => true,
+            Never => false,
+            Auto => {
+                stdout_is_tty()
+                    && os::getenv("NO_COLOR").is_none()
+                    && os::getenv("TERM").map_or(true, |term| term.as_slice() != "dumb")
+            }
+        }
+    }
+}
+
+/// Returns true if stdout is a terminal.
+pub fn stdout_is_tty() -> bool {
+    unsafe { libc::isatty(1) != 0 }
+}
+
+/// Returns `s` wrapped in the escape codes that show it in `color`.
+pub fn paint(s: &str, color: Color) -> ~str {
+    // The bright colors follow the eight normal ones.
+    if color < 8 {
+        format!("\x1b[3{}m{}\x1b[0m", color, s)
+    } else {
+        format!("\x1b[9{}m{}\x1b[0m", color - 8, s)
+    }
+}
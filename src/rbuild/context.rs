@@ -1,23 +1,127 @@
+use std::io;
 use std::io::{File, IoError, IoResult};
-use std::io::MemWriter;
+use std::io::{MemWriter, Process, ProcessConfig};
+use std::io::fs;
+use std::io::process::PleaseExitSignal;
+use std::io::signal::{Listener, Interrupt};
+use std::os;
+use std::io::timer;
 use std::str;
+use std::task;
+use std::cmp;
 use std::hash;
+use std::mem;
 use std::num::ToStrRadix;
+use std::sync::atomics::{AtomicBool, AtomicUint, INIT_ATOMIC_UINT, SeqCst};
 use collections::TreeMap;
+use libc;
 use serialize::json;
 use serialize::{Encodable, Decodable};
-use sync::Future;
+use sync::{Arc, Future, Mutex};
+use time;
 
+use config::Config;
+use console::{ColorChoice, Auto};
+use executor::{Executor, LocalExecutor, ProcessGroups, SharedExecutor};
+use graph::Graph;
+use into_future::IntoFuture;
 use into_path::IntoPath;
+use jobserver::JobServer;
+use path_util::{FIND_PROGRAM, PathNormalizer};
 use process_builder::ProcessBuilder;
+use report;
+use scheduler::Scheduler;
+use sha256;
+use shared_future::SharedFuture;
+use shell;
+use tool_version::VersionReq;
 use workcache;
+use workcache::RunStats;
 
 #[deriving(Clone)]
 pub struct Context {
     ctx: ::workcache::Context,
     pub root: Path,
+    /// Settings for builders and execs. See `config`.
+    pub cfg: Config,
+    graph: Arc<Mutex<Graph>>,
+    /// Every input path declared or discovered, for watch mode.
+    inputs: Arc<Mutex<Vec<Path>>>,
+    executor: SharedExecutor,
+    process_groups: Arc<ProcessGroups>,
+    /// Normalizes the paths that go into cache keys.
+    paths: PathNormalizer,
+    verbosity: uint,
+    quiet: bool,
+    color: ColorChoice,
+    compiler_launcher: Option<Path>,
+    hardening: bool,
+    offline: bool,
+    deterministic: bool,
+    /// Version constraints on tools, by program name.
+    tool_versions: TreeMap<~str, ~str>,
+    /// Directories searched for programs before `PATH`.
+    program_dirs: Vec<Path>,
+    /// The default search directories of the compilers in use, whose files
+    /// belong to the system rather than the project.
+    system_dirs: Arc<Mutex<Vec<Path>>>,
+    /// How inputs in the system directories are checked for changes.
+    system_hash_policy: HashPolicy,
+    /// How other inputs outside the project are checked for changes.
+    external_hash_policy: HashPolicy,
+    jobserver: Option<Arc<JobServer>>,
+    scheduler: Arc<Scheduler>,
+    output_layout: OutputLayout,
+    /// Whether each step's inputs are checked before the step starts.
+    validate: bool,
+    /// What `path_util::find_program` couldn't find, reported by `validate`.
+    missing_programs: Arc<Mutex<Vec<~str>>>,
+    /// Set once the first step has been validated along with everything
+    /// before it.
+    validated: Arc<AtomicBool>,
+    /// Where this run started, to measure it when it's done.
+    run: Arc<Mutex<RunStart>>,
 }
 
+/// The clock and counters at the start of a run.
+struct RunStart {
+    /// From `time::precise_time_ns`.
+    time: u64,
+    /// In seconds since the epoch.
+    clock: i64,
+    bytes_hashed: uint,
+}
+
+impl RunStart {
+    fn now() -> RunStart {
+        RunStart {
+            time: time::precise_time_ns(),
+            clock: time::get_time().sec,
+            bytes_hashed: unsafe { BYTES_HASHED.load(SeqCst) },
+        }
+    }
+}
+
+/// How many bytes `digest_path` has read, in every context.
+static mut BYTES_HASHED: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Where builders put the outputs they're given relative paths for.
+#[deriving(Clone)]
+pub enum OutputLayout {
+    /// Keep the relative path under the build root, so `src/foo.o` is
+    /// written to `build/src/foo.o`. This is the default.
+    MirrorOutputs,
+    /// Put every output directly in the build root, so `src/foo.o` is
+    /// written to `build/foo.o`.
+    FlatOutputs,
+    /// Let a function pick the path, given the build root and the path the
+    /// builder was given.
+    CustomOutputs(fn(&Path, &Path) -> Path),
+}
+
+/// How often watch mode polls the inputs for changes, in milliseconds.
+static WATCH_INTERVAL: u64 = 500;
+
 impl Context {
     pub fn new() -> Context {
         Context::new_in_path("build")
@@ -29,24 +133,671 @@ impl Context {
 
         let db = ::workcache::Database::new(db_path);
         let logger = ::workcache::Logger::new();
-        let cfg = TreeMap::new();
 
         let mut freshness = TreeMap::new();
         freshness.insert(~"Call", call_is_fresh);
+        freshness.insert(~"Config", value_is_fresh);
+        freshness.insert(~"EnvVar", env_var_is_fresh);
+        freshness.insert(~"GitRev", git_rev_is_fresh);
+        freshness.insert(~"InputDir", input_dir_is_fresh);
         freshness.insert(~"InputPath", input_path_is_fresh);
+        freshness.insert(~"Missing", missing_is_fresh);
+        freshness.insert(~"OrderOnly", order_only_is_fresh);
         freshness.insert(~"OutputPath", output_path_is_fresh);
         freshness.insert(~"value", value_is_fresh);
 
-        let ctx = workcache::Context::new_with_freshness(db, logger, cfg, freshness);
+        let ctx = workcache::Context::new_with_freshness(db, logger, freshness);
 
         Context {
             ctx: ctx,
             root: root,
+            cfg: Config::new(),
+            graph: Arc::new(Mutex::new(Graph::new())),
+            inputs: Arc::new(Mutex::new(Vec::new())),
+            executor: Arc::new(~LocalExecutor as ~Executor:Send+Share),
+            process_groups: Arc::new(ProcessGroups::new()),
+            paths: PathNormalizer::new(os::getcwd()),
+            verbosity: 0,
+            quiet: false,
+            color: Auto,
+            compiler_launcher: None,
+            hardening: false,
+            offline: false,
+            deterministic: false,
+            tool_versions: TreeMap::new(),
+            program_dirs: Vec::new(),
+            system_dirs: Arc::new(Mutex::new(Vec::new())),
+            system_hash_policy: MtimeOnly,
+            external_hash_policy: FullHash,
+            jobserver: JobServer::from_env().map(|jobserver| Arc::new(jobserver)),
+            scheduler: Arc::new(Scheduler::new()),
+            output_layout: MirrorOutputs,
+            validate: true,
+            missing_programs: Arc::new(Mutex::new(Vec::new())),
+            validated: Arc::new(AtomicBool::new(false)),
+            run: Arc::new(Mutex::new(RunStart::now())),
+        }
+    }
+
+    /// Returns a context that shares this one's database, settings and job
+    /// limits, but writes its outputs under `root`.
+    pub fn with_root<T: IntoPath>(&self, root: T) -> Context {
+        let mut ctx = self.clone();
+        ctx.root = root.into_path();
+        ctx
+    }
+
+    /// Choose where outputs go. See `OutputLayout`.
+    pub fn set_output_layout(mut self, output_layout: OutputLayout) -> Context {
+        self.output_layout = output_layout;
+        self
+    }
+
+    /// Returns where an output given as `path` should be written. Paths that
+    /// are absolute or already in the build root are left alone.
+    pub fn output_path(&self, path: Path) -> Path {
+        if path.is_absolute() || self.root.is_ancestor_of(&path) {
+            return path;
+        }
+
+        match self.output_layout {
+            MirrorOutputs => self.root.join(path),
+            FlatOutputs => self.root.join(path.filename().unwrap()),
+            CustomOutputs(layout) => layout(&self.root, &path),
+        }
+    }
+
+    /// Set the default compiler launcher (e.g. `ccache`) for compilers
+    /// created from this context.
+    pub fn set_compiler_launcher<T: IntoPath>(mut self, launcher: T) -> Context {
+        self.compiler_launcher = Some(launcher.into_path());
+        self
+    }
+
+    pub fn compiler_launcher(&self) -> Option<Path> {
+        self.compiler_launcher.clone()
+    }
+
+    /// Enable the hardening preset for compilers created from this context.
+    /// See `Gcc::set_hardening`.
+    pub fn set_hardening(mut self, hardening: bool) -> Context {
+        self.hardening = hardening;
+        self
+    }
+
+    pub fn hardening(&self) -> bool {
+        self.hardening
+    }
+
+    /// Forbid network access. Any step that would have to download something
+    /// fails right away, rather than after timing out.
+    pub fn set_offline(mut self, offline: bool) -> Context {
+        self.offline = offline;
+        self
+    }
+
+    pub fn offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Make the build reproducible: archives get no timestamps or owners,
+    /// every compile gets a fixed random seed, the working directory is
+    /// stripped from file names, and processes run with a fixed
+    /// `SOURCE_DATE_EPOCH` (0, unless it is already set).
+    pub fn set_deterministic(mut self, deterministic: bool) -> Context {
+        self.deterministic = deterministic;
+        self
+    }
+
+    pub fn deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    /// Require the program `name` to have a version matching `req`, such as
+    /// ">=9". `find_program` checks the version of the program it finds, so
+    /// a mismatch fails the build up front rather than with confusing errors
+    /// halfway through.
+    pub fn require_tool_version(mut self, name: &str, req: &str) -> Context {
+        if VersionReq::parse(req).is_none() {
+            fail!("invalid version requirement for {}: {}", name, req);
+        }
+
+        self.tool_versions.insert(name.to_owned(), req.to_owned());
+        self
+    }
+
+    pub fn tool_version_requirement(&self, name: &str) -> Option<~str> {
+        self.tool_versions.find_equiv(&name).map(|req| req.clone())
+    }
+
+    /// Search `dir` for programs before `PATH`, e.g. for a toolchain that
+    /// isn't installed system-wide.
+    pub fn add_program_dir<T: IntoPath>(mut self, dir: T) -> Context {
+        self.program_dirs.push(dir.into_path());
+        self
+    }
+
+    pub fn program_dirs(&self) -> Vec<Path> {
+        self.program_dirs.clone()
+    }
+
+    /// Treat the files under `dir` as part of the system, such as a
+    /// compiler's default include directory.
+    pub fn add_system_dir(&self, dir: Path) {
+        let mut system_dirs = self.system_dirs.lock();
+        if !system_dirs.contains(&dir) {
+            system_dirs.push(dir);
+        }
+    }
+
+    pub fn system_dirs(&self) -> Vec<Path> {
+        self.system_dirs.lock().clone()
+    }
+
+    /// Whether `path` is under one of the system directories.
+    pub fn is_system_path(&self, path: &Path) -> bool {
+        self.system_dirs.lock().iter().any(|dir| dir.is_ancestor_of(path))
+    }
+
+    /// How to check inputs in the system directories for changes. Defaults
+    /// to `MtimeOnly`, since hashing every system header a compile includes
+    /// makes null builds slow.
+    pub fn set_system_hash_policy(mut self, policy: HashPolicy) -> Context {
+        self.system_hash_policy = policy;
+        self
+    }
+
+    /// How to check other inputs outside the project for changes. Defaults
+    /// to `FullHash`.
+    pub fn set_external_hash_policy(mut self, policy: HashPolicy) -> Context {
+        self.external_hash_policy = policy;
+        self
+    }
+
+    /// The policy for the input `path`, as normalized by the context. Paths
+    /// in the project are always hashed.
+    fn hash_policy(&self, path: &Path) -> HashPolicy {
+        if !path.is_absolute() {
+            FullHash
+        } else if self.is_system_path(path) {
+            self.system_hash_policy
+        } else {
+            self.external_hash_policy
+        }
+    }
+
+    /// Don't start new commands while the load average is at or above
+    /// `max_load`, like `make -l`, so builds on shared machines leave room
+    /// for everyone else. Where there's no load average, as on Windows, it
+    /// has no effect. The limit is shared by every clone of the context.
+    pub fn set_max_load(&self, max_load: f64) {
+        self.scheduler.set_max_load(Some(max_load));
+    }
+
+    /// Limit the total memory weight of the commands running at once, so
+    /// that e.g. with a budget of 8 and links weighing 4, at most two links
+    /// run together whatever the job limit. Ordinary commands weigh 1. The
+    /// budget is shared by every clone of the context.
+    pub fn set_memory_budget(&self, memory_budget: uint) {
+        self.scheduler.set_memory_budget(Some(memory_budget));
+    }
+
+    /// Run at most `jobs` commands at once, counting the jobs of any makes
+    /// run by the build where jobservers are supported. When rbuild itself
+    /// runs under a make with a jobserver, that make's limit applies
+    /// instead. If the jobserver can't be created, only rbuild's own
+    /// commands are limited.
+    pub fn set_jobs(mut self, jobs: uint) -> Context {
+        self.scheduler.set_max_jobs(Some(jobs));
+        self.ctx.set_freshness_jobs(jobs);
+
+        if self.jobserver.is_none() && JobServer::is_supported() {
+            match JobServer::new(jobs) {
+                Ok(jobserver) => { self.jobserver = Some(Arc::new(jobserver)); }
+                Err(err) => {
+                    self.ctx.logger.error(format!(
+                        "warning: could not create a jobserver, so makes run by the build \
+                         aren't limited: {}", err));
+                }
+            }
+        }
+        self
+    }
+
+    /// Whether to check that the programs the builders need were found, and
+    /// that the inputs of each step exist or are produced by another step,
+    /// before the step starts. Everything known when the first step is
+    /// declared is checked at once. On by default.
+    pub fn set_validate(mut self, validate: bool) -> Context {
+        self.validate = validate;
+        self
+    }
+
+    /// Check that every program the builders looked up was found, and that
+    /// every input path the steps declared so far depend on exists, unless
+    /// another step produces it. Every problem is returned at once, rather
+    /// than the build stopping at the first one halfway through.
+    pub fn validate(&self) -> Result<(), Vec<~str>> {
+        let graph = self.graph.lock();
+        let mut problems = Vec::new();
+
+        for program in self.missing_programs.lock().iter() {
+            problems.push(format!("missing program: {}", program));
+        }
+
+        for path in self.inputs.lock().iter() {
+            if graph.producer(path).is_none() && !path.exists() {
+                problems.push(format!("missing input: {}", path.display()));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Whether a program that isn't found should be recorded for `validate`
+    /// instead of failing right away, which is until the first step is
+    /// declared.
+    pub fn defers_missing_programs(&self) -> bool {
+        self.validate && !self.validated.load(SeqCst)
+    }
+
+    /// Record a program that `path_util::find_program` couldn't find, for
+    /// `validate` to report.
+    pub fn record_missing_program(&self, msg: ~str) {
+        self.missing_programs.lock().push(msg);
+    }
+
+    /// Fails with every one of `inputs` that's missing and that no step
+    /// produces. Producers are declared before their consumers, so this is
+    /// checked as a step is declared, before it or anything after it runs.
+    fn validate_inputs(&self, inputs: &[Path]) {
+        let problems: Vec<~str> = {
+            let graph = self.graph.lock();
+            inputs.iter()
+                .filter(|path| graph.producer(*path).is_none() && !path.exists())
+                .map(|path| format!("missing input: {}", path.display()))
+                .collect()
+        };
+
+        if !problems.is_empty() {
+            fail!("the build is invalid:\n  {}", problems.connect("\n  "));
+        }
+    }
+
+    /// Run every command through `executor`, e.g. a `RemoteExecutor` to
+    /// spread compiles over several machines. Commands run as local
+    /// processes by default.
+    pub fn set_executor<E: Executor>(mut self, executor: E) -> Context {
+        self.executor = Arc::new(~executor as ~Executor:Send+Share);
+        self
+    }
+
+    /// How much to print about the commands that run. At
+    /// `process_builder::STREAM_VERBOSITY` and above, their output is shown
+    /// as it's written.
+    pub fn set_verbosity(mut self, verbosity: uint) -> Context {
+        self.ctx.logger.set_verbosity(verbosity);
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Don't print a line for each command, only the failures as they
+    /// happen. Call `summary` at the end of the build to print the totals.
+    pub fn set_quiet(mut self, quiet: bool) -> Context {
+        self.ctx.logger.set_quiet(quiet);
+        self.quiet = quiet;
+        self
+    }
+
+    /// Where to report what the build is doing, rather than printing to
+    /// stdout directly.
+    pub fn logger<'a>(&'a self) -> &'a ::workcache::Logger {
+        &*self.ctx.logger
+    }
+
+    /// Whether to color the output. By default it's only colored when
+    /// stdout is a terminal.
+    pub fn set_color(mut self, color: ColorChoice) -> Context {
+        self.color = color;
+        self
+    }
+
+    pub fn use_color(&self) -> bool {
+        self.color.enabled()
+    }
+
+    /// Keep building independent targets after a command fails. Failures
+    /// are collected and listed by `report_failures`.
+    pub fn set_keep_going(mut self, keep_going: bool) -> Context {
+        self.ctx.set_keep_going(keep_going);
+        self
+    }
+
+    /// Print why each step that runs couldn't be taken from the cache: which
+    /// input changed, with its old and new value, or which output is
+    /// missing.
+    pub fn set_explain(mut self, explain: bool) -> Context {
+        self.ctx.set_explain(explain);
+        self
+    }
+
+    /// Build a target, returning `None` instead of failing if it (or
+    /// anything it depends on) failed while in keep-going mode.
+    pub fn try_build<T: Send>(&self, blk: proc():Send -> T) -> Option<T> {
+        match task::try(blk) {
+            Ok(value) => Some(value),
+            Err(cause) => {
+                if !self.ctx.keep_going() {
+                    fail!(workcache::failure_msg(&cause));
+                }
+                None
+            }
+        }
+    }
+
+    /// Print a summary of every failed step with its command and output,
+    /// and how many more failed because of them. Returns true if nothing
+    /// failed.
+    pub fn report_failures(&self) -> bool {
+        let failures = self.ctx.failures();
+
+        if failures.is_empty() {
+            return true;
+        }
+
+        let logger = &self.ctx.logger;
+        logger.error(format!("{} failed:", failures.len()));
+        for failure in failures.iter() {
+            logger.error(format!("  {}:", failure.fn_name));
+            for line in failure.msg.lines() {
+                logger.error(format!("  {}", line));
+            }
+        }
+
+        let cascaded = self.ctx.cascaded();
+        if cascaded > 0 {
+            logger.error(format!("{} more didn't run because of them", cascaded));
+        }
+
+        false
+    }
+
+    /// Save the database now instead of when the context is dropped. This
+    /// also records how far each step is from the end of the build, so the
+    /// next build can start the steps on the critical path first, and the
+    /// stats of this run for `stats_report`. Call it once at the end of a
+    /// build.
+    pub fn flush(&self) -> IoResult<()> {
+        let priorities = report::path_lengths(&self.ctx.durations(), &*self.graph.lock());
+        self.ctx.set_priorities(priorities);
+        self.ctx.record_run(self.run_stats());
+
+        self.ctx.flush()
+    }
+
+    /// Run `build`, then keep re-running it whenever one of the inputs it
+    /// used changes on disk. Only the steps whose inputs changed re-execute;
+    /// everything else is a cache hit. This never returns.
+    pub fn watch(&self, build: fn(Context)) {
+        loop {
+            self.ctx.reset_run();
+            *self.run.lock() = RunStart::now();
+            *self.graph.lock() = Graph::new();
+            self.inputs.lock().clear();
+
+            let ctx = self.clone();
+            match task::try(proc() build(ctx)) {
+                Ok(()) => { self.ctx.logger.info("build succeeded, watching for changes"); }
+                Err(_) => { self.ctx.logger.info("build failed, watching for changes"); }
+            }
+
+            self.flush().unwrap();
+
+            // A cache hit doesn't run its proc, so the inputs it discovered
+            // the last time it ran are only known from its record.
+            for event in self.ctx.events().iter().filter(|event| event.cached) {
+                for &(ref kind, _, ref value) in event.discovered_inputs.iter() {
+                    if kind.as_slice() == "InputPath" {
+                        match try_json_decode::<InputPath>(value.as_slice()) {
+                            Some(input) => self.record_input(&input.path),
+                            None => { }
+                        }
+                    }
+                }
+            }
+
+            let inputs = self.inputs.lock().clone();
+            let stamps: Vec<Option<u64>> = inputs.iter().map(modified).collect();
+
+            loop {
+                timer::sleep(WATCH_INTERVAL);
+
+                let changed = inputs.iter().zip(stamps.iter()).any(|(path, stamp)| {
+                    modified(path) != *stamp
+                });
+
+                if changed { break; }
+            }
         }
     }
 
+    /// Handle Ctrl-C until the returned guard is dropped: no new commands
+    /// are started, the running ones are terminated, the cache entries of
+    /// the steps that completed are saved, and the process exits with the
+    /// usual status for an interrupt.
+    pub fn handle_interrupts(&self) -> InterruptGuard {
+        let ctx = self.clone();
+        let (stop_tx, stop_rx) = channel();
+
+        spawn(proc() {
+            let mut listener = Listener::new();
+            listener.register(Interrupt).unwrap();
+
+            select! {
+                _ = stop_rx.recv_opt() => { return; },
+                _ = listener.rx.recv() => { }
+            }
+
+            ctx.ctx.logger.error("interrupted, stopping the running commands");
+
+            ctx.ctx.abort();
+            ctx.scheduler.cancel();
+
+            // The commands run in their own process groups, so they didn't
+            // get the interrupt from the terminal.
+            ctx.process_groups.kill_all(PleaseExitSignal);
+            ctx.scheduler.wait_idle();
+
+            match ctx.flush() {
+                Ok(()) => { }
+                Err(err) => { ctx.ctx.logger.error(format!("could not save the database: {}", err)); }
+            }
+
+            // 128 + SIGINT, like a shell.
+            unsafe { libc::exit(130); }
+        });
+
+        InterruptGuard { stop: stop_tx }
+    }
+
+    fn record_input(&self, path: &Path) {
+        let mut inputs = self.inputs.lock();
+        if !inputs.contains(path) {
+            inputs.push(path.clone());
+        }
+    }
+
+    /// Record the section sizes of `artifact` in the database, returning the
+    /// sizes from the last time it was measured.
+    pub fn record_sizes(&self, artifact: &Path, sizes: TreeMap<~str, u64>) -> Option<TreeMap<~str, u64>> {
+        self.ctx.record_sizes(artifact.as_str().unwrap(), sizes)
+    }
+
+    /// Register a freshness function for a new kind of work, so build
+    /// scripts can declare their own inputs (e.g. "GitRev" or "Url").
+    pub fn register_freshness(&self, kind: &str, freshness: workcache::FreshnessFn) {
+        self.ctx.register_freshness(kind, freshness)
+    }
+
+    /// Write the dependency graph of every call declared so far in graphviz
+    /// dot format, for debugging.
+    pub fn write_graph<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        self.graph.lock().write_dot(w)
+    }
+
+    pub fn dump_graph<T: IntoPath>(&self, path: T) -> IoResult<()> {
+        let mut file = try!(File::create(&path.into_path()));
+        self.write_graph(&mut file)
+    }
+
+    /// Write the commands declared so far as a standalone ninja file, for
+    /// building somewhere the build script can't run, such as a CI system.
+    /// Only steps that run a command are included, and paths are relative
+    /// to the directory the build script ran in. A warning is logged for
+    /// every file in the build root that the exported commands read but
+    /// none of them writes.
+    pub fn export_ninja<T: IntoPath>(&self, path: T) -> IoResult<()> {
+        let mut file = try!(File::create(&path.into_path()));
+        let graph = self.graph.lock();
+        self.warn_unexported(&*graph);
+        graph.write_ninja(&mut file)
+    }
+
+    /// Like `export_ninja`, but writes a makefile.
+    pub fn export_makefile<T: IntoPath>(&self, path: T) -> IoResult<()> {
+        let mut file = try!(File::create(&path.into_path()));
+        let graph = self.graph.lock();
+        self.warn_unexported(&*graph);
+        graph.write_makefile(&mut file)
+    }
+
+    fn warn_unexported(&self, graph: &Graph) {
+        for path in graph.unproduced_inputs(&self.root).iter() {
+            self.ctx.logger.error(format!(
+                "warning: {} is written by a step that doesn't run a command, and isn't exported",
+                path.display()));
+        }
+    }
+
+    /// Write a manifest of the final outputs of the steps declared so far,
+    /// the ones no other step consumes, as JSON: each artifact's path, size
+    /// and SHA-256, and the step that produced it. It's for signing and
+    /// packaging pipelines, so it should be written once the build is done.
+    pub fn write_manifest<T: IntoPath>(&self, path: T) -> IoResult<()> {
+        let mut entries = Vec::new();
+
+        {
+            let graph = self.graph.lock();
+            for (idx, artifact) in graph.final_outputs().move_iter() {
+                let bytes = try!(File::open(&artifact).read_to_end());
+
+                entries.push(ManifestEntry {
+                    path: artifact,
+                    size: bytes.len() as u64,
+                    sha256: sha256::hex_digest(bytes.as_slice()),
+                    target: graph.nodes()[idx].label.clone(),
+                });
+            }
+        }
+
+        let path = path.into_path();
+        try!(fs::mkdir_recursive(&path.dir_path(), io::UserDir));
+
+        let mut file = try!(File::create(&path));
+        let mut encoder = json::Encoder::new(&mut file);
+        entries.encode(&mut encoder)
+    }
+
+    /// Print a profile of this run: cache hit ratio, slowest commands, time
+    /// per tool and the critical path.
+    pub fn report(&self) {
+        let mut stdout = io::stdout();
+        self.write_report(&mut stdout).unwrap();
+    }
+
+    /// Print how many steps ran, how many were cache hits, how many failed
+    /// and how long the build took. Returns true if nothing failed.
+    pub fn summary(&self) -> bool {
+        let events = self.ctx.events();
+        let failed = self.ctx.failures().len();
+        let elapsed = time::precise_time_ns() - self.run.lock().time;
+        report::write_summary(&mut io::stdout(), events.as_slice(), failed, elapsed).unwrap();
+
+        failed == 0
+    }
+
+    /// The totals of this run so far.
+    pub fn run_stats(&self) -> RunStats {
+        let events = self.ctx.events();
+        let run = self.run.lock();
+        let bytes_hashed = unsafe { BYTES_HASHED.load(SeqCst) } - run.bytes_hashed;
+
+        RunStats {
+            started: run.clock,
+            wall_time: time::precise_time_ns() - run.time,
+            commands: events.len(),
+            cached: events.iter().filter(|event| event.cached).count(),
+            bytes_hashed: bytes_hashed as u64,
+        }
+    }
+
+    /// Print the stats of the last `n` runs saved by `flush`, and how the
+    /// latest compares to the ones before it, to notice a build getting
+    /// slower or missing the cache more often.
+    pub fn stats_report(&self, n: uint) {
+        let runs = self.ctx.runs();
+        let start = runs.len() - cmp::min(n, runs.len());
+
+        let mut stdout = io::stdout();
+        report::write_stats(&mut stdout, runs.slice_from(start)).unwrap();
+    }
+
+    pub fn write_report<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        let events = self.ctx.events();
+        report::write_report(w, events.as_slice(), &*self.graph.lock())
+    }
+
+    /// Write this run's execs in the Chrome trace event format.
+    pub fn write_trace<T: IntoPath>(&self, path: T) -> IoResult<()> {
+        let events = self.ctx.events();
+        let mut file = try!(File::create(&path.into_path()));
+        report::write_trace(&mut file, events.as_slice())
+    }
+
+    /// Look at what the build database knows about previous runs, without
+    /// building anything, e.g. to list the targets for an IDE.
+    pub fn query(&self) -> Query {
+        Query {
+            entries: self.ctx.entries(),
+            paths: self.paths.clone(),
+        }
+    }
+
+    /// Start declaring a step. The first one, other than program lookups,
+    /// runs `validate` when validation is on, so every missing program and
+    /// input known by then is reported before anything runs.
     pub fn prep<T: str::IntoMaybeOwned<'static>>(&self, fn_name: T) -> Prep {
-        Prep { prep: self.ctx.prep(fn_name) }
+        let fn_name = fn_name.into_maybe_owned();
+        if self.validate && fn_name.as_slice() != FIND_PROGRAM &&
+           !self.validated.swap(true, SeqCst) {
+            match self.validate() {
+                Ok(()) => { }
+                Err(problems) => { fail!("the build is invalid:\n  {}", problems.connect("\n  ")); }
+            }
+        }
+
+        Prep {
+            ctx: self.clone(),
+            prep: self.ctx.prep(fn_name),
+            outputs: Vec::new(),
+            order_only: Vec::new(),
+            inputs: Vec::new(),
+            node: None,
+        }
     }
 
     pub fn prep_call<T: str::IntoMaybeOwned<'static>>(&self, fn_name: T, call: &Call) -> Prep {
@@ -56,8 +807,102 @@ impl Context {
     }
 }
 
+/// A read-only view of the build database, from `Context::query`. Paths
+/// are in the form they're recorded in, relative to the working directory
+/// when they're under it.
+pub struct Query {
+    entries: Vec<workcache::CacheEntry>,
+    paths: PathNormalizer,
+}
+
+impl Query {
+    /// Every step in the database.
+    pub fn entries<'a>(&'a self) -> &'a [workcache::CacheEntry] {
+        self.entries.as_slice()
+    }
+
+    /// Every file a step is known to write, sorted.
+    pub fn targets(&self) -> Vec<Path> {
+        let mut targets = Vec::new();
+
+        for entry in self.entries.iter() {
+            for target in output_paths(entry).move_iter() {
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+
+        targets.sort_by(|a, b| a.as_vec().cmp(&b.as_vec()));
+        targets
+    }
+
+    /// The step that last wrote `target`.
+    pub fn producer<'a>(&'a self, target: &Path) -> Option<&'a workcache::CacheEntry> {
+        let target = self.paths.normalize(target);
+
+        self.entries.iter().find(|entry| output_paths(*entry).contains(&target))
+    }
+
+    /// The files the step that last wrote `target` read, both declared and
+    /// discovered.
+    pub fn inputs(&self, target: &Path) -> Vec<Path> {
+        let entry = match self.producer(target) {
+            Some(entry) => entry,
+            None => { return Vec::new(); }
+        };
+
+        entry.declared_inputs.iter().chain(entry.discovered_inputs.iter()).filter_map(|&(ref kind, _, ref value)| {
+            if kind.as_slice() == "InputPath" {
+                try_json_decode::<InputPath>(value.as_slice()).map(|input| input.path)
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    /// Whether `target` would be taken from the cache if it were built now.
+    /// `None` if it isn't known.
+    pub fn is_fresh(&self, target: &Path) -> Option<bool> {
+        self.producer(target).map(|entry| entry.fresh)
+    }
+}
+
+/// The files `entry` wrote.
+fn output_paths(entry: &workcache::CacheEntry) -> Vec<Path> {
+    entry.discovered_outputs.iter().filter_map(|&(ref kind, _, ref value)| {
+        if kind.as_slice() == "OutputPath" {
+            try_json_decode::<OutputPath>(value.as_slice()).map(|output| output.path)
+        } else {
+            None
+        }
+    }).collect()
+}
+
+/// Returned by `Context::handle_interrupts`. Interrupts get their default
+/// handling back once it's dropped.
+pub struct InterruptGuard {
+    stop: Sender<()>,
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        let _ = self.stop.send_opt(());
+    }
+}
+
 pub struct Prep {
+    ctx: Context,
     prep: workcache::Prep,
+    /// The outputs of the declared calls, which are recorded along with
+    /// their digests once the step has run.
+    outputs: Vec<Path>,
+    /// What the step waits for before it runs, from `declare_order_only`.
+    order_only: Vec<Future<Path>>,
+    /// The paths declared as inputs, which are validated before it runs.
+    inputs: Vec<Path>,
+    /// The step's node in the dependency graph, once a call is declared.
+    node: Option<uint>,
 }
 
 impl Prep {
@@ -68,29 +913,179 @@ impl Prep {
         self.prep.declare_input(kind, name, json_encode(value))
     }
 
+    /// Wait for a value produced by another step and declare it as an input
+    /// named `name`, so this step re-runs whenever that value changes.
+    pub fn declare_value<
+        'a,
+        V: Encodable<json::Encoder<'a>, IoError>,
+        T: IntoFuture<V>
+    >(&mut self, name: &str, value: T) -> V {
+        let value = value.into_future().unwrap();
+        self.declare_input("value", name, &value);
+        value
+    }
+
+    fn record_input(&mut self, path: &Path) {
+        self.ctx.record_input(path);
+        if !self.inputs.contains(path) {
+            self.inputs.push(path.clone());
+        }
+    }
+
     pub fn declare_input_path(&mut self, path: Path) -> IoResult<()> {
-        let path = try!(InputPath::new(path));
-        self.declare_input("InputPath", "", &path);
+        self.declare_named_input_path("", path)
+    }
+
+    /// Like `declare_input_path`, but keyed by `name`, so a step can declare
+    /// several paths without them replacing each other.
+    pub fn declare_named_input_path(&mut self, name: &str, path: Path) -> IoResult<()> {
+        self.record_input(&path);
+        let path = self.ctx.paths.normalize(&path);
+        let policy = self.ctx.hash_policy(&path);
+        let path = try!(InputPath::with_policy(path, policy));
+        self.declare_input("InputPath", name, &path);
         Ok(())
     }
 
+    /// Declare that this step must run after `dep` is done (e.g. a stamp
+    /// written by a setup step). It's waited on when the step runs, and
+    /// isn't part of the cache key, so a step that's fresh doesn't wait.
+    pub fn declare_order_only<T: IntoFuture<Path>>(&mut self, dep: T) {
+        self.order_only.push(dep.into_future());
+    }
+
+    /// Declare a whole directory tree as an input. It is considered changed
+    /// when any file under it is added, removed or modified.
+    pub fn declare_input_dir(&mut self, path: Path) -> IoResult<()> {
+        self.record_input(&path);
+        let path = self.ctx.paths.normalize(&path);
+        let name = path.as_str().unwrap().to_owned();
+        let dir = try!(InputDir::new(path));
+        self.declare_input("InputDir", name, &dir);
+        Ok(())
+    }
+
+    /// Declare the config setting `name` as an input, so the step reruns when
+    /// it's set to something else.
+    pub fn declare_config(&mut self, name: &str) {
+        let value = self.ctx.cfg.json(name);
+        self.declare_input("Config", name, &value)
+    }
+
+    /// Declare the current value of an environment variable as an input, so
+    /// the step re-runs when it changes between invocations.
+    pub fn declare_input_env(&mut self, name: &str) {
+        let value = os::getenv(name);
+        self.declare_input("EnvVar", name, &value)
+    }
+
+    /// Declare the revision of the git repository at `dir` as an input,
+    /// returning the revision that was recorded.
+    pub fn declare_input_git_rev(&mut self, dir: &Path) -> IoResult<~str> {
+        let name = match dir.as_str() {
+            Some(name) => name,
+            None => {
+                return Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: "git repository path isn't valid UTF-8",
+                    detail: Some(dir.display().to_str()),
+                });
+            }
+        };
+        let rev = try!(git_rev(dir));
+        self.declare_input("GitRev", name, &rev);
+        Ok(rev)
+    }
+
     pub fn declare_call(&mut self, call: &Call) {
-        self.declare_input("Call", "", call)
+        // Record the call in the dependency graph, refusing to wire up a
+        // cycle that would otherwise deadlock on its own futures. Most
+        // builders run the call with `tmp_cmd`, which is what's exported
+        // unless they say otherwise with `export_command`.
+        let command = call.shell_cmd(None, true);
+        match self.ctx.graph.lock().add_node(call.label(), command, call.input_paths(), call.output_paths()) {
+            Ok(idx) => { self.node = Some(idx); }
+            Err(msg) => fail!(msg),
+        }
+
+        for path in call.input_paths().iter() {
+            self.record_input(path);
+        }
+
+        self.outputs.push_all_move(call.output_paths());
+
+        self.prep.set_label(call.label());
+        self.declare_input("Call", "", &call.normalized(&self.ctx.paths))
+    }
+
+    /// Record how the declared call really runs, for `export_ninja` and
+    /// `export_makefile`, when it isn't the call's command line writing to
+    /// temporary outputs: `command` is a shell command, usually from
+    /// `Call::shell_cmd`, and `depfile` lists the headers it reads.
+    pub fn export_command(&mut self, command: ~str, depfile: Option<Path>) {
+        match self.node {
+            Some(idx) => { self.ctx.graph.lock().set_command(idx, command, depfile); }
+            None => { fail!("export_command needs a declared call"); }
+        }
     }
 
     pub fn exec<
         'a,
         T: Send + Encodable<json::Encoder<'a>, IoError> + Decodable<json::Decoder, json::Error>
     >(self, blk: proc(&mut Exec):Send -> T) -> Future<T> {
-        self.prep.exec(proc(exec) {
-            let mut exec = Exec { exec: exec };
-            blk(&mut exec)
+        let Prep { ctx, prep, outputs, order_only, inputs, .. } = self;
+        if ctx.validate {
+            ctx.validate_inputs(inputs.as_slice());
+        }
+
+        prep.exec(proc(exec) {
+            for dep in order_only.move_iter() {
+                dep.unwrap();
+            }
+
+            let mut exec = Exec {
+                exec: exec,
+                ctx: ctx,
+                inputs: Arc::new(Mutex::new(Vec::new())),
+                outputs: Arc::new(Mutex::new(Vec::new())),
+            };
+            let value = blk(&mut exec);
+
+            let read = exec.inputs.lock().clone();
+            for path in read.iter() {
+                exec.discover_input_path(path.as_str().unwrap(), path).unwrap();
+            }
+
+            // Record what the outputs hold now, so that the step re-runs if
+            // one is modified or truncated afterwards.
+            let written = exec.outputs.lock().clone();
+            for path in outputs.iter().chain(written.iter()) {
+                exec.discover_output_path(path.as_str().unwrap(), path);
+            }
+
+            value
         })
     }
+
+    /// Like `exec`, for procs that produce several outputs at once, such as
+    /// a struct holding a library, its debug symbols and a map file. The
+    /// outputs are cached and decoded as a unit, and each can be exposed as
+    /// its own future with `SharedFuture::map`.
+    pub fn exec_shared<
+        'a,
+        T: Send + Clone + Encodable<json::Encoder<'a>, IoError> + Decodable<json::Decoder, json::Error>
+    >(self, blk: proc(&mut Exec):Send -> T) -> SharedFuture<T> {
+        SharedFuture::new(self.exec(blk))
+    }
 }
 
 pub struct Exec<'a> {
     exec: &'a mut workcache::Exec,
+    ctx: Context,
+    /// Files the processes of this step read, according to their depfiles.
+    inputs: Arc<Mutex<Vec<Path>>>,
+    /// Files the processes of this step wrote with `stdout_to`.
+    outputs: Arc<Mutex<Vec<Path>>>,
 }
 
 impl<'a> Exec<'a> {
@@ -101,19 +1096,38 @@ impl<'a> Exec<'a> {
     }
 
     pub fn discover_input_path(&mut self, name: &str, path: &Path) -> IoResult<()> {
-        let path = try!(InputPath::new(path.clone()));
+        self.ctx.record_input(path);
+        let path = self.ctx.paths.normalize(path);
+        let policy = self.ctx.hash_policy(&path);
+        let path = try!(InputPath::with_policy(path, policy));
         self.discover_input("InputPath", name, &path);
         Ok(())
     }
 
+    pub fn discover_input_env(&mut self, name: &str) -> Option<~str> {
+        let value = os::getenv(name);
+        self.discover_input("EnvVar", name, &value);
+        value
+    }
+
     pub fn discover_output<
         T: Encodable<json::Encoder<'a>, IoError>
     >(&mut self, kind: &str, name: &str, value: &T) {
         self.exec.discover_output(kind, name, json_encode(value))
     }
 
+    /// Write an empty stamp file recording that this step ran, and register
+    /// it as an output. Later steps can depend on it with
+    /// `declare_order_only`.
+    pub fn write_stamp(&mut self, name: &str, path: &Path) -> IoResult<()> {
+        try!(fs::mkdir_recursive(&path.dir_path(), io::UserDir));
+        try!(File::create(path).write([]));
+        self.discover_output_path(name, path);
+        Ok(())
+    }
+
     pub fn discover_output_path(&mut self, name: &str, path: &Path) {
-        let path = OutputPath::new(path.clone());
+        let path = OutputPath::new(self.ctx.paths.normalize(path));
         self.discover_output("OutputPath", name, &path)
     }
 
@@ -122,37 +1136,161 @@ impl<'a> Exec<'a> {
         program: &'a str,
         args: &'a [~str]
     ) -> ProcessBuilder<'a> {
-        ProcessBuilder::new(program, args)
+        let priority = self.ctx.ctx.priority(self.exec.label());
+
+        let builder = ProcessBuilder::new(program, args)
+            .executor(self.ctx.executor.clone())
+            .process_groups(self.ctx.process_groups.clone())
+            .verbosity(self.ctx.verbosity)
+            .quiet(self.ctx.quiet)
+            .use_color(self.ctx.use_color())
+            .stream_prefix(self.exec.label())
+            .inputs(self.inputs.clone())
+            .outputs(self.outputs.clone())
+            .scheduler(self.ctx.scheduler.clone())
+            .priority(priority);
+
+        let builder = if self.ctx.deterministic && os::getenv("SOURCE_DATE_EPOCH").is_none() {
+            builder.env("SOURCE_DATE_EPOCH", "0")
+        } else {
+            builder
+        };
+
+        match self.ctx.jobserver {
+            Some(ref jobserver) => builder.jobserver(jobserver.clone()),
+            None => builder,
+        }
     }
 }
 
+/// Returns when the path was last modified, or `None` if it is missing.
+fn modified(path: &Path) -> Option<u64> {
+    path.stat().ok().map(|st| st.modified)
+}
+
 /// Hashes the path contents
 fn digest_path(path: &Path) -> IoResult<~str> {
     let mut file = try!(File::open(path));
     let bytes = try!(file.read_to_end());
     let digest = hash::hash(&bytes);
 
+    unsafe { BYTES_HASHED.fetch_add(bytes.len(), SeqCst); }
+
     debug!("digesting: {} {}", path.display(), digest);
 
     Ok(digest.to_str_radix(16))
 }
 
-#[deriving(Encodable, Decodable)]
+/// Hashes every file under `dir` along with its path relative to `dir`.
+fn digest_dir(dir: &Path) -> IoResult<~str> {
+    let mut files = Vec::new();
+    for path in try!(fs::walk_dir(dir)) {
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort_by(|a, b| a.as_vec().cmp(&b.as_vec()));
+
+    let mut entries = Vec::new();
+    for path in files.iter() {
+        let relative = path.path_relative_from(dir).unwrap();
+        entries.push((relative.as_vec().to_owned(), try!(digest_path(path))));
+    }
+
+    let digest = hash::hash(&entries);
+
+    debug!("digesting dir: {} {}", dir.display(), digest);
+
+    Ok(digest.to_str_radix(16))
+}
+
+/// Returns the revision checked out in the git repository at `dir`. A dirty
+/// working tree, including one with new untracked files, gets a digest of
+/// its status and diff appended, so uncommitted changes count as a new
+/// revision.
+pub fn git_rev(dir: &Path) -> IoResult<~str> {
+    let head = try!(git_output(dir, [~"rev-parse", ~"HEAD"]));
+    let head = str::from_utf8_lossy(head.as_slice()).as_slice().trim().to_owned();
+
+    // Unlike `git diff`, `git status` also lists the untracked files.
+    let status = try!(git_output(dir, [~"status", ~"--porcelain"]));
+    if status.is_empty() {
+        Ok(head)
+    } else {
+        let diff = try!(git_output(dir, [~"diff", ~"HEAD"]));
+        Ok(format!("{}-dirty-{}", head, hash::hash(&(status, diff)).to_str_radix(16)))
+    }
+}
+
+fn git_output(dir: &Path, args: &[~str]) -> IoResult<Vec<u8>> {
+    let config = ProcessConfig {
+        program: "git",
+        args: args,
+        cwd: Some(dir),
+        .. ProcessConfig::new()
+    };
+
+    let mut process = try!(Process::configure(config));
+    let output = process.wait_with_output();
+
+    if !output.status.success() {
+        let err = str::from_utf8_lossy(output.error.as_slice()).into_owned();
+        return Err(IoError {
+            kind: io::OtherIoError,
+            desc: "git failed",
+            detail: Some(err),
+        });
+    }
+
+    Ok(output.output.as_slice().to_owned())
+}
+
+/// An artifact listed by `Context::write_manifest`.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct ManifestEntry {
+    pub path: Path,
+    pub size: u64,
+    pub sha256: ~str,
+    /// The label of the step that produced it.
+    pub target: ~str,
+}
+
+/// How the contents of an input path are checked for changes.
+#[deriving(Clone, Eq, Show, Encodable, Decodable)]
+pub enum HashPolicy {
+    FullHash,
+    /// Only compare the modification time.
+    MtimeOnly,
+    /// Never consider the path changed, as long as it exists.
+    IgnoreChanges,
+}
+
+#[deriving(Clone, Encodable, Decodable)]
 struct InputPath {
     path: Path,
-    digest: ~str,
+    /// Only paths with the `FullHash` policy are hashed.
+    digest: Option<~str>,
     modified: u64,
+    policy: HashPolicy,
 }
 
 impl InputPath {
     fn new(path: Path) -> IoResult<InputPath> {
-        let digest = try!(digest_path(&path));
+        InputPath::with_policy(path, FullHash)
+    }
+
+    fn with_policy(path: Path, policy: HashPolicy) -> IoResult<InputPath> {
+        let digest = match policy {
+            FullHash => Some(try!(digest_path(&path))),
+            MtimeOnly | IgnoreChanges => None,
+        };
         let st = try!(path.stat());
 
         Ok(InputPath {
             path: path,
             digest: digest,
             modified: st.modified,
+            policy: policy,
         })
     }
 
@@ -161,31 +1299,71 @@ impl InputPath {
     }
 
     fn is_fresh(&self) -> bool {
-        self.exists() && self.digest == digest_path(&self.path).unwrap()
+        match self.policy {
+            FullHash => self.exists() && self.digest == digest_path(&self.path).ok(),
+            MtimeOnly => modified(&self.path) == Some(self.modified),
+            IgnoreChanges => self.exists(),
+        }
+    }
+}
+
+#[deriving(Encodable, Decodable)]
+struct InputDir {
+    path: Path,
+    digest: ~str,
+}
+
+impl InputDir {
+    fn new(path: Path) -> IoResult<InputDir> {
+        let digest = try!(digest_dir(&path));
+
+        Ok(InputDir {
+            path: path,
+            digest: digest,
+        })
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.path.is_dir() && match digest_dir(&self.path) {
+            Ok(digest) => self.digest == digest,
+            Err(_) => false,
+        }
     }
 }
 
 #[deriving(Encodable, Decodable)]
 struct OutputPath {
     path: Path,
+    /// The digest of the output when it was produced, or `None` if it
+    /// couldn't be read, e.g. because it's a directory.
+    digest: Option<~str>,
 }
 
 impl OutputPath {
     fn new(path: Path) -> OutputPath {
+        let digest = digest_path(&path).ok();
+
         OutputPath {
             path: path,
+            digest: digest,
         }
     }
 
     fn is_fresh(&self) -> bool {
-        self.path.exists()
+        match self.digest {
+            Some(ref digest) => digest_path(&self.path).ok().as_ref() == Some(digest),
+            None => self.path.exists(),
+        }
     }
 }
 
-#[deriving(Encodable, Decodable)]
+#[deriving(Clone, Encodable, Decodable)]
 pub struct Call {
     prog: CallArg,
     args: Vec<CallArg>,
+    /// How much memory the command is expected to use, relative to an
+    /// ordinary compile. Not part of the cache key.
+    memory_weight: uint,
 }
 
 impl Call {
@@ -194,6 +1372,7 @@ impl Call {
         Ok(Call {
             prog: InputPath(prog),
             args: Vec::new(),
+            memory_weight: 1,
         })
     }
 
@@ -212,37 +1391,284 @@ impl Call {
         self.args.push(OutputPath(value))
     }
 
+    /// Push a prefix map flag such as `-fdebug-prefix-map`, which the tool
+    /// is given as `flag=<absolute from>=to`. The cache key only has `from`
+    /// relative to the working directory, so moving the tree keeps it.
+    pub fn push_prefix_map(&mut self, flag: &str, from: Path, to: ~str) {
+        self.args.push(PrefixMap(flag.to_owned(), from, to))
+    }
+
     fn is_fresh(&self) -> bool {
         self.args.iter().all(|arg| arg.is_fresh())
     }
 
+    /// Mark the command as needing about `memory_weight` times the memory
+    /// of an ordinary compile, for `Context::set_memory_budget`.
+    pub fn set_memory_weight(&mut self, memory_weight: uint) {
+        self.memory_weight = memory_weight;
+    }
+
+    pub fn memory_weight(&self) -> uint {
+        self.memory_weight
+    }
+
+    /// The canonical form of the call that is used as its cache key, so
+    /// that equivalent commands share an entry. Arguments are trimmed, runs
+    /// of `-D` macros are sorted by name (keeping the relative order of
+    /// redefinitions) and repeated `-I` directories are dropped, since only
+    /// the first occurrence affects the search order. Paths are normalized
+    /// with `paths`.
+    fn normalized(&self, paths: &PathNormalizer) -> Call {
+        // Pair up flags with their separate values first, so that a flag
+        // like "-D FOO" moves as a unit.
+        let mut groups: Vec<Vec<CallArg>> = Vec::new();
+        let mut iter = self.args.iter().map(|arg| arg.normalized(paths));
+        loop {
+            let arg = match iter.next() {
+                Some(arg) => arg,
+                None => { break; }
+            };
+
+            let takes_value = match arg {
+                Str(ref s) => s.as_slice() == "-D" || s.as_slice() == "-I",
+                _ => false,
+            };
+
+            let mut group = vec!(arg);
+            if takes_value {
+                match iter.next() {
+                    Some(value) => { group.push(value); }
+                    None => { }
+                }
+            }
+            groups.push(group);
+        }
+
+        let mut args = Vec::new();
+        let mut macros: Vec<(~str, Vec<CallArg>)> = Vec::new();
+        let mut includes = Vec::new();
+
+        for group in groups.move_iter() {
+            match group_flag(group.as_slice()) {
+                Some(('D', value)) => {
+                    let name = value.split('=').next().unwrap_or("").to_owned();
+                    macros.push((name, group));
+                    continue;
+                }
+                Some(('I', value)) => {
+                    if !includes.contains(&value) {
+                        includes.push(value);
+                        flush_macros(&mut args, &mut macros);
+                        args.push_all_move(group);
+                    }
+                    continue;
+                }
+                _ => { }
+            }
+
+            flush_macros(&mut args, &mut macros);
+            args.push_all_move(group);
+        }
+        flush_macros(&mut args, &mut macros);
+
+        Call {
+            prog: self.prog.normalized(paths),
+            args: args,
+            memory_weight: 1,
+        }
+    }
+
+    /// A short description of the call: the program and what it produces.
+    pub fn label(&self) -> ~str {
+        let (prog, _) = self.cmd();
+        let mut label = Path::new(prog).filename_display().to_str();
+
+        for path in self.output_paths().iter() {
+            label.push_str(" ");
+            label.push_str(path.display().to_str());
+        }
+
+        label
+    }
+
+    pub fn input_paths(&self) -> Vec<Path> {
+        self.args.iter().filter_map(|arg| {
+            match *arg {
+                InputPath(ref p) => Some(p.path.clone()),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    pub fn output_paths(&self) -> Vec<Path> {
+        self.args.iter().filter_map(|arg| {
+            match *arg {
+                OutputPath(ref p) => Some(p.clone()),
+                _ => None,
+            }
+        }).collect()
+    }
+
     pub fn cmd(&self) -> (~str, Vec<~str>) {
-        fn f(arg: &CallArg) -> ~str {
+        let prog = self.prog.value();
+        let args = self.args.iter().map(|arg| arg.value()).collect();
+
+        (prog, args)
+    }
+
+    /// Like `cmd`, but with the outputs written to temporary paths, which
+    /// `rename_outputs` moves into place once the command succeeds. A
+    /// command that is killed halfway then can't leave a truncated output
+    /// behind that looks fresh. Leftovers from an earlier attempt are
+    /// removed first, since tools like `ar` add to an existing output.
+    pub fn tmp_cmd(&self) -> IoResult<(~str, Vec<~str>)> {
+        for path in self.output_paths().iter() {
+            let tmp = tmp_path(path);
+            if tmp.exists() {
+                try!(fs::unlink(&tmp));
+            }
+        }
+
+        let prog = self.prog.value();
+        let args = self.args.iter().map(|arg| {
             match *arg {
-                Str(ref s) => s.clone(),
-                InputPath(ref p) => p.path.as_str().unwrap().to_owned(),
-                OutputPath(ref p) => p.as_str().unwrap().to_owned(),
+                OutputPath(ref p) => tmp_path(p).as_str().unwrap().to_owned(),
+                _ => arg.value(),
+            }
+        }).collect();
+
+        Ok((prog, args))
+    }
+
+    /// The call as a shell command, the way a builder runs it: through
+    /// `launcher`, if any, and when `atomic`, writing the outputs to the
+    /// temporary paths of `tmp_cmd` and renaming them into place.
+    pub fn shell_cmd(&self, launcher: Option<&Path>, atomic: bool) -> ~str {
+        let mut args: Vec<~str> = Vec::new();
+        match launcher {
+            Some(_) => { args.push(self.prog.value()); }
+            None => { }
+        }
+        for arg in self.args.iter() {
+            match *arg {
+                OutputPath(ref p) if atomic => { args.push(tmp_path(p).as_str().unwrap().to_owned()); }
+                _ => { args.push(arg.value()); }
             }
         }
 
-        let prog = f(&self.prog);
-        let args = self.args.iter().map(f).collect();
+        let mut cmd = match launcher {
+            Some(launcher) => shell::join(launcher.as_str().unwrap(), args.as_slice()),
+            None => shell::join(self.prog.value(), args.as_slice()),
+        };
 
-        (prog, args)
+        if atomic {
+            for path in self.output_paths().iter() {
+                cmd.push_str(format!(" && mv -f {} {}",
+                                     shell::quote(tmp_path(path).as_str().unwrap()),
+                                     shell::quote(path.as_str().unwrap())));
+            }
+        }
+
+        cmd
+    }
+
+    /// Atomically replace the outputs with what the command run by `tmp_cmd`
+    /// wrote.
+    pub fn rename_outputs(&self) -> IoResult<()> {
+        for path in self.output_paths().iter() {
+            try!(fs::rename(&tmp_path(path), path));
+        }
+
+        Ok(())
     }
 }
 
-#[deriving(Encodable, Decodable)]
+/// Returns the temporary path an output is written to before being renamed
+/// into place. The extension is kept, since some tools pick the output
+/// format from it.
+fn tmp_path(path: &Path) -> Path {
+    let filename = match (path.filestem_str(), path.extension_str()) {
+        (Some(stem), Some(ext)) => format!("{}.tmp.{}", stem, ext),
+        _ => format!("{}.tmp", path.filename_str().unwrap()),
+    };
+
+    path.with_filename(filename)
+}
+
+/// Returns the kind of a `-D`/`-I` flag and its value.
+fn group_flag(group: &[CallArg]) -> Option<(char, ~str)> {
+    let flag = match group.get(0) {
+        Some(&Str(ref flag)) => flag.as_slice(),
+        _ => { return None; }
+    };
+
+    if flag.len() < 2 || !flag.starts_with("-") {
+        return None;
+    }
+
+    let kind = flag.char_at(1);
+    if kind != 'D' && kind != 'I' {
+        return None;
+    }
+
+    let value = match group.get(1) {
+        Some(arg) => arg.value(),
+        None => flag.slice_from(2).to_owned(),
+    };
+
+    Some((kind, value))
+}
+
+fn flush_macros(args: &mut Vec<CallArg>, macros: &mut Vec<(~str, Vec<CallArg>)>) {
+    // The sort is stable, so redefinitions of a macro keep their order.
+    macros.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+
+    let mut last: Option<~str> = None;
+    for (_, group) in mem::replace(macros, Vec::new()).move_iter() {
+        let value = group.iter().map(|arg| arg.value()).collect::<Vec<~str>>().concat();
+        if last.as_ref() != Some(&value) {
+            args.push_all_move(group);
+        }
+        last = Some(value);
+    }
+}
+
+#[deriving(Clone, Encodable, Decodable)]
 enum CallArg {
     Str(~str),
     InputPath(InputPath),
     OutputPath(Path),
+    PrefixMap(~str, Path, ~str),
 }
 
 impl CallArg {
+    fn normalized(&self, paths: &PathNormalizer) -> CallArg {
+        match *self {
+            Str(ref s) => Str(s.trim().to_owned()),
+            InputPath(ref p) => {
+                InputPath(InputPath { path: paths.normalize(&p.path), .. p.clone() })
+            }
+            OutputPath(ref p) => OutputPath(paths.normalize(p)),
+            PrefixMap(ref flag, ref from, ref to) => {
+                PrefixMap(flag.clone(), paths.normalize(from), to.clone())
+            }
+        }
+    }
+
+    fn value(&self) -> ~str {
+        match *self {
+            Str(ref s) => s.clone(),
+            InputPath(ref p) => p.path.as_str().unwrap().to_owned(),
+            OutputPath(ref p) => p.as_str().unwrap().to_owned(),
+            PrefixMap(ref flag, ref from, ref to) => {
+                format!("{}={}={}", flag, os::make_absolute(from).display(), to)
+            }
+        }
+    }
+
     fn is_fresh(&self) -> bool {
         match *self {
-            Str(_) => true,
+            Str(_) | PrefixMap(..) => true,
             InputPath(ref p) => p.is_fresh(),
             OutputPath(ref p) => p.exists(),
         }
@@ -262,22 +1688,71 @@ fn json_decode<T: Decodable<json::Decoder, json::Error>>(s: &str) -> T {
     Decodable::decode(&mut decoder).unwrap()
 }
 
+/// Like `json_decode`, but returns `None` for values that don't decode, such
+/// as ones recorded by an older version of rbuild.
+fn try_json_decode<T: Decodable<json::Decoder, json::Error>>(s: &str) -> Option<T> {
+    let j = match json::from_str(s) {
+        Ok(j) => j,
+        Err(_) => { return None; }
+    };
+    let mut decoder = json::Decoder::new(j);
+    Decodable::decode(&mut decoder).ok()
+}
+
 fn call_is_fresh(_name: &str, value: &str) -> bool {
-    let call: Call = json_decode(value);
+    // Calls recorded before hash policies existed are run again.
+    let call: Option<Call> = try_json_decode(value);
+
+    call.map_or(false, |call| call.is_fresh())
+}
+
+fn env_var_is_fresh(name: &str, value: &str) -> bool {
+    let value: Option<~str> = json_decode(value);
 
-    call.is_fresh()
+    os::getenv(name) == value
+}
+
+fn git_rev_is_fresh(name: &str, value: &str) -> bool {
+    let rev: ~str = json_decode(value);
+
+    match git_rev(&Path::new(name)) {
+        Ok(current) => current == rev,
+        Err(_) => false,
+    }
+}
+
+fn input_dir_is_fresh(_name: &str, value: &str) -> bool {
+    let dir: InputDir = json_decode(value);
+
+    dir.is_fresh()
 }
 
 fn input_path_is_fresh(_name: &str, value: &str) -> bool {
-    let path: InputPath = json_decode(value);
+    // Paths recorded before hash policies existed are checked again.
+    let path: Option<InputPath> = try_json_decode(value);
+
+    path.map_or(false, |path| path.is_fresh())
+}
+
+fn missing_is_fresh(_name: &str, value: &str) -> bool {
+    let path: Path = json_decode(value);
+
+    !path.exists()
+}
+
+/// Order-only inputs are no longer part of cache keys, but databases
+/// written before may still have them.
+fn order_only_is_fresh(_name: &str, value: &str) -> bool {
+    let path: Path = json_decode(value);
 
-    path.is_fresh()
+    path.exists()
 }
 
 fn output_path_is_fresh(_name: &str, value: &str) -> bool {
-    let path: OutputPath = json_decode(value);
+    // Outputs recorded without a digest are rebuilt to get one.
+    let path: Option<OutputPath> = try_json_decode(value);
 
-    path.is_fresh()
+    path.map_or(false, |path| path.is_fresh())
 }
 
 fn value_is_fresh(_name: &str, _value: &str) -> bool {
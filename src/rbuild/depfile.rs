@@ -0,0 +1,210 @@
+//! Parses the Makefile-style dependency files (`.d`) written by compilers
+//! and many other tools, e.g. `gcc -MD` or `sass --update`.
+
+use std::io::{File, IoError, IoResult};
+use std::io;
+
+/// One rule of a depfile: `targets` depend on `deps`.
+#[deriving(Clone, Eq, Show)]
+pub struct Rule {
+    pub targets: Vec<Path>,
+    pub deps: Vec<Path>,
+}
+
+/// Parses the rules in `s`. Rules may continue over several lines with a
+/// trailing backslash, spaces in paths are escaped as `\ `, `#` as `\#`
+/// and `$` as `$$`. A colon only separates the targets when it is followed
+/// by whitespace, so Windows paths such as `C:\foo.h` are left alone.
+pub fn parse(s: &str) -> Result<Vec<Rule>, ~str> {
+    let chars: Vec<char> = s.chars().collect();
+    let chars = chars.as_slice();
+
+    let mut rules = Vec::new();
+    let mut targets: Option<Vec<Path>> = None;
+    let mut words = Vec::new();
+    let mut word = StrBuf::new();
+    let mut line = 1;
+
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = if i + 1 < chars.len() { Some(chars[i + 1]) } else { None };
+
+        match c {
+            '\\' if next == Some('\n') => {
+                end_word(&mut word, &mut words);
+                line += 1;
+                i += 1;
+            }
+            '\\' if next == Some('\r') && i + 2 < chars.len() && chars[i + 2] == '\n' => {
+                end_word(&mut word, &mut words);
+                line += 1;
+                i += 2;
+            }
+            '\\' if next == Some(' ') || next == Some('#') => {
+                word.push_char(next.unwrap());
+                i += 1;
+            }
+            '$' if next == Some('$') => {
+                word.push_char('$');
+                i += 1;
+            }
+            '#' if word.len() == 0 => {
+                // Skip the comment, but not the end of its line.
+                while i + 1 < chars.len() && chars[i + 1] != '\n' {
+                    i += 1;
+                }
+            }
+            ':' if next.map_or(true, |next| next.is_whitespace()) => {
+                end_word(&mut word, &mut words);
+                if targets.is_some() {
+                    return Err(format!("line {}: unexpected `:`", line));
+                }
+                if words.is_empty() {
+                    return Err(format!("line {}: rule without a target", line));
+                }
+                targets = Some(words.move_iter().map(|word| Path::new(word)).collect());
+                words = Vec::new();
+            }
+            '\n' => {
+                end_word(&mut word, &mut words);
+                try!(end_rule(line, &mut targets, &mut words, &mut rules));
+                line += 1;
+            }
+            c if c.is_whitespace() => {
+                end_word(&mut word, &mut words);
+            }
+            c => {
+                word.push_char(c);
+            }
+        }
+
+        i += 1;
+    }
+
+    end_word(&mut word, &mut words);
+    try!(end_rule(line, &mut targets, &mut words, &mut rules));
+
+    Ok(rules)
+}
+
+fn end_word(word: &mut StrBuf, words: &mut Vec<~str>) {
+    if word.len() != 0 {
+        words.push(word.as_slice().to_owned());
+        *word = StrBuf::new();
+    }
+}
+
+fn end_rule(
+    line: uint,
+    targets: &mut Option<Vec<Path>>,
+    words: &mut Vec<~str>,
+    rules: &mut Vec<Rule>
+) -> Result<(), ~str> {
+    match targets.take() {
+        Some(targets) => {
+            let deps = words.iter().map(|word| Path::new(word.as_slice())).collect();
+            rules.push(Rule { targets: targets, deps: deps });
+        }
+        None => {
+            if !words.is_empty() {
+                return Err(format!("line {}: expected `:`", line));
+            }
+        }
+    }
+
+    words.clear();
+    Ok(())
+}
+
+/// Reads and parses the depfile at `path`.
+pub fn read(path: &Path) -> IoResult<Vec<Rule>> {
+    let contents = try!(File::open(path).read_to_str());
+
+    match parse(contents) {
+        Ok(rules) => Ok(rules),
+        Err(err) => {
+            Err(IoError {
+                kind: io::InvalidInput,
+                desc: "malformed depfile",
+                detail: Some(format!("{}: {}", path.display(), err)),
+            })
+        }
+    }
+}
+
+/// Returns every dependency listed in `rules`, without duplicates.
+pub fn inputs(rules: &[Rule]) -> Vec<Path> {
+    let mut inputs = Vec::new();
+
+    for rule in rules.iter() {
+        for dep in rule.deps.iter() {
+            if !inputs.contains(dep) {
+                inputs.push(dep.clone());
+            }
+        }
+    }
+
+    inputs
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, inputs, Rule};
+
+    fn rule(targets: &[&str], deps: &[&str]) -> Rule {
+        Rule {
+            targets: targets.iter().map(|target| Path::new(*target)).collect(),
+            deps: deps.iter().map(|dep| Path::new(*dep)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_gcc() {
+        let s = "foo.o: foo.c foo.h \\\n  include/bar.h\n";
+        assert_eq!(parse(s), Ok(vec!(rule(["foo.o"], ["foo.c", "foo.h", "include/bar.h"]))));
+    }
+
+    // `gcc -MP` adds a rule without dependencies for every header.
+    #[test]
+    fn test_phony_targets() {
+        let s = "foo.o: foo.c foo.h\n\nfoo.h:\n";
+        assert_eq!(parse(s), Ok(vec!(rule(["foo.o"], ["foo.c", "foo.h"]), rule(["foo.h"], []))));
+    }
+
+    #[test]
+    fn test_several_targets() {
+        assert_eq!(parse("a.o b.o: c.h"), Ok(vec!(rule(["a.o", "b.o"], ["c.h"]))));
+    }
+
+    #[test]
+    fn test_escapes() {
+        let s = "out: a\\ b.h c$$d.h \\#e.h\n";
+        assert_eq!(parse(s), Ok(vec!(rule(["out"], ["a b.h", "c$d.h", "#e.h"]))));
+    }
+
+    #[test]
+    fn test_windows() {
+        let s = "foo.obj: C:\\src\\foo.c \\\r\n  C:\\src\\foo.h\r\n";
+        assert_eq!(parse(s), Ok(vec!(rule(["foo.obj"], ["C:\\src\\foo.c", "C:\\src\\foo.h"]))));
+    }
+
+    #[test]
+    fn test_comments() {
+        let s = "# generated\nfoo.o: foo.c\n";
+        assert_eq!(parse(s), Ok(vec!(rule(["foo.o"], ["foo.c"]))));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse("foo.c foo.h\n"), Err(~"line 1: expected `:`"));
+        assert_eq!(parse("\n: foo.c\n"), Err(~"line 2: rule without a target"));
+        assert_eq!(parse("a: b: c\n"), Err(~"line 1: unexpected `:`"));
+    }
+
+    #[test]
+    fn test_inputs() {
+        let rules = [rule(["a"], ["x", "y"]), rule(["b"], ["y", "z"])];
+        assert_eq!(inputs(rules), vec!(Path::new("x"), Path::new("y"), Path::new("z")));
+    }
+}
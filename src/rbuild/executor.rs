@@ -0,0 +1,638 @@
+use std::io;
+use std::io::{BufferedReader, File, IoError, IoResult, Process, ProcessConfig, TempDir};
+use std::io::pipe::PipeStream;
+use std::io::{Acceptor, Listener};
+use std::io::net::ip::SocketAddr;
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::process::{ExitSignal, ExitStatus, MustDieSignal, ProcessOutput};
+use std::io::timer::Timer;
+use std::str;
+use std::sync::atomics::{AtomicUint, SeqCst};
+use libc::pid_t;
+use sync::{Arc, Mutex};
+
+/// Something that can run a command to completion and capture its output.
+pub trait Executor: Send + Share {
+    fn execute<'a>(&self, config: ProcessConfig<'a>) -> IoResult<ProcessOutput> {
+        self.execute_with(config, &ExecOptions::new())
+    }
+
+    /// Like `execute`, with the extra control over local processes in
+    /// `options`. Every local process an executor starts must honor them,
+    /// or interrupting the build and timeouts won't reach it.
+    fn execute_with<'a>(
+        &self,
+        config: ProcessConfig<'a>,
+        options: &ExecOptions
+    ) -> IoResult<ProcessOutput>;
+}
+
+/// How `Executor::execute_with` runs a local process.
+pub struct ExecOptions {
+    /// Run the command in its own process group, registered here while it
+    /// runs. That way the tools the command starts, like the `cc1` and `ld`
+    /// run by `gcc`, can be killed along with it.
+    pub groups: Option<Arc<ProcessGroups>>,
+    /// Kill the command's process group if it runs for longer than this
+    /// many milliseconds.
+    pub timeout: Option<u64>,
+    /// Copy the command's output to the console as it is written, each line
+    /// prefixed with this, as well as capturing it.
+    pub stream: Option<~str>,
+    /// What to feed the command on stdin.
+    pub stdin: Option<Vec<u8>>,
+}
+
+impl ExecOptions {
+    /// Run the command with none of the extra control.
+    pub fn new() -> ExecOptions {
+        ExecOptions {
+            groups: None,
+            timeout: None,
+            stream: None,
+            stdin: None,
+        }
+    }
+}
+
+/// An executor shared between every step of a build.
+pub type SharedExecutor = Arc<~Executor:Send+Share>;
+
+/// Runs commands as local processes. This is the default executor.
+pub struct LocalExecutor;
+
+impl Executor for LocalExecutor {
+    fn execute<'a>(&self, config: ProcessConfig<'a>) -> IoResult<ProcessOutput> {
+        let mut process = try!(Process::configure(config));
+        Ok(process.wait_with_output())
+    }
+
+    fn execute_with<'a>(
+        &self,
+        config: ProcessConfig<'a>,
+        options: &ExecOptions
+    ) -> IoResult<ProcessOutput> {
+        // Detaching puts the process in a new session, and so a new group.
+        let config = ProcessConfig { detach: options.groups.is_some(), .. config };
+        let mut process = try!(Process::configure(config));
+        let pid = process.id();
+
+        match options.groups {
+            Some(ref groups) => { groups.add(pid); }
+            None => { }
+        }
+
+        // Write stdin from another task, so a command that writes a lot
+        // before reading all its input can't deadlock with us.
+        match (process.stdin.take(), options.stdin.clone()) {
+            (Some(mut pipe), Some(bytes)) => {
+                spawn(proc() {
+                    let _ = pipe.write(bytes.as_slice());
+                });
+            }
+            _ => { }
+        }
+
+        let (done_tx, done_rx) = channel();
+        match (&options.groups, options.timeout) {
+            (&Some(_), Some(timeout)) => {
+                spawn(proc() {
+                    let mut timer = Timer::new().unwrap();
+                    let timeout_rx = timer.oneshot(timeout);
+
+                    select! {
+                        _ = done_rx.recv_opt() => { },
+                        _ = timeout_rx.recv() => { kill_group(pid, MustDieSignal); }
+                    }
+                });
+            }
+            _ => { }
+        }
+
+        let output = match options.stream {
+            Some(ref prefix) => {
+                let stdout = tee(process.stdout.take(), prefix.clone(), false);
+                let stderr = tee(process.stderr.take(), prefix.clone(), true);
+                let status = process.wait();
+
+                ProcessOutput {
+                    status: status,
+                    output: stdout.recv(),
+                    error: stderr.recv(),
+                }
+            }
+            None => process.wait_with_output(),
+        };
+        let _ = done_tx.send_opt(());
+
+        match options.groups {
+            Some(ref groups) => { groups.remove(pid); }
+            None => { }
+        }
+
+        Ok(output)
+    }
+}
+
+/// Reads `pipe` to the end in another task, copying each line to stdout (or
+/// stderr) after `prefix`, and sends back everything that was read.
+fn tee(pipe: Option<PipeStream>, prefix: ~str, to_stderr: bool) -> Receiver<Vec<u8>> {
+    let (tx, rx) = channel();
+
+    spawn(proc() {
+        let mut captured = Vec::new();
+
+        match pipe {
+            Some(pipe) => {
+                let mut reader = BufferedReader::new(pipe);
+                let mut out = if to_stderr { io::stderr() } else { io::stdout() };
+
+                loop {
+                    match reader.read_until('\n' as u8) {
+                        Ok(line) => {
+                            captured.push_all(line.as_slice());
+                            let _ = write!(&mut out, "[{}] ", prefix);
+                            let _ = out.write(line.as_slice());
+                        }
+                        Err(_) => { break; }
+                    }
+                }
+            }
+            None => { }
+        }
+
+        tx.send(captured);
+    });
+
+    rx
+}
+
+/// Copies the output of a command that didn't run locally to the console,
+/// the way `tee` would have as it ran.
+fn echo(prefix: &str, output: &ProcessOutput) {
+    echo_lines(&mut io::stdout(), prefix, output.output.as_slice());
+    echo_lines(&mut io::stderr(), prefix, output.error.as_slice());
+}
+
+fn echo_lines<W: Writer>(out: &mut W, prefix: &str, bytes: &[u8]) {
+    for line in bytes.split(|b| *b == '\n' as u8) {
+        if !line.is_empty() {
+            let _ = write!(out, "[{}] ", prefix);
+            let _ = out.write(line);
+            let _ = out.write_u8('\n' as u8);
+        }
+    }
+}
+
+/// Sends `signal` to every process in the group led by `pid`.
+fn kill_group(pid: pid_t, signal: int) {
+    let _ = Process::kill(-pid, signal);
+}
+
+/// The process groups of the commands that are running.
+pub struct ProcessGroups {
+    pids: Mutex<Vec<pid_t>>,
+}
+
+impl ProcessGroups {
+    pub fn new() -> ProcessGroups {
+        ProcessGroups {
+            pids: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn add(&self, pid: pid_t) {
+        self.pids.lock().push(pid);
+    }
+
+    fn remove(&self, pid: pid_t) {
+        let mut pids = self.pids.lock();
+        match pids.iter().position(|p| *p == pid) {
+            Some(idx) => { pids.remove(idx); }
+            None => { }
+        }
+    }
+
+    /// Send `signal` to every running command and whatever it started.
+    pub fn kill_all(&self, signal: int) {
+        for pid in self.pids.lock().iter() {
+            kill_group(*pid, signal);
+        }
+    }
+}
+
+/// Ships compiles to workers started with `serve`, distcc style. Sources are
+/// preprocessed locally, so workers only need a compatible compiler and no
+/// headers. Anything that isn't a single source compile, such as a link or an
+/// archive, runs locally, as does any compile whose worker can't be reached.
+pub struct RemoteExecutor {
+    hosts: Vec<SocketAddr>,
+    next: AtomicUint,
+}
+
+impl RemoteExecutor {
+    pub fn new(hosts: Vec<SocketAddr>) -> RemoteExecutor {
+        assert!(!hosts.is_empty());
+
+        RemoteExecutor {
+            hosts: hosts,
+            next: AtomicUint::new(0),
+        }
+    }
+
+    fn next_host(&self) -> SocketAddr {
+        let idx = self.next.fetch_add(1, SeqCst);
+        *self.hosts.get(idx % self.hosts.len())
+    }
+
+    fn compile(&self, program: &str, job: &CompileJob, source: &[u8], dst: &Path) -> IoResult<ProcessOutput> {
+        let mut stream = try!(TcpStream::connect(self.next_host()));
+
+        try!(write_str(&mut stream, program));
+        try!(write_str(&mut stream, job.lang));
+        try!(stream.write_be_u32(job.flags.len() as u32));
+        for flag in job.flags.iter() {
+            try!(write_str(&mut stream, *flag));
+        }
+        try!(write_bytes(&mut stream, source));
+
+        let output = try!(read_output(&mut stream));
+        let object = try!(read_bytes(&mut stream, MAX_DATA_LEN));
+
+        if output.status.success() {
+            let mut file = try!(File::create(dst));
+            try!(file.write(object.as_slice()));
+        }
+
+        Ok(output)
+    }
+}
+
+impl Executor for RemoteExecutor {
+    /// The preprocessor and anything run locally get all of `options`. A
+    /// command that reads stdin always runs locally, since the workers only
+    /// get the preprocessed source.
+    fn execute_with<'a>(
+        &self,
+        config: ProcessConfig<'a>,
+        options: &ExecOptions
+    ) -> IoResult<ProcessOutput> {
+        let program = config.program;
+
+        if options.stdin.is_some() {
+            return LocalExecutor.execute_with(config, options);
+        }
+
+        let job = match CompileJob::parse(config.args) {
+            Some(job) => job,
+            None => { return LocalExecutor.execute_with(config, options); }
+        };
+
+        // The preprocessed source is captured, not streamed to the console.
+        let preprocess_options = ExecOptions {
+            groups: options.groups.clone(),
+            timeout: options.timeout,
+            stream: None,
+            stdin: None,
+        };
+
+        let preprocessed = try!(LocalExecutor.execute_with(ProcessConfig {
+            program: program,
+            args: job.preprocess_args.as_slice(),
+            env: config.env,
+            cwd: config.cwd,
+            .. ProcessConfig::new()
+        }, &preprocess_options));
+
+        if !preprocessed.status.success() {
+            return Ok(preprocessed);
+        }
+
+        // The output is relative to the directory the compile runs in.
+        let dst = match config.cwd {
+            Some(cwd) => cwd.join(job.output.as_slice()),
+            None => Path::new(job.output.as_slice()),
+        };
+
+        match self.compile(program, &job, preprocessed.output.as_slice(), &dst) {
+            Ok(output) => {
+                match options.stream {
+                    Some(ref prefix) => { echo(prefix.as_slice(), &output); }
+                    None => { }
+                }
+                Ok(output)
+            }
+            Err(err) => {
+                debug!("remote compile of {} failed, running locally: {}", job.source, err);
+                LocalExecutor.execute_with(config, options)
+            }
+        }
+    }
+}
+
+/// A single source compile, split into what has to happen locally (the
+/// preprocessor) and what can happen remotely.
+struct CompileJob {
+    source: ~str,
+    output: ~str,
+    lang: &'static str,
+    preprocess_args: Vec<~str>,
+    flags: Vec<~str>,
+}
+
+/// Options that only matter to the preprocessor and take a separate value.
+static PREPROCESS_OPTS: &'static [&'static str] = &[
+    "-I", "-D", "-U", "-include", "-isystem", "-MF", "-MT", "-MQ",
+];
+
+fn preprocessed_lang(source: &str) -> Option<&'static str> {
+    match Path::new(source).extension_str() {
+        Some("c") => Some("cpp-output"),
+        Some("cc") | Some("cpp") | Some("cxx") | Some("C") => Some("c++-cpp-output"),
+        Some("m") => Some("objective-c-cpp-output"),
+        Some("mm") => Some("objective-c++-cpp-output"),
+        _ => None,
+    }
+}
+
+impl CompileJob {
+    fn parse(args: &[~str]) -> Option<CompileJob> {
+        let mut compile = false;
+        let mut source = None;
+        let mut output = None;
+        let mut preprocess_args = Vec::new();
+        let mut flags = Vec::new();
+
+        let mut iter = args.iter();
+        loop {
+            let arg = match iter.next() {
+                Some(arg) => arg.as_slice(),
+                None => { break; }
+            };
+
+            if arg == "-c" {
+                compile = true;
+            } else if arg == "-o" {
+                output = iter.next().map(|arg| arg.clone());
+            } else if arg == "-x" {
+                // Explicit languages aren't worth guessing about.
+                return None;
+            } else if PREPROCESS_OPTS.contains(&arg) {
+                preprocess_args.push(arg.to_owned());
+                match iter.next() {
+                    Some(value) => { preprocess_args.push(value.clone()); }
+                    None => { return None; }
+                }
+            } else if arg.starts_with("-I") || arg.starts_with("-D") ||
+                      arg.starts_with("-U") || arg.starts_with("-M") {
+                preprocess_args.push(arg.to_owned());
+            } else if arg.starts_with("-") {
+                // The server would refuse the compile.
+                if !remote_flag_allowed(arg) {
+                    return None;
+                }
+
+                // Flags such as -O2 can change predefined macros, so the
+                // preprocessor needs them as well as the compiler.
+                preprocess_args.push(arg.to_owned());
+                flags.push(arg.to_owned());
+            } else if source.is_none() && preprocessed_lang(arg).is_some() {
+                source = Some(arg.to_owned());
+            } else {
+                return None;
+            }
+        }
+
+        match (compile, source, output) {
+            (true, Some(source), Some(output)) => {
+                preprocess_args.push(~"-E");
+                preprocess_args.push(source.clone());
+
+                Some(CompileJob {
+                    lang: preprocessed_lang(source.as_slice()).unwrap(),
+                    source: source,
+                    output: output,
+                    preprocess_args: preprocess_args,
+                    flags: flags,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The most flags a remote compile may have.
+static MAX_ARGS: uint = 1024;
+
+/// The longest program name, language or flag in a request.
+static MAX_STR_LEN: uint = 64 * 1024;
+
+/// The largest source, object or process output sent either way.
+static MAX_DATA_LEN: uint = 512 * 1024 * 1024;
+
+/// The flags the server accepts, by prefix. Anything else, such as
+/// `-specs=`, `-wrapper` or an `@file`, could make the compiler run other
+/// programs or read files on the server.
+static REMOTE_FLAG_PREFIXES: &'static [&'static str] = &[
+    "-O", "-g", "-f", "-m", "-W", "-std=", "-pedantic",
+];
+
+static REMOTE_FLAGS: &'static [&'static str] = &[
+    "-w", "-ansi", "-pipe", "-pthread",
+];
+
+/// Allowed prefixes whose flags load code or read and write files.
+static REMOTE_DENIED_PREFIXES: &'static [&'static str] = &[
+    "-Wl,", "-Wa,", "-Wp,", "-fplugin", "-fprofile", "-fauto-profile", "-fdump",
+    "-fsanitize-blacklist", "-fsanitize-ignorelist", "-fdebug-prefix-map",
+];
+
+/// Whether a remote compile may be given `flag`. The values of flags may
+/// not be absolute paths or climb out of the server's directory.
+fn remote_flag_allowed(flag: &str) -> bool {
+    let allowed = REMOTE_FLAGS.contains(&flag) ||
+        REMOTE_FLAG_PREFIXES.iter().any(|prefix| flag.starts_with(*prefix));
+    let denied = REMOTE_DENIED_PREFIXES.iter().any(|prefix| flag.starts_with(*prefix));
+
+    let value = match flag.find('=') {
+        Some(i) => flag.slice_from(i + 1),
+        None => "",
+    };
+    let paths_ok = value.split(',').all(|part| {
+        !part.starts_with("/") && !part.starts_with("\\") && !part.contains("..") &&
+            !(part.len() > 1 && part.char_at(1) == ':')
+    });
+
+    allowed && !denied && paths_ok
+}
+
+/// The languages a client may send, those `preprocessed_lang` returns.
+static REMOTE_LANGS: &'static [&'static str] = &[
+    "cpp-output", "c++-cpp-output", "objective-c-cpp-output", "objective-c++-cpp-output",
+];
+
+fn refuse(desc: &'static str, detail: ~str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: Some(detail),
+    }
+}
+
+/// Serve remote compiles on `addr` until the listener fails. Only programs
+/// named in `compilers` will be run, with the flags `remote_flag_allowed`
+/// accepts. The preprocessed source is compiled in a temporary directory,
+/// but this should still only listen on a trusted network.
+pub fn serve(addr: SocketAddr, compilers: &'static [&'static str]) -> IoResult<()> {
+    let listener = try!(TcpListener::bind(addr));
+    let mut acceptor = try!(listener.listen());
+
+    for stream in acceptor.incoming() {
+        match stream {
+            Ok(stream) => {
+                spawn(proc() {
+                    match handle(stream, compilers) {
+                        Ok(()) => { }
+                        Err(err) => { error!("remote compile failed: {}", err); }
+                    }
+                });
+            }
+            Err(err) => { error!("could not accept connection: {}", err); }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, compilers: &'static [&'static str]) -> IoResult<()> {
+    let program = try!(read_str(&mut stream));
+    if !compilers.contains(&program.as_slice()) {
+        return Err(refuse("refusing to run program", program));
+    }
+
+    let lang = try!(read_str(&mut stream));
+    if !REMOTE_LANGS.contains(&lang.as_slice()) {
+        return Err(refuse("refusing to compile language", lang));
+    }
+
+    let count = try!(stream.read_be_u32()) as uint;
+    if count > MAX_ARGS {
+        return Err(refuse("too many flags", count.to_str()));
+    }
+
+    let mut args = Vec::new();
+    for _ in range(0, count) {
+        let arg = try!(read_str(&mut stream));
+        if !remote_flag_allowed(arg.as_slice()) {
+            return Err(refuse("refusing flag", arg));
+        }
+        args.push(arg);
+    }
+
+    let source = try!(read_bytes(&mut stream, MAX_DATA_LEN));
+
+    let dir = match TempDir::new("rbuild") {
+        Some(dir) => dir,
+        None => {
+            return Err(IoError {
+                kind: io::OtherIoError,
+                desc: "could not create temporary directory",
+                detail: None,
+            });
+        }
+    };
+
+    let src = dir.path().join("input");
+    let obj = dir.path().join("output.o");
+    try!(File::create(&src).write(source.as_slice()));
+
+    args.push(~"-x");
+    args.push(lang);
+    args.push(~"-c");
+    args.push(src.as_str().unwrap().to_owned());
+    args.push(~"-o");
+    args.push(obj.as_str().unwrap().to_owned());
+
+    // Anything the compiler writes next to its output stays in the
+    // temporary directory.
+    let output = try!(LocalExecutor.execute(ProcessConfig {
+        program: program.as_slice(),
+        args: args.as_slice(),
+        cwd: Some(dir.path()),
+        .. ProcessConfig::new()
+    }));
+
+    try!(write_output(&mut stream, &output));
+
+    if output.status.success() {
+        let object = try!(File::open(&obj).read_to_end());
+        write_bytes(&mut stream, object.as_slice())
+    } else {
+        write_bytes(&mut stream, [])
+    }
+}
+
+fn write_bytes<W: Writer>(w: &mut W, bytes: &[u8]) -> IoResult<()> {
+    try!(w.write_be_u32(bytes.len() as u32));
+    w.write(bytes)
+}
+
+fn write_str<W: Writer>(w: &mut W, s: &str) -> IoResult<()> {
+    write_bytes(w, s.as_bytes())
+}
+
+/// Reads a length and that many bytes, failing if the length is over
+/// `limit` rather than trusting it for the allocation.
+fn read_bytes<R: Reader>(r: &mut R, limit: uint) -> IoResult<Vec<u8>> {
+    let len = try!(r.read_be_u32()) as uint;
+    if len > limit {
+        return Err(refuse("message too long", len.to_str()));
+    }
+    let bytes = try!(r.read_exact(len));
+    Ok(bytes.move_iter().collect())
+}
+
+fn read_str<R: Reader>(r: &mut R) -> IoResult<~str> {
+    let bytes = try!(read_bytes(r, MAX_STR_LEN));
+    match str::from_utf8(bytes.as_slice()) {
+        Some(s) => Ok(s.to_owned()),
+        None => {
+            Err(IoError {
+                kind: io::InvalidInput,
+                desc: "invalid utf-8 in remote compile request",
+                detail: None,
+            })
+        }
+    }
+}
+
+fn write_output<W: Writer>(w: &mut W, output: &ProcessOutput) -> IoResult<()> {
+    match output.status {
+        ExitStatus(code) => {
+            try!(w.write_u8(0));
+            try!(w.write_be_i32(code as i32));
+        }
+        ExitSignal(signal) => {
+            try!(w.write_u8(1));
+            try!(w.write_be_i32(signal as i32));
+        }
+    }
+
+    try!(write_bytes(w, output.output.as_slice()));
+    write_bytes(w, output.error.as_slice())
+}
+
+fn read_output<R: Reader>(r: &mut R) -> IoResult<ProcessOutput> {
+    let kind = try!(r.read_u8());
+    let code = try!(r.read_be_i32()) as int;
+    let status = if kind == 0 { ExitStatus(code) } else { ExitSignal(code) };
+
+    let output = try!(read_bytes(r, MAX_DATA_LEN));
+    let error = try!(read_bytes(r, MAX_DATA_LEN));
+
+    Ok(ProcessOutput {
+        status: status,
+        output: output.move_iter().collect(),
+        error: error.move_iter().collect(),
+    })
+}
@@ -0,0 +1,285 @@
+use std::io::IoResult;
+use collections::TreeMap;
+
+use shell;
+
+/// A step in the build, as recorded when its call was declared.
+pub struct Node {
+    pub label: ~str,
+    /// The command line, quoted for a shell.
+    pub command: ~str,
+    pub inputs: Vec<Path>,
+    pub outputs: Vec<Path>,
+    /// The makefile-style depfile the command writes, listing the headers
+    /// and other files it read.
+    pub depfile: Option<Path>,
+}
+
+/// The producer/consumer graph of every step declared so far. Edges are
+/// implied by paths: a step depends on whichever step produces one of its
+/// inputs.
+pub struct Graph {
+    nodes: Vec<Node>,
+    producers: TreeMap<Vec<u8>, uint>,
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph {
+            nodes: Vec::new(),
+            producers: TreeMap::new(),
+        }
+    }
+
+    pub fn nodes<'a>(&'a self) -> &'a [Node] {
+        self.nodes.as_slice()
+    }
+
+    /// Returns the index of the step that produces `path`, if any.
+    pub fn producer(&self, path: &Path) -> Option<uint> {
+        self.producers.find(&Vec::from_slice(path.as_vec())).map(|idx| *idx)
+    }
+
+    /// Returns the steps that produce the inputs of step `idx`.
+    pub fn dependencies(&self, idx: uint) -> Vec<uint> {
+        let mut deps = Vec::new();
+        for input in self.nodes.get(idx).inputs.iter() {
+            match self.producer(input) {
+                Some(dep) if !deps.contains(&dep) => { deps.push(dep); }
+                _ => { }
+            }
+        }
+        deps
+    }
+
+    /// Returns the outputs no step consumes, along with the index of the
+    /// step producing each: the final artifacts of the build.
+    pub fn final_outputs(&self) -> Vec<(uint, Path)> {
+        let mut outputs = Vec::new();
+
+        for (idx, node) in self.nodes.iter().enumerate() {
+            for output in node.outputs.iter() {
+                if !self.nodes.iter().any(|node| node.inputs.contains(output)) {
+                    outputs.push((idx, output.clone()));
+                }
+            }
+        }
+
+        outputs
+    }
+
+    /// Add a step, failing with a description of the cycle if it would
+    /// (transitively) depend on one of its own outputs.
+    pub fn add_node(&mut self,
+                    label: ~str,
+                    command: ~str,
+                    inputs: Vec<Path>,
+                    outputs: Vec<Path>) -> Result<uint, ~str> {
+        let node = Node {
+            label: label,
+            command: command,
+            inputs: inputs,
+            outputs: outputs,
+            depfile: None,
+        };
+
+        match self.find_cycle(&node) {
+            Some(cycle) => {
+                let mut msg = format!("dependency cycle: {}", node.label);
+                for idx in cycle.iter() {
+                    msg.push_str(format!(" -> {}", self.nodes.get(*idx).label));
+                }
+                msg.push_str(format!(" -> {}", node.label));
+                return Err(msg);
+            }
+            None => { }
+        }
+
+        let idx = self.nodes.len();
+        for output in node.outputs.iter() {
+            self.producers.insert(Vec::from_slice(output.as_vec()), idx);
+        }
+        self.nodes.push(node);
+
+        Ok(idx)
+    }
+
+    /// Replace the command of step `idx` with the one that actually runs,
+    /// along with the depfile it writes.
+    pub fn set_command(&mut self, idx: uint, command: ~str, depfile: Option<Path>) {
+        let node = self.nodes.get_mut(idx);
+        node.command = command;
+        node.depfile = depfile;
+    }
+
+    /// Returns the inputs under `dir` that no step produces. When `dir` is
+    /// the build root, these are written by steps that don't run a command,
+    /// such as downloads, which an exported build can't rerun.
+    pub fn unproduced_inputs(&self, dir: &Path) -> Vec<Path> {
+        let mut inputs = Vec::new();
+
+        for node in self.nodes.iter() {
+            for input in node.inputs.iter() {
+                if dir.is_ancestor_of(input) && self.producer(input).is_none() && !inputs.contains(input) {
+                    inputs.push(input.clone());
+                }
+            }
+        }
+
+        inputs
+    }
+
+    /// Walks back from the producers of `node`'s inputs looking for a step
+    /// that consumes one of `node`'s outputs.
+    fn find_cycle(&self, node: &Node) -> Option<Vec<uint>> {
+        fn consumes(node: &Node, outputs: &[Path]) -> bool {
+            node.inputs.iter().any(|input| outputs.contains(input))
+        }
+
+        if consumes(node, node.outputs.as_slice()) {
+            return Some(Vec::new());
+        }
+
+        let mut visited = Vec::from_elem(self.nodes.len(), false);
+        let mut stack = Vec::new();
+
+        for input in node.inputs.iter() {
+            match self.producer(input) {
+                Some(idx) => {
+                    if self.walk(idx, node.outputs.as_slice(), &mut visited, &mut stack) {
+                        return Some(stack);
+                    }
+                }
+                None => { }
+            }
+        }
+
+        None
+    }
+
+    fn walk(&self,
+            idx: uint,
+            outputs: &[Path],
+            visited: &mut Vec<bool>,
+            stack: &mut Vec<uint>) -> bool {
+        if *visited.get(idx) {
+            return false;
+        }
+        *visited.get_mut(idx) = true;
+        stack.push(idx);
+
+        let node = self.nodes.get(idx);
+        if node.inputs.iter().any(|input| outputs.contains(input)) {
+            return true;
+        }
+
+        for dep in self.dependencies(idx).move_iter() {
+            if self.walk(dep, outputs, visited, stack) {
+                return true;
+            }
+        }
+
+        stack.pop();
+        false
+    }
+
+    /// Write the graph in graphviz dot format.
+    pub fn write_dot<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        try!(w.write_str("digraph rbuild {\n"));
+
+        for (idx, node) in self.nodes.iter().enumerate() {
+            try!(writeln!(w, "    n{} [label=\"{}\"];", idx, node.label.escape_default()));
+        }
+
+        for idx in range(0, self.nodes.len()) {
+            for dep in self.dependencies(idx).iter() {
+                try!(writeln!(w, "    n{} -> n{};", *dep, idx));
+            }
+        }
+
+        w.write_str("}\n")
+    }
+
+    /// Write the graph as a ninja file with one build statement per step.
+    pub fn write_ninja<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        try!(w.write_str("# Generated by rbuild.\n\n"));
+        try!(w.write_str("rule run\n  command = $command\n  description = $label\n"));
+
+        for node in self.nodes.iter() {
+            let outputs: Vec<~str> = node.outputs.iter().map(|p| ninja_path(p)).collect();
+            let inputs: Vec<~str> = node.inputs.iter().map(|p| ninja_path(p)).collect();
+
+            try!(writeln!(w, "\nbuild {}: run {}", outputs.as_slice().connect(" "), inputs.as_slice().connect(" ")));
+            try!(writeln!(w, "  command = {}", node.command.replace("$", "$$")));
+            try!(writeln!(w, "  label = {}", node.label.replace("$", "$$")));
+
+            // Ninja reads the headers out of the depfile, so editing one
+            // reruns the commands that include it.
+            match node.depfile {
+                Some(ref depfile) => {
+                    try!(writeln!(w, "  depfile = {}", ninja_path(depfile)));
+                    try!(w.write_str("  deps = gcc\n"));
+                }
+                None => { }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the graph as a makefile whose default target builds the final
+    /// outputs. A step with several outputs is a rule for the first, which
+    /// the others depend on.
+    pub fn write_makefile<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        try!(w.write_str("# Generated by rbuild.\n\n"));
+
+        let finals: Vec<~str> = self.final_outputs().iter().map(|&(_, ref p)| make_path(p)).collect();
+        try!(writeln!(w, "all: {}\n.PHONY: all", finals.as_slice().connect(" ")));
+
+        for node in self.nodes.iter() {
+            let first = match node.outputs.as_slice().head() {
+                Some(first) => make_path(first),
+                None => { continue; }
+            };
+            let inputs: Vec<~str> = node.inputs.iter().map(|p| make_path(p)).collect();
+
+            try!(writeln!(w, "\n{}: {}", first, inputs.as_slice().connect(" ")));
+            let mut dirs: Vec<Path> = Vec::new();
+            for output in node.outputs.iter() {
+                let dir = output.dir_path();
+                if !dirs.contains(&dir) {
+                    try!(writeln!(w, "\t@mkdir -p {}", shell::quote(dir.as_str().unwrap()).replace("$", "$$")));
+                    dirs.push(dir);
+                }
+            }
+            try!(writeln!(w, "\t{}", node.command.replace("$", "$$")));
+
+            for output in node.outputs.tail().iter() {
+                try!(writeln!(w, "{}: {}", make_path(output), first));
+            }
+        }
+
+        // The depfiles are rules of their own, which make merges in once
+        // the commands have written them.
+        let depfiles: Vec<~str> = self.nodes.iter().filter_map(|node| {
+            node.depfile.as_ref().map(|depfile| make_path(depfile))
+        }).collect();
+        if !depfiles.is_empty() {
+            try!(writeln!(w, "\n-include {}", depfiles.as_slice().connect(" ")));
+        }
+
+        Ok(())
+    }
+}
+
+/// `path` escaped for a ninja build statement.
+fn ninja_path(path: &Path) -> ~str {
+    let path = path.display().to_str();
+    path.replace("$", "$$").replace(" ", "$ ").replace(":", "$:")
+}
+
+/// `path` escaped for a make rule.
+fn make_path(path: &Path) -> ~str {
+    let path = path.display().to_str();
+    path.replace("$", "$$").replace(" ", "\\ ").replace(":", "\\:")
+}
@@ -0,0 +1,149 @@
+//! Finds the headers a C-family source includes by scanning it for
+//! `#include` directives, for compilers and tools that can't report their
+//! dependencies. It doesn't evaluate the preprocessor, so headers behind
+//! `#if`s that are off are included too, and computed includes are missed:
+//!
+//! ```ignore
+//! let scanner = IncludeScanner::new(ctx.clone()).add_include("include");
+//! for header in scanner.scan(&src).move_iter() {
+//!     prep.declare_input_path(header).unwrap();
+//! }
+//! ```
+
+use std::io::File;
+
+use context::Context;
+use into_path::IntoPath;
+
+#[deriving(Clone, Encodable, Decodable)]
+struct Directive {
+    /// Whether the header was named with `<>`, so that it isn't looked for
+    /// next to the file including it.
+    angle: bool,
+    name: ~str,
+}
+
+#[deriving(Clone)]
+pub struct IncludeScanner {
+    ctx: Context,
+    includes: Vec<Path>,
+}
+
+impl IncludeScanner {
+    pub fn new(ctx: Context) -> IncludeScanner {
+        IncludeScanner {
+            ctx: ctx,
+            includes: Vec::new(),
+        }
+    }
+
+    /// Add a directory to search for headers, in the order of `-I` flags.
+    pub fn add_include<T: IntoPath>(mut self, include: T) -> IncludeScanner {
+        self.includes.push(include.into_path());
+        self
+    }
+
+    /// Returns every header `src` includes, directly or through other
+    /// headers, that can be found. Headers that can't, such as those of the
+    /// system, are skipped.
+    pub fn scan(&self, src: &Path) -> Vec<Path> {
+        let mut found = Vec::new();
+        let mut pending = vec!(src.clone());
+
+        loop {
+            let path = match pending.pop() {
+                Some(path) => path,
+                None => { break; }
+            };
+
+            for directive in self.directives(&path).iter() {
+                match self.resolve(directive, &path) {
+                    Some(header) => {
+                        if header != *src && !found.contains(&header) {
+                            found.push(header.clone());
+                            pending.push(header);
+                        }
+                    }
+                    None => { }
+                }
+            }
+        }
+
+        found
+    }
+
+    /// The directives in `path`, which are cached by the digest of the
+    /// file, so unchanged files aren't read again.
+    fn directives(&self, path: &Path) -> Vec<Directive> {
+        let mut prep = self.ctx.prep("IncludeScanner");
+        prep.declare_input_path(path.clone()).unwrap();
+
+        let path = path.clone();
+        prep.exec(proc(_exec) {
+            let contents = File::open(&path).read_to_str().unwrap();
+            parse(contents)
+        }).unwrap()
+    }
+
+    fn resolve(&self, directive: &Directive, from: &Path) -> Option<Path> {
+        if !directive.angle {
+            let header = from.dir_path().join(directive.name.as_slice());
+            if header.is_file() {
+                return Some(header);
+            }
+        }
+
+        for include in self.includes.iter() {
+            let header = include.join(directive.name.as_slice());
+            if header.is_file() {
+                return Some(header);
+            }
+        }
+
+        None
+    }
+}
+
+/// Returns the `#include` and `#import` directives in `s`.
+fn parse(s: &str) -> Vec<Directive> {
+    let mut directives = Vec::new();
+
+    for line in s.lines_any() {
+        let line = line.trim_left();
+        if !line.starts_with("#") {
+            continue;
+        }
+
+        let line = line.slice_from(1).trim_left();
+        let rest = if line.starts_with("include") {
+            line.slice_from("include".len())
+        } else if line.starts_with("import") {
+            line.slice_from("import".len())
+        } else {
+            continue;
+        };
+        let rest = rest.trim_left();
+
+        let (angle, close) = if rest.starts_with("\"") {
+            (false, '"')
+        } else if rest.starts_with("<") {
+            (true, '>')
+        } else {
+            // A computed include, which we can't follow.
+            continue;
+        };
+
+        let rest = rest.slice_from(1);
+        match rest.find(close) {
+            Some(end) => {
+                directives.push(Directive {
+                    angle: angle,
+                    name: rest.slice_to(end).to_owned(),
+                });
+            }
+            None => { }
+        }
+    }
+
+    directives
+}
@@ -23,8 +23,65 @@ impl IntoFuture<Path> for Path {
     }
 }
 
+impl<'a> IntoFuture<~str> for &'a str {
+    fn into_future(self) -> Future<~str> {
+        Future::from_value(self.to_owned())
+    }
+}
+
+impl IntoFuture<~str> for ~str {
+    fn into_future(self) -> Future<~str> {
+        Future::from_value(self)
+    }
+}
+
 impl<T> IntoFuture<T> for Future<T> {
     fn into_future(self) -> Future<T> {
         self
     }
 }
+
+impl<T: Send> IntoFuture<Vec<T>> for Vec<T> {
+    fn into_future(self) -> Future<Vec<T>> {
+        Future::from_value(self)
+    }
+}
+
+impl<T: Send> IntoFuture<Vec<T>> for Vec<Future<T>> {
+    fn into_future(self) -> Future<Vec<T>> {
+        Future::from_fn(proc() {
+            self.move_iter().map(|future| future.unwrap()).collect()
+        })
+    }
+}
+
+/// Wraps any value so it can be passed wherever an `IntoFuture` is expected.
+pub struct Value<T>(pub T);
+
+impl<T: Send> IntoFuture<T> for Value<T> {
+    fn into_future(self) -> Future<T> {
+        let Value(value) = self;
+        Future::from_value(value)
+    }
+}
+
+/// Adapters for chaining the values of cached steps. To keep the caching
+/// correct, a step consuming such a value should declare it with
+/// `Prep::declare_value`, so that it re-runs when the value changes.
+pub trait FutureExt<T> {
+    /// Transform the value once it is ready.
+    fn map<U: Send>(self, f: proc(T):Send -> U) -> Future<U>;
+
+    /// Wait for both futures.
+    fn join<U: Send>(self, other: Future<U>) -> Future<(T, U)>;
+}
+
+impl<T: Send> FutureExt<T> for Future<T> {
+    fn map<U: Send>(self, f: proc(T):Send -> U) -> Future<U> {
+        Future::from_fn(proc() f(self.unwrap()))
+    }
+
+    fn join<U: Send>(self, other: Future<U>) -> Future<(T, U)> {
+        Future::from_fn(proc() (self.unwrap(), other.unwrap()))
+    }
+}
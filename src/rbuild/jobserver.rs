@@ -0,0 +1,208 @@
+//! The GNU make jobserver protocol, so that rbuild and the makes it runs (or
+//! the make that runs it) share one pool of job slots.
+//!
+//! Every process in the pool owns one implicit slot. Any further concurrent
+//! job needs a token: a byte read from the jobserver pipe, which is written
+//! back once the job is done.
+
+use std::io;
+use std::io::{File, IoError, IoResult, Open, ReadWrite, TempDir};
+use std::io::pipe::PipeStream;
+use std::os;
+use libc;
+use sync::Mutex;
+
+pub struct JobServer {
+    reader: Mutex<~Reader:Send>,
+    writer: Mutex<~Writer:Send>,
+    implicit_free: Mutex<bool>,
+    /// What to pass on to child processes in `MAKEFLAGS`.
+    makeflags: ~str,
+    /// Keeps the fifo of a jobserver we created alive.
+    #[allow(dead_code)]
+    dir: Option<TempDir>,
+}
+
+impl JobServer {
+    /// Whether `new` can create a jobserver on this platform. Elsewhere,
+    /// rbuild limits only its own jobs.
+    pub fn is_supported() -> bool {
+        cfg!(unix)
+    }
+
+    /// Join the jobserver advertised in `MAKEFLAGS`, if we were started by
+    /// a make that has one.
+    pub fn from_env() -> Option<JobServer> {
+        let makeflags = match os::getenv("MAKEFLAGS") {
+            Some(makeflags) => makeflags,
+            None => { return None; }
+        };
+
+        let auth = makeflags.words()
+            .filter_map(|word| {
+                if word.starts_with("--jobserver-auth=") {
+                    Some(word.slice_from("--jobserver-auth=".len()))
+                } else if word.starts_with("--jobserver-fds=") {
+                    Some(word.slice_from("--jobserver-fds=".len()))
+                } else {
+                    None
+                }
+            })
+            .last();
+
+        let auth = match auth {
+            Some(auth) => auth,
+            None => { return None; }
+        };
+
+        let server = if auth.starts_with("fifo:") {
+            JobServer::open_fifo(&Path::new(auth.slice_from("fifo:".len())), makeflags.clone(), None)
+        } else {
+            let fds: Vec<Option<libc::c_int>> = auth.split(',').map(|fd| from_str(fd)).collect();
+            if fds.len() != 2 {
+                return None;
+            }
+
+            match (*fds.get(0), *fds.get(1)) {
+                (Some(read_fd), Some(write_fd)) => JobServer::open_fds(read_fd, write_fd, makeflags.clone()),
+                _ => { return None; }
+            }
+        };
+
+        // If make didn't pass the pipe on to us (e.g. the rule wasn't marked
+        // with `+`), just run without it.
+        server.ok()
+    }
+
+    /// Create a jobserver with `jobs` slots, to bound the total parallelism
+    /// of rbuild and any makes it runs. Children find it through a fifo,
+    /// which needs GNU make 4.4 or later.
+    pub fn new(jobs: uint) -> IoResult<JobServer> {
+        assert!(jobs > 0);
+
+        let dir = match TempDir::new("rbuild-jobserver") {
+            Some(dir) => dir,
+            None => {
+                return Err(IoError {
+                    kind: io::OtherIoError,
+                    desc: "could not create the jobserver directory",
+                    detail: None,
+                });
+            }
+        };
+
+        let fifo = dir.path().join("fifo");
+        try!(mkfifo(&fifo));
+
+        let makeflags = format!("-j{} --jobserver-auth=fifo:{}", jobs, fifo.display());
+        let server = try!(JobServer::open_fifo(&fifo, makeflags, Some(dir)));
+
+        // We hold the implicit slot, so there are `jobs - 1` tokens.
+        {
+            let mut writer = server.writer.lock();
+            for _ in range(1, jobs) {
+                try!(writer.write_u8('+' as u8));
+            }
+            try!(writer.flush());
+        }
+
+        Ok(server)
+    }
+
+    fn open_fifo(path: &Path, makeflags: ~str, dir: Option<TempDir>) -> IoResult<JobServer> {
+        // Opening both ends read-write keeps the open from blocking.
+        let reader = try!(File::open_mode(path, Open, ReadWrite));
+        let writer = try!(File::open_mode(path, Open, ReadWrite));
+
+        Ok(JobServer {
+            reader: Mutex::new(~reader as ~Reader:Send),
+            writer: Mutex::new(~writer as ~Writer:Send),
+            implicit_free: Mutex::new(true),
+            makeflags: makeflags,
+            dir: dir,
+        })
+    }
+
+    fn open_fds(read_fd: libc::c_int, write_fd: libc::c_int, makeflags: ~str) -> IoResult<JobServer> {
+        let reader = try!(PipeStream::open(read_fd));
+        let writer = try!(PipeStream::open(write_fd));
+
+        Ok(JobServer {
+            reader: Mutex::new(~reader as ~Reader:Send),
+            writer: Mutex::new(~writer as ~Writer:Send),
+            implicit_free: Mutex::new(true),
+            makeflags: makeflags,
+            dir: None,
+        })
+    }
+
+    /// Wait for a job slot. The slot is given back when the token is
+    /// dropped.
+    pub fn acquire<'a>(&'a self) -> IoResult<Token<'a>> {
+        {
+            let mut implicit_free = self.implicit_free.lock();
+            if *implicit_free {
+                *implicit_free = false;
+                return Ok(Token { server: self, byte: None });
+            }
+        }
+
+        let byte = try!(self.reader.lock().read_u8());
+        Ok(Token { server: self, byte: Some(byte) })
+    }
+
+    /// The environment for a child process, with `MAKEFLAGS` pointing at
+    /// this jobserver.
+    pub fn child_env(&self) -> Vec<(~str, ~str)> {
+        let mut env: Vec<(~str, ~str)> = os::env().move_iter()
+            .filter(|&(ref key, _)| key.as_slice() != "MAKEFLAGS")
+            .collect();
+        env.push((~"MAKEFLAGS", self.makeflags.clone()));
+        env
+    }
+
+    fn release(&self, byte: Option<u8>) {
+        match byte {
+            Some(byte) => {
+                let mut writer = self.writer.lock();
+                writer.write_u8(byte).and_then(|()| writer.flush()).unwrap();
+            }
+            None => {
+                *self.implicit_free.lock() = true;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn mkfifo(path: &Path) -> IoResult<()> {
+    let ret = path.with_c_str(|path| unsafe { libc::mkfifo(path, 0o600) });
+    if ret != 0 {
+        return Err(IoError::last_error());
+    }
+    Ok(())
+}
+
+/// Make's jobserver uses a named semaphore on Windows, which isn't
+/// supported.
+#[cfg(not(unix))]
+fn mkfifo(_path: &Path) -> IoResult<()> {
+    Err(IoError {
+        kind: io::OtherIoError,
+        desc: "jobservers aren't supported on this platform",
+        detail: None,
+    })
+}
+
+/// A job slot, returned to the pool when dropped.
+pub struct Token<'a> {
+    server: &'a JobServer,
+    byte: Option<u8>,
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for Token<'a> {
+    fn drop(&mut self) {
+        self.server.release(self.byte);
+    }
+}
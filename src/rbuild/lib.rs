@@ -2,20 +2,39 @@
 #![crate_type = "dylib"]
 #![crate_type = "rlib"]
 
-#![feature(phase)]
+#![feature(macro_rules, phase)]
 
 extern crate collections;
+extern crate libc;
 extern crate serialize;
 extern crate sync;
 extern crate term;
+extern crate time;
 
 #[phase(syntax, link)]
 extern crate log;
 
+pub mod archive;
 pub mod builders;
+pub mod config;
+pub mod console;
 pub mod context;
+pub mod depfile;
+pub mod executor;
+pub mod graph;
+pub mod include_scanner;
 pub mod into_future;
 pub mod into_path;
+pub mod jobserver;
 pub mod path_util;
 pub mod process_builder;
+pub mod report;
+pub mod scheduler;
+pub mod sha256;
+pub mod sha512;
+pub mod shared_future;
+pub mod shell;
+pub mod show_includes;
+pub mod tool_version;
 pub mod workcache;
+pub mod workspace;
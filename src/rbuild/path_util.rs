@@ -1,7 +1,14 @@
+use std::ascii::StrAsciiExt;
+use std::io;
+use std::io::{IoError, IoResult};
+use std::io::fs;
 use std::os;
 use sync::Future;
 
-use context::Context;
+use context::{Context, Exec};
+use shell;
+use tool_version;
+use tool_version::VersionReq;
 
 pub fn add_prefix_suffix(mut path: Path, prefix: Option<&str>, suffix: Option<&str>) -> Path {
     match (prefix, suffix) {
@@ -29,36 +36,474 @@ pub fn add_prefix_suffix(mut path: Path, prefix: Option<&str>, suffix: Option<&s
     path
 }
 
-pub fn find_program(ctx: Context, names: &'static [&'static str]) -> Future<Path> {
-    let mut prep = ctx.prep("find_program");
-    prep.declare_input("value", "names", &names);
+/// Returns every file under `dir` whose extension is one of `extensions`,
+/// sorted so that the result is stable between runs.
+pub fn find_files(dir: &Path, extensions: &[&str]) -> IoResult<Vec<Path>> {
+    find_files_with(dir, extensions, true, [])
+}
 
-    prep.exec(proc(exec) {
-        let paths = os::getenv("PATH").unwrap();
+/// Like `find_files`, optionally only looking at the files directly in
+/// `dir`, and skipping files whose path relative to `dir` matches one of the
+/// `excludes` glob patterns (e.g. "test_*" or "*/generated/*").
+pub fn find_files_with(
+    dir: &Path,
+    extensions: &[&str],
+    recursive: bool,
+    excludes: &[&str]
+) -> IoResult<Vec<Path>> {
+    let paths: Vec<Path> = if recursive {
+        try!(fs::walk_dir(dir)).collect()
+    } else {
+        try!(fs::readdir(dir))
+    };
 
-        for name in names.iter() {
-            print!("looking for program {}", name);
+    let mut files = Vec::new();
+
+    for path in paths.move_iter() {
+        let matches = match path.extension_str() {
+            Some(ext) => extensions.contains(&ext),
+            None => false,
+        };
+
+        let excluded = match path.path_relative_from(dir) {
+            Some(rel) => {
+                let rel = rel.as_str().unwrap();
+                excludes.iter().any(|pattern| glob_matches(*pattern, rel))
+            }
+            None => false,
+        };
+
+        if matches && !excluded && path.is_file() {
+            files.push(path);
+        }
+    }
+
+    files.sort_by(|a, b| a.as_vec().cmp(&b.as_vec()));
+
+    Ok(files)
+}
+
+/// Returns the subdirectories of `dir` whose name matches the glob
+/// `pattern`, sorted. The listing is cached with `dir` as an input, so
+/// adding a directory is noticed on the next run.
+pub fn find_dirs(ctx: Context, dir: &Path, pattern: &str) -> IoResult<Vec<Path>> {
+    let mut prep = ctx.prep("find_dirs");
+    try!(prep.declare_input_dir(dir.clone()));
+    prep.declare_input("value", "pattern", &pattern);
+
+    let dir = dir.clone();
+    let pattern = pattern.to_owned();
+
+    let dirs = prep.exec(proc(_exec) {
+        let mut dirs: Vec<Path> = fs::readdir(&dir).unwrap().move_iter().filter(|path| {
+            path.is_dir() && match path.filename_str() {
+                Some(name) => glob_matches(pattern.as_slice(), name),
+                None => false,
+            }
+        }).collect();
+
+        dirs.sort_by(|a, b| a.as_vec().cmp(&b.as_vec()));
+        dirs
+    }).unwrap();
+
+    Ok(dirs)
+}
+
+/// Matches `s` against a glob pattern where `*` matches any run of
+/// characters and `?` matches a single character.
+pub fn glob_matches(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    return matches(pattern.as_slice(), s.as_slice());
+
+    fn matches(pattern: &[char], s: &[char]) -> bool {
+        match pattern.head() {
+            None => s.is_empty(),
+            Some(&'*') => {
+                range(0, s.len() + 1).any(|i| matches(pattern.tail(), s.slice_from(i)))
+            }
+            Some(&'?') => !s.is_empty() && matches(pattern.tail(), s.tail()),
+            Some(c) => {
+                s.head() == Some(c) && matches(pattern.tail(), s.tail())
+            }
+        }
+    }
+}
+
+/// Puts paths in the form they take in cache keys, so that different
+/// spellings of the same file share an entry: `.` and `..` are resolved,
+/// paths under the anchor are made relative to it, and on case-insensitive
+/// file systems they are lowercased.
+#[deriving(Clone)]
+pub struct PathNormalizer {
+    anchor: Path,
+    fold_case: bool,
+}
+
+impl PathNormalizer {
+    pub fn new(anchor: Path) -> PathNormalizer {
+        let anchor = os::make_absolute(&anchor);
+        let fold_case = is_case_insensitive(&anchor);
+
+        PathNormalizer {
+            anchor: anchor,
+            fold_case: fold_case,
+        }
+    }
+
+    pub fn normalize(&self, path: &Path) -> Path {
+        // Joining onto an absolute path also resolves `.` and `..`.
+        let path = os::make_absolute(path);
+
+        let path = if self.anchor.is_ancestor_of(&path) {
+            path.path_relative_from(&self.anchor).unwrap()
+        } else {
+            path
+        };
+
+        if self.fold_case {
+            Path::new(path.as_str().unwrap().to_ascii_lower())
+        } else {
+            path
+        }
+    }
+}
+
+/// Returns whether the file system holding `dir` ignores case, by checking
+/// whether `dir` can also be found with the case of its name swapped.
+fn is_case_insensitive(dir: &Path) -> bool {
+    let name = match dir.filename_str() {
+        Some(name) => name,
+        None => { return false; }
+    };
+
+    let swapped: ~str = name.chars().map(|c| {
+        if c.is_uppercase() { c.to_lowercase() } else { c.to_uppercase() }
+    }).collect();
+
+    // Without any letters to go by, assume the platform's default.
+    if swapped.as_slice() == name {
+        return cfg!(windows) || cfg!(target_os = "macos");
+    }
+
+    match (dir.stat(), dir.with_filename(swapped).stat()) {
+        (Ok(a), Ok(b)) => {
+            a.unstable.device == b.unstable.device && a.unstable.inode == b.unstable.inode
+        }
+        _ => false,
+    }
+}
+
+/// Recursively copy the directory tree `src` to `dst`.
+pub fn copy_dir(src: &Path, dst: &Path) -> IoResult<()> {
+    try!(fs::mkdir_recursive(dst, io::UserDir));
+
+    for path in try!(fs::walk_dir(src)) {
+        let target = dst.join(path.path_relative_from(src).unwrap());
+
+        if path.is_dir() {
+            try!(fs::mkdir_recursive(&target, io::UserDir));
+        } else {
+            try!(fs::mkdir_recursive(&target.dir_path(), io::UserDir));
+            try!(fs::copy(&path, &target));
+        }
+    }
+
+    Ok(())
+}
+
+/// Environment variables that override the lookup of a program, following
+/// the usual make conventions.
+/// The name of the step that looks up a program, which can run before the
+/// build is validated.
+pub static FIND_PROGRAM: &'static str = "find_program";
+
+static ENV_OVERRIDES: &'static [(&'static str, &'static str)] = &[
+    ("gcc", "CC"),
+    ("cc", "CC"),
+    ("clang", "CC"),
+    ("g++", "CXX"),
+    ("c++", "CXX"),
+    ("clang++", "CXX"),
+    ("ar", "AR"),
+];
+
+#[cfg(not(windows))]
+static PATH_SEP: char = ':';
+
+#[cfg(windows)]
+static PATH_SEP: char = ';';
+
+/// The extensions to try when looking for `name`. On Windows these come from
+/// `PATHEXT`, unless the name already has one.
+#[cfg(not(windows))]
+fn program_exts(_name: &str) -> Vec<~str> {
+    vec!(~"")
+}
+
+#[cfg(windows)]
+fn program_exts(name: &str) -> Vec<~str> {
+    if Path::new(name).extension().is_some() {
+        return vec!(~"");
+    }
+
+    let pathext = os::getenv("PATHEXT").unwrap_or(~".COM;.EXE;.BAT;.CMD");
+    let mut exts = vec!(~"");
+    exts.extend(pathext.split(';').filter(|ext| !ext.is_empty()).map(|ext| ext.to_owned()));
+    exts
+}
+
+/// Looks for `name` in `dirs`, adding every candidate that doesn't exist to
+/// `missing`. Names containing a directory are only resolved relative to
+/// the working directory.
+fn search(name: &str, dirs: &[Path], missing: &mut Vec<Path>) -> Option<Path> {
+    let exts = program_exts(name);
+
+    let path = Path::new(name);
+    let mut candidates = Vec::new();
+    if path.is_absolute() || path.dirname() != bytes!(".") {
+        for ext in exts.iter() {
+            candidates.push(Path::new(name + ext.as_slice()));
+        }
+    } else {
+        for dir in dirs.iter() {
+            for ext in exts.iter() {
+                candidates.push(dir.join(name + ext.as_slice()));
+            }
+        }
+    }
+
+    for path in candidates.move_iter() {
+        if path.exists() {
+            return Some(path);
+        }
+        missing.push(path);
+    }
+
+    None
+}
+
+/// Find the first of `names` that is installed. The environment variable
+/// overriding a name (e.g. `CC` for "gcc") is honored first, then the
+/// context's program directories are searched, then `PATH`. An override has
+/// to name a single program, by name or path: a launcher like the one in
+/// `CC="ccache gcc"` goes in `Context::set_compiler_launcher` instead.
+/// Fails with an error if nothing is found, if an override names a program
+/// that doesn't exist, or if the program doesn't meet its version
+/// requirement.
+pub fn try_find_program(ctx: Context, names: &'static [&'static str]) -> IoResult<Path> {
+    lookup_program(ctx, names.iter().map(|name| name.to_owned()).collect())
+}
+
+/// Like `try_find_program`, for a program whose name isn't known until the
+/// build runs, such as one named in a file.
+pub fn try_find_program_named(ctx: Context, name: &str) -> IoResult<Path> {
+    lookup_program(ctx, vec!(name.to_owned()))
+}
 
-            let path = Path::new(name.as_slice());
-            if path.exists() {
-                println!(" ok {}", path.display());
-                exec.discover_output_path("output", &path);
+fn lookup_program(ctx: Context, names: Vec<~str>) -> IoResult<Path> {
+    let requirements: Vec<(~str, ~str)> = names.iter().filter_map(|name| {
+        ctx.tool_version_requirement(name.as_slice()).map(|req| (name.clone(), req))
+    }).collect();
 
-                return path;
+    let mut overrides: Vec<(&'static str, &'static str)> = Vec::new();
+    for name in names.iter() {
+        for &(program, var) in ENV_OVERRIDES.iter() {
+            if program == name.as_slice() && !overrides.iter().any(|&(_, v)| v == var) {
+                overrides.push((program, var));
             }
+        }
+    }
+
+    let program_dirs = ctx.program_dirs();
+
+    let mut prep = ctx.prep(FIND_PROGRAM);
+    prep.declare_input("value", "names", &names);
+    prep.declare_input("value", "requirements", &requirements);
+    prep.declare_input("value", "program_dirs", &program_dirs);
+
+    // Installing or upgrading a program changes what we'd find, so redo the
+    // lookup whenever the search path or the program itself changes.
+    prep.declare_input_env("PATH");
+    prep.declare_input_env("PATHEXT");
+
+    for &(_, var) in overrides.iter() {
+        prep.declare_input_env(var);
+    }
 
-            for path in paths.split(':') {
-                let path = Path::new(path).join(*name);
+    let lookup = prep.exec(proc(exec) {
+        let mut missing = Vec::new();
+        let mut dirs = program_dirs.clone();
+        match os::getenv("PATH") {
+            Some(paths) => {
+                dirs.extend(paths.split(PATH_SEP).filter(|dir| !dir.is_empty()).map(|dir| Path::new(dir)));
+            }
+            None => { }
+        }
 
-                if path.exists() {
-                    println!(" ok {}", path.display());
-                    exec.discover_output_path("output", &path);
+        for &(program, var) in overrides.iter() {
+            match os::getenv(var) {
+                Some(ref value) if !value.is_empty() => {
+                    let words = shell::split(value.as_slice());
+                    if words.len() != 1 {
+                        return BadOverride(format!("{}=\"{}\" has to name a single program; set \
+                                                    launchers such as ccache with \
+                                                    `Context::set_compiler_launcher`", var, value));
+                    }
+
+                    // An override has to exist; silently falling back to
+                    // another compiler would be surprising.
+                    let path = search(words.get(0).as_slice(), dirs.as_slice(), &mut missing);
+                    discover_missing(exec, missing.as_slice());
+                    return match path {
+                        Some(path) => found(exec, requirements.as_slice(), program, path),
+                        None => NotFound(format!("{}=\"{}\" names a program that doesn't exist", var, value)),
+                    };
+                }
+                _ => { }
+            }
+        }
 
-                    return path;
+        for name in names.iter() {
+            match search(name.as_slice(), dirs.as_slice(), &mut missing) {
+                Some(path) => {
+                    discover_missing(exec, missing.as_slice());
+                    return found(exec, requirements.as_slice(), name.as_slice(), path);
                 }
+                None => { }
+            }
+        }
+
+        discover_missing(exec, missing.as_slice());
+        NotFound(format!("looked for {}", names.as_slice().connect(", ")))
+    }).unwrap();
+
+    match lookup {
+        Found(path) => Ok(path),
+        NotFound(detail) => {
+            Err(IoError {
+                kind: io::FileNotFound,
+                desc: "program not found",
+                detail: Some(detail),
+            })
+        }
+        WrongVersion(detail) => {
+            Err(IoError {
+                kind: io::InvalidInput,
+                desc: "program has the wrong version",
+                detail: Some(detail),
+            })
+        }
+        BadOverride(detail) => {
+            Err(IoError {
+                kind: io::InvalidInput,
+                desc: "invalid program override",
+                detail: Some(detail),
+            })
+        }
+    }
+}
+
+/// The outcome of a program lookup, which is cached along with what it
+/// looked at.
+#[deriving(Encodable, Decodable)]
+enum Lookup {
+    Found(Path),
+    NotFound(~str),
+    WrongVersion(~str),
+    BadOverride(~str),
+}
+
+/// Checks the version of the program `name` found at `path`, and records the
+/// program as an input of the lookup.
+fn found(exec: &mut Exec, requirements: &[(~str, ~str)], name: &str, path: Path) -> Lookup {
+    exec.discover_input_path("program", &path).unwrap();
+
+    match check_version(exec, requirements, name, &path) {
+        Ok(()) => {
+            exec.discover_output_path("output", &path);
+            Found(path)
+        }
+        Err(detail) => WrongVersion(detail),
+    }
+}
+
+/// Like `try_find_program`, but fails the build if none of the programs are
+/// installed, since builders can't do anything without their tool. Until the
+/// build is validated, a missing program is recorded for
+/// `Context::validate` instead, along with every other one, and the first
+/// name stands in for it.
+pub fn find_program(ctx: Context, names: &'static [&'static str]) -> Future<Path> {
+    match try_find_program(ctx.clone(), names) {
+        Ok(path) => Future::from_value(path),
+        Err(err) => {
+            if !ctx.defers_missing_programs() {
+                fail!("{}", err);
             }
+
+            ctx.record_missing_program(err.detail.unwrap_or(err.desc.to_owned()));
+            Future::from_value(Path::new(names[0]))
+        }
+    }
+}
+
+/// Records the candidates that were passed over, so that the lookup is redone
+/// if a program shows up at any of them.
+fn discover_missing(exec: &mut Exec, missing: &[Path]) {
+    for path in missing.iter() {
+        exec.discover_input("Missing", path.as_str().unwrap(), path);
+    }
+}
+
+/// Returns why the version of `path` doesn't satisfy the requirement on
+/// `name`, if it doesn't, and records the version otherwise.
+fn check_version(exec: &mut Exec, requirements: &[(~str, ~str)], name: &str, path: &Path) -> Result<(), ~str> {
+    let req = match requirements.iter().find(|&&(ref n, _)| n.as_slice() == name) {
+        Some(&(_, ref req)) => req,
+        None => { return Ok(()); }
+    };
+
+    let version = match tool_version::tool_version(path) {
+        Some(version) => version,
+        None => {
+            return Err(format!("{} is required to have version {}, but the version of {} \
+                                could not be determined", name, req, path.display()));
         }
+    };
+
+    if !VersionReq::parse(req.as_slice()).unwrap().matches(version.as_slice()) {
+        return Err(format!("{} is required to have version {}, but {} is version {}",
+                           name, req, path.display(), tool_version::version_to_str(version.as_slice())));
+    }
 
-        fail!(" failed");
-    })
+    exec.discover_input("value", "version", &tool_version::version_to_str(version.as_slice()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::glob_matches;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("foo.c", "foo.c"));
+        assert!(!glob_matches("foo.c", "foo.cc"));
+        assert!(glob_matches("*.c", "foo.c"));
+        assert!(glob_matches("*.c", ".c"));
+        assert!(!glob_matches("*.c", "foo.h"));
+        assert!(glob_matches("lib*.a", "libfoo.a"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("**", "anything"));
+        assert!(glob_matches("a*b*c", "aXbYc"));
+        assert!(glob_matches("a*b*c", "abbc"));
+        assert!(!glob_matches("a*b*c", "acb"));
+        assert!(glob_matches("?.o", "a.o"));
+        assert!(!glob_matches("?.o", ".o"));
+        assert!(!glob_matches("?.o", "ab.o"));
+        assert!(glob_matches("t?st_*", "test_glob"));
+        assert!(!glob_matches("", "a"));
+        assert!(glob_matches("", ""));
+        assert!(glob_matches("ü*", "über"));
+    }
 }
@@ -1,18 +1,59 @@
 use std::fmt::Show;
 use std::io;
-use std::io::{IoResult, MemWriter, Process, ProcessConfig};
+use std::io::{File, IoResult, MemWriter, ProcessConfig};
+use std::io::fs;
 use std::io::process::{ProcessExit, ProcessOutput};
+use std::os;
 use std::str;
+use sync::{Arc, Mutex};
+use term::color;
 use term::color::Color;
 
+use console;
+use depfile;
+use executor::{ExecOptions, Executor, LocalExecutor, ProcessGroups, SharedExecutor};
+use jobserver::JobServer;
+use scheduler::Scheduler;
+use workcache::Reported;
+use shell;
+use show_includes;
+
+/// At this verbosity and above, the output of processes is copied to the
+/// console as they run instead of only being shown when they fail, so
+/// progress of long links or code generators is visible.
+pub static STREAM_VERBOSITY: uint = 2;
+
+/// Where a process reads its stdin from.
+enum Stdin {
+    StdinBytes(Vec<u8>),
+    StdinFile(Path),
+}
+
 pub struct ProcessBuilder<'a> {
     config: ProcessConfig<'a>,
+    env: Vec<(~str, ~str)>,
     color: Option<Color>,
+    use_color: bool,
+    description: Option<~str>,
     verbosity: uint,
+    quiet: bool,
     stdout_verbosity: Option<uint>,
     stderr_verbosity: Option<uint>,
     msgs: MemWriter,
-    timeout: Option<uint>,
+    stream_prefix: Option<~str>,
+    stdin: Option<Stdin>,
+    stdout_path: Option<Path>,
+    outputs: Option<Arc<Mutex<Vec<Path>>>>,
+    depfile: Option<Path>,
+    show_includes: Option<~str>,
+    inputs: Option<Arc<Mutex<Vec<Path>>>>,
+    timeout: Option<u64>,
+    executor: Option<SharedExecutor>,
+    process_groups: Option<Arc<ProcessGroups>>,
+    jobserver: Option<Arc<JobServer>>,
+    scheduler: Option<Arc<Scheduler>>,
+    memory_weight: uint,
+    priority: u64,
 }
 
 impl<'a> ProcessBuilder<'a> {
@@ -25,25 +66,125 @@ impl<'a> ProcessBuilder<'a> {
 
         ProcessBuilder {
             config: config,
+            env: Vec::new(),
             color: None,
+            use_color: false,
+            description: None,
             verbosity: 0,
+            quiet: false,
             stdout_verbosity: None,
             stderr_verbosity: None,
             msgs: MemWriter::new(),
+            stream_prefix: None,
+            stdin: None,
+            stdout_path: None,
+            outputs: None,
+            depfile: None,
+            show_includes: None,
+            inputs: None,
             timeout: None,
+            executor: None,
+            process_groups: None,
+            jobserver: None,
+            scheduler: None,
+            memory_weight: 1,
+            priority: 0,
         }
     }
 
+    /// Run the process in `cwd` instead of the current directory.
+    pub fn cwd(mut self, cwd: &'a Path) -> ProcessBuilder<'a> {
+        self.config.cwd = Some(cwd);
+        self
+    }
+
+    /// Set `key` to `value` in the environment of the process only; the
+    /// rest of the environment is inherited.
+    pub fn env(mut self, key: &str, value: &str) -> ProcessBuilder<'a> {
+        self.env.retain(|&(ref k, _)| k.as_slice() != key);
+        self.env.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// The color of the description. Defaults to green.
     pub fn color(mut self, color: Color) -> ProcessBuilder<'a> {
         self.color = Some(color);
         self
     }
 
+    /// Whether to color the description and the report of a failure.
+    pub fn use_color(mut self, use_color: bool) -> ProcessBuilder<'a> {
+        self.use_color = use_color;
+        self
+    }
+
     pub fn verbosity(mut self, verbosity: uint) -> ProcessBuilder<'a> {
         self.verbosity = verbosity;
         self
     }
 
+    /// Don't print the description line. A failed process is still
+    /// reported with its output.
+    pub fn quiet(mut self, quiet: bool) -> ProcessBuilder<'a> {
+        self.quiet = quiet;
+        self
+    }
+
+    /// The prefix that marks the lines of this process when its output is
+    /// streamed, to tell apart the output of processes running at once.
+    pub fn stream_prefix<T: Str>(mut self, prefix: T) -> ProcessBuilder<'a> {
+        self.stream_prefix = Some(prefix.into_owned());
+        self
+    }
+
+    /// Feed the contents of `path` to the process on stdin, for tools that
+    /// read their source from it.
+    pub fn stdin_file(mut self, path: &Path) -> ProcessBuilder<'a> {
+        self.stdin = Some(StdinFile(path.clone()));
+        self
+    }
+
+    pub fn stdin_bytes(mut self, bytes: Vec<u8>) -> ProcessBuilder<'a> {
+        self.stdin = Some(StdinBytes(bytes));
+        self
+    }
+
+    /// Write what the process prints on stdout to `path`, for tools that
+    /// produce their output that way (`foo > out.h`). The file is only
+    /// written if the process succeeds.
+    pub fn stdout_to(mut self, path: &Path) -> ProcessBuilder<'a> {
+        self.stdout_path = Some(path.clone());
+        self
+    }
+
+    /// Add the files written with `stdout_to` to `outputs`.
+    pub fn outputs(mut self, outputs: Arc<Mutex<Vec<Path>>>) -> ProcessBuilder<'a> {
+        self.outputs = Some(outputs);
+        self
+    }
+
+    /// Read the Makefile-style depfile the process writes to `path` once
+    /// it succeeds, and add the files listed in it to `inputs`.
+    pub fn depfile(mut self, path: &Path) -> ProcessBuilder<'a> {
+        self.depfile = Some(path.clone());
+        self
+    }
+
+    /// Collect the headers a compiler run with `/showIncludes` reports into
+    /// `inputs`, leaving them out of its output. `prefix` is what precedes
+    /// each header, which depends on the compiler's locale; `None` uses
+    /// the English one.
+    pub fn show_includes(mut self, prefix: Option<~str>) -> ProcessBuilder<'a> {
+        self.show_includes = Some(prefix.unwrap_or(show_includes::DEFAULT_PREFIX.to_owned()));
+        self
+    }
+
+    /// Where the inputs read from the depfile or `/showIncludes` go.
+    pub fn inputs(mut self, inputs: Arc<Mutex<Vec<Path>>>) -> ProcessBuilder<'a> {
+        self.inputs = Some(inputs);
+        self
+    }
+
     pub fn stdout_verbosity(mut self, verbosity: uint) -> ProcessBuilder<'a> {
         self.stdout_verbosity = Some(verbosity);
         self
@@ -54,8 +195,57 @@ impl<'a> ProcessBuilder<'a> {
         self
     }
 
+    /// Run the process with `executor` instead of as a local process.
+    pub fn executor(mut self, executor: SharedExecutor) -> ProcessBuilder<'a> {
+        self.executor = Some(executor);
+        self
+    }
+
+    /// Run the process in its own process group, tracked in `groups` so
+    /// that it and everything it starts can be killed, e.g. when the build
+    /// is interrupted.
+    pub fn process_groups(mut self, groups: Arc<ProcessGroups>) -> ProcessBuilder<'a> {
+        self.process_groups = Some(groups);
+        self
+    }
+
+    /// Kill the process, and everything it started, if it runs for longer
+    /// than `timeout` milliseconds. Only applies to processes run in their
+    /// own process group.
+    pub fn timeout(mut self, timeout: u64) -> ProcessBuilder<'a> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Take a slot from `jobserver` for as long as the process runs, and
+    /// share the jobserver with the process if it is a make.
+    pub fn jobserver(mut self, jobserver: Arc<JobServer>) -> ProcessBuilder<'a> {
+        self.jobserver = Some(jobserver);
+        self
+    }
+
+    /// Wait for `scheduler` to allow the process to start.
+    pub fn scheduler(mut self, scheduler: Arc<Scheduler>) -> ProcessBuilder<'a> {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// How much memory the process is expected to use, relative to an
+    /// ordinary compile. Defaults to 1.
+    pub fn memory_weight(mut self, memory_weight: uint) -> ProcessBuilder<'a> {
+        self.memory_weight = memory_weight;
+        self
+    }
+
+    /// When several processes are waiting to start, the one with the
+    /// highest priority goes first.
+    pub fn priority(mut self, priority: u64) -> ProcessBuilder<'a> {
+        self.priority = priority;
+        self
+    }
+
     pub fn description<T: Show>(mut self, description: T) -> ProcessBuilder<'a> {
-        (write!(&mut self.msgs, " * {:10}:", description)).unwrap();
+        self.description = Some(format!("{:10}", description));
         self
     }
 
@@ -77,49 +267,225 @@ impl<'a> ProcessBuilder<'a> {
     }
 
     pub fn run_with_output(self) -> IoResult<ProcessOutput> {
-        let mut cmd = StrBuf::from_str(self.config.program);
-        for arg in self.config.args.iter() {
-            cmd.push_str(" ");
-            cmd.push_str(*arg);
-        }
+        let cmd = self.cmd();
 
         debug!("running {}", cmd);
 
+        let program = Path::new(self.config.program).filename_display().to_str();
+
+        let ProcessBuilder {
+            config,
+            env: extra_env,
+            color,
+            use_color,
+            description,
+            msgs,
+            verbosity,
+            quiet,
+            stream_prefix,
+            stdin,
+            stdout_path,
+            outputs,
+            depfile,
+            show_includes,
+            inputs,
+            timeout,
+            executor,
+            process_groups,
+            jobserver,
+            scheduler,
+            memory_weight,
+            priority,
+            ..
+        } = self;
+
+        let cwd = config.cwd.map(|cwd| cwd.clone());
+
+        let stdin = match stdin {
+            Some(StdinBytes(bytes)) => Some(bytes),
+            Some(StdinFile(path)) => Some(try!(File::open(&path).read_to_end())),
+            None => None,
+        };
+
+        // Take a slot in priority order first, so the most urgent command
+        // is the one that gets the next job token.
+        let _slot = scheduler.as_ref().map(|scheduler| scheduler.acquire(memory_weight, priority));
+
+        // Wait for a job slot before announcing the command.
+        let env;
+        let (config, _token) = match jobserver {
+            Some(ref jobserver) => {
+                env = merge_env(jobserver.child_env(), extra_env.as_slice());
+                let token = try!(jobserver.acquire());
+                (ProcessConfig { env: Some(env.as_slice()), .. config }, Some(token))
+            }
+            None if !extra_env.is_empty() => {
+                env = merge_env(os::env().move_iter().collect(), extra_env.as_slice());
+                (ProcessConfig { env: Some(env.as_slice()), .. config }, None)
+            }
+            None => (config, None),
+        };
+
         let mut stdout = io::stdout();
 
-        let msgs = self.msgs.get_ref();
-        if !msgs.is_empty() {
+        match description {
+            _ if quiet => { }
+            Some(ref description) => {
+                let description = if use_color {
+                    console::paint(description.as_slice(), color.unwrap_or(color::GREEN))
+                } else {
+                    description.clone()
+                };
+                try!(stdout.write_str(format!(" * {}:", description)));
+            }
+            None => { }
+        }
+
+        let msgs = msgs.get_ref();
+        if !quiet && !msgs.is_empty() {
             try!(stdout.write(msgs));
+        }
+
+        if !quiet && (description.is_some() || !msgs.is_empty()) {
             try!(stdout.write_str("\n"));
         }
 
-        let mut process = try!(Process::configure(self.config));
-        let output = process.wait_with_output();
+        let executor = match executor {
+            Some(executor) => executor,
+            None => Arc::new(~LocalExecutor as ~Executor:Send+Share),
+        };
+
+        let options = ExecOptions {
+            groups: process_groups,
+            timeout: timeout,
+            stream: if verbosity >= STREAM_VERBOSITY {
+                Some(stream_prefix.unwrap_or(program))
+            } else {
+                None
+            },
+            stdin: stdin,
+        };
+
+        let mut output = try!(executor.execute_with(config, &options));
+
+        let includes = match show_includes {
+            Some(prefix) => {
+                let (includes, rest) = {
+                    let out = str::from_utf8_lossy(output.output.as_slice());
+                    show_includes::parse(out.as_slice(), prefix.as_slice())
+                };
+                output.output = Vec::from_slice(rest.as_bytes());
+                includes
+            }
+            None => Vec::new(),
+        };
 
         // If we errored out, log the error.
         if !output.status.success() {
-            try!(stdout.write_str(" + "));
-            try!(stdout.write_str(cmd.as_slice().trim_right()));
-            try!(stdout.write_str("\n"));
+            try!(stdout.write_str(failure_report(cmd.as_slice(), &output, use_color)));
+            try!(stdout.flush());
+            return Ok(output);
+        }
 
-            let out = output.output.as_slice();
-            let out1 = str::from_utf8_lossy(out);
-            let out2 = out1.as_slice().trim_right();
-            if !out2.is_empty() {
-                try!(stdout.write_str(out2));
-                try!(stdout.write_str("\n"));
+        match stdout_path {
+            Some(path) => {
+                // Write the whole file before it appears under its name.
+                let mut tmp = path.clone();
+                tmp.set_filename(format!("{}.tmp", path.filename_str().unwrap()));
+                try!(fs::mkdir_recursive(&path.dir_path(), io::UserDir));
+                try!(File::create(&tmp).write(output.output.as_slice()));
+                try!(fs::rename(&tmp, &path));
+
+                match outputs {
+                    Some(outputs) => { outputs.lock().push(path); }
+                    None => { }
+                }
             }
+            None => { }
+        }
 
-            let err = output.error.as_slice();
-            let err1 = str::from_utf8_lossy(err);
-            let err2 = err1.as_slice().trim_right();
-            if !err2.is_empty() {
-                try!(stdout.write_str(err2));
-                try!(stdout.write_str("\n"));
+        let mut read = includes;
+
+        match depfile {
+            Some(path) => {
+                let rules = try!(depfile::read(&path));
+                read.push_all_move(depfile::inputs(rules.as_slice()));
             }
+            None => { }
+        }
 
-            try!(stdout.flush());
+        // The process names what it read relative to where it ran.
+        let read = match cwd {
+            Some(cwd) => read.move_iter().map(|path| cwd.join(path)).collect(),
+            None => read,
+        };
+
+        match inputs {
+            Some(inputs) => { inputs.lock().push_all_move(read); }
+            None => { }
         }
+
         Ok(output)
     }
+
+    /// Run the process, failing the task with the command and its captured
+    /// output if it does not exit successfully.
+    pub fn run_or_fail(self) -> ProcessOutput {
+        let cmd = self.cmd();
+        let output = match self.run_with_output() {
+            Ok(output) => output,
+            Err(err) => fail!("could not run {}: {}", cmd, err),
+        };
+
+        // The output was already printed by `run_with_output`, but it's
+        // kept for the summary of `Context::report_failures`.
+        if !output.status.success() {
+            fail!(Reported(failure_report(cmd.as_slice(), &output, false)));
+        }
+
+        output
+    }
+
+    fn cmd(&self) -> ~str {
+        shell::join(self.config.program, self.config.args)
+    }
+}
+
+/// Formats a failed command along with everything it wrote, with the
+/// command in red if `use_color` is set.
+fn failure_report(cmd: &str, output: &ProcessOutput, use_color: bool) -> ~str {
+    let mut report = StrBuf::new();
+
+    let line = format!(" + {}", cmd.trim_right());
+    if use_color {
+        report.push_str(console::paint(line, color::RED));
+    } else {
+        report.push_str(line);
+    }
+    report.push_str("\n");
+
+    let out = output.output.as_slice();
+    let out1 = str::from_utf8_lossy(out);
+    let out2 = out1.as_slice().trim_right();
+    if !out2.is_empty() {
+        report.push_str(out2);
+        report.push_str("\n");
+    }
+
+    let err = output.error.as_slice();
+    let err1 = str::from_utf8_lossy(err);
+    let err2 = err1.as_slice().trim_right();
+    if !err2.is_empty() {
+        report.push_str(err2);
+        report.push_str("\n");
+    }
+
+    report.into_owned()
+}
+
+/// Overrides the variables in `env` with `extra`.
+fn merge_env(mut env: Vec<(~str, ~str)>, extra: &[(~str, ~str)]) -> Vec<(~str, ~str)> {
+    env.retain(|&(ref key, _)| !extra.iter().any(|&(ref k, _)| k == key));
+    env.push_all(extra);
+    env
 }
@@ -0,0 +1,276 @@
+use std::io::IoResult;
+use collections::TreeMap;
+use time;
+use serialize::json;
+use serialize::json::ToJson;
+
+use graph::Graph;
+use workcache::{ExecEvent, RunStats};
+
+static SLOWEST: uint = 10;
+
+fn secs(ns: u64) -> f64 {
+    ns as f64 / 1e9
+}
+
+/// The program a label describes, e.g. "gcc" for "gcc build/foo.o".
+fn tool<'a>(label: &'a str) -> &'a str {
+    label.words().next().unwrap_or(label)
+}
+
+/// Write a summary of a run: the cache hit ratio, the slowest commands,
+/// the time spent in each tool and the critical path through `graph`.
+pub fn write_report<W: Writer>(w: &mut W, events: &[ExecEvent], graph: &Graph) -> IoResult<()> {
+    let hits = events.iter().filter(|event| event.cached).count();
+    let total = events.len();
+
+    try!(writeln!(w, "cache hits: {}/{} ({:.1}%)",
+        hits,
+        total,
+        if total == 0 { 100.0 } else { 100.0 * hits as f64 / total as f64 }));
+
+    let mut ran: Vec<&ExecEvent> = events.iter().filter(|event| !event.cached).collect();
+    ran.sort_by(|a, b| b.duration.cmp(&a.duration));
+
+    if !ran.is_empty() {
+        try!(writeln!(w, "slowest commands:"));
+        for event in ran.iter().take(SLOWEST) {
+            try!(writeln!(w, "  {:8.3}s {}", secs(event.duration), event.label));
+        }
+    }
+
+    let mut tools: TreeMap<~str, (uint, u64)> = TreeMap::new();
+    for event in ran.iter() {
+        let name = tool(event.label.as_slice()).to_owned();
+        let (count, duration) = match tools.find(&name) {
+            Some(&(count, duration)) => (count, duration),
+            None => (0, 0),
+        };
+        tools.insert(name, (count + 1, duration + event.duration));
+    }
+
+    if !tools.is_empty() {
+        try!(writeln!(w, "time per tool:"));
+        for (name, &(count, duration)) in tools.iter() {
+            try!(writeln!(w, "  {:8.3}s {} ({} runs)", secs(duration), *name, count));
+        }
+    }
+
+    let path = critical_path(events, graph);
+    if !path.is_empty() {
+        let total = path.iter().fold(0, |total, &(_, duration)| total + duration);
+        try!(writeln!(w, "critical path ({:.3}s):", secs(total)));
+        for &(ref label, duration) in path.iter() {
+            try!(writeln!(w, "  {:8.3}s {}", secs(duration), *label));
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a one-line summary of a run that took `elapsed` nanoseconds: how
+/// many steps ran, how many were taken from the cache and how many failed.
+pub fn write_summary<W: Writer>(
+    w: &mut W,
+    events: &[ExecEvent],
+    failed: uint,
+    elapsed: u64
+) -> IoResult<()> {
+    let hits = events.iter().filter(|event| event.cached).count();
+
+    try!(write!(w, "{} built, {} cache hits", events.len() - hits, hits));
+    if failed > 0 {
+        try!(write!(w, ", {} failed", failed));
+    }
+    writeln!(w, " in {:.1}s", secs(elapsed))
+}
+
+/// `bytes` in the largest unit that keeps it above 1.
+fn human_bytes(bytes: u64) -> ~str {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, units[unit])
+}
+
+/// The change from `old` to `new` in percent, signed.
+fn percent_change(old: f64, new: f64) -> ~str {
+    if old == 0.0 {
+        return ~"n/a";
+    }
+
+    let change = 100.0 * (new - old) / old;
+    if change >= 0.0 {
+        format!("+{:.1}%", change)
+    } else {
+        format!("{:.1}%", change)
+    }
+}
+
+fn mean(runs: &[RunStats], f: |&RunStats| -> f64) -> f64 {
+    runs.iter().fold(0.0, |total, run| total + f(run)) / runs.len() as f64
+}
+
+/// Write a line for each of `runs`, oldest first, then how the latest run
+/// compares to the average of the ones before it.
+pub fn write_stats<W: Writer>(w: &mut W, runs: &[RunStats]) -> IoResult<()> {
+    if runs.is_empty() {
+        return writeln!(w, "no runs recorded");
+    }
+
+    for run in runs.iter() {
+        let started = time::at(time::Timespec::new(run.started, 0));
+        try!(writeln!(w, "{}  {:8.1}s  {:6} commands  {:5.1}% cached  {} hashed",
+            started.strftime("%Y-%m-%d %H:%M"),
+            secs(run.wall_time),
+            run.commands,
+            100.0 * run.hit_rate(),
+            human_bytes(run.bytes_hashed)));
+    }
+
+    if runs.len() < 2 {
+        return Ok(());
+    }
+
+    let last = runs.last().unwrap();
+    let before = runs.init();
+
+    let wall_time = mean(before, |run| secs(run.wall_time));
+    let hit_rate = mean(before, |run| run.hit_rate());
+    let bytes_hashed = mean(before, |run| run.bytes_hashed as f64);
+    let points = 100.0 * (last.hit_rate() - hit_rate);
+
+    try!(writeln!(w, "latest run against the average of the {} before it:", before.len()));
+    try!(writeln!(w, "  wall time:    {:.1}s ({})",
+        secs(last.wall_time), percent_change(wall_time, secs(last.wall_time))));
+    try!(writeln!(w, "  cache hits:   {:.1}% ({}{:.1} points)",
+        100.0 * last.hit_rate(), if points >= 0.0 { "+" } else { "" }, points));
+    try!(writeln!(w, "  bytes hashed: {} ({})",
+        human_bytes(last.bytes_hashed), percent_change(bytes_hashed, last.bytes_hashed as f64)));
+
+    Ok(())
+}
+
+/// Returns the chain of steps through the dependency graph with the
+/// longest total duration, in build order.
+pub fn critical_path(events: &[ExecEvent], graph: &Graph) -> Vec<(~str, u64)> {
+    let mut durations = TreeMap::new();
+    for event in events.iter() {
+        durations.insert(event.label.clone(), event.duration);
+    }
+
+    let nodes = graph.nodes();
+
+    // Producers are always declared before their consumers, so the nodes
+    // are already in topological order.
+    let mut costs: Vec<u64> = Vec::with_capacity(nodes.len());
+    let mut prevs: Vec<Option<uint>> = Vec::with_capacity(nodes.len());
+
+    for (idx, node) in nodes.iter().enumerate() {
+        let duration = durations.find(&node.label).map_or(0, |d| *d);
+
+        let mut prev = None;
+        let mut cost = 0;
+        for dep in graph.dependencies(idx).move_iter() {
+            if dep < idx && *costs.get(dep) >= cost {
+                cost = *costs.get(dep);
+                prev = Some(dep);
+            }
+        }
+
+        costs.push(cost + duration);
+        prevs.push(prev);
+    }
+
+    let mut end = None;
+    for (idx, cost) in costs.iter().enumerate() {
+        match end {
+            Some(best) if *costs.get(best) >= *cost => { }
+            _ => { end = Some(idx); }
+        }
+    }
+
+    let mut path = Vec::new();
+    while end.is_some() {
+        let idx = end.unwrap();
+        let label = nodes[idx].label.clone();
+        let duration = durations.find(&label).map_or(0, |d| *d);
+        path.push((label, duration));
+        end = *prevs.get(idx);
+    }
+    path.reverse();
+
+    path
+}
+
+/// Returns, for every step, the length of the longest path from its start to
+/// the end of the build, using the last known duration of each step. Steps
+/// with the longest paths should be started first.
+pub fn path_lengths(durations: &TreeMap<~str, u64>, graph: &Graph) -> TreeMap<~str, u64> {
+    let nodes = graph.nodes();
+
+    // Walk the steps backwards, so that every consumer is done before the
+    // steps it depends on.
+    let mut longest_tail: Vec<u64> = Vec::from_elem(nodes.len(), 0u64);
+    let mut lengths = TreeMap::new();
+
+    for idx in range(0, nodes.len()).rev() {
+        let label = nodes[idx].label.clone();
+        let duration = durations.find(&label).map_or(0, |d| *d);
+        let length = duration + *longest_tail.get(idx);
+
+        for dep in graph.dependencies(idx).move_iter() {
+            if dep < idx && *longest_tail.get(dep) < length {
+                *longest_tail.get_mut(dep) = length;
+            }
+        }
+
+        let longest = lengths.find(&label).map_or(length, |l: &u64| if *l > length { *l } else { length });
+        lengths.insert(label, longest);
+    }
+
+    lengths
+}
+
+/// Write the execs that ran as Chrome trace events, viewable in
+/// chrome://tracing. Overlapping execs are spread over separate lanes.
+pub fn write_trace<W: Writer>(w: &mut W, events: &[ExecEvent]) -> IoResult<()> {
+    let mut ran: Vec<&ExecEvent> = events.iter().filter(|event| !event.cached).collect();
+    ran.sort_by(|a, b| a.start.cmp(&b.start));
+
+    let base = ran.iter().next().map_or(0, |event| event.start);
+    let mut lanes: Vec<u64> = Vec::new();
+    let mut trace = Vec::new();
+
+    for event in ran.iter() {
+        let end = event.start + event.duration;
+        let lane = match lanes.iter().position(|lane_end| *lane_end <= event.start) {
+            Some(lane) => {
+                *lanes.get_mut(lane) = end;
+                lane
+            }
+            None => {
+                lanes.push(end);
+                lanes.len() - 1
+            }
+        };
+
+        let mut obj = TreeMap::new();
+        obj.insert(~"name", event.label.to_json());
+        obj.insert(~"cat", event.fn_name.to_json());
+        obj.insert(~"ph", (~"X").to_json());
+        obj.insert(~"ts", ((event.start - base) / 1000).to_json());
+        obj.insert(~"dur", (event.duration / 1000).to_json());
+        obj.insert(~"pid", 0u.to_json());
+        obj.insert(~"tid", lane.to_json());
+        trace.push(json::Object(~obj));
+    }
+
+    json::List(trace.move_iter().collect()).to_pretty_writer(w as &mut Writer)
+}
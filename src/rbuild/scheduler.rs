@@ -0,0 +1,181 @@
+//! Decides when the commands of a build may start: at most `jobs` at once,
+//! within the memory budget and load limit, and the most urgent first.
+
+use std::io::timer;
+#[cfg(unix)]
+use libc::{c_double, c_int};
+use sync::{Mutex, RWLock};
+
+/// How often to check the load average again while throttled, in
+/// milliseconds.
+static LOAD_POLL_INTERVAL: u64 = 250;
+
+#[cfg(unix)]
+extern {
+    fn getloadavg(loadavg: *mut c_double, nelem: c_int) -> c_int;
+}
+
+/// Returns the one minute load average of the system.
+#[cfg(unix)]
+pub fn load_average() -> Option<f64> {
+    let mut load = 0.0 as c_double;
+    if unsafe { getloadavg(&mut load, 1) } == 1 {
+        Some(load as f64)
+    } else {
+        None
+    }
+}
+
+/// There's no load average on Windows, so a load limit never throttles.
+#[cfg(not(unix))]
+pub fn load_average() -> Option<f64> {
+    None
+}
+
+struct State {
+    /// The number of running commands.
+    running: uint,
+    /// The sum of the memory weights of the running commands.
+    running_weight: uint,
+    /// The priorities of the commands waiting to start.
+    waiting: Vec<u64>,
+    /// Set once the build is cancelled, after which nothing else starts.
+    cancelled: bool,
+}
+
+pub struct Scheduler {
+    max_jobs: RWLock<Option<uint>>,
+    max_load: RWLock<Option<f64>>,
+    memory_budget: RWLock<Option<uint>>,
+    state: Mutex<State>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            max_jobs: RWLock::new(None),
+            max_load: RWLock::new(None),
+            memory_budget: RWLock::new(None),
+            state: Mutex::new(State {
+                running: 0,
+                running_weight: 0,
+                waiting: Vec::new(),
+                cancelled: false,
+            }),
+        }
+    }
+
+    pub fn set_max_jobs(&self, max_jobs: Option<uint>) {
+        *self.max_jobs.write() = max_jobs;
+    }
+
+    pub fn set_max_load(&self, max_load: Option<f64>) {
+        *self.max_load.write() = max_load;
+    }
+
+    /// Limit the total memory weight of the commands running at once. See
+    /// `Call::set_memory_weight`.
+    pub fn set_memory_budget(&self, memory_budget: Option<uint>) {
+        *self.memory_budget.write() = memory_budget;
+    }
+
+    /// Wait until a command with memory weight `weight` may start. Of the
+    /// waiting commands, the one with the highest `priority` goes first.
+    /// Like `make -l`, a command isn't started while the load average is at
+    /// or above the limit, nor while it would exceed the memory budget,
+    /// unless nothing else is running.
+    pub fn acquire<'a>(&'a self, weight: uint, priority: u64) -> Slot<'a> {
+        let mut state = self.state.lock();
+        state.waiting.push(priority);
+
+        loop {
+            if state.cancelled {
+                let pos = state.waiting.iter().position(|p| *p == priority).unwrap();
+                state.waiting.remove(pos);
+                drop(state);
+                fail!("build interrupted");
+            }
+
+            let first = state.waiting.iter().all(|p| *p <= priority);
+            let idle = state.running == 0;
+
+            if first && (idle || self.fits(state.running, state.running_weight + weight)) {
+                if idle || !self.overloaded() {
+                    let pos = state.waiting.iter().position(|p| *p == priority).unwrap();
+                    state.waiting.remove(pos);
+                    state.running += 1;
+                    state.running_weight += weight;
+
+                    // Let the next most urgent command check whether it
+                    // fits too.
+                    state.cond.broadcast();
+
+                    return Slot { scheduler: self, weight: weight };
+                }
+
+                // The load only drops with time, so poll for it.
+                drop(state);
+                timer::sleep(LOAD_POLL_INTERVAL);
+                state = self.state.lock();
+            } else {
+                state.cond.wait();
+            }
+        }
+    }
+
+    /// Fail every command that is waiting or tries to start from now on.
+    pub fn cancel(&self) {
+        let mut state = self.state.lock();
+        state.cancelled = true;
+        state.cond.broadcast();
+    }
+
+    /// Wait for the running commands to finish.
+    pub fn wait_idle(&self) {
+        let state = self.state.lock();
+        while state.running > 0 {
+            state.cond.wait();
+        }
+    }
+
+    fn fits(&self, running: uint, weight: uint) -> bool {
+        let jobs_ok = match *self.max_jobs.read() {
+            Some(max_jobs) => running < max_jobs,
+            None => true,
+        };
+
+        let memory_ok = match *self.memory_budget.read() {
+            Some(budget) => weight <= budget,
+            None => true,
+        };
+
+        jobs_ok && memory_ok
+    }
+
+    fn overloaded(&self) -> bool {
+        match *self.max_load.read() {
+            Some(max_load) => load_average().map_or(false, |load| load >= max_load),
+            None => false,
+        }
+    }
+
+    fn release(&self, weight: uint) {
+        let mut state = self.state.lock();
+        state.running -= 1;
+        state.running_weight -= weight;
+        state.cond.broadcast();
+    }
+}
+
+/// Permission to run a command, given back when dropped.
+pub struct Slot<'a> {
+    scheduler: &'a Scheduler,
+    weight: uint,
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        self.scheduler.release(self.weight);
+    }
+}
@@ -0,0 +1,140 @@
+//! SHA-256, for digests that other tools have to be able to check, such as
+//! release manifests. Cache keys use the faster `std::hash` instead.
+
+static K: [u32, ..64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+static H: [u32, ..8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+fn rotr(x: u32, n: u32) -> u32 {
+    (x >> n) | (x << (32 - n))
+}
+
+fn compress(state: &mut [u32, ..8], block: &[u8]) {
+    let mut w = [0u32, ..64];
+
+    for i in range(0u, 16) {
+        w[i] = (block[4 * i] as u32 << 24) |
+               (block[4 * i + 1] as u32 << 16) |
+               (block[4 * i + 2] as u32 << 8) |
+               (block[4 * i + 3] as u32);
+    }
+
+    for i in range(16u, 64) {
+        let s0 = rotr(w[i - 15], 7) ^ rotr(w[i - 15], 18) ^ (w[i - 15] >> 3);
+        let s1 = rotr(w[i - 2], 17) ^ rotr(w[i - 2], 19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+    }
+
+    let mut a = state[0];
+    let mut b = state[1];
+    let mut c = state[2];
+    let mut d = state[3];
+    let mut e = state[4];
+    let mut f = state[5];
+    let mut g = state[6];
+    let mut h = state[7];
+
+    for i in range(0u, 64) {
+        let s1 = rotr(e, 6) ^ rotr(e, 11) ^ rotr(e, 25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h + s1 + ch + K[i] + w[i];
+        let s0 = rotr(a, 2) ^ rotr(a, 13) ^ rotr(a, 22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0 + maj;
+
+        h = g;
+        g = f;
+        f = e;
+        e = d + t1;
+        d = c;
+        c = b;
+        b = a;
+        a = t1 + t2;
+    }
+
+    state[0] += a;
+    state[1] += b;
+    state[2] += c;
+    state[3] += d;
+    state[4] += e;
+    state[5] += f;
+    state[6] += g;
+    state[7] += h;
+}
+
+/// Returns the SHA-256 digest of `bytes` in lowercase hex.
+pub fn hex_digest(bytes: &[u8]) -> ~str {
+    let mut state = H;
+
+    let full = bytes.len() / 64 * 64;
+    for block in bytes.slice_to(full).chunks(64) {
+        compress(&mut state, block);
+    }
+
+    // The message is padded with a one bit, zeros and its length in bits,
+    // to a multiple of the block size.
+    let mut tail = Vec::from_slice(bytes.slice_from(full));
+    tail.push(0x80);
+    while tail.len() % 64 != 56 {
+        tail.push(0);
+    }
+
+    let bits = (bytes.len() as u64) * 8;
+    for i in range(0u, 8) {
+        tail.push((bits >> (56 - 8 * i)) as u8);
+    }
+
+    for block in tail.as_slice().chunks(64) {
+        compress(&mut state, block);
+    }
+
+    let mut hex = StrBuf::new();
+    for word in state.iter() {
+        hex.push_str(format!("{:08x}", *word));
+    }
+    hex.into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::hex_digest;
+
+    // The examples from FIPS 180-2.
+    #[test]
+    fn test_nist_vectors() {
+        assert_eq!(hex_digest("abc".as_bytes()),
+                   ~"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex_digest("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq".as_bytes()),
+                   ~"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(hex_digest(Vec::from_elem(1000000, 'a' as u8).as_slice()),
+                   ~"cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(hex_digest([]),
+                   ~"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    // The length only fits in the last block up to 55 bytes.
+    #[test]
+    fn test_padding() {
+        assert_eq!(hex_digest(Vec::from_elem(55, 'a' as u8).as_slice()),
+                   ~"9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318");
+        assert_eq!(hex_digest(Vec::from_elem(56, 'a' as u8).as_slice()),
+                   ~"b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a");
+        assert_eq!(hex_digest(Vec::from_elem(64, 'a' as u8).as_slice()),
+                   ~"ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb");
+    }
+}
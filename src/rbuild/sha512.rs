@@ -0,0 +1,164 @@
+//! SHA-512, for checking downloads against published digests.
+
+static K: [u64, ..80] = [
+    0x428a2f98d728ae22, 0x7137449123ef65cd, 0xb5c0fbcfec4d3b2f, 0xe9b5dba58189dbbc,
+    0x3956c25bf348b538, 0x59f111f1b605d019, 0x923f82a4af194f9b, 0xab1c5ed5da6d8118,
+    0xd807aa98a3030242, 0x12835b0145706fbe, 0x243185be4ee4b28c, 0x550c7dc3d5ffb4e2,
+    0x72be5d74f27b896f, 0x80deb1fe3b1696b1, 0x9bdc06a725c71235, 0xc19bf174cf692694,
+    0xe49b69c19ef14ad2, 0xefbe4786384f25e3, 0x0fc19dc68b8cd5b5, 0x240ca1cc77ac9c65,
+    0x2de92c6f592b0275, 0x4a7484aa6ea6e483, 0x5cb0a9dcbd41fbd4, 0x76f988da831153b5,
+    0x983e5152ee66dfab, 0xa831c66d2db43210, 0xb00327c898fb213f, 0xbf597fc7beef0ee4,
+    0xc6e00bf33da88fc2, 0xd5a79147930aa725, 0x06ca6351e003826f, 0x142929670a0e6e70,
+    0x27b70a8546d22ffc, 0x2e1b21385c26c926, 0x4d2c6dfc5ac42aed, 0x53380d139d95b3df,
+    0x650a73548baf63de, 0x766a0abb3c77b2a8, 0x81c2c92e47edaee6, 0x92722c851482353b,
+    0xa2bfe8a14cf10364, 0xa81a664bbc423001, 0xc24b8b70d0f89791, 0xc76c51a30654be30,
+    0xd192e819d6ef5218, 0xd69906245565a910, 0xf40e35855771202a, 0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8, 0x1e376c085141ab53, 0x2748774cdf8eeb99, 0x34b0bcb5e19b48a8,
+    0x391c0cb3c5c95a63, 0x4ed8aa4ae3418acb, 0x5b9cca4f7763e373, 0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc, 0x78a5636f43172f60, 0x84c87814a1f0ab72, 0x8cc702081a6439ec,
+    0x90befffa23631e28, 0xa4506cebde82bde9, 0xbef9a3f7b2c67915, 0xc67178f2e372532b,
+    0xca273eceea26619c, 0xd186b8c721c0c207, 0xeada7dd6cde0eb1e, 0xf57d4f7fee6ed178,
+    0x06f067aa72176fba, 0x0a637dc5a2c898a6, 0x113f9804bef90dae, 0x1b710b35131c471b,
+    0x28db77f523047d84, 0x32caab7b40c72493, 0x3c9ebe0a15c9bebc, 0x431d67c49c100d4c,
+    0x4cc5d4becb3e42b6, 0x597f299cfc657e2a, 0x5fcb6fab3ad6faec, 0x6c44198c4a475817,
+];
+
+static H: [u64, ..8] = [
+    0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+    0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+];
+
+fn rotr(x: u64, n: u64) -> u64 {
+    (x >> n) | (x << (64 - n))
+}
+
+fn compress(state: &mut [u64, ..8], block: &[u8]) {
+    let mut w = [0u64, ..80];
+
+    for i in range(0u, 16) {
+        let mut word = 0u64;
+        for j in range(0u, 8) {
+            word = (word << 8) | (block[8 * i + j] as u64);
+        }
+        w[i] = word;
+    }
+
+    for i in range(16u, 80) {
+        let s0 = rotr(w[i - 15], 1) ^ rotr(w[i - 15], 8) ^ (w[i - 15] >> 7);
+        let s1 = rotr(w[i - 2], 19) ^ rotr(w[i - 2], 61) ^ (w[i - 2] >> 6);
+        w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+    }
+
+    let mut a = state[0];
+    let mut b = state[1];
+    let mut c = state[2];
+    let mut d = state[3];
+    let mut e = state[4];
+    let mut f = state[5];
+    let mut g = state[6];
+    let mut h = state[7];
+
+    for i in range(0u, 80) {
+        let s1 = rotr(e, 14) ^ rotr(e, 18) ^ rotr(e, 41);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h + s1 + ch + K[i] + w[i];
+        let s0 = rotr(a, 28) ^ rotr(a, 34) ^ rotr(a, 39);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0 + maj;
+
+        h = g;
+        g = f;
+        f = e;
+        e = d + t1;
+        d = c;
+        c = b;
+        b = a;
+        a = t1 + t2;
+    }
+
+    state[0] += a;
+    state[1] += b;
+    state[2] += c;
+    state[3] += d;
+    state[4] += e;
+    state[5] += f;
+    state[6] += g;
+    state[7] += h;
+}
+
+/// Returns the SHA-512 digest of `bytes` in lowercase hex.
+pub fn hex_digest(bytes: &[u8]) -> ~str {
+    let mut state = H;
+
+    let full = bytes.len() / 128 * 128;
+    for block in bytes.slice_to(full).chunks(128) {
+        compress(&mut state, block);
+    }
+
+    // Like SHA-256's padding, but with a 128 bit length. No input comes
+    // close to needing the upper half.
+    let mut tail = Vec::from_slice(bytes.slice_from(full));
+    tail.push(0x80);
+    while tail.len() % 128 != 112 {
+        tail.push(0);
+    }
+
+    let bits = (bytes.len() as u64) * 8;
+    for _ in range(0u, 8) {
+        tail.push(0);
+    }
+    for i in range(0u, 8) {
+        tail.push((bits >> (56 - 8 * i)) as u8);
+    }
+
+    for block in tail.as_slice().chunks(128) {
+        compress(&mut state, block);
+    }
+
+    let mut hex = StrBuf::new();
+    for word in state.iter() {
+        hex.push_str(format!("{:016x}", *word));
+    }
+    hex.into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::hex_digest;
+
+    // The examples from FIPS 180-2.
+    #[test]
+    fn test_nist_vectors() {
+        assert_eq!(hex_digest("abc".as_bytes()),
+                   ~"ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                     2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f");
+        assert_eq!(hex_digest(("abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                                hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu").as_bytes()),
+                   ~"8e959b75dae313da8cf4f72814fc143f8f7779c6eb9f7fa17299aeadb6889018\
+                     501d289e4900f7e4331b99dec4b5433ac7d329eeb6dd26545e96e55b874be909");
+        assert_eq!(hex_digest(Vec::from_elem(1000000, 'a' as u8).as_slice()),
+                   ~"e718483d0ce769644e2e42c7bc15b4638e1f98b13b2044285632a803afa973eb\
+                     de0ff244877ea60a4cb0432ce577c31beb009c5c2c49aa2e4eadb217ad8cc09b");
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(hex_digest([]),
+                   ~"cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce\
+                     47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e");
+    }
+
+    // The length only fits in the last block up to 111 bytes.
+    #[test]
+    fn test_padding() {
+        assert_eq!(hex_digest(Vec::from_elem(111, 'a' as u8).as_slice()),
+                   ~"fa9121c7b32b9e01733d034cfc78cbf67f926c7ed83e82200ef8681819692176\
+                     0b4beff48404df811b953828274461673c68d04e297b0eb7b2b4d60fc6b566a2");
+        assert_eq!(hex_digest(Vec::from_elem(112, 'a' as u8).as_slice()),
+                   ~"c01d080efd492776a1c43bd23dd99d0a2e626d481e16782e75d54c2503b5dc32\
+                     bd05f0f1ba33e568b88fd2d970929b719ecbb152f58f130a407c8830604b70ca");
+        assert_eq!(hex_digest(Vec::from_elem(128, 'a' as u8).as_slice()),
+                   ~"b73d1929aa615934e61a871596b3f3b33359f42b8175602e89f7e06e5f658a24\
+                     3667807ed300314b95cacdd579f3e33abdfbe351909519a846d465c59582f321");
+    }
+}
@@ -0,0 +1,66 @@
+use sync::{Arc, Future, Mutex};
+
+use into_future::IntoFuture;
+
+struct SharedState<T> {
+    future: Option<Future<T>>,
+    value: Option<T>,
+}
+
+/// A future whose value can be read from several places. This is how a
+/// multi-output exec exposes each of its named outputs as its own future:
+///
+/// ```ignore
+/// let outputs = prep.exec_shared(proc(exec) { LinkOutputs { .. } });
+/// let lib = outputs.map(proc(outputs) outputs.lib);
+/// let map_file = outputs.map(proc(outputs) outputs.map_file);
+/// ```
+pub struct SharedFuture<T> {
+    state: Arc<Mutex<SharedState<T>>>,
+}
+
+impl<T: Send + Clone> SharedFuture<T> {
+    pub fn new(future: Future<T>) -> SharedFuture<T> {
+        SharedFuture {
+            state: Arc::new(Mutex::new(SharedState {
+                future: Some(future),
+                value: None,
+            })),
+        }
+    }
+
+    pub fn from_value(value: T) -> SharedFuture<T> {
+        SharedFuture::new(Future::from_value(value))
+    }
+
+    /// Wait for the value and return a copy of it.
+    pub fn get(&self) -> T {
+        let mut state = self.state.lock();
+
+        if state.value.is_none() {
+            let future = state.future.take_unwrap();
+            state.value = Some(future.unwrap());
+        }
+
+        state.value.get_ref().clone()
+    }
+
+    /// Returns a future of part of the value, computed by `f` once the value
+    /// is ready.
+    pub fn map<U: Send>(&self, f: proc(T):Send -> U) -> Future<U> {
+        let shared = self.clone();
+        Future::from_fn(proc() f(shared.get()))
+    }
+}
+
+impl<T: Send> Clone for SharedFuture<T> {
+    fn clone(&self) -> SharedFuture<T> {
+        SharedFuture { state: self.state.clone() }
+    }
+}
+
+impl<T: Send + Clone> IntoFuture<T> for SharedFuture<T> {
+    fn into_future(self) -> Future<T> {
+        Future::from_fn(proc() self.get())
+    }
+}
@@ -0,0 +1,207 @@
+//! Quoting of command lines, so that logged commands can be pasted into a
+//! shell and arguments with spaces survive response files.
+
+use std::io::{File, IoResult};
+
+/// Returns `arg` quoted for the platform's shell, or unchanged if it doesn't
+/// need quoting.
+#[cfg(not(windows))]
+pub fn quote(arg: &str) -> ~str {
+    let safe = !arg.is_empty() && arg.chars().all(|c| {
+        c.is_alphanumeric() || "-_./=:,+@%".contains_char(c)
+    });
+
+    if safe {
+        return arg.to_owned();
+    }
+
+    // Nothing is special inside single quotes, except the quote itself.
+    format!("'{}'", arg.replace("'", "'\\''"))
+}
+
+/// Returns `arg` quoted the way `CommandLineToArgvW` parses it, or unchanged
+/// if it doesn't need quoting.
+#[cfg(windows)]
+pub fn quote(arg: &str) -> ~str {
+    if !arg.is_empty() && !arg.chars().any(|c| c == ' ' || c == '\t' || c == '"') {
+        return arg.to_owned();
+    }
+
+    let mut quoted = StrBuf::from_str("\"");
+    let mut backslashes = 0;
+
+    for c in arg.chars() {
+        match c {
+            '\\' => { backslashes += 1; }
+            '"' => {
+                // Backslashes are only special before a quote.
+                for _ in range(0, backslashes * 2 + 1) { quoted.push_char('\\'); }
+                quoted.push_char('"');
+                backslashes = 0;
+            }
+            c => {
+                for _ in range(0, backslashes) { quoted.push_char('\\'); }
+                quoted.push_char(c);
+                backslashes = 0;
+            }
+        }
+    }
+
+    // The closing quote doubles any trailing backslashes.
+    for _ in range(0, backslashes * 2) { quoted.push_char('\\'); }
+    quoted.push_char('"');
+
+    quoted.into_owned()
+}
+
+/// Returns the command line running `program` with `args`, quoted so that
+/// it can be pasted into a shell.
+pub fn join<S: Str>(program: &str, args: &[S]) -> ~str {
+    let mut cmd = StrBuf::from_str(quote(program));
+    for arg in args.iter() {
+        cmd.push_char(' ');
+        cmd.push_str(quote(arg.as_slice()));
+    }
+    cmd.into_owned()
+}
+
+/// Splits a command line into its arguments the way a POSIX shell does,
+/// handling quotes and backslashes but not expansions.
+pub fn split(cmd: &str) -> Vec<~str> {
+    let mut args = Vec::new();
+    let mut arg = StrBuf::new();
+    let mut in_arg = false;
+    let mut chars = cmd.chars();
+
+    loop {
+        let c = match chars.next() {
+            Some(c) => c,
+            None => { break; }
+        };
+
+        match c {
+            '\'' => {
+                in_arg = true;
+                for c in chars.by_ref() {
+                    if c == '\'' { break; }
+                    arg.push_char(c);
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        None | Some('"') => { break; }
+                        Some('\\') => {
+                            // Only these are escaped inside double quotes.
+                            match chars.next() {
+                                Some(c) if "\"\\$`".contains_char(c) => { arg.push_char(c); }
+                                Some(c) => { arg.push_char('\\'); arg.push_char(c); }
+                                None => { break; }
+                            }
+                        }
+                        Some(c) => { arg.push_char(c); }
+                    }
+                }
+            }
+            '\\' => {
+                in_arg = true;
+                match chars.next() {
+                    Some(c) => { arg.push_char(c); }
+                    None => { }
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(arg.into_owned());
+                    arg = StrBuf::new();
+                    in_arg = false;
+                }
+            }
+            c => {
+                in_arg = true;
+                arg.push_char(c);
+            }
+        }
+    }
+
+    if in_arg {
+        args.push(arg.into_owned());
+    }
+
+    args
+}
+
+/// Returns `arg` quoted for a gcc style response file, where arguments are
+/// separated by whitespace and can be quoted with backslashes.
+pub fn quote_response_arg(arg: &str) -> ~str {
+    let mut quoted = StrBuf::new();
+    for c in arg.chars() {
+        if c.is_whitespace() || c == '\\' || c == '"' || c == '\'' {
+            quoted.push_char('\\');
+        }
+        quoted.push_char(c);
+    }
+    quoted.into_owned()
+}
+
+/// Writes `args` to a response file at `path`, one per line, for passing
+/// long command lines as `@path`.
+pub fn write_response_file<S: Str>(path: &Path, args: &[S]) -> IoResult<()> {
+    let mut file = try!(File::create(path));
+    for arg in args.iter() {
+        try!(file.write_str(quote_response_arg(arg.as_slice())));
+        try!(file.write_str("\n"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{quote, join, split};
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("gcc -c foo.c"), vec!(~"gcc", ~"-c", ~"foo.c"));
+        assert_eq!(split("  ccache\tgcc  "), vec!(~"ccache", ~"gcc"));
+        assert_eq!(split("a 'b c' \"d e\" f\\ g"), vec!(~"a", ~"b c", ~"d e", ~"f g"));
+        assert_eq!(split("-DNAME=\"\\\"x\\\"\""), vec!(~"-DNAME=\"x\""));
+        assert_eq!(split("'it'\\''s'"), vec!(~"it's"));
+        assert_eq!(split("\"a\\nb\""), vec!(~"a\\nb"));
+        assert_eq!(split("'' x"), vec!(~"", ~"x"));
+        assert_eq!(split(""), Vec::<~str>::new());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("foo.c"), ~"foo.c");
+        assert_eq!(quote("-I/usr/include"), ~"-I/usr/include");
+        assert_eq!(quote(""), ~"''");
+        assert_eq!(quote("a b"), ~"'a b'");
+        assert_eq!(quote("it's"), ~"'it'\\''s'");
+        assert_eq!(quote("$HOME"), ~"'$HOME'");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("foo.c"), ~"foo.c");
+        assert_eq!(quote(""), ~"\"\"");
+        assert_eq!(quote("a b"), ~"\"a b\"");
+        assert_eq!(quote("a\"b"), ~"\"a\\\"b\"");
+        assert_eq!(quote("C:\\my dir\\"), ~"\"C:\\my dir\\\\\"");
+    }
+
+    // What's logged can be pasted back into a shell.
+    #[cfg(not(windows))]
+    #[test]
+    fn test_round_trip() {
+        let args = [~"a b", ~"it's", ~"", ~"$HOME", ~"back\\slash", ~"tab\there", ~"\"quoted\"", ~"*.c"];
+        let cmd = join("prog", args);
+
+        let mut expected = vec!(~"prog");
+        expected.push_all(args);
+        assert_eq!(split(cmd), expected);
+    }
+}
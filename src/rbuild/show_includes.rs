@@ -0,0 +1,59 @@
+//! Parses the header dependencies MSVC and clang-cl report with
+//! `/showIncludes`, since they can't write depfiles.
+
+/// What cl prints before each header with an English locale. Other locales
+/// translate it, so it can be overridden with `ProcessBuilder::show_includes`.
+pub static DEFAULT_PREFIX: &'static str = "Note: including file:";
+
+/// Splits the output of a compile into the headers it included and the
+/// rest of the output, such as the name of the source and diagnostics.
+pub fn parse(output: &str, prefix: &str) -> (Vec<Path>, ~str) {
+    let mut includes = Vec::new();
+    let mut rest = StrBuf::new();
+
+    for line in output.lines_any() {
+        if line.starts_with(prefix) {
+            // Nested includes are indented by their depth.
+            let include = Path::new(line.slice_from(prefix.len()).trim());
+            if !includes.contains(&include) {
+                includes.push(include);
+            }
+        } else {
+            rest.push_str(line);
+            rest.push_char('\n');
+        }
+    }
+
+    (includes, rest.into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, DEFAULT_PREFIX};
+
+    #[test]
+    fn test_parse() {
+        let output = "foo.c\r\n\
+                      Note: including file: C:\\src\\foo.h\r\n\
+                      Note: including file:  C:\\src\\bar.h\r\n\
+                      Note: including file: C:\\src\\foo.h\r\n\
+                      foo.c(3): warning C4101: 'x': unreferenced local variable\r\n";
+
+        let (includes, rest) = parse(output, DEFAULT_PREFIX);
+        assert_eq!(includes, vec!(Path::new("C:\\src\\foo.h"), Path::new("C:\\src\\bar.h")));
+        assert_eq!(rest, ~"foo.c\nfoo.c(3): warning C4101: 'x': unreferenced local variable\n");
+    }
+
+    #[test]
+    fn test_localized_prefix() {
+        let output = "Hinweis: Einlesen der Datei: C:\\src\\foo.h\n";
+
+        let (includes, rest) = parse(output, "Hinweis: Einlesen der Datei:");
+        assert_eq!(includes, vec!(Path::new("C:\\src\\foo.h")));
+        assert_eq!(rest, ~"");
+
+        let (includes, rest) = parse(output, DEFAULT_PREFIX);
+        assert!(includes.is_empty());
+        assert_eq!(rest, ~"Hinweis: Einlesen der Datei: C:\\src\\foo.h\n");
+    }
+}
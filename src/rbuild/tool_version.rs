@@ -0,0 +1,179 @@
+use std::cmp;
+use std::io::Process;
+use std::str;
+
+/// A constraint on the version of a tool, such as ">=9" or "<4.2". A bare
+/// version like "9" or "9.3" matches any version it is a prefix of. Missing
+/// parts count as zeros, so ">9" rejects 9.0, and upper bounds only look as
+/// far as the requirement does, so "<=9" accepts any 9.x.
+#[deriving(Clone, Show)]
+pub struct VersionReq {
+    op: Op,
+    version: Vec<uint>,
+}
+
+#[deriving(Clone, Eq, Show)]
+enum Op {
+    Ge,
+    Gt,
+    Le,
+    Lt,
+    Prefix,
+}
+
+impl VersionReq {
+    pub fn parse(s: &str) -> Option<VersionReq> {
+        let s = s.trim();
+
+        let (op, rest) = if s.starts_with(">=") {
+            (Ge, s.slice_from(2))
+        } else if s.starts_with("<=") {
+            (Le, s.slice_from(2))
+        } else if s.starts_with(">") {
+            (Gt, s.slice_from(1))
+        } else if s.starts_with("<") {
+            (Lt, s.slice_from(1))
+        } else if s.starts_with("=") {
+            (Prefix, s.trim_left_chars('='))
+        } else {
+            (Prefix, s)
+        };
+
+        parse_version(rest.trim()).map(|version| {
+            VersionReq { op: op, version: version }
+        })
+    }
+
+    pub fn matches(&self, version: &[uint]) -> bool {
+        let req = self.version.as_slice();
+        let len = cmp::max(version.len(), req.len());
+
+        match self.op {
+            Ge => pad(version, len) >= pad(req, len),
+            Gt => pad(version, len) > pad(req, len),
+            Le => pad(version, req.len()).as_slice() <= req,
+            Lt => pad(version, req.len()).as_slice() < req,
+            Prefix => version.starts_with(req),
+        }
+    }
+}
+
+/// Cuts or zero-extends `version` to `len` parts.
+fn pad(version: &[uint], len: uint) -> Vec<uint> {
+    Vec::from_fn(len, |i| if i < version.len() { version[i] } else { 0 })
+}
+
+/// Parses a dotted version such as "9.3.0".
+pub fn parse_version(s: &str) -> Option<Vec<uint>> {
+    let mut version = Vec::new();
+
+    for part in s.split('.') {
+        match from_str::<uint>(part) {
+            Some(n) => { version.push(n); }
+            None => { return None; }
+        }
+    }
+
+    Some(version)
+}
+
+/// Finds the version in the output of `tool --version`: the last word on
+/// the first line that looks like a dotted version, e.g. "9.3.0" in
+/// "gcc (Ubuntu 9.3.0-17ubuntu1~20.04) 9.3.0". It's the last one because
+/// distributions put their own package versions in front of it.
+pub fn find_version(output: &str) -> Option<Vec<uint>> {
+    let line = match output.lines().next() {
+        Some(line) => line,
+        None => { return None; }
+    };
+
+    for word in line.words().rev() {
+        // Strip suffixes like "-17ubuntu1" or a trailing ")".
+        let word = word.split(|c: char| c != '.' && !c.is_digit()).next().unwrap_or("");
+        if word.contains_char('.') {
+            match parse_version(word.trim_chars('.')) {
+                Some(version) => { return Some(version); }
+                None => { }
+            }
+        }
+    }
+
+    None
+}
+
+/// Runs `prog --version` and returns the version it reports.
+pub fn tool_version(prog: &Path) -> Option<Vec<uint>> {
+    let args = [~"--version"];
+    match Process::output(prog.as_str().unwrap(), args) {
+        Ok(output) => {
+            let stdout = str::from_utf8_lossy(output.output.as_slice()).into_owned();
+            find_version(stdout)
+        }
+        Err(_) => None,
+    }
+}
+
+pub fn version_to_str(version: &[uint]) -> ~str {
+    let parts: Vec<~str> = version.iter().map(|n| n.to_str()).collect();
+    parts.connect(".")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{VersionReq, parse_version, find_version, version_to_str};
+
+    #[test]
+    fn test_find_version() {
+        assert_eq!(find_version("gcc (Ubuntu 9.3.0-17ubuntu1~20.04) 9.3.0\nCopyright (C) 2019\n"),
+                   Some(vec!(9, 3, 0)));
+        assert_eq!(find_version("gcc (GCC) 4.8.5 20150623 (Red Hat 4.8.5-44)\n"), Some(vec!(4, 8, 5)));
+        assert_eq!(find_version("clang version 10.0.0-4ubuntu1 \nTarget: x86_64-pc-linux-gnu\n"),
+                   Some(vec!(10, 0, 0)));
+        assert_eq!(find_version("Apple clang version 12.0.0 (clang-1200.0.32.29)\n"), Some(vec!(12, 0, 0)));
+        assert_eq!(find_version("rustc 1.50.0 (cb75ad5db 2021-02-10)\n"), Some(vec!(1, 50, 0)));
+        assert_eq!(find_version("GNU Make 4.2.1\n"), Some(vec!(4, 2, 1)));
+        assert_eq!(find_version("no version here\n"), None);
+        assert_eq!(find_version(""), None);
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("9.3.0"), Some(vec!(9, 3, 0)));
+        assert_eq!(parse_version("9"), Some(vec!(9)));
+        assert_eq!(parse_version("9.x"), None);
+        assert_eq!(version_to_str([9, 3, 0]), ~"9.3.0");
+    }
+
+    #[test]
+    fn test_version_req() {
+        let req = |s: &str| VersionReq::parse(s).unwrap();
+
+        assert!(req(">=9").matches([9, 3]));
+        assert!(!req(">=9").matches([8, 5]));
+        assert!(req(">9").matches([9, 0, 1]));
+        assert!(!req(">9").matches([9]));
+        assert!(req("<4.2").matches([4, 1, 9]));
+        assert!(!req("<4.2").matches([4, 2]));
+        assert!(req("<= 4.2").matches([4, 2]));
+        assert!(req("9.3").matches([9, 3, 0]));
+        assert!(!req("9.3").matches([9, 4]));
+        assert!(req("=9").matches([9, 1]));
+        assert!(VersionReq::parse(">=nine").is_none());
+    }
+
+    #[test]
+    fn test_version_req_lengths() {
+        let req = |s: &str| VersionReq::parse(s).unwrap();
+
+        assert!(req(">=9").matches([9]));
+        assert!(req(">=9.0").matches([9]));
+        assert!(!req(">9").matches([9, 0]));
+        assert!(!req(">9.0").matches([9]));
+        assert!(req("<=9").matches([9, 3]));
+        assert!(req("<=9").matches([9, 3, 1]));
+        assert!(!req("<=9").matches([10]));
+        assert!(!req("<9").matches([9, 3]));
+        assert!(req("<9").matches([8, 9]));
+        assert!(req("<4.2").matches([4]));
+    }
+}
@@ -14,13 +14,23 @@
 use serialize::json;
 use serialize::json::ToJson;
 use serialize::{Encoder, Encodable, Decoder, Decodable};
-use sync::{Arc, RWLock, Future};
+use sync::{Arc, Mutex, RWLock, Future};
 use collections::TreeMap;
 use std::str;
 use std::io;
 use std::io::{File, IoError, MemWriter};
+use std::io::fs;
+use std::os;
+use std::any::{Any, AnyRefExt};
 use std::cell::RefCell;
+use std::cmp;
+use std::fmt;
 use std::str::IntoMaybeOwned;
+use std::sync::atomics::{AtomicBool, AtomicUint, SeqCst};
+use std::task;
+use time;
+
+use shared_future::SharedFuture;
 
 /**
 *
@@ -116,6 +126,37 @@ struct KindMap(TreeMap<~str, ~str>);
 impl WorkMap {
     fn new() -> WorkMap { WorkMap(TreeMap::new()) }
 
+    // returns pairs of (kind, name)
+    fn work_keys(&self) -> Vec<(~str, ~str)> {
+        let mut rs = Vec::new();
+        let WorkMap(ref map) = *self;
+        for (name, kindmap) in map.iter() {
+            let KindMap(ref kindmap) = *kindmap;
+            for (kind, _) in kindmap.iter() {
+                rs.push((kind.clone(), name.clone()));
+            }
+        }
+        rs
+    }
+
+    fn find<'a>(&'a self, kind: &str, name: &str) -> Option<&'a ~str> {
+        let WorkMap(ref map) = *self;
+        map.find(&name.to_owned()).and_then(|&KindMap(ref kindmap)| kindmap.find(&kind.to_owned()))
+    }
+
+    /// Returns triples of (kind, name, value).
+    fn works(&self) -> Vec<(~str, ~str, ~str)> {
+        let mut rs = Vec::new();
+        let WorkMap(ref map) = *self;
+        for (name, kindmap) in map.iter() {
+            let KindMap(ref kindmap) = *kindmap;
+            for (kind, value) in kindmap.iter() {
+                rs.push((kind.clone(), name.clone(), value.clone()));
+            }
+        }
+        rs
+    }
+
     fn insert_work_key(&mut self, k: WorkKey, value: ~str) {
         let WorkKey { kind, name } = k;
 
@@ -135,17 +176,151 @@ impl WorkMap {
     }
 }
 
+/// A cache entry with its works already decoded, so that checking whether
+/// it's fresh doesn't have to decode anything. The result is only decoded
+/// on a cache hit, so it's kept as JSON.
+#[deriving(Clone)]
+struct Record {
+    discovered_inputs: WorkMap,
+    discovered_outputs: WorkMap,
+    result: ~str,
+}
+
+impl Record {
+    /// Parses the form records are saved in: a JSON list of the discovered
+    /// inputs, the discovered outputs and the result.
+    fn parse(s: &str) -> Option<Record> {
+        let mut list = match json::from_str(s) {
+            Ok(json::List(list)) => list,
+            _ => { return None; }
+        };
+
+        if list.len() != 3 {
+            return None;
+        }
+
+        let result = list.pop().unwrap();
+        let mut outputs = json::Decoder::new(list.pop().unwrap());
+        let mut inputs = json::Decoder::new(list.pop().unwrap());
+
+        match (Decodable::decode(&mut inputs), Decodable::decode(&mut outputs)) {
+            (Ok(inputs), Ok(outputs)) => {
+                Some(Record {
+                    discovered_inputs: inputs,
+                    discovered_outputs: outputs,
+                    result: result.to_str(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn to_json_str(&self) -> ~str {
+        format!("[{},{},{}]",
+                json_encode(&self.discovered_inputs),
+                json_encode(&self.discovered_outputs),
+                self.result)
+    }
+}
+
 pub struct Database {
     db_filename: Path,
-    db_cache: TreeMap<~str, ~str>,
+    /// The directory paths in the database are relative to. It's recorded
+    /// so that the absolute paths that remain can be moved along with the
+    /// project.
+    db_root: Path,
+    db_cache: TreeMap<~str, Record>,
+    /// How long the last exec of each cache entry took, in nanoseconds.
+    db_timings: TreeMap<~str, u64>,
+    /// The section sizes of each artifact that was measured, in bytes.
+    db_sizes: TreeMap<~str, TreeMap<~str, u64>>,
+    /// How long the last run of each step took, by label. Unlike the
+    /// timings, these survive changes to the inputs.
+    db_durations: TreeMap<~str, u64>,
+    /// The length of the longest path from each step to the end of the
+    /// build, by label, for scheduling the long poles first.
+    db_priorities: TreeMap<~str, u64>,
+    /// The stats of the last `MAX_RUNS` runs, oldest first.
+    db_runs: Vec<RunStats>,
     pub db_dirty: bool,
 }
 
+/// How many runs' stats the database keeps.
+static MAX_RUNS: uint = 100;
+
+/// The totals of one run, kept to show how the build changes over time.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct RunStats {
+    /// When the run started, in seconds since the epoch.
+    pub started: i64,
+    /// How long the run took in nanoseconds.
+    pub wall_time: u64,
+    /// How many execs were looked up, and how many of those were cached.
+    pub commands: uint,
+    pub cached: uint,
+    /// How many bytes of files were hashed to check them for changes.
+    pub bytes_hashed: u64,
+}
+
+impl RunStats {
+    /// The share of commands that were cache hits, from 0 to 1.
+    pub fn hit_rate(&self) -> f64 {
+        if self.commands == 0 {
+            1.0
+        } else {
+            self.cached as f64 / self.commands as f64
+        }
+    }
+}
+
+impl ToJson for RunStats {
+    fn to_json(&self) -> json::Json {
+        let mut obj = TreeMap::new();
+        obj.insert(~"started", self.started.to_json());
+        obj.insert(~"wall_time", self.wall_time.to_json());
+        obj.insert(~"commands", self.commands.to_json());
+        obj.insert(~"cached", self.cached.to_json());
+        obj.insert(~"bytes_hashed", self.bytes_hashed.to_json());
+        json::Object(~obj)
+    }
+}
+
+/// The version of the database layout written by this rbuild. Databases
+/// from before versions were recorded are version 0.
+static DB_VERSION: u64 = 1;
+
+/// The on-disk layout of the database, one section per kind of record.
+/// Sections added after the first version may be missing from an older
+/// database, and start out empty.
+struct DatabaseFile {
+    version: u64,
+    root: ~str,
+    cache: TreeMap<~str, ~str>,
+    timings: TreeMap<~str, u64>,
+    sizes: TreeMap<~str, TreeMap<~str, u64>>,
+    durations: TreeMap<~str, u64>,
+    priorities: TreeMap<~str, u64>,
+    runs: Vec<RunStats>,
+}
+
 impl Database {
     pub fn new(p: Path) -> Database {
+        Database::new_in_root(p, os::getcwd())
+    }
+
+    /// Open the database at `p` for the project in `root`. If the project
+    /// was moved since the database was written, the paths into the old
+    /// location are moved to `root`.
+    pub fn new_in_root(p: Path, root: Path) -> Database {
         let mut db = Database {
             db_filename: p,
+            db_root: root,
             db_cache: TreeMap::new(),
+            db_timings: TreeMap::new(),
+            db_sizes: TreeMap::new(),
+            db_durations: TreeMap::new(),
+            db_priorities: TreeMap::new(),
+            db_runs: Vec::new(),
             db_dirty: false
         };
         if db.db_filename.exists() {
@@ -154,59 +329,329 @@ impl Database {
         db
     }
 
-    fn prepare<'a, T: Decodable<json::Decoder, json::Error>>(
-                   &self,
-                   fn_name: &str,
-                   declared_inputs: &WorkMap)
-                   -> Option<(WorkMap, WorkMap, T)> {
+    fn prepare(&self, fn_name: &str, declared_inputs: &WorkMap) -> Option<Record> {
         let k = json_encode(&(fn_name, declared_inputs));
-        self.db_cache.find(&k).and_then(|v| {
-            Some(json_decode(*v))
-        })
+        self.db_cache.find(&k).map(|record| record.clone())
     }
 
-    fn cache<'a, T: Encodable<json::Encoder<'a>, IoError>>(
-                 &mut self,
-                 fn_name: &str,
-                 declared_inputs: &WorkMap,
-                 discovered_inputs: &WorkMap,
-                 discovered_outputs: &WorkMap,
-                 result: &T) {
+    /// Record an exec, whose result has already been encoded as JSON.
+    fn cache(&mut self,
+             fn_name: &str,
+             declared_inputs: &WorkMap,
+             discovered_inputs: &WorkMap,
+             discovered_outputs: &WorkMap,
+             result: ~str,
+             duration: u64) {
         let k = json_encode(&(fn_name, declared_inputs));
-        let v = json_encode(&(discovered_inputs,
-                              discovered_outputs,
-                              result));
-        self.db_cache.insert(k,v);
+        let record = Record {
+            discovered_inputs: discovered_inputs.clone(),
+            discovered_outputs: discovered_outputs.clone(),
+            result: result,
+        };
+        self.db_cache.insert(k.clone(), record);
+        self.db_timings.insert(k, duration);
         self.db_dirty = true
     }
 
+    /// Returns the declared inputs of the cached exec of `fn_name` that is
+    /// most like one with `declared_inputs`: the one sharing the most
+    /// inputs, and then the most values. This is most likely the same step
+    /// before some of its inputs changed.
+    fn closest_entry(&self, fn_name: &str, declared_inputs: &WorkMap) -> Option<WorkMap> {
+        let works = declared_inputs.works();
+        let mut best: Option<((uint, uint), WorkMap)> = None;
+
+        for k in self.db_cache.keys() {
+            let (name, inputs): (~str, WorkMap) = json_decode(*k);
+            if name.as_slice() != fn_name {
+                continue;
+            }
+
+            let mut score = (0, 0);
+            for &(ref kind, ref name, ref value) in works.iter() {
+                match inputs.find(kind.as_slice(), name.as_slice()) {
+                    Some(old) => {
+                        let (keys, values) = score;
+                        score = (keys + 1, if old == value { values + 1 } else { values });
+                    }
+                    None => { }
+                }
+            }
+
+            let (keys, _) = score;
+            let better = match best {
+                Some((ref best_score, _)) => score > *best_score,
+                None => keys > 0,
+            };
+            if better {
+                best = Some((score, inputs));
+            }
+        }
+
+        best.map(|(_, inputs)| inputs)
+    }
+
+    /// Returns every cached exec as its function name, declared inputs,
+    /// discovered inputs and outputs, and how long it took.
+    fn entries(&self) -> Vec<(~str, WorkMap, WorkMap, WorkMap, Option<u64>)> {
+        let mut entries = Vec::new();
+
+        for (k, record) in self.db_cache.iter() {
+            let (fn_name, declared_inputs): (~str, WorkMap) = json_decode(*k);
+            let duration = self.db_timings.find(k).map(|duration| *duration);
+
+            entries.push((fn_name,
+                          declared_inputs,
+                          record.discovered_inputs.clone(),
+                          record.discovered_outputs.clone(),
+                          duration));
+        }
+
+        entries
+    }
+
+    /// Returns how long the last exec of this function took, if it has run.
+    pub fn timing(&self, fn_name: &str, declared_inputs: &WorkMap) -> Option<u64> {
+        let k = json_encode(&(fn_name, declared_inputs));
+        self.db_timings.find(&k).map(|duration| *duration)
+    }
+
+    /// Record the section sizes of `artifact`, returning the sizes from the
+    /// last time it was measured.
+    pub fn record_sizes(&mut self, artifact: &str, sizes: TreeMap<~str, u64>) -> Option<TreeMap<~str, u64>> {
+        self.db_dirty = true;
+        self.db_sizes.swap(artifact.to_owned(), sizes)
+    }
+
+    pub fn record_duration(&mut self, label: &str, duration: u64) {
+        self.db_durations.insert(label.to_owned(), duration);
+        self.db_dirty = true;
+    }
+
+    pub fn durations(&self) -> TreeMap<~str, u64> {
+        self.db_durations.clone()
+    }
+
+    pub fn priority(&self, label: &str) -> Option<u64> {
+        self.db_priorities.find_equiv(&label).map(|priority| *priority)
+    }
+
+    pub fn set_priorities(&mut self, priorities: TreeMap<~str, u64>) {
+        self.db_priorities = priorities;
+        self.db_dirty = true;
+    }
+
+    /// Record the stats of a run, forgetting the oldest run once there are
+    /// more than `MAX_RUNS`.
+    pub fn record_run(&mut self, stats: RunStats) {
+        self.db_runs.push(stats);
+        if self.db_runs.len() > MAX_RUNS {
+            self.db_runs.shift();
+        }
+        self.db_dirty = true;
+    }
+
+    pub fn runs(&self) -> Vec<RunStats> {
+        self.db_runs.clone()
+    }
+
     // FIXME #4330: This should have &mut self and should set self.db_dirty to false.
     fn save(&self) -> io::IoResult<()> {
-        let mut f = File::create(&self.db_filename);
-        self.db_cache.to_json().to_pretty_writer(&mut f)
+        let cache: TreeMap<~str, ~str> = self.db_cache.iter().map(|(k, record)| {
+            (k.clone(), record.to_json_str())
+        }).collect();
+
+        let mut db = TreeMap::new();
+        db.insert(~"version", DB_VERSION.to_json());
+        db.insert(~"root", self.db_root.as_str().unwrap().to_json());
+        db.insert(~"cache", cache.to_json());
+        db.insert(~"timings", self.db_timings.to_json());
+        db.insert(~"sizes", self.db_sizes.to_json());
+        db.insert(~"durations", self.db_durations.to_json());
+        db.insert(~"priorities", self.db_priorities.to_json());
+        db.insert(~"runs", self.db_runs.to_json());
+
+        // Write to the side and rename, so a crash can't leave a half
+        // written database behind.
+        let mut tmp = self.db_filename.clone();
+        tmp.set_filename(format!("{}.tmp", self.db_filename.filename_str().unwrap()));
+
+        {
+            let mut f = File::create(&tmp);
+            try!(json::Object(~db).to_pretty_writer(&mut f));
+        }
+
+        fs::rename(&tmp, &self.db_filename)
     }
 
+    /// Write out any changes now rather than waiting for the database to be
+    /// dropped.
+    pub fn flush(&mut self) -> io::IoResult<()> {
+        if self.db_dirty {
+            try!(self.save());
+            self.db_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Load the database, starting over with an empty one if it can't be
+    /// read, e.g. because a crash left it half written. The bad file is
+    /// kept next to it for inspection.
     fn load(&mut self) {
         assert!(!self.db_dirty);
         assert!(self.db_filename.exists());
-        match File::open(&self.db_filename) {
-            Err(e) => {
-                fail!("Couldn't load workcache database {}: {}",
-                      self.db_filename.display(),
-                      e)
-            }
-            Ok(mut stream) => {
-                match json::from_reader(&mut stream) {
-                    Err(e) => fail!("Couldn't parse workcache database (from file {}): {}",
-                                    self.db_filename.display(), e.to_str()),
-                    Ok(r) => {
-                        let mut decoder = json::Decoder::new(r);
-                        self.db_cache = Decodable::decode(&mut decoder).unwrap();
+
+        let db = match self.read() {
+            Ok(db) => db,
+            Err(err) => {
+                let mut backup = self.db_filename.clone();
+                backup.set_filename(format!("{}.corrupt", self.db_filename.filename_str().unwrap()));
+
+                io::println(format!("warning: couldn't load workcache database {}: {}",
+                                    self.db_filename.display(), err));
+
+                match fs::rename(&self.db_filename, &backup) {
+                    Ok(()) => {
+                        io::println(format!("warning: moved it to {} and starting with an empty cache",
+                                            backup.display()));
+                    }
+                    Err(err) => {
+                        io::println(format!("warning: couldn't move it aside: {}", err));
                     }
                 }
+                return;
             }
+        };
+
+        // Rewrite an older database in the current layout.
+        if db.version < DB_VERSION {
+            self.db_dirty = true;
+        }
+
+        let old_root = db.root;
+        let new_root = self.db_root.as_str().unwrap();
+
+        if old_root.as_slice() == new_root {
+            self.db_cache = parse_records(&db.cache);
+            self.db_timings = db.timings;
+            self.db_sizes = db.sizes;
+            self.db_durations = db.durations;
+            self.db_priorities = db.priorities;
+            self.db_runs = db.runs;
+        } else {
+            let moved = |s: &~str| relocate(s.as_slice(), old_root.as_slice(), new_root);
+
+            let cache: TreeMap<~str, ~str> = db.cache.iter().map(|(k, v)| (moved(k), moved(v))).collect();
+            self.db_cache = parse_records(&cache);
+            self.db_timings = db.timings.iter().map(|(k, v)| (moved(k), *v)).collect();
+            self.db_sizes = db.sizes.iter().map(|(k, v)| (moved(k), v.clone())).collect();
+            self.db_durations = db.durations.iter().map(|(k, v)| (moved(k), *v)).collect();
+            self.db_priorities = db.priorities.iter().map(|(k, v)| (moved(k), *v)).collect();
+            self.db_runs = db.runs;
+
+            // Save the moved paths.
+            self.db_dirty = true;
         }
     }
+
+    fn read(&self) -> Result<DatabaseFile, ~str> {
+        let mut stream = match File::open(&self.db_filename) {
+            Ok(stream) => stream,
+            Err(err) => { return Err(err.to_str()); }
+        };
+
+        let json = match json::from_reader(&mut stream) {
+            Ok(json) => json,
+            Err(err) => { return Err(err.to_str()); }
+        };
+
+        let mut obj = match json {
+            json::Object(obj) => obj,
+            _ => { return Err(~"expected an object"); }
+        };
+
+        let version = try!(section(&mut *obj, "version")).unwrap_or(0);
+        if version > DB_VERSION {
+            return Err(format!("version {} was written by a newer rbuild", version));
+        }
+
+        Ok(DatabaseFile {
+            version: version,
+            root: try!(required_section(&mut *obj, "root")),
+            cache: try!(required_section(&mut *obj, "cache")),
+            timings: try!(section(&mut *obj, "timings")).unwrap_or_else(|| TreeMap::new()),
+            sizes: try!(section(&mut *obj, "sizes")).unwrap_or_else(|| TreeMap::new()),
+            durations: try!(section(&mut *obj, "durations")).unwrap_or_else(|| TreeMap::new()),
+            priorities: try!(section(&mut *obj, "priorities")).unwrap_or_else(|| TreeMap::new()),
+            runs: try!(section(&mut *obj, "runs")).unwrap_or_else(|| Vec::new()),
+        })
+    }
+}
+
+/// Decodes the section `name` of the database, or returns `None` if it
+/// isn't there.
+fn section<T: Decodable<json::Decoder, json::Error>>(
+    obj: &mut json::Object,
+    name: &str
+) -> Result<Option<T>, ~str> {
+    match obj.pop(&name.to_owned()) {
+        Some(json) => {
+            let mut decoder = json::Decoder::new(json);
+            match Decodable::decode(&mut decoder) {
+                Ok(value) => Ok(Some(value)),
+                Err(err) => Err(format!("{}: {}", name, err)),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+fn required_section<T: Decodable<json::Decoder, json::Error>>(
+    obj: &mut json::Object,
+    name: &str
+) -> Result<T, ~str> {
+    match try!(section(obj, name)) {
+        Some(value) => Ok(value),
+        None => Err(format!("missing section {}", name)),
+    }
+}
+
+/// Decodes the works of every cache entry up front, so that looking one up
+/// doesn't decode it again. Entries that don't parse are dropped.
+fn parse_records(cache: &TreeMap<~str, ~str>) -> TreeMap<~str, Record> {
+    cache.iter().filter_map(|(k, v)| {
+        Record::parse(v.as_slice()).map(|record| (k.clone(), record))
+    }).collect()
+}
+
+/// Replaces the paths into `old_root` in `s` with paths into `new_root`.
+/// Only whole path components match, so moving `/src/a` doesn't touch
+/// `/src/ab`.
+fn relocate(s: &str, old_root: &str, new_root: &str) -> ~str {
+    let mut result = StrBuf::new();
+    let mut rest = s;
+
+    loop {
+        match rest.find_str(old_root) {
+            Some(idx) => {
+                let end = idx + old_root.len();
+                let boundary = end == rest.len() || match rest.char_at(end) {
+                    '/' | '\\' | '"' | '=' => true,
+                    _ => false,
+                };
+
+                result.push_str(rest.slice_to(idx));
+                result.push_str(if boundary { new_root } else { old_root });
+                rest = rest.slice_from(end);
+            }
+            None => {
+                result.push_str(rest);
+                break;
+            }
+        }
+    }
+
+    result.into_owned()
 }
 
 #[unsafe_destructor]
@@ -219,47 +664,206 @@ impl Drop for Database {
     }
 }
 
-pub struct Logger;
+/// Where the build reports what it's doing. It's shared by every clone of
+/// a context, so its settings apply to all of them.
+pub struct Logger {
+    verbosity: AtomicUint,
+    quiet: AtomicBool,
+}
 
 impl Logger {
     pub fn new() -> Logger {
-        Logger
+        Logger {
+            verbosity: AtomicUint::new(0),
+            quiet: AtomicBool::new(false),
+        }
+    }
+
+    /// Show the messages logged with `verbose` when `verbosity` is above 0.
+    pub fn set_verbosity(&self, verbosity: uint) {
+        self.verbosity.store(verbosity, SeqCst);
+    }
+
+    /// Hide the messages logged with `info`.
+    pub fn set_quiet(&self, quiet: bool) {
+        self.quiet.store(quiet, SeqCst);
     }
 
     pub fn debug(&self, msg: &str) {
         debug!("{}", msg);
     }
 
+    /// Details that are only shown when the verbosity is raised.
+    pub fn verbose(&self, msg: &str) {
+        if self.verbosity.load(SeqCst) > 0 {
+            io::println(msg);
+        }
+    }
+
+    /// Progress, which isn't shown in quiet mode.
     pub fn info(&self, msg: &str) {
+        if !self.quiet.load(SeqCst) {
+            io::println(msg);
+        }
+    }
+
+    /// Failures and warnings, which are always shown.
+    pub fn error(&self, msg: &str) {
         io::println(msg);
     }
 }
 
-pub type FreshnessMap = TreeMap<~str, fn(name: &str, value: &str) -> bool>;
+pub type FreshnessFn = fn(name: &str, value: &str) -> bool;
+
+/// The results of freshness checks. Its generation changes every time it's
+/// cleared, so that a check that started before an exec changed the files
+/// can't record a result that's already out of date.
+struct FreshMemo {
+    generation: uint,
+    works: TreeMap<(~str, ~str, ~str), bool>,
+}
+
+impl FreshMemo {
+    fn new() -> FreshMemo {
+        FreshMemo {
+            generation: 0,
+            works: TreeMap::new(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.works.clear();
+    }
+}
+
+pub type FreshnessMap = TreeMap<~str, FreshnessFn>;
 
 #[deriving(Clone)]
 pub struct Context {
     pub db: Arc<RWLock<Database>>,
     pub logger: Arc<Logger>,
-    cfg: Arc<json::Object>,
     /// Map from kinds (source, exe, url, etc.) to a freshness function.
     /// The freshness function takes a name (e.g. file path) and value
     /// (e.g. hash of file contents) and determines whether it's up-to-date.
     /// For example, in the file case, this would read the file off disk,
     /// hash it, and return the result of comparing the given hash and the
     /// read hash for equality.
-    freshness: Arc<FreshnessMap>
+    freshness: Arc<RWLock<FreshnessMap>>,
+    /// Set once an exec has failed, so no new work gets started.
+    aborted: Arc<AtomicBool>,
+    /// How many execs failed only because one they depend on did.
+    cascaded: Arc<AtomicUint>,
+    /// Every exec failure seen so far in this run.
+    failures: Arc<Mutex<Vec<ExecError>>>,
+    /// Keep starting independent work after a failure instead of aborting.
+    keep_going: bool,
+    /// Print why each exec that misses the cache has to run.
+    explain: bool,
+    /// The works checked for freshness since an exec last ran, so that
+    /// files shared by many execs, like headers, are only hashed once.
+    fresh_memo: Arc<Mutex<FreshMemo>>,
+    /// How many works to check for freshness at once.
+    freshness_jobs: uint,
+    /// The encoded results of the execs started in this run, by cache key,
+    /// so that an identical exec prepared again shares the first one.
+    running: Arc<Mutex<TreeMap<~str, SharedFuture<~str>>>>,
+    /// Every exec looked up in this run, in the order they were looked up.
+    events: Arc<Mutex<Vec<ExecEvent>>>,
+}
+
+/// What the database knows about one cached exec, for `Context::entries`.
+#[deriving(Clone)]
+pub struct CacheEntry {
+    pub fn_name: ~str,
+    /// The works of the exec, as (kind, name, value) triples.
+    pub declared_inputs: Vec<(~str, ~str, ~str)>,
+    pub discovered_inputs: Vec<(~str, ~str, ~str)>,
+    pub discovered_outputs: Vec<(~str, ~str, ~str)>,
+    /// How long the exec took the last time it ran, in nanoseconds.
+    pub duration: Option<u64>,
+    /// Whether the exec would be taken from the cache if it ran now.
+    pub fresh: bool,
+}
+
+/// A record of one exec in this run, used for profiling.
+#[deriving(Clone)]
+pub struct ExecEvent {
+    pub fn_name: ~str,
+    pub label: ~str,
+    /// When the exec started, from `time::precise_time_ns`.
+    pub start: u64,
+    /// How long the exec took in nanoseconds. Cache hits take no time.
+    pub duration: u64,
+    pub cached: bool,
+    /// The inputs the exec discovered, as (kind, name, value) triples. For
+    /// a cache hit, the ones recorded when it last ran.
+    pub discovered_inputs: Vec<(~str, ~str, ~str)>,
+}
+
+/// The error an exec fails with when its proc (or a step it depends on)
+/// fails. It is raised as the failure value of the task that unwraps the
+/// exec's future.
+#[deriving(Clone)]
+pub struct ExecError {
+    pub fn_name: ~str,
+    /// The declared inputs of the failed exec, as (kind, name) pairs.
+    pub declared_inputs: Vec<(~str, ~str)>,
+    pub msg: ~str,
+}
+
+impl fmt::Show for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f.buf, "{} failed: {}", self.fn_name, self.msg));
+        for &(ref kind, ref name) in self.declared_inputs.iter() {
+            try!(write!(f.buf, "\n    {} {}", kind, name));
+        }
+        Ok(())
+    }
+}
+
+/// A failure whose details were already printed where it happened, such as
+/// a command that failed after printing its output. It's recorded like any
+/// other failure, but not printed again.
+#[deriving(Clone, Show)]
+pub struct Reported(pub ~str);
+
+/// Extracts a readable message out of a task failure value.
+pub fn failure_msg(cause: &~Any:Send) -> ~str {
+    match cause.as_ref::<ExecError>() {
+        Some(err) => return err.to_str(),
+        None => { }
+    }
+
+    match cause.as_ref::<Reported>() {
+        Some(&Reported(ref msg)) => return msg.clone(),
+        None => { }
+    }
+
+    match cause.as_ref::<&'static str>() {
+        Some(msg) => return msg.to_owned(),
+        None => { }
+    }
+
+    match cause.as_ref::<~str>() {
+        Some(msg) => msg.clone(),
+        None => ~"unknown failure",
+    }
 }
 
 pub struct Prep {
     ctxt: Context,
     fn_name: str::SendStr,
+    label: Option<~str>,
     declared_inputs: WorkMap,
 }
 
 pub struct Exec {
     discovered_inputs: WorkMap,
-    discovered_outputs: WorkMap
+    discovered_outputs: WorkMap,
+    label: ~str,
+    start: u64,
+    duration: u64,
 }
 
 fn json_encode<'a, T: Encodable<json::Encoder<'a>, IoError>>(t: &T) -> ~str {
@@ -276,29 +880,182 @@ fn json_decode<T: Decodable<json::Decoder, json::Error>>(s: &str) -> T {
     Decodable::decode(&mut decoder).unwrap()
 }
 
+/// Like `json_decode`, but returns `None` if `s` doesn't decode as a `T`.
+fn json_try_decode<T: Decodable<json::Decoder, json::Error>>(s: &str) -> Option<T> {
+    json::from_str(s).ok().and_then(|j| {
+        let mut decoder = json::Decoder::new(j);
+        Decodable::decode(&mut decoder).ok()
+    })
+}
+
 impl Context {
     pub fn new(db: Database) -> Context {
-        Context::new_with_freshness(db, Logger::new(), TreeMap::new(), TreeMap::new())
+        Context::new_with_freshness(db, Logger::new(), TreeMap::new())
     }
 
     pub fn new_with_freshness(db: Database,
                               logger: Logger,
-                              cfg: json::Object,
                               freshness: FreshnessMap) -> Context {
         Context {
             db: Arc::new(RWLock::new(db)),
             logger: Arc::new(logger),
-            cfg: Arc::new(cfg),
-            freshness: Arc::new(freshness),
+            freshness: Arc::new(RWLock::new(freshness)),
+            aborted: Arc::new(AtomicBool::new(false)),
+            cascaded: Arc::new(AtomicUint::new(0)),
+            failures: Arc::new(Mutex::new(Vec::new())),
+            keep_going: false,
+            explain: false,
+            fresh_memo: Arc::new(Mutex::new(FreshMemo::new())),
+            freshness_jobs: os::num_cpus(),
+            running: Arc::new(Mutex::new(TreeMap::new())),
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Forget the failures and events of the previous run so the context
+    /// can be used to build again.
+    pub fn reset_run(&self) {
+        self.aborted.store(false, SeqCst);
+        self.cascaded.store(0, SeqCst);
+        self.failures.lock().clear();
+        self.events.lock().clear();
+        self.fresh_memo.lock().clear();
+        self.running.lock().clear();
+    }
+
+    pub fn flush(&self) -> io::IoResult<()> {
+        self.db.write().flush()
+    }
+
+    pub fn record_sizes(&self, artifact: &str, sizes: TreeMap<~str, u64>) -> Option<TreeMap<~str, u64>> {
+        self.db.write().record_sizes(artifact, sizes)
+    }
+
+    /// The last known duration of every step, by label.
+    pub fn durations(&self) -> TreeMap<~str, u64> {
+        self.db.read().durations()
+    }
+
+    /// How urgent the step `label` is: the length of the longest path from
+    /// it to the end of the last build, or 0 if it hasn't run before.
+    pub fn priority(&self, label: &str) -> u64 {
+        self.db.read().priority(label).unwrap_or(0)
+    }
+
+    pub fn set_priorities(&self, priorities: TreeMap<~str, u64>) {
+        self.db.write().set_priorities(priorities)
+    }
+
+    /// Save the stats of a run in the database, for `runs`.
+    pub fn record_run(&self, stats: RunStats) {
+        self.db.write().record_run(stats)
+    }
+
+    /// The stats of the previous runs, oldest first.
+    pub fn runs(&self) -> Vec<RunStats> {
+        self.db.read().runs()
+    }
+
+    /// Returns the execs looked up so far in this run.
+    pub fn events(&self) -> Vec<ExecEvent> {
+        self.events.lock().clone()
+    }
+
+    pub fn set_keep_going(&mut self, keep_going: bool) {
+        self.keep_going = keep_going;
+    }
+
+    pub fn keep_going(&self) -> bool {
+        self.keep_going
+    }
+
+    pub fn set_explain(&mut self, explain: bool) {
+        self.explain = explain;
+    }
+
+    /// Check at most `jobs` works for freshness at once. Defaults to the
+    /// number of CPUs.
+    pub fn set_freshness_jobs(&mut self, jobs: uint) {
+        self.freshness_jobs = cmp::max(jobs, 1);
+    }
+
+    /// Returns the exec failures recorded so far.
+    pub fn failures(&self) -> Vec<ExecError> {
+        self.failures.lock().clone()
+    }
+
+    /// Stop starting new work, e.g. because the build was interrupted.
+    pub fn abort(&self) {
+        self.aborted.store(true, SeqCst)
+    }
+
+    /// Returns whether an exec has failed and the build is being aborted.
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(SeqCst)
+    }
+
+    /// How many execs failed only because one they depend on did. They
+    /// aren't in `failures`.
+    pub fn cascaded(&self) -> uint {
+        self.cascaded.load(SeqCst)
+    }
+
+    fn fail_exec(&self, err: ExecError) {
+        self.failures.lock().push(err);
+
+        if !self.keep_going {
+            self.aborted.store(true, SeqCst)
         }
     }
 
+    /// Register (or replace) the freshness function for a kind of work.
+    /// The map is shared, so every clone of this context sees the change.
+    pub fn register_freshness(&self, kind: &str, freshness: FreshnessFn) {
+        self.freshness.write().insert(kind.to_owned(), freshness);
+    }
+
     pub fn prep<T: IntoMaybeOwned<'static>>(&self, fn_name: T) -> Prep {
         Prep::new(self.clone(), fn_name)
     }
+
+    /// Returns every exec in the database, checking whether each is fresh
+    /// without running anything.
+    pub fn entries(&self) -> Vec<CacheEntry> {
+        let entries = self.db.read().entries();
+
+        entries.move_iter().map(|(fn_name, declared, inputs, outputs, duration)| {
+            let fresh = self.is_fresh(&declared) && self.is_fresh(&inputs) && self.is_fresh(&outputs);
+
+            CacheEntry {
+                fn_name: fn_name,
+                declared_inputs: declared.works(),
+                discovered_inputs: inputs.works(),
+                discovered_outputs: outputs.works(),
+                duration: duration,
+                fresh: fresh,
+            }
+        }).collect()
+    }
+
+    /// Whether every work in `map` is fresh. Kinds without a freshness
+    /// function, e.g. ones registered by another version, are not.
+    fn is_fresh(&self, map: &WorkMap) -> bool {
+        let freshness = self.freshness.read();
+
+        map.works().iter().all(|&(ref kind, ref name, ref value)| {
+            match freshness.find(kind) {
+                Some(f) => (*f)(name.as_slice(), value.as_slice()),
+                None => false,
+            }
+        })
+    }
 }
 
 impl Exec {
+    pub fn label<'a>(&'a self) -> &'a str {
+        self.label.as_slice()
+    }
+
     pub fn discover_input(&mut self, kind: &str, name: &str, value: ~str) {
         debug!("Discovering input {} {} {:?}", kind, name, value);
         self.discovered_inputs.insert_work_key(WorkKey::new(kind, name), value)
@@ -328,10 +1085,35 @@ impl Prep {
         Prep {
             ctxt: ctxt,
             fn_name: fn_name.into_maybe_owned(),
+            label: None,
             declared_inputs: WorkMap::new()
         }
     }
 
+    /// Set a human readable description of this work for reports. It
+    /// defaults to the function name.
+    pub fn set_label(&mut self, label: ~str) {
+        self.label = Some(label);
+    }
+
+    fn label(&self) -> ~str {
+        match self.label {
+            Some(ref label) => label.clone(),
+            None => self.fn_name.as_slice().to_owned(),
+        }
+    }
+
+    fn record_event(&self, start: u64, duration: u64, cached: bool, discovered_inputs: &WorkMap) {
+        self.ctxt.events.lock().push(ExecEvent {
+            fn_name: self.fn_name.as_slice().to_owned(),
+            label: self.label(),
+            start: start,
+            duration: duration,
+            cached: cached,
+            discovered_inputs: discovered_inputs.works(),
+        });
+    }
+
     pub fn lookup_declared_inputs(&self) -> Vec<~str> {
         let mut rs = Vec::new();
         let WorkMap(ref declared_inputs) = self.declared_inputs;
@@ -353,35 +1135,165 @@ impl Prep {
 
     fn is_fresh(&self, cat: &str, kind: &str, name: &str, value: &str) -> bool {
         let k = kind.to_owned();
-        let f = self.ctxt.freshness.find(&k);
+        let freshness = self.ctxt.freshness.read();
+        let f = freshness.find(&k);
         debug!("freshness for: {}/{}/{}/{}", cat, kind, name, value)
         let fresh = match f {
             None => fail!("missing freshness-function for '{}'", kind),
             Some(f) => (*f)(name, value),
         };
 
+        // Only formatted when debug logging is on, since this runs for
+        // every work of every step.
         if fresh {
-            self.ctxt.logger.debug(format!("{} {}:{} is fresh", cat, kind, name));
+            debug!("{} {}:{} is fresh", cat, kind, name);
         } else {
-            self.ctxt.logger.debug(format!("{} {}:{} is not fresh", cat, kind, name));
+            debug!("{} {}:{} is not fresh", cat, kind, name);
         }
 
         fresh
     }
 
-    fn all_fresh(&self, cat: &str, map: &WorkMap) -> bool {
-        let WorkMap(ref map) = *map;
+    /// Describes the works in `map` that aren't fresh, with the value that
+    /// was recorded for them.
+    fn stale(&self, cat: &str, map: &WorkMap) -> Vec<~str> {
+        let mut stale = Vec::new();
+        for (kind, name, value) in map.works().move_iter() {
+            if !self.is_fresh(cat, kind.as_slice(), name.as_slice(), value.as_slice()) {
+                stale.push(format!("{} {} `{}` is out of date\n        was: {}", cat, kind, name, value));
+            }
+        }
+        stale
+    }
+
+    /// Describes how the declared inputs differ from those of the closest
+    /// exec in the database.
+    fn changed(&self) -> Vec<~str> {
+        let previous = self.ctxt.db.read().closest_entry(self.fn_name.as_slice(), &self.declared_inputs);
+
+        let previous = match previous {
+            Some(previous) => previous,
+            None => { return vec!(~"it has not run before"); }
+        };
+
+        let mut changed = Vec::new();
+        for (kind, name, value) in self.declared_inputs.works().move_iter() {
+            match previous.find(kind.as_slice(), name.as_slice()) {
+                Some(old) if *old == value => { }
+                Some(old) => {
+                    changed.push(format!("{} `{}` changed\n        old: {}\n        new: {}",
+                                         kind, name, old, value));
+                }
+                None => { changed.push(format!("new input {} `{}`", kind, name)); }
+            }
+        }
+
+        for (kind, name, _) in previous.works().move_iter() {
+            if self.declared_inputs.find(kind.as_slice(), name.as_slice()).is_none() {
+                changed.push(format!("input {} `{}` was removed", kind, name));
+            }
+        }
 
-        for (k_name, kindmap) in map.iter() {
-            let KindMap(ref kindmap_) = *kindmap;
-            for (k_kind, value) in kindmap_.iter() {
-                if !self.is_fresh(cat, *k_kind, *k_name, *value) {
-                    return false;
+        changed
+    }
+
+    fn explain(&self, reasons: Vec<~str>) {
+        let logger = &self.ctxt.logger;
+        logger.info(format!(" * {:10}: {}", "explain", self.label()));
+        for reason in reasons.iter() {
+            logger.info(format!("     {}", reason));
+        }
+    }
+
+    fn all_fresh(&self, cat: &str, map: &WorkMap) -> bool {
+        let mut unchecked = Vec::new();
+        let generation;
+
+        {
+            let memo = self.ctxt.fresh_memo.lock();
+            generation = memo.generation;
+            for work in map.works().move_iter() {
+                match memo.works.find(&work) {
+                    Some(&true) => { }
+                    Some(&false) => { return false; }
+                    None => { unchecked.push(work); }
                 }
             }
         }
 
-        true
+        if unchecked.is_empty() {
+            return true;
+        }
+
+        let checked = if unchecked.len() == 1 || self.ctxt.freshness_jobs == 1 {
+            let mut checked = Vec::new();
+            for (kind, name, value) in unchecked.move_iter() {
+                let fresh = self.is_fresh(cat, kind.as_slice(), name.as_slice(), value.as_slice());
+                checked.push(((kind, name, value), fresh));
+                if !fresh { break; }
+            }
+            checked
+        } else {
+            self.check_parallel(cat, unchecked)
+        };
+
+        // Files may have changed while they were checked, in which case
+        // the results are only good for this exec.
+        let mut memo = self.ctxt.fresh_memo.lock();
+        let current = memo.generation == generation;
+        let mut all_fresh = true;
+        for (work, fresh) in checked.move_iter() {
+            all_fresh = all_fresh && fresh;
+            if current {
+                memo.works.insert(work, fresh);
+            }
+        }
+
+        all_fresh
+    }
+
+    /// Checks `works` in `freshness_jobs` tasks, since each check may hash
+    /// a large file.
+    fn check_parallel(&self, cat: &str, works: Vec<(~str, ~str, ~str)>) -> Vec<((~str, ~str, ~str), bool)> {
+        let count = works.len();
+        let jobs = cmp::min(self.ctxt.freshness_jobs, count);
+
+        let mut batches = Vec::from_fn(jobs, |_| Vec::new());
+        {
+            let freshness = self.ctxt.freshness.read();
+            for (i, work) in works.move_iter().enumerate() {
+                let f = match freshness.find(work.ref0()) {
+                    Some(f) => *f,
+                    None => fail!("missing freshness-function for '{}'", work.ref0()),
+                };
+                batches.get_mut(i % jobs).push((work, f));
+            }
+        }
+
+        let (tx, rx) = channel();
+        for batch in batches.move_iter() {
+            let tx = tx.clone();
+            spawn(proc() {
+                for ((kind, name, value), f) in batch.move_iter() {
+                    let fresh = f(name.as_slice(), value.as_slice());
+                    tx.send(((kind, name, value), fresh));
+                }
+            });
+        }
+        drop(tx);
+
+        let checked: Vec<((~str, ~str, ~str), bool)> = rx.iter().collect();
+
+        // A check that failed took its task down, just as it would have
+        // failed the caller.
+        if checked.len() != count {
+            fail!("checking the freshness of {} failed", cat);
+        }
+
+        for &((ref kind, ref name, _), fresh) in checked.iter() {
+            debug!("{} {}:{} is {}", cat, kind, name, if fresh { "fresh" } else { "not fresh" });
+        }
+        checked
     }
 
     pub fn exec<
@@ -390,55 +1302,142 @@ impl Prep {
     >(self, blk: proc(&mut Exec):Send -> T) -> Future<T> {
         debug!("exec_work: looking up {} and {:?}", self.fn_name, self.declared_inputs);
 
-        let cached: Option<(WorkMap, WorkMap, T)> = self.ctxt.db.read().prepare(
-            self.fn_name.as_slice(),
-            &self.declared_inputs);
+        let key = json_encode(&(self.fn_name.as_slice(), &self.declared_inputs));
+
+        // An identical exec was already started in this run.
+        let running = self.ctxt.running.lock().find(&key).map(|shared| shared.clone());
+        match running {
+            Some(shared) => {
+                debug!("Sharing a running exec");
+                return Future::from_fn(proc() json_decode(shared.get()));
+            }
+            None => { }
+        }
+
+        let cached = self.ctxt.db.read().prepare(self.fn_name.as_slice(), &self.declared_inputs);
 
         match cached {
-            Some((disc_in, disc_out, res)) => {
+            Some(record) => {
+                // Hits return right away, without spawning anything.
                 if self.all_fresh("declared input", &self.declared_inputs) &&
-                   self.all_fresh("discovered input", &disc_in) &&
-                   self.all_fresh("discovered output", &disc_out) {
+                   self.all_fresh("discovered input", &record.discovered_inputs) &&
+                   self.all_fresh("discovered output", &record.discovered_outputs) {
 
-                    debug!("Cache hit!");
-                    debug!("Trying to decode: {:?} / {:?} / {:?}",
-                        disc_in,
-                        disc_out,
-                        res);
+                    // A result cached by an older rbuild may have a
+                    // different type, and is run again.
+                    match json_try_decode(record.result) {
+                        Some(value) => {
+                            debug!("Cache hit!");
 
-                    return Future::from_value(res);
+                            self.record_event(time::precise_time_ns(), 0, true, &record.discovered_inputs);
+
+                            return Future::from_value(value);
+                        }
+                        None => { debug!("Cached result doesn't decode"); }
+                    }
+                }
+
+                if self.ctxt.explain {
+                    let mut reasons = self.stale("declared input", &self.declared_inputs);
+                    reasons.push_all_move(self.stale("discovered input", &record.discovered_inputs));
+                    reasons.push_all_move(self.stale("discovered output", &record.discovered_outputs));
+                    self.explain(reasons);
+                }
+            }
+            None => {
+                if self.ctxt.explain {
+                    self.explain(self.changed());
                 }
             }
-            None => { }
         }
 
         debug!("Cache miss!");
 
-        // FIXME: What happens if the task fails?
+        // Don't start any new work once something has failed.
+        if self.ctxt.is_aborted() {
+            let err = self.error(~"build aborted after an earlier failure");
+            self.ctxt.cascaded.fetch_add(1, SeqCst);
+            return Future::from_fn(proc() { fail!(err) });
+        }
+
+        // Run the proc in its own task so that a failure can be caught and
+        // reported with the context of what was being built.
+        let label = self.label();
+        let fresh_memo = self.ctxt.fresh_memo.clone();
         let future = Future::spawn(proc() {
-            let mut exe = Exec {
-                discovered_inputs: WorkMap::new(),
-                discovered_outputs: WorkMap::new(),
-            };
-            let v = blk(&mut exe);
-            (exe, v)
+            let result = task::try(proc() {
+                let mut exe = Exec {
+                    discovered_inputs: WorkMap::new(),
+                    discovered_outputs: WorkMap::new(),
+                    label: label,
+                    start: time::precise_time_ns(),
+                    duration: 0,
+                };
+                let v = blk(&mut exe);
+                exe.duration = time::precise_time_ns() - exe.start;
+                (exe, v)
+            });
+
+            // The proc may have changed any file, even if it failed.
+            fresh_memo.lock().clear();
+
+            result
         });
 
+        let running = self.ctxt.running.clone();
         let prep = RefCell::new(self);
 
-        Future::from_fn(proc() {
+        let encoded = Future::from_fn(proc() {
             let prep = prep.unwrap();
-            let (exe, value) = future.unwrap();
+
+            let (exe, value) = match future.unwrap() {
+                Ok(result) => result,
+                Err(cause) => {
+                    let err = prep.error(failure_msg(&cause));
+
+                    if cause.is::<ExecError>() {
+                        // A step this one depends on failed, and was
+                        // already reported.
+                        prep.ctxt.cascaded.fetch_add(1, SeqCst);
+                    } else {
+                        if !cause.is::<Reported>() {
+                            prep.ctxt.logger.error(err.to_str());
+                        }
+                        prep.ctxt.fail_exec(err.clone());
+                    }
+
+                    fail!(err)
+                }
+            };
+
+            let encoded = json_encode(&value);
 
             prep.ctxt.db.write().cache(
                 prep.fn_name.as_slice(),
                 &prep.declared_inputs,
                 &exe.discovered_inputs,
                 &exe.discovered_outputs,
-                &value);
+                encoded.clone(),
+                exe.duration);
+            prep.ctxt.db.write().record_duration(prep.label().as_slice(), exe.duration);
 
-            value
-        })
+            prep.record_event(exe.start, exe.duration, false, &exe.discovered_inputs);
+
+            encoded
+        });
+
+        let shared = SharedFuture::new(encoded);
+        running.lock().insert(key, shared.clone());
+
+        Future::from_fn(proc() json_decode(shared.get()))
+    }
+
+    fn error(&self, msg: ~str) -> ExecError {
+        ExecError {
+            fn_name: self.fn_name.as_slice().to_owned(),
+            declared_inputs: self.declared_inputs.work_keys(),
+            msg: msg,
+        }
     }
 }
 
@@ -0,0 +1,173 @@
+//! Builds made of several packages, each with its own build function, that
+//! share one context, and so one database, toolchain and job limit. A
+//! package can use the targets of another by name:
+//!
+//! ```ignore
+//! fn build_app(pkg: Package) {
+//!     let lib = pkg.target("util:lib");
+//!     ...
+//! }
+//!
+//! Workspace::new(Context::new())
+//!     .add_package("util", "util", build_util)
+//!     .add_package("app", "app", build_app)
+//!     .load();
+//! ```
+
+use collections::TreeMap;
+use sync::{Arc, Future, Mutex};
+
+use context::Context;
+use into_future::IntoFuture;
+use into_path::IntoPath;
+use shared_future::SharedFuture;
+
+struct PackageEntry {
+    name: ~str,
+    dir: Path,
+    build: fn(Package),
+    loaded: bool,
+}
+
+struct WorkspaceState {
+    ctx: Context,
+    packages: Mutex<Vec<PackageEntry>>,
+    /// The targets of the loaded packages, by "package:target".
+    targets: Mutex<TreeMap<~str, SharedFuture<Path>>>,
+}
+
+pub struct Workspace {
+    state: Arc<WorkspaceState>,
+}
+
+impl Workspace {
+    pub fn new(ctx: Context) -> Workspace {
+        Workspace {
+            state: Arc::new(WorkspaceState {
+                ctx: ctx,
+                packages: Mutex::new(Vec::new()),
+                targets: Mutex::new(TreeMap::new()),
+            }),
+        }
+    }
+
+    /// Add the package `name`, whose sources are in `dir`, built by `build`.
+    /// Its outputs go in a directory of the build root named after it.
+    pub fn add_package<T: IntoPath>(self, name: &str, dir: T, build: fn(Package)) -> Workspace {
+        {
+            let mut packages = self.state.packages.lock();
+            if packages.iter().any(|package| package.name.as_slice() == name) {
+                fail!("package {} was added twice", name);
+            }
+
+            packages.push(PackageEntry {
+                name: name.to_owned(),
+                dir: dir.into_path(),
+                build: build,
+                loaded: false,
+            });
+        }
+        self
+    }
+
+    /// Run the build function of every package, in the order they were
+    /// added. Packages whose targets are used by an earlier package are
+    /// loaded when they're first used.
+    pub fn load(&self) {
+        let names: Vec<~str> = self.state.packages.lock().iter()
+            .map(|package| package.name.clone())
+            .collect();
+
+        for name in names.iter() {
+            load_package(&self.state, name.as_slice());
+        }
+    }
+
+    /// Returns the target named "package:target".
+    pub fn target(&self, name: &str) -> Future<Path> {
+        find_target(&self.state, name)
+    }
+}
+
+#[deriving(Clone)]
+pub struct Package {
+    name: ~str,
+    dir: Path,
+    ctx: Context,
+    state: Arc<WorkspaceState>,
+}
+
+impl Package {
+    pub fn name<'a>(&'a self) -> &'a str {
+        self.name.as_slice()
+    }
+
+    /// The directory the package's sources are in.
+    pub fn dir<'a>(&'a self) -> &'a Path {
+        &self.dir
+    }
+
+    /// The context to build the package with.
+    pub fn ctx(&self) -> Context {
+        self.ctx.clone()
+    }
+
+    /// Make `target` available to other packages as "package:name".
+    pub fn add_target<T: IntoFuture<Path>>(&self, name: &str, target: T) {
+        let key = format!("{}:{}", self.name, name);
+        let mut targets = self.state.targets.lock();
+
+        if targets.contains_key(&key) {
+            fail!("target {} was added twice", key);
+        }
+
+        targets.insert(key, SharedFuture::new(target.into_future()));
+    }
+
+    /// Returns the target `name` of this package, or of another package if
+    /// it's given as "package:target".
+    pub fn target(&self, name: &str) -> Future<Path> {
+        if name.contains_char(':') {
+            find_target(&self.state, name)
+        } else {
+            find_target(&self.state, format!("{}:{}", self.name, name).as_slice())
+        }
+    }
+}
+
+fn find_target(state: &Arc<WorkspaceState>, name: &str) -> Future<Path> {
+    let package = name.split(':').next().unwrap();
+    load_package(state, package);
+
+    match state.targets.lock().find_equiv(&name) {
+        Some(target) => target.clone().into_future(),
+        None => fail!("unknown target {}", name),
+    }
+}
+
+/// Runs the build function of `name`, unless it already ran.
+fn load_package(state: &Arc<WorkspaceState>, name: &str) {
+    let package = {
+        let mut packages = state.packages.lock();
+
+        let entry = match packages.mut_iter().find(|package| package.name.as_slice() == name) {
+            Some(entry) => entry,
+            None => fail!("unknown package {}", name),
+        };
+
+        // Marked before building, so packages that use each other's targets
+        // see the ones added so far instead of recursing forever.
+        if entry.loaded { return; }
+        entry.loaded = true;
+
+        (entry.build, Package {
+            name: entry.name.clone(),
+            dir: entry.dir.clone(),
+            ctx: state.ctx.with_root(state.ctx.root.join(entry.name.as_slice())),
+            state: state.clone(),
+        })
+    };
+
+    let (build, package) = package;
+    build(package);
+}